@@ -0,0 +1,2 @@
+-- Hosts report data loss prevention hits on the clipboard and file transfers
+ALTER TYPE audit_action ADD VALUE IF NOT EXISTS 'DLP_MATCH';
@@ -7,11 +7,12 @@ use chrono::{DateTime, Utc};
 use scrdesk_shared::{
     error::{Error, Result},
     models::{
-        audit::{AuditLog, AuditLogResponse},
+        audit::{AuditAction, AuditLog, AuditLogResponse},
+        policy::{DlpAction, DlpChannel},
         PaginationParams, PaginatedResponse,
     },
 };
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use std::sync::Arc;
 use uuid::Uuid;
 
@@ -193,3 +194,63 @@ pub async fn export_audit_logs(
 
     Ok((StatusCode::OK, Json(log_responses)))
 }
+
+/// A data loss prevention hit a host reports; never the matched data itself
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DlpEventRequest {
+    pub device_id: String,
+    pub channel: DlpChannel,
+    pub rule: String,
+    pub detector: String, // e.g. "credit_card"
+    pub action: DlpAction, // What the host did
+    pub matches: u32,
+    pub filename: Option<String>,
+}
+
+/// Record a data loss prevention hit as an audit event of the reporting device
+pub async fn record_dlp_event(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(payload): Json<DlpEventRequest>,
+) -> Result<StatusCode> {
+    let auth_header = headers
+        .get("Authorization")
+        .and_then(|h| h.to_str().ok())
+        .ok_or_else(|| Error::Authentication("Missing authorization header".to_string()))?;
+
+    let token = auth_header.strip_prefix("Bearer ")
+        .ok_or_else(|| Error::Authentication("Invalid authorization header".to_string()))?;
+
+    let claims = state.jwt_manager.verify_access_token(token)?;
+
+    if payload.rule.len() > 100 || payload.detector.len() > 50 || payload.filename.as_ref().is_some_and(|f| f.len() > 255) {
+        return Err(Error::Validation("DLP event fields are too long".to_string()));
+    }
+
+    let device: Uuid = sqlx::query_scalar(
+        "SELECT id FROM devices WHERE device_id = $1 AND tenant_id = $2"
+    )
+    .bind(&payload.device_id)
+    .bind(claims.tenant_id)
+    .fetch_optional(&state.db_pool)
+    .await?
+    .ok_or_else(|| Error::NotFound("Device not found".to_string()))?;
+
+    let metadata = serde_json::to_value(&payload)
+        .map_err(|e| Error::Internal(e.to_string()))?;
+
+    sqlx::query(
+        "INSERT INTO audit_logs (tenant_id, user_id, action, resource_type, resource_id, metadata)
+         VALUES ($1, $2, $3, $4, $5, $6)"
+    )
+    .bind(claims.tenant_id)
+    .bind(claims.sub)
+    .bind(AuditAction::DlpMatch)
+    .bind("device")
+    .bind(device)
+    .bind(metadata)
+    .execute(&state.db_pool)
+    .await?;
+
+    Ok(StatusCode::CREATED)
+}
@@ -1,4 +1,4 @@
-use axum::{routing::get, Router};
+use axum::{routing::{get, post}, Router};
 use scrdesk_shared::{config::Config, database};
 use std::sync::Arc;
 use tower_http::cors::{Any, CorsLayer};
@@ -49,6 +49,8 @@ async fn main() -> anyhow::Result<()> {
         .route("/api/v1/audit-logs", get(handlers::audit::list_audit_logs))
         .route("/api/v1/audit-logs/:id", get(handlers::audit::get_audit_log))
         .route("/api/v1/audit-logs/export", get(handlers::audit::export_audit_logs))
+        // Data loss prevention hits reported by hosts
+        .route("/api/v1/audit-logs/dlp", post(handlers::audit::record_dlp_event))
         .layer(
             CorsLayer::new()
                 .allow_origin(Any)
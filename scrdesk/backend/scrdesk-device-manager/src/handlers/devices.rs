@@ -171,6 +171,41 @@ pub async fn get_device(
     Ok((StatusCode::OK, Json(device.into())))
 }
 
+/// Get the registered key of an approved device by its user-facing ID, so clients
+/// can check the key a peer presents for end-to-end encryption
+pub async fn get_device_key(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path(device_id): Path<String>,
+) -> Result<(StatusCode, Json<Value>)> {
+    let auth_header = headers
+        .get("Authorization")
+        .and_then(|h| h.to_str().ok())
+        .ok_or_else(|| Error::Authentication("Missing authorization header".to_string()))?;
+
+    let token = auth_header.strip_prefix("Bearer ")
+        .ok_or_else(|| Error::Authentication("Invalid authorization header".to_string()))?;
+
+    let claims = state.jwt_manager.verify_access_token(token)?;
+
+    let public_key: String = sqlx::query_scalar(
+        "SELECT public_key FROM devices WHERE device_id = $1 AND tenant_id = $2 AND is_approved = true"
+    )
+    .bind(&device_id)
+    .bind(claims.tenant_id)
+    .fetch_optional(&state.db_pool)
+    .await?
+    .ok_or_else(|| Error::NotFound("Device not found or not approved".to_string()))?;
+
+    Ok((
+        StatusCode::OK,
+        Json(json!({
+            "device_id": device_id,
+            "public_key": public_key,
+        })),
+    ))
+}
+
 /// Update device
 pub async fn update_device(
     State(state): State<Arc<AppState>>,
@@ -588,3 +623,68 @@ pub async fn request_connection(
         })),
     ))
 }
+
+/// Connection quality a device measured over a session, sent when the session ends
+#[derive(Debug, Serialize, Deserialize, Validate)]
+pub struct SessionQualityReport {
+    #[validate(range(min = 0.0))]
+    pub duration_secs: f64,
+    pub frames_sent: u64,
+    pub frames_received: u64,
+    pub frames_dropped: u64,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    #[validate(range(min = 0.0))]
+    pub avg_rtt_ms: Option<f64>,
+    #[validate(range(min = 0.0))]
+    pub avg_jitter_ms: Option<f64>,
+    #[validate(range(min = 0.0))]
+    pub avg_input_latency_ms: Option<f64>,
+    #[serde(default)]
+    pub redactions: u64, // Screen areas the host masked, summed over frames
+}
+
+/// Record a device's connection quality report with the session it took part in
+pub async fn report_session_quality(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path((device_id, session_id)): Path<(String, Uuid)>,
+    Json(payload): Json<SessionQualityReport>,
+) -> Result<StatusCode> {
+    payload.validate().map_err(|e| Error::Validation(e.to_string()))?;
+
+    let auth_header = headers
+        .get("Authorization")
+        .and_then(|h| h.to_str().ok())
+        .ok_or_else(|| Error::Authentication("Missing authorization header".to_string()))?;
+
+    let token = auth_header.strip_prefix("Bearer ")
+        .ok_or_else(|| Error::Authentication("Invalid authorization header".to_string()))?;
+
+    let claims = state.jwt_manager.verify_access_token(token)?;
+
+    let report = serde_json::to_string(&payload)
+        .map_err(|e| Error::Internal(e.to_string()))?;
+
+    // Each device keeps its own report under metadata.quality
+    let result = sqlx::query(
+        "UPDATE sessions
+         SET metadata = jsonb_set(
+             metadata, '{quality}',
+             COALESCE(metadata->'quality', '{}'::jsonb) || jsonb_build_object($3::text, $4::text::jsonb))
+         WHERE id = $1 AND tenant_id = $2
+           AND EXISTS (SELECT 1 FROM devices WHERE device_id = $3 AND tenant_id = $2)"
+    )
+    .bind(session_id)
+    .bind(claims.tenant_id)
+    .bind(&device_id)
+    .bind(report)
+    .execute(&state.db_pool)
+    .await?;
+
+    if result.rows_affected() == 0 {
+        return Err(Error::NotFound("Session or device not found".to_string()));
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
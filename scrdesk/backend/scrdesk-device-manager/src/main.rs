@@ -55,6 +55,7 @@ async fn main() -> anyhow::Result<()> {
         .route("/api/v1/devices", post(handlers::devices::register_device))
         .route("/api/v1/devices", get(handlers::devices::list_devices))
         .route("/api/v1/devices/:id", get(handlers::devices::get_device))
+        .route("/api/v1/devices/keys/:device_id", get(handlers::devices::get_device_key))
         .route("/api/v1/devices/:id", put(handlers::devices::update_device))
         .route("/api/v1/devices/:id", delete(handlers::devices::delete_device))
         .route("/api/v1/devices/:id/approve", post(handlers::devices::approve_device))
@@ -67,6 +68,8 @@ async fn main() -> anyhow::Result<()> {
         .route("/api/v1/devices/:id/groups/:group_id", delete(handlers::devices::remove_device_from_group))
         // Device connection
         .route("/api/v1/devices/:id/connect", post(handlers::devices::request_connection))
+        // Connection quality a device measured over a session, by the device ID rather than the UUID
+        .route("/api/v1/devices/:id/sessions/:session_id/quality", post(handlers::devices::report_session_quality))
         .layer(
             CorsLayer::new()
                 .allow_origin(Any)
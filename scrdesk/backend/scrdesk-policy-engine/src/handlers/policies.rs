@@ -6,7 +6,7 @@ use axum::{
 use scrdesk_shared::{
     error::{Error, Result},
     models::{
-        policy::{Policy, PolicyResponse},
+        policy::{DlpRule, Policy, PolicyResponse, RedactionZone},
         PaginationParams, PaginatedResponse, PolicyId,
     },
 };
@@ -34,6 +34,7 @@ pub async fn create_policy(
     Json(payload): Json<CreatePolicyRequest>,
 ) -> Result<(StatusCode, Json<PolicyResponse>)> {
     payload.validate().map_err(|e| Error::Validation(e.to_string()))?;
+    payload.rules.check()?;
 
     let auth_header = headers
         .get("Authorization")
@@ -157,6 +158,9 @@ pub async fn update_policy(
     Json(payload): Json<UpdatePolicyRequest>,
 ) -> Result<(StatusCode, Json<PolicyResponse>)> {
     payload.validate().map_err(|e| Error::Validation(e.to_string()))?;
+    if let Some(rules) = &payload.rules {
+        rules.check()?;
+    }
 
     let auth_header = headers
         .get("Authorization")
@@ -539,3 +543,121 @@ pub async fn unassign_policy_from_group(
         })),
     ))
 }
+
+/// The active policies of a device's groups, by the device ID rather than the UUID
+async fn device_policies(state: &AppState, device_id: &str, tenant_id: Uuid) -> Result<Vec<Policy>> {
+    let policies = sqlx::query_as::<_, Policy>(
+        "SELECT DISTINCT p.* FROM policies p
+         JOIN group_policies gp ON p.id = gp.policy_id
+         JOIN device_groups dg ON dg.group_id = gp.group_id
+         JOIN devices d ON d.id = dg.device_id
+         WHERE d.device_id = $1 AND d.tenant_id = $2 AND p.is_active = true AND p.tenant_id = $2"
+    )
+    .bind(device_id)
+    .bind(tenant_id)
+    .fetch_all(&state.db_pool)
+    .await?;
+
+    Ok(policies)
+}
+
+#[derive(Debug, Serialize)]
+pub struct RedactionZonesResponse {
+    pub zones: Vec<RedactionZone>,
+}
+
+/// The redaction zones of the active policies of a device's groups; the host masks
+/// them before frames leave the device
+pub async fn get_device_redactions(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path(device_id): Path<String>,
+) -> Result<(StatusCode, Json<RedactionZonesResponse>)> {
+    let auth_header = headers
+        .get("Authorization")
+        .and_then(|h| h.to_str().ok())
+        .ok_or_else(|| Error::Authentication("Missing authorization header".to_string()))?;
+
+    let token = auth_header.strip_prefix("Bearer ")
+        .ok_or_else(|| Error::Authentication("Invalid authorization header".to_string()))?;
+
+    let claims = state.jwt_manager.verify_access_token(token)?;
+
+    let policies = device_policies(&state, &device_id, claims.tenant_id).await?;
+
+    // Every zone of every policy applies
+    let mut zones = Vec::new();
+    for zone in policies.into_iter().flat_map(|policy| policy.rules.redaction_zones) {
+        if !zones.contains(&zone) {
+            zones.push(zone);
+        }
+    }
+
+    Ok((StatusCode::OK, Json(RedactionZonesResponse { zones })))
+}
+
+#[derive(Debug, Serialize)]
+pub struct WatermarkPolicyResponse {
+    pub required: bool,
+    pub policy_id: Option<PolicyId>, // The first policy that requires it
+}
+
+/// Whether a policy of a device's groups requires it to watermark remote screens
+pub async fn get_device_watermark(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path(device_id): Path<String>,
+) -> Result<(StatusCode, Json<WatermarkPolicyResponse>)> {
+    let auth_header = headers
+        .get("Authorization")
+        .and_then(|h| h.to_str().ok())
+        .ok_or_else(|| Error::Authentication("Missing authorization header".to_string()))?;
+
+    let token = auth_header.strip_prefix("Bearer ")
+        .ok_or_else(|| Error::Authentication("Invalid authorization header".to_string()))?;
+
+    let claims = state.jwt_manager.verify_access_token(token)?;
+
+    let policy_id = device_policies(&state, &device_id, claims.tenant_id)
+        .await?
+        .into_iter()
+        .find(|policy| policy.rules.require_watermark)
+        .map(|policy| policy.id);
+
+    Ok((StatusCode::OK, Json(WatermarkPolicyResponse { required: policy_id.is_some(), policy_id })))
+}
+
+#[derive(Debug, Serialize)]
+pub struct DlpRulesResponse {
+    pub rules: Vec<DlpRule>,
+}
+
+/// The data loss prevention rules of the active policies of a device's groups; the
+/// host inspects its clipboard and outgoing files with them
+pub async fn get_device_dlp_rules(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path(device_id): Path<String>,
+) -> Result<(StatusCode, Json<DlpRulesResponse>)> {
+    let auth_header = headers
+        .get("Authorization")
+        .and_then(|h| h.to_str().ok())
+        .ok_or_else(|| Error::Authentication("Missing authorization header".to_string()))?;
+
+    let token = auth_header.strip_prefix("Bearer ")
+        .ok_or_else(|| Error::Authentication("Invalid authorization header".to_string()))?;
+
+    let claims = state.jwt_manager.verify_access_token(token)?;
+
+    let policies = device_policies(&state, &device_id, claims.tenant_id).await?;
+
+    // Every rule of every policy applies
+    let mut rules = Vec::new();
+    for rule in policies.into_iter().flat_map(|policy| policy.rules.dlp_rules) {
+        if !rules.contains(&rule) {
+            rules.push(rule);
+        }
+    }
+
+    Ok((StatusCode::OK, Json(DlpRulesResponse { rules })))
+}
@@ -61,6 +61,10 @@ async fn main() -> anyhow::Result<()> {
         .route("/api/v1/policies/:id/groups", get(handlers::policies::get_policy_groups))
         .route("/api/v1/policies/:id/groups/:group_id", post(handlers::policies::assign_policy_to_group))
         .route("/api/v1/policies/:id/groups/:group_id", delete(handlers::policies::unassign_policy_from_group))
+        // What a device must enforce on screens and outgoing data, by the device ID rather than the UUID
+        .route("/api/v1/policies/devices/:device_id/redactions", get(handlers::policies::get_device_redactions))
+        .route("/api/v1/policies/devices/:device_id/watermark", get(handlers::policies::get_device_watermark))
+        .route("/api/v1/policies/devices/:device_id/dlp", get(handlers::policies::get_device_dlp_rules))
         .layer(
             CorsLayer::new()
                 .allow_origin(Any)
@@ -1,6 +1,15 @@
-use axum::{http::StatusCode, Json};
+use axum::{extract::State, http::StatusCode, Json};
 use serde_json::{json, Value};
+use std::sync::Arc;
+use crate::AppState;
 
-pub async fn health_check() -> (StatusCode, Json<Value>) {
-    (StatusCode::OK, Json(json!({"status": "ok", "service": "scrdesk-relay-cluster", "version": env!("CARGO_PKG_VERSION")})))
+/// Reports 503 while draining so load balancers stop sending new clients here
+pub async fn health_check(State(state): State<Arc<AppState>>) -> (StatusCode, Json<Value>) {
+    let (status_code, status) = if state.sessions.is_draining() {
+        (StatusCode::SERVICE_UNAVAILABLE, "draining")
+    } else {
+        (StatusCode::OK, "ok")
+    };
+
+    (status_code, Json(json!({"status": status, "service": "scrdesk-relay-cluster", "version": env!("CARGO_PKG_VERSION")})))
 }
@@ -1,13 +1,112 @@
-use axum::{extract::State, http::StatusCode, Json};
+use axum::{extract::{Path, Query, State}, http::{header, HeaderMap, StatusCode}, response::IntoResponse, Json};
+use scrdesk_shared::{error::{Error, Result}, models::UserRole};
+use serde::Deserialize;
 use serde_json::{json, Value};
 use std::sync::Arc;
 use crate::AppState;
 
-pub async fn get_relay_status(State(_state): State<Arc<AppState>>) -> (StatusCode, Json<Value>) {
+#[derive(Debug, Deserialize)]
+pub struct TerminateParams {
+    pub reason: Option<String>,
+}
+
+pub async fn get_relay_status(State(state): State<Arc<AppState>>) -> (StatusCode, Json<Value>) {
+    let metrics = state.sessions.metrics_snapshot().await;
+
     (StatusCode::OK, Json(json!({
-        "status": "running",
-        "port": 21117,
-        "active_connections": 0,
-        "protocol": "RustDesk compatible"
+        "status": if state.sessions.is_draining() { "draining" } else { "running" },
+        "port": state.config.relay.port,
+        "node_id": state.sessions.cluster().map(|c| c.node_id().to_string()),
+        "active_connections": metrics.connected_clients,
+        "active_sessions": metrics.active_sessions,
+        "protocol": "RustDesk compatible",
+        "metrics": metrics
     })))
 }
+
+/// Per-tenant relay usage on this node, for billing
+pub async fn get_usage(State(state): State<Arc<AppState>>, headers: HeaderMap) -> Result<(StatusCode, Json<Value>)> {
+    require_admin(&state, &headers)?;
+    let tenants = state.sessions.limits().usage();
+
+    Ok((StatusCode::OK, Json(json!({
+        "node_id": state.sessions.cluster().map(|c| c.node_id().to_string()),
+        "tenants": tenants
+    }))))
+}
+
+/// Prometheus scrape endpoint
+pub async fn get_metrics(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    let metrics = state.sessions.metrics_snapshot().await;
+
+    (
+        StatusCode::OK,
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        metrics.to_prometheus(),
+    )
+}
+
+/// Live sessions on this node, with their peers and traffic
+pub async fn list_sessions(State(state): State<Arc<AppState>>, headers: HeaderMap) -> Result<(StatusCode, Json<Value>)> {
+    require_admin(&state, &headers)?;
+    let sessions = state.sessions.list_sessions().await;
+
+    Ok((StatusCode::OK, Json(json!({
+        "node_id": state.sessions.cluster().map(|c| c.node_id().to_string()),
+        "draining": state.sessions.is_draining(),
+        "sessions": sessions
+    }))))
+}
+
+/// Forcibly end a session; both peers receive `Disconnect` with the reason
+pub async fn terminate_session(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path(session_id): Path<String>,
+    Query(params): Query<TerminateParams>,
+) -> Result<StatusCode> {
+    require_admin(&state, &headers)?;
+    let reason = params.reason.unwrap_or_else(|| "Session terminated by administrator".to_string());
+
+    if !state.sessions.terminate_session(&session_id, &reason).await {
+        return Err(Error::NotFound(format!("Session {} is not active on this relay", session_id)));
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Stop accepting new sessions; existing ones run until they end
+pub async fn start_drain(State(state): State<Arc<AppState>>, headers: HeaderMap) -> Result<(StatusCode, Json<Value>)> {
+    require_admin(&state, &headers)?;
+    state.sessions.set_draining(true);
+    Ok(drain_status(&state).await)
+}
+
+pub async fn stop_drain(State(state): State<Arc<AppState>>, headers: HeaderMap) -> Result<(StatusCode, Json<Value>)> {
+    require_admin(&state, &headers)?;
+    state.sessions.set_draining(false);
+    Ok(drain_status(&state).await)
+}
+
+async fn drain_status(state: &AppState) -> (StatusCode, Json<Value>) {
+    let metrics = state.sessions.metrics_snapshot().await;
+
+    (StatusCode::OK, Json(json!({
+        "draining": state.sessions.is_draining(),
+        "active_sessions": metrics.active_sessions
+    })))
+}
+
+fn require_admin(state: &AppState, headers: &HeaderMap) -> Result<()> {
+    let auth_header = headers.get("Authorization").and_then(|h| h.to_str().ok())
+        .ok_or_else(|| Error::Authentication("Missing authorization header".to_string()))?;
+    let token = auth_header.strip_prefix("Bearer ")
+        .ok_or_else(|| Error::Authentication("Invalid authorization header".to_string()))?;
+    let claims = state.jwt_manager.verify_access_token(token)?;
+
+    if claims.role != UserRole::SuperAdmin {
+        return Err(Error::Authorization("Super admin access required".to_string()));
+    }
+
+    Ok(())
+}
@@ -1,13 +1,20 @@
-use axum::{routing::get, Router};
-use scrdesk_shared::config::Config;
+use axum::{routing::{delete, get, post}, Router};
+use relay::{cluster::Cluster, limits::Limits, store::SessionStore, Keepalive, SessionManager};
+use scrdesk_shared::{auth::JwtManager, config::Config, database};
 use std::sync::Arc;
+use std::time::Duration;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
 mod handlers;
 mod relay;
 
+/// Time given to clients to receive the shutdown notice before the process exits
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(2);
+
 pub struct AppState {
     pub config: Config,
+    pub jwt_manager: Arc<JwtManager>,
+    pub sessions: Arc<SessionManager>,
 }
 
 #[tokio::main]
@@ -21,26 +28,122 @@ async fn main() -> anyhow::Result<()> {
 
     dotenv::dotenv().ok();
     let config = Config::from_env()?;
-    let state = Arc::new(AppState { config: config.clone() });
+
+    // Share device presence through Redis so any node can reach any device
+    let node_id = config.relay.node_id.clone()
+        .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+    let jwt_manager = Arc::new(JwtManager::new(
+        &config.jwt.secret,
+        config.jwt.access_token_expiry,
+        config.jwt.refresh_token_expiry,
+    ));
+    let mut manager = SessionManager::new()
+        .with_auth(jwt_manager.clone())
+        .with_keepalive(Keepalive {
+            interval: Duration::from_secs(config.relay.ping_interval_secs.max(1)),
+            max_missed: config.relay.max_missed_pings,
+        })
+        .with_resume_grace(Duration::from_secs(config.relay.resume_grace_secs));
+    if config.relay.rendezvous_port > 0 {
+        manager = manager.with_rendezvous_port(config.relay.rendezvous_port);
+    }
+
+    match Cluster::connect(&config.redis.url, node_id.clone(), config.relay.presence_ttl_secs).await {
+        Ok(cluster) => {
+            tracing::info!("Relay node {} joined the cluster", node_id);
+            manager = manager.with_cluster(Arc::new(cluster));
+        }
+        Err(e) => tracing::warn!("Redis unavailable, running as a standalone relay: {}", e),
+    }
+
+    // Session history for the admin panel
+    let db_pool = match database::create_pool(&config.database).await {
+        Ok(db_pool) => {
+            manager = manager.with_store(SessionStore::start(db_pool.clone(), node_id.clone()));
+            Some(db_pool)
+        }
+        Err(e) => {
+            tracing::warn!("Database unavailable, sessions will not be recorded: {}", e);
+            None
+        }
+    };
+
+    // Tenant session limits come from the database; bandwidth caps from config
+    let kbps = |limit: Option<u64>| limit.map(|kbps| kbps * 1024);
+    manager = manager.with_limits(Limits::new(
+        db_pool,
+        kbps(config.relay.session_bandwidth_kbps),
+        kbps(config.relay.tenant_bandwidth_kbps),
+    ));
+
+    let sessions = Arc::new(manager);
+    let state = Arc::new(AppState {
+        config: config.clone(),
+        jwt_manager,
+        sessions: sessions.clone(),
+    });
 
     // Management API
     let app = Router::new()
         .route("/health", get(handlers::health::health_check))
         .route("/api/v1/relay/status", get(handlers::relay::get_relay_status))
+        .route("/api/v1/relay/usage", get(handlers::relay::get_usage))
+        .route("/api/v1/relay/sessions", get(handlers::relay::list_sessions))
+        .route("/api/v1/relay/sessions/:session_id", delete(handlers::relay::terminate_session))
+        .route("/api/v1/relay/drain", post(handlers::relay::start_drain).delete(handlers::relay::stop_drain))
+        .route("/metrics", get(handlers::relay::get_metrics))
         .with_state(state.clone());
 
-    let mgmt_addr = format!("{}:21116", config.server.host);
+    let mgmt_addr = format!("{}:{}", config.server.host, config.server.port);
     tracing::info!("Management API listening on {}", mgmt_addr);
 
     // Start relay server in background
     let relay_config = config.clone();
+    let relay_sessions = sessions.clone();
     tokio::spawn(async move {
-        if let Err(e) = relay::start_relay_server(relay_config).await {
+        if let Err(e) = relay::start_relay_server(relay_config, relay_sessions).await {
             tracing::error!("Relay server error: {}", e);
         }
     });
 
     let listener = tokio::net::TcpListener::bind(&mgmt_addr).await?;
-    axum::serve(listener, app).await?;
+    axum::serve(listener, app)
+        .with_graceful_shutdown(shutdown_signal())
+        .await?;
+
+    tracing::info!("Shutting down, notifying connected clients");
+    sessions.shutdown("Relay server shutting down").await;
+    tokio::time::sleep(SHUTDOWN_GRACE).await;
     Ok(())
 }
+
+/// Resolves on Ctrl+C or SIGTERM
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            tracing::error!("Failed to listen for Ctrl+C: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(e) => {
+                tracing::error!("Failed to listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
@@ -0,0 +1,324 @@
+use anyhow::{Context, Result};
+use futures::StreamExt;
+use redis::aio::ConnectionManager;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+use uuid::Uuid;
+use super::participants::Roster;
+
+const DEVICE_KEY_PREFIX: &str = "relay:device:";
+const NODE_CHANNEL_PREFIX: &str = "relay:node:";
+const TENANT_SESSIONS_PREFIX: &str = "relay:tenant-sessions:";
+
+// Delete the presence key only if it still points at this node, so a device
+// that already reconnected elsewhere is not removed by a late disconnect
+const UNREGISTER_SCRIPT: &str = r#"
+if redis.call("GET", KEYS[1]) == ARGV[1] then
+    return redis.call("DEL", KEYS[1])
+end
+return 0
+"#;
+
+// Take a concurrent session slot for a tenant if one is free. Slots are
+// scored by expiry, so sessions of a node that died are eventually released.
+const ACQUIRE_SESSION_SCRIPT: &str = r#"
+redis.call("ZREMRANGEBYSCORE", KEYS[1], "-inf", ARGV[1])
+if redis.call("ZCARD", KEYS[1]) >= tonumber(ARGV[4]) then
+    return 0
+end
+redis.call("ZADD", KEYS[1], ARGV[2], ARGV[3])
+return 1
+"#;
+
+/// Events exchanged between relay nodes over Redis pub/sub
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(tag = "kind")]
+pub enum ClusterEvent {
+    /// Current state of a session with a participant on the receiving node,
+    /// sent whenever the session is opened or its participants change
+    SessionSync {
+        session_id: String,
+        origin_node: String, // Node that opened the session and records it
+        roster: Roster,
+        #[serde(default)]
+        tenant_id: Option<Uuid>, // Tenant the session is billed to
+        #[serde(default)]
+        resume_tokens: HashMap<String, String>, // So participants can resume on any node holding the session
+    },
+    SessionClosed {
+        session_id: String,
+    },
+    /// Relay a WebSocket message to a device connected to the receiving node
+    Deliver {
+        from: String,
+        to: String,
+        binary: bool,
+    },
+}
+
+/// An event plus the raw message it carries (only used by `Deliver`)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Envelope {
+    pub from_node: String,
+    pub event: ClusterEvent,
+    pub payload: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct EnvelopeHeader {
+    from_node: String,
+    #[serde(flatten)]
+    event: ClusterEvent,
+}
+
+impl Envelope {
+    /// JSON header, a newline, then the payload bytes as-is
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let header = EnvelopeHeader {
+            from_node: self.from_node.clone(),
+            event: self.event.clone(),
+        };
+        let mut bytes = serde_json::to_vec(&header)?;
+        bytes.push(b'\n');
+        bytes.extend_from_slice(&self.payload);
+        Ok(bytes)
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self> {
+        let split = bytes
+            .iter()
+            .position(|b| *b == b'\n')
+            .context("Malformed cluster envelope")?;
+        let header: EnvelopeHeader = serde_json::from_slice(&bytes[..split])?;
+
+        Ok(Self {
+            from_node: header.from_node,
+            event: header.event,
+            payload: bytes[split + 1..].to_vec(),
+        })
+    }
+}
+
+/// Redis-backed presence registry (device ID -> relay node) and the
+/// channel used to forward traffic between nodes.
+pub struct Cluster {
+    node_id: String,
+    client: redis::Client,
+    conn: ConnectionManager,
+    presence_ttl: u64,
+}
+
+impl Cluster {
+    pub async fn connect(redis_url: &str, node_id: String, presence_ttl: u64) -> Result<Self> {
+        let client = redis::Client::open(redis_url).context("Invalid Redis URL")?;
+        let conn = client
+            .get_connection_manager()
+            .await
+            .context("Failed to connect to Redis")?;
+
+        Ok(Self {
+            node_id,
+            client,
+            conn,
+            presence_ttl: presence_ttl.max(1),
+        })
+    }
+
+    pub fn node_id(&self) -> &str {
+        &self.node_id
+    }
+
+    pub fn presence_ttl(&self) -> u64 {
+        self.presence_ttl
+    }
+
+    pub async fn register_device(&self, device_id: &str) -> Result<()> {
+        let mut conn = self.conn.clone();
+        redis::cmd("SET")
+            .arg(device_key(device_id))
+            .arg(&self.node_id)
+            .arg("EX")
+            .arg(self.presence_ttl)
+            .query_async::<_, ()>(&mut conn)
+            .await
+            .context("Failed to register device presence")
+    }
+
+    pub async fn unregister_device(&self, device_id: &str) -> Result<()> {
+        let mut conn = self.conn.clone();
+        redis::Script::new(UNREGISTER_SCRIPT)
+            .key(device_key(device_id))
+            .arg(&self.node_id)
+            .invoke_async::<_, i64>(&mut conn)
+            .await
+            .context("Failed to remove device presence")?;
+        Ok(())
+    }
+
+    /// Extend the presence of every device connected to this node
+    pub async fn refresh_devices(&self, device_ids: &[String]) -> Result<()> {
+        if device_ids.is_empty() {
+            return Ok(());
+        }
+
+        let mut pipe = redis::pipe();
+        for device_id in device_ids {
+            pipe.cmd("SET")
+                .arg(device_key(device_id))
+                .arg(&self.node_id)
+                .arg("EX")
+                .arg(self.presence_ttl)
+                .ignore();
+        }
+
+        let mut conn = self.conn.clone();
+        pipe.query_async::<_, ()>(&mut conn)
+            .await
+            .context("Failed to refresh device presence")
+    }
+
+    /// Reserve one of the tenant's `limit` concurrent session slots, cluster-wide
+    pub async fn try_acquire_session(&self, tenant_id: Uuid, session_id: &str, limit: u32) -> Result<bool> {
+        let now = unix_time();
+        let mut conn = self.conn.clone();
+        let acquired: i64 = redis::Script::new(ACQUIRE_SESSION_SCRIPT)
+            .key(tenant_sessions_key(tenant_id))
+            .arg(now)
+            .arg(now + self.presence_ttl)
+            .arg(session_id)
+            .arg(limit)
+            .invoke_async(&mut conn)
+            .await
+            .context("Failed to reserve session slot")?;
+        Ok(acquired == 1)
+    }
+
+    pub async fn release_session(&self, tenant_id: Uuid, session_id: &str) -> Result<()> {
+        let mut conn = self.conn.clone();
+        redis::cmd("ZREM")
+            .arg(tenant_sessions_key(tenant_id))
+            .arg(session_id)
+            .query_async::<_, i64>(&mut conn)
+            .await
+            .context("Failed to release session slot")?;
+        Ok(())
+    }
+
+    /// Keep the session slots held by this node from expiring
+    pub async fn refresh_sessions(&self, sessions: &[(Uuid, String)]) -> Result<()> {
+        if sessions.is_empty() {
+            return Ok(());
+        }
+
+        let expiry = unix_time() + self.presence_ttl;
+        let mut pipe = redis::pipe();
+        for (tenant_id, session_id) in sessions {
+            pipe.cmd("ZADD")
+                .arg(tenant_sessions_key(*tenant_id))
+                .arg("XX")
+                .arg(expiry)
+                .arg(session_id)
+                .ignore();
+        }
+
+        let mut conn = self.conn.clone();
+        pipe.query_async::<_, ()>(&mut conn)
+            .await
+            .context("Failed to refresh session slots")
+    }
+
+    /// Node the device is connected to, if it is online anywhere in the cluster
+    pub async fn locate(&self, device_id: &str) -> Result<Option<String>> {
+        let mut conn = self.conn.clone();
+        redis::cmd("GET")
+            .arg(device_key(device_id))
+            .query_async(&mut conn)
+            .await
+            .context("Failed to look up device presence")
+    }
+
+    pub async fn publish(&self, node_id: &str, event: ClusterEvent, payload: Vec<u8>) -> Result<()> {
+        let envelope = Envelope {
+            from_node: self.node_id.clone(),
+            event,
+            payload,
+        };
+
+        let mut conn = self.conn.clone();
+        redis::cmd("PUBLISH")
+            .arg(format!("{}{}", NODE_CHANNEL_PREFIX, node_id))
+            .arg(envelope.encode()?)
+            .query_async::<_, i64>(&mut conn)
+            .await
+            .context("Failed to forward message to relay node")?;
+        Ok(())
+    }
+
+    /// Receive events addressed to this node until the subscription drops
+    pub async fn subscribe(&self, events: mpsc::UnboundedSender<Envelope>) -> Result<()> {
+        let mut pubsub = self.client
+            .get_async_connection()
+            .await
+            .context("Failed to connect to Redis")?
+            .into_pubsub();
+        pubsub
+            .subscribe(format!("{}{}", NODE_CHANNEL_PREFIX, self.node_id))
+            .await
+            .context("Failed to subscribe to node channel")?;
+
+        let mut messages = pubsub.on_message();
+        while let Some(msg) = messages.next().await {
+            match Envelope::decode(msg.get_payload_bytes()) {
+                Ok(envelope) => {
+                    if events.send(envelope).is_err() {
+                        break;
+                    }
+                }
+                Err(e) => tracing::warn!("Ignoring malformed cluster message: {}", e),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn device_key(device_id: &str) -> String {
+    format!("{}{}", DEVICE_KEY_PREFIX, device_id)
+}
+
+fn tenant_sessions_key(tenant_id: Uuid) -> String {
+    format!("{}{}", TENANT_SESSIONS_PREFIX, tenant_id)
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_envelope_round_trip() -> Result<()> {
+        let envelope = Envelope {
+            from_node: "node-a".to_string(),
+            event: ClusterEvent::Deliver {
+                from: "111".to_string(),
+                to: "222".to_string(),
+                binary: true,
+            },
+            payload: vec![0, b'\n', 255, 7],
+        };
+
+        let decoded = Envelope::decode(&envelope.encode()?)?;
+        assert_eq!(decoded, envelope);
+
+        assert!(Envelope::decode(b"no header").is_err());
+        Ok(())
+    }
+}
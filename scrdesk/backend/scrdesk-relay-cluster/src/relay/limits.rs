@@ -0,0 +1,238 @@
+use anyhow::{Context, Result};
+use scrdesk_shared::models::PlanType;
+use serde::Serialize;
+use sqlx::PgPool;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+/// How long a tenant's session limit is cached before it is read again
+const TENANT_LIMIT_TTL: Duration = Duration::from_secs(60);
+
+/// Bandwidth usage of one tenant on this relay node, for billing
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct TenantUsage {
+    pub bytes_relayed: u64,
+    pub sessions_started: u64,
+    pub active_sessions: u64,
+}
+
+/// Tenant session limits and relay bandwidth caps.
+///
+/// Bandwidth caps are token buckets kept per session and per tenant on this
+/// node; a sender that exceeds them is slowed down rather than cut off.
+pub struct Limits {
+    pool: Option<PgPool>,
+    session_bandwidth: Option<u64>, // Bytes per second
+    tenant_bandwidth: Option<u64>,
+    tenant_limits: Mutex<HashMap<Uuid, (Option<u32>, Instant)>>,
+    session_buckets: Mutex<HashMap<String, TokenBucket>>,
+    tenant_buckets: Mutex<HashMap<Uuid, TokenBucket>>,
+    usage: Mutex<HashMap<Uuid, TenantUsage>>,
+    session_bytes: Mutex<HashMap<String, u64>>,
+}
+
+impl Limits {
+    pub fn new(pool: Option<PgPool>, session_bandwidth: Option<u64>, tenant_bandwidth: Option<u64>) -> Self {
+        Self {
+            pool,
+            session_bandwidth,
+            tenant_bandwidth,
+            tenant_limits: Mutex::new(HashMap::new()),
+            session_buckets: Mutex::new(HashMap::new()),
+            tenant_buckets: Mutex::new(HashMap::new()),
+            usage: Mutex::new(HashMap::new()),
+            session_bytes: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Concurrent session limit of a tenant: its own override, else its plan's default.
+    /// `None` means unlimited. If the limit cannot be read, the last one read is used,
+    /// and without one it is an error, so a database outage never lifts the limit.
+    pub async fn max_concurrent_sessions(&self, tenant_id: Uuid) -> Result<Option<u32>> {
+        let cached = self.tenant_limits.lock().unwrap().get(&tenant_id).copied();
+        if let Some((limit, fetched_at)) = cached {
+            if fetched_at.elapsed() < TENANT_LIMIT_TTL {
+                return Ok(limit);
+            }
+        }
+
+        let Some(pool) = self.pool.as_ref() else {
+            return Ok(None);
+        };
+        let row: Option<(PlanType, Option<i32>)> = match sqlx::query_as(
+            "SELECT plan, max_concurrent_sessions FROM tenants WHERE id = $1"
+        )
+        .bind(tenant_id)
+        .fetch_optional(pool)
+        .await
+        {
+            Ok(row) => row,
+            Err(e) => {
+                tracing::error!("Failed to read session limit of tenant {}: {}", tenant_id, e);
+                return match cached {
+                    Some((limit, _)) => Ok(limit),
+                    None => Err(e).context("Session limit unavailable"),
+                };
+            }
+        };
+
+        let limit = row.and_then(|(plan, custom)| match custom {
+            Some(custom) => Some(custom.max(0) as u32),
+            None => plan.max_concurrent_sessions(),
+        });
+
+        self.tenant_limits.lock().unwrap().insert(tenant_id, (limit, Instant::now()));
+        Ok(limit)
+    }
+
+    pub fn session_opened(&self, session_id: &str, tenant_id: Option<Uuid>) {
+        self.session_bytes.lock().unwrap().insert(session_id.to_string(), 0);
+
+        if let Some(tenant_id) = tenant_id {
+            let mut usage = self.usage.lock().unwrap();
+            let tenant = usage.entry(tenant_id).or_default();
+            tenant.sessions_started += 1;
+            tenant.active_sessions += 1;
+        }
+    }
+
+    /// Forget a finished session; returns the bytes relayed for it on this node
+    pub fn session_closed(&self, session_id: &str, tenant_id: Option<Uuid>) -> u64 {
+        self.session_buckets.lock().unwrap().remove(session_id);
+
+        if let Some(tenant_id) = tenant_id {
+            let mut usage = self.usage.lock().unwrap();
+            if let Some(tenant) = usage.get_mut(&tenant_id) {
+                tenant.active_sessions = tenant.active_sessions.saturating_sub(1);
+            }
+        }
+
+        self.session_bytes.lock().unwrap().remove(session_id).unwrap_or(0)
+    }
+
+    /// Bytes relayed for a session on this node so far
+    pub fn session_bytes(&self, session_id: &str) -> u64 {
+        self.session_bytes.lock().unwrap().get(session_id).copied().unwrap_or(0)
+    }
+
+    /// Count `bytes` against the session and its tenant; returns how long the
+    /// sender has to wait to stay within the bandwidth caps
+    pub fn reserve(&self, session_id: &str, tenant_id: Option<Uuid>, bytes: usize) -> Duration {
+        let now = Instant::now();
+        let bytes = bytes as u64;
+
+        if let Some(total) = self.session_bytes.lock().unwrap().get_mut(session_id) {
+            *total += bytes;
+        }
+
+        let mut wait = Duration::ZERO;
+
+        if let Some(rate) = self.session_bandwidth {
+            let mut buckets = self.session_buckets.lock().unwrap();
+            let bucket = buckets
+                .entry(session_id.to_string())
+                .or_insert_with(|| TokenBucket::new(rate, now));
+            wait = wait.max(bucket.reserve(bytes, now));
+        }
+
+        if let Some(tenant_id) = tenant_id {
+            // Also for sessions opened on other nodes, which only count the bytes they relay
+            self.usage.lock().unwrap().entry(tenant_id).or_default().bytes_relayed += bytes;
+
+            if let Some(rate) = self.tenant_bandwidth {
+                let mut buckets = self.tenant_buckets.lock().unwrap();
+                let bucket = buckets
+                    .entry(tenant_id)
+                    .or_insert_with(|| TokenBucket::new(rate, now));
+                wait = wait.max(bucket.reserve(bytes, now));
+            }
+        }
+
+        wait
+    }
+
+    pub fn usage(&self) -> HashMap<Uuid, TenantUsage> {
+        self.usage.lock().unwrap().clone()
+    }
+}
+
+/// Token bucket allowing one second worth of burst
+#[derive(Debug)]
+struct TokenBucket {
+    rate: u64, // Bytes per second
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: u64, now: Instant) -> Self {
+        Self {
+            rate: rate.max(1),
+            tokens: rate as f64,
+            last_refill: now,
+        }
+    }
+
+    /// Take `bytes` tokens, going into debt if needed; returns the time until the debt is repaid
+    fn reserve(&mut self, bytes: u64, now: Instant) -> Duration {
+        let elapsed = now.saturating_duration_since(self.last_refill).as_secs_f64();
+        self.last_refill = now;
+        self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.rate as f64);
+        self.tokens -= bytes as f64;
+
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate as f64)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bandwidth_caps_and_usage() {
+        let limits = Limits::new(None, Some(1000), Some(1500));
+        let tenant = Uuid::new_v4();
+        limits.session_opened("a", Some(tenant));
+        limits.session_opened("b", Some(tenant));
+
+        // Each session may burst its own second, but together they hit the tenant cap
+        assert_eq!(limits.reserve("a", Some(tenant), 1000), Duration::ZERO);
+        let wait = limits.reserve("b", Some(tenant), 1000);
+        assert!(wait > Duration::from_millis(300) && wait <= Duration::from_millis(334));
+
+        // Sessions without a tenant only have the per-session cap
+        limits.session_opened("guest", None);
+        assert_eq!(limits.reserve("guest", None, 1000), Duration::ZERO);
+        assert!(limits.reserve("guest", None, 500) > Duration::ZERO);
+
+        let usage = limits.usage();
+        assert_eq!(usage[&tenant].bytes_relayed, 2000);
+        assert_eq!(usage[&tenant].active_sessions, 2);
+
+        assert_eq!(limits.session_closed("a", Some(tenant)), 1000);
+        assert_eq!(limits.usage()[&tenant].active_sessions, 1);
+        assert_eq!(limits.usage()[&tenant].sessions_started, 2);
+    }
+
+    #[tokio::test]
+    async fn test_unreadable_limit_is_not_unlimited() {
+        let pool = sqlx::postgres::PgPoolOptions::new()
+            .acquire_timeout(Duration::from_millis(200))
+            .connect_lazy("postgres://scrdesk@127.0.0.1:1/scrdesk")
+            .unwrap();
+        let limits = Limits::new(Some(pool), None, None);
+        let tenant = Uuid::new_v4();
+        assert!(limits.max_concurrent_sessions(tenant).await.is_err());
+
+        // An expired limit is still better than none
+        let stale = Instant::now() - TENANT_LIMIT_TTL * 2;
+        limits.tenant_limits.lock().unwrap().insert(tenant, (Some(3), stale));
+        assert_eq!(limits.max_concurrent_sessions(tenant).await.unwrap(), Some(3));
+    }
+}
@@ -0,0 +1,206 @@
+use serde::Serialize;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Upper bounds (seconds) of the session duration histogram buckets
+const DURATION_BUCKETS: [u64; 7] = [60, 300, 900, 1800, 3600, 14400, 86400];
+
+/// Which way a relayed message travelled within a session
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    InitiatorToTarget, // From the device that sent ConnectRequest
+    TargetToInitiator,
+}
+
+#[derive(Debug, Default)]
+struct DirectionCounters {
+    messages: AtomicU64,
+    bytes: AtomicU64,
+}
+
+/// Counters kept by the session manager; gauges (clients, sessions) are
+/// read from its maps when a snapshot is taken.
+#[derive(Debug, Default)]
+pub struct RelayMetrics {
+    connections_total: AtomicU64,
+    handshake_failures: AtomicU64,
+    sessions_total: AtomicU64,
+    initiator_to_target: DirectionCounters,
+    target_to_initiator: DirectionCounters,
+    duration_buckets: [AtomicU64; DURATION_BUCKETS.len()],
+    duration_count: AtomicU64,
+    duration_sum_secs: AtomicU64,
+}
+
+impl RelayMetrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn connection_opened(&self) {
+        self.connections_total.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn handshake_failed(&self) {
+        self.handshake_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn session_started(&self) {
+        self.sessions_total.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn session_ended(&self, duration: Duration) {
+        let secs = duration.as_secs();
+        for (bucket, bound) in self.duration_buckets.iter().zip(DURATION_BUCKETS) {
+            if secs <= bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.duration_count.fetch_add(1, Ordering::Relaxed);
+        self.duration_sum_secs.fetch_add(secs, Ordering::Relaxed);
+    }
+
+    pub fn message_relayed(&self, direction: Direction, bytes: usize) {
+        let counters = match direction {
+            Direction::InitiatorToTarget => &self.initiator_to_target,
+            Direction::TargetToInitiator => &self.target_to_initiator,
+        };
+        counters.messages.fetch_add(1, Ordering::Relaxed);
+        counters.bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self, connected_clients: usize, active_sessions: usize) -> MetricsSnapshot {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+
+        MetricsSnapshot {
+            connected_clients: connected_clients as u64,
+            active_sessions: active_sessions as u64,
+            connections_total: load(&self.connections_total),
+            handshake_failures: load(&self.handshake_failures),
+            sessions_total: load(&self.sessions_total),
+            initiator_to_target: DirectionSnapshot {
+                messages: load(&self.initiator_to_target.messages),
+                bytes: load(&self.initiator_to_target.bytes),
+            },
+            target_to_initiator: DirectionSnapshot {
+                messages: load(&self.target_to_initiator.messages),
+                bytes: load(&self.target_to_initiator.bytes),
+            },
+            session_duration: DurationSnapshot {
+                buckets: DURATION_BUCKETS
+                    .iter()
+                    .zip(&self.duration_buckets)
+                    .map(|(bound, count)| (*bound, load(count)))
+                    .collect(),
+                count: load(&self.duration_count),
+                sum_secs: load(&self.duration_sum_secs),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DirectionSnapshot {
+    pub messages: u64,
+    pub bytes: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DurationSnapshot {
+    pub buckets: Vec<(u64, u64)>, // (upper bound in seconds, cumulative count)
+    pub count: u64,
+    pub sum_secs: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MetricsSnapshot {
+    pub connected_clients: u64,
+    pub active_sessions: u64,
+    pub connections_total: u64,
+    pub handshake_failures: u64,
+    pub sessions_total: u64,
+    pub initiator_to_target: DirectionSnapshot,
+    pub target_to_initiator: DirectionSnapshot,
+    pub session_duration: DurationSnapshot,
+}
+
+impl MetricsSnapshot {
+    /// Render in the Prometheus text exposition format
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+
+        let mut metric = |name: &str, kind: &str, help: &str, samples: &[(&str, u64)]| {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+            for (labels, value) in samples {
+                let _ = writeln!(out, "{}{} {}", name, labels, value);
+            }
+        };
+
+        metric("scrdesk_relay_connected_clients", "gauge", "Clients currently registered with the relay",
+            &[("", self.connected_clients)]);
+        metric("scrdesk_relay_active_sessions", "gauge", "Sessions currently being relayed",
+            &[("", self.active_sessions)]);
+        metric("scrdesk_relay_connections_total", "counter", "TCP connections accepted",
+            &[("", self.connections_total)]);
+        metric("scrdesk_relay_handshake_failures_total", "counter", "Connections that failed the WebSocket handshake",
+            &[("", self.handshake_failures)]);
+        metric("scrdesk_relay_sessions_total", "counter", "Sessions created",
+            &[("", self.sessions_total)]);
+        metric("scrdesk_relay_messages_total", "counter", "Messages relayed between peers", &[
+            ("{direction=\"initiator_to_target\"}", self.initiator_to_target.messages),
+            ("{direction=\"target_to_initiator\"}", self.target_to_initiator.messages),
+        ]);
+        metric("scrdesk_relay_bytes_total", "counter", "Payload bytes relayed between peers", &[
+            ("{direction=\"initiator_to_target\"}", self.initiator_to_target.bytes),
+            ("{direction=\"target_to_initiator\"}", self.target_to_initiator.bytes),
+        ]);
+
+        let name = "scrdesk_relay_session_duration_seconds";
+        let _ = writeln!(out, "# HELP {} Duration of finished sessions", name);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        for (bound, count) in &self.session_duration.buckets {
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, count);
+        }
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, self.session_duration.count);
+        let _ = writeln!(out, "{}_sum {}", name, self.session_duration.sum_secs);
+        let _ = writeln!(out, "{}_count {}", name, self.session_duration.count);
+
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_counters_and_histogram() {
+        let metrics = RelayMetrics::new();
+        metrics.connection_opened();
+        metrics.connection_opened();
+        metrics.handshake_failed();
+        metrics.message_relayed(Direction::InitiatorToTarget, 100);
+        metrics.message_relayed(Direction::TargetToInitiator, 40);
+        metrics.message_relayed(Direction::TargetToInitiator, 2);
+        metrics.session_ended(Duration::from_secs(120));
+        metrics.session_ended(Duration::from_secs(100_000));
+
+        let snapshot = metrics.snapshot(3, 1);
+        assert_eq!(snapshot.connections_total, 2);
+        assert_eq!(snapshot.handshake_failures, 1);
+        assert_eq!(snapshot.initiator_to_target.bytes, 100);
+        assert_eq!(snapshot.target_to_initiator.messages, 2);
+        assert_eq!(snapshot.target_to_initiator.bytes, 42);
+        assert_eq!(snapshot.session_duration.buckets[0], (60, 0));
+        assert_eq!(snapshot.session_duration.buckets[1], (300, 1));
+        assert_eq!(snapshot.session_duration.count, 2);
+
+        let text = snapshot.to_prometheus();
+        assert!(text.contains("scrdesk_relay_connected_clients 3\n"));
+        assert!(text.contains("scrdesk_relay_bytes_total{direction=\"target_to_initiator\"} 42\n"));
+        assert!(text.contains("scrdesk_relay_session_duration_seconds_bucket{le=\"86400\"} 1\n"));
+        assert!(text.contains("scrdesk_relay_session_duration_seconds_bucket{le=\"+Inf\"} 2\n"));
+    }
+}
@@ -1,25 +1,81 @@
+pub mod cluster;
+pub mod limits;
+pub mod metrics;
+pub mod participants;
+pub mod quic;
+pub mod rendezvous;
 mod session;
+pub mod store;
+pub mod tls;
+
+pub use session::{Keepalive, SessionManager};
 
 use scrdesk_shared::config::Config;
-use session::{SessionManager, handle_client};
+use session::{handle_client, handle_quic_client};
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::net::TcpListener;
+use tokio::sync::mpsc;
 
-pub async fn start_relay_server(config: Config) -> anyhow::Result<()> {
-    let relay_addr = format!("{}:21117", config.server.host);
-    tracing::info!("Relay server listening on {} (WebSocket relay)", relay_addr);
+const CLUSTER_RETRY_DELAY: Duration = Duration::from_secs(2);
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+const RESUME_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+const TLS_RELOAD_INTERVAL: Duration = Duration::from_secs(60);
 
-    let listener = TcpListener::bind(&relay_addr).await?;
-    let manager = Arc::new(SessionManager::new());
+pub async fn start_relay_server(config: Config, manager: Arc<SessionManager>) -> anyhow::Result<()> {
+    let relay_addr = format!("{}:{}", config.server.host, config.relay.port);
+    let certificate = load_certificate(&config)?;
+    let acceptor = certificate.clone().map(tls::acceptor).transpose()?;
+    let scheme = if acceptor.is_some() { "wss" } else { "ws" };
+    tracing::info!("Relay server listening on {} (WebSocket relay, {})", relay_addr, scheme);
+
+    // QUIC needs TLS; clients fall back to WebSocket when it is unavailable
+    match certificate {
+        Some(resolver) if config.relay.quic_enabled => {
+            let endpoint = quic::server_endpoint(relay_addr.parse()?, resolver)?;
+            tracing::info!("Relay server listening on {} (QUIC relay)", relay_addr);
+            start_quic_server(endpoint, manager.clone());
+        }
+        None if config.relay.quic_enabled => tracing::info!("QUIC relay disabled: it requires a TLS certificate"),
+        _ => {}
+    }
+
+    // Public address discovery for direct peer-to-peer connections
+    if config.relay.rendezvous_port > 0 {
+        let rendezvous_addr = format!("{}:{}", config.server.host, config.relay.rendezvous_port);
+        match rendezvous::start(rendezvous_addr.parse()?).await {
+            Ok(addr) => tracing::info!("Rendezvous service listening on {} (UDP and TCP)", addr),
+            Err(e) => tracing::error!("Failed to start rendezvous service on {}: {}", rendezvous_addr, e),
+        }
+    }
+
+    start_cluster_tasks(manager.clone());
+    if config.relay.idle_timeout_secs > 0 {
+        start_idle_sweeper(manager.clone(), Duration::from_secs(config.relay.idle_timeout_secs));
+    }
+    start_resume_sweeper(manager.clone());
 
+    let listener = TcpListener::bind(&relay_addr).await?;
     loop {
         match listener.accept().await {
             Ok((socket, addr)) => {
                 tracing::info!("New relay connection from {}", addr);
                 let manager_clone = manager.clone();
+                let acceptor = acceptor.clone();
 
                 tokio::spawn(async move {
-                    if let Err(e) = handle_client(socket, addr, manager_clone).await {
+                    let result = match acceptor {
+                        Some(acceptor) => match acceptor.accept(socket).await {
+                            Ok(stream) => handle_client(stream, addr, manager_clone).await,
+                            Err(e) => {
+                                manager_clone.metrics().connection_opened();
+                                manager_clone.metrics().handshake_failed();
+                                Err(anyhow::anyhow!("TLS handshake failed: {}", e))
+                            }
+                        },
+                        None => handle_client(socket, addr, manager_clone).await,
+                    };
+                    if let Err(e) = result {
                         tracing::error!("Error handling client {}: {}", addr, e);
                     }
                 });
@@ -30,3 +86,114 @@ pub async fn start_relay_server(config: Config) -> anyhow::Result<()> {
         }
     }
 }
+
+/// Accept QUIC connections and serve them like WebSocket clients
+fn start_quic_server(endpoint: quinn::Endpoint, manager: Arc<SessionManager>) {
+    tokio::spawn(async move {
+        while let Some(incoming) = endpoint.accept().await {
+            let manager = manager.clone();
+            tokio::spawn(async move {
+                let connection = match incoming.await {
+                    Ok(connection) => connection,
+                    Err(e) => {
+                        manager.metrics().connection_opened();
+                        manager.metrics().handshake_failed();
+                        tracing::warn!("QUIC handshake failed: {}", e);
+                        return;
+                    }
+                };
+
+                let addr = connection.remote_address();
+                tracing::info!("New QUIC relay connection from {}", addr);
+                if let Err(e) = handle_quic_client(connection, manager).await {
+                    tracing::error!("Error handling client {}: {}", addr, e);
+                }
+            });
+        }
+    });
+}
+
+/// Certificate for the configured files, or None to serve plain ws://
+fn load_certificate(config: &Config) -> anyhow::Result<Option<Arc<tls::CertificateResolver>>> {
+    let (cert_path, key_path) = match (&config.relay.tls_cert_path, &config.relay.tls_key_path) {
+        (Some(cert_path), Some(key_path)) => (cert_path, key_path),
+        (None, None) => {
+            tracing::warn!("No TLS certificate configured, relay traffic is not encrypted");
+            return Ok(None);
+        }
+        _ => anyhow::bail!("Both RELAY__TLS_CERT_PATH and RELAY__TLS_KEY_PATH must be set to enable TLS"),
+    };
+
+    let resolver = Arc::new(tls::CertificateResolver::load(cert_path, key_path)?);
+    tls::start_reloader(resolver.clone(), TLS_RELOAD_INTERVAL);
+    Ok(Some(resolver))
+}
+
+/// Keep this node's presence entries and session slots alive and apply events from other nodes
+fn start_cluster_tasks(manager: Arc<SessionManager>) {
+    let Some(cluster) = manager.cluster().cloned() else {
+        return;
+    };
+
+    // Refresh well within the TTL so a single missed round does not drop devices
+    let heartbeat_manager = manager.clone();
+    let heartbeat_cluster = cluster.clone();
+    tokio::spawn(async move {
+        let period = Duration::from_secs((heartbeat_cluster.presence_ttl() / 3).max(1));
+        let mut interval = tokio::time::interval(period);
+        loop {
+            interval.tick().await;
+            let devices = heartbeat_manager.local_device_ids().await;
+            if let Err(e) = heartbeat_cluster.refresh_devices(&devices).await {
+                tracing::warn!("Presence refresh failed: {}", e);
+            }
+
+            let slots = heartbeat_manager.held_session_slots().await;
+            if let Err(e) = heartbeat_cluster.refresh_sessions(&slots).await {
+                tracing::warn!("Session slot refresh failed: {}", e);
+            }
+        }
+    });
+
+    let (events_tx, mut events_rx) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        loop {
+            if let Err(e) = cluster.subscribe(events_tx.clone()).await {
+                tracing::error!("Cluster subscription failed: {}", e);
+            }
+            tracing::warn!("Cluster subscription lost, resubscribing");
+            tokio::time::sleep(CLUSTER_RETRY_DELAY).await;
+        }
+    });
+
+    tokio::spawn(async move {
+        while let Some(envelope) = events_rx.recv().await {
+            manager.handle_cluster_event(envelope).await;
+        }
+    });
+}
+
+/// Periodically end sessions that have been idle for longer than `timeout`
+fn start_idle_sweeper(manager: Arc<SessionManager>, timeout: Duration) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(IDLE_CHECK_INTERVAL.min(timeout));
+        loop {
+            interval.tick().await;
+            let ended = manager.end_idle_sessions(timeout).await;
+            if ended > 0 {
+                tracing::info!("Ended {} idle sessions", ended);
+            }
+        }
+    });
+}
+
+/// Remove participants that did not come back within the resume grace period
+fn start_resume_sweeper(manager: Arc<SessionManager>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(RESUME_CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+            manager.end_expired_holds().await;
+        }
+    });
+}
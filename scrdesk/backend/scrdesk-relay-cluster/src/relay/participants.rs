@@ -0,0 +1,223 @@
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+
+/// What a participant may do in a session
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ParticipantRole {
+    Host,       // Shares its screen and receives input
+    Controller, // Views and sends input; at most one per session
+    Observer,   // Views only
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Participant {
+    pub device_id: String,
+    pub role: ParticipantRole,
+    pub node: String, // Relay node the device is connected to
+}
+
+/// A participant as shown to the other participants
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ParticipantInfo {
+    pub device_id: String,
+    pub role: ParticipantRole,
+}
+
+/// Result of removing a device from a roster
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Removal {
+    NotPresent,
+    Left,
+    SessionEnded, // The host left, or nobody is watching any more
+}
+
+/// Who is in a session: one host and any number of viewers, plus pending invitations
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Roster {
+    pub participants: Vec<Participant>,
+    pub invited: Vec<Participant>,
+}
+
+impl Roster {
+    pub fn new(host: Participant, controller: Participant) -> Self {
+        Self {
+            participants: vec![host, controller],
+            invited: Vec::new(),
+        }
+    }
+
+    pub fn get(&self, device_id: &str) -> Option<&Participant> {
+        self.participants.iter().find(|p| p.device_id == device_id)
+    }
+
+    pub fn host(&self) -> Option<&Participant> {
+        self.participants.iter().find(|p| p.role == ParticipantRole::Host)
+    }
+
+    pub fn controller(&self) -> Option<&Participant> {
+        self.participants.iter().find(|p| p.role == ParticipantRole::Controller)
+    }
+
+    pub fn viewers(&self) -> impl Iterator<Item = &Participant> {
+        self.participants.iter().filter(|p| p.role != ParticipantRole::Host)
+    }
+
+    pub fn contains(&self, device_id: &str) -> bool {
+        self.get(device_id).is_some() || self.invited.iter().any(|p| p.device_id == device_id)
+    }
+
+    /// Relay nodes with a participant or invitee connected
+    pub fn nodes(&self) -> BTreeSet<&str> {
+        self.participants
+            .iter()
+            .chain(&self.invited)
+            .map(|p| p.node.as_str())
+            .collect()
+    }
+
+    pub fn info(&self) -> Vec<ParticipantInfo> {
+        self.participants
+            .iter()
+            .map(|p| ParticipantInfo { device_id: p.device_id.clone(), role: p.role })
+            .collect()
+    }
+
+    /// Who receives a message from `from`: the host's media goes to every viewer
+    /// and everything else it sends to the controller; only the controller's
+    /// messages reach the host, so observers cannot send input.
+    pub fn recipients(&self, from: &str, media: bool) -> Vec<&Participant> {
+        match self.get(from).map(|p| p.role) {
+            Some(ParticipantRole::Host) if media => self.viewers().collect(),
+            Some(ParticipantRole::Host) => self.controller().into_iter().collect(),
+            Some(ParticipantRole::Controller) => self.host().into_iter().collect(),
+            Some(ParticipantRole::Observer) | None => Vec::new(),
+        }
+    }
+
+    /// Invite a device to join; only the host and the controller may invite
+    pub fn invite(&mut self, by: &str, invitee: Participant) -> Result<()> {
+        self.ensure_can_manage(by)?;
+        if invitee.role == ParticipantRole::Host {
+            bail!("A session has only one host");
+        }
+        if self.get(&invitee.device_id).is_some() {
+            bail!("Device {} is already in the session", invitee.device_id);
+        }
+
+        self.invited.retain(|p| p.device_id != invitee.device_id);
+        self.invited.push(invitee);
+        Ok(())
+    }
+
+    /// Accept an invitation; joining as controller takes control from the current one
+    pub fn join(&mut self, device_id: &str) -> Result<ParticipantRole> {
+        let Some(index) = self.invited.iter().position(|p| p.device_id == device_id) else {
+            bail!("Device {} was not invited to the session", device_id);
+        };
+
+        let participant = self.invited.remove(index);
+        let role = participant.role;
+        if role == ParticipantRole::Controller {
+            self.demote_controller();
+        }
+        self.participants.push(participant);
+        Ok(role)
+    }
+
+    /// Hand control to another viewer, or to nobody with `None`.
+    /// Only the host and the current controller may do this.
+    pub fn transfer_control(&mut self, by: &str, to: Option<&str>) -> Result<()> {
+        self.ensure_can_manage(by)?;
+
+        if let Some(to) = to {
+            match self.get(to).map(|p| p.role) {
+                Some(ParticipantRole::Host) => bail!("The host cannot take control of its own screen"),
+                Some(_) => {}
+                None => bail!("Device {} is not in the session", to),
+            }
+        }
+
+        self.demote_controller();
+        if let Some(participant) = to.and_then(|to| self.participants.iter_mut().find(|p| p.device_id == to)) {
+            participant.role = ParticipantRole::Controller;
+        }
+        Ok(())
+    }
+
+    pub fn remove(&mut self, device_id: &str) -> Removal {
+        self.invited.retain(|p| p.device_id != device_id);
+
+        let Some(index) = self.participants.iter().position(|p| p.device_id == device_id) else {
+            return Removal::NotPresent;
+        };
+
+        let removed = self.participants.remove(index);
+        if removed.role == ParticipantRole::Host || self.viewers().next().is_none() {
+            Removal::SessionEnded
+        } else {
+            Removal::Left
+        }
+    }
+
+    fn ensure_can_manage(&self, device_id: &str) -> Result<()> {
+        match self.get(device_id).map(|p| p.role) {
+            Some(ParticipantRole::Host | ParticipantRole::Controller) => Ok(()),
+            Some(ParticipantRole::Observer) => bail!("Only the host or the controller can do this"),
+            None => bail!("Device {} is not in the session", device_id),
+        }
+    }
+
+    fn demote_controller(&mut self) {
+        for participant in &mut self.participants {
+            if participant.role == ParticipantRole::Controller {
+                participant.role = ParticipantRole::Observer;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn participant(device_id: &str, role: ParticipantRole) -> Participant {
+        Participant {
+            device_id: device_id.to_string(),
+            role,
+            node: "node-a".to_string(),
+        }
+    }
+
+    fn devices(recipients: Vec<&Participant>) -> Vec<&str> {
+        recipients.iter().map(|p| p.device_id.as_str()).collect()
+    }
+
+    #[test]
+    fn test_roles_and_control_transfer() -> Result<()> {
+        let mut roster = Roster::new(
+            participant("host", ParticipantRole::Host),
+            participant("senior", ParticipantRole::Controller),
+        );
+
+        roster.invite("senior", participant("junior", ParticipantRole::Observer))?;
+        assert!(roster.join("stranger").is_err());
+        assert_eq!(roster.join("junior")?, ParticipantRole::Observer);
+
+        // Media fans out; everything else only flows between host and controller
+        assert_eq!(devices(roster.recipients("host", true)), ["senior", "junior"]);
+        assert_eq!(devices(roster.recipients("host", false)), ["senior"]);
+        assert_eq!(devices(roster.recipients("senior", false)), ["host"]);
+        assert!(roster.recipients("junior", false).is_empty());
+
+        assert!(roster.transfer_control("junior", Some("junior")).is_err());
+        roster.transfer_control("senior", Some("junior"))?;
+        assert_eq!(roster.controller().map(|p| p.device_id.as_str()), Some("junior"));
+        assert!(roster.recipients("senior", false).is_empty());
+
+        assert_eq!(roster.remove("senior"), Removal::Left);
+        assert_eq!(roster.remove("junior"), Removal::SessionEnded);
+        Ok(())
+    }
+}
@@ -0,0 +1,82 @@
+use anyhow::Result;
+use std::net::SocketAddr;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, UdpSocket};
+
+/// Request datagram a client sends to learn the address the relay sees it from
+pub const WHOAMI: &[u8] = b"SCRDESK-WHOAMI";
+/// Prefix of the reply, followed by the observed `ip:port`
+pub const REPLY_PREFIX: &str = "SCRDESK-YOUARE ";
+
+/// Reflects each client's observed public address over UDP and TCP, so peers can
+/// exchange candidates for direct connections. Returns the bound address.
+pub async fn start(addr: SocketAddr) -> Result<SocketAddr> {
+    let udp = UdpSocket::bind(addr).await?;
+    let addr = udp.local_addr()?;
+    let tcp = TcpListener::bind(addr).await?;
+
+    tokio::spawn(async move {
+        let mut buf = [0u8; 64];
+        loop {
+            match udp.recv_from(&mut buf).await {
+                Ok((len, peer)) if &buf[..len] == WHOAMI => {
+                    let reply = format!("{}{}", REPLY_PREFIX, peer);
+                    if let Err(e) = udp.send_to(reply.as_bytes(), peer).await {
+                        tracing::debug!("Rendezvous reply to {} failed: {}", peer, e);
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => tracing::debug!("Rendezvous receive failed: {}", e),
+            }
+        }
+    });
+
+    tokio::spawn(async move {
+        loop {
+            let (mut socket, peer) = match tcp.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    tracing::warn!("Rendezvous accept failed: {}", e);
+                    continue;
+                }
+            };
+            tokio::spawn(async move {
+                let reply = format!("{}{}\n", REPLY_PREFIX, peer);
+                let _ = socket.write_all(reply.as_bytes()).await;
+                let _ = socket.shutdown().await;
+            });
+        }
+    });
+
+    Ok(addr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpSocket;
+
+    #[tokio::test]
+    async fn test_reflects_observed_address() -> Result<()> {
+        let addr = start("127.0.0.1:0".parse()?).await?;
+
+        let client = UdpSocket::bind("127.0.0.1:0").await?;
+        client.send_to(WHOAMI, addr).await?;
+        let mut buf = [0u8; 64];
+        let (len, _) = client.recv_from(&mut buf).await?;
+        assert_eq!(
+            std::str::from_utf8(&buf[..len])?,
+            format!("{}{}", REPLY_PREFIX, client.local_addr()?)
+        );
+
+        let socket = TcpSocket::new_v4()?;
+        socket.bind("127.0.0.1:0".parse()?)?;
+        let local = socket.local_addr()?;
+        let mut stream = socket.connect(addr).await?;
+        let mut reply = String::new();
+        stream.read_to_string(&mut reply).await?;
+        assert_eq!(reply, format!("{}{}\n", REPLY_PREFIX, local));
+        Ok(())
+    }
+}
@@ -1,11 +1,30 @@
-use anyhow::Result;
-use std::collections::HashMap;
+use anyhow::{Context, Result};
+use std::collections::{BTreeSet, HashMap};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
-use tokio::net::TcpStream;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::sync::{mpsc, Mutex, RwLock};
-use tokio_tungstenite::{accept_async, tungstenite::Message as WsMessage, WebSocketStream};
-use futures::{SinkExt, StreamExt};
+use tokio_tungstenite::{accept_async, tungstenite::Message as WsMessage, tungstenite::Error as WsError, WebSocketStream};
+use futures::{Sink, SinkExt, Stream, StreamExt};
 use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use super::cluster::{Cluster, ClusterEvent, Envelope};
+use super::limits::Limits;
+use super::metrics::{Direction, MetricsSnapshot, RelayMetrics};
+use super::quic;
+use super::participants::{Participant, ParticipantInfo, ParticipantRole, Removal, Roster};
+use super::store::{ClientIdentity, SessionRecord, SessionStore};
+use scrdesk_shared::{auth::JwtManager, Error};
+
+/// Node ID used in session rosters when running without a cluster
+const LOCAL_NODE: &str = "local";
+
+/// How long a session is held for a participant whose connection dropped
+const DEFAULT_RESUME_GRACE: Duration = Duration::from_secs(30);
+
+/// Message types the host sends to every viewer rather than only the controller
+pub(super) const MEDIA_TYPES: &[&str] = &["VideoFrame", "SealedMedia"];
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type")]
@@ -14,6 +33,8 @@ pub enum Message {
         device_id: String,
         platform: String,
         capabilities: Vec<String>,
+        #[serde(default)]
+        auth_token: Option<String>, // Access token of the signed-in user, if any
     },
     ConnectRequest {
         target_id: String,
@@ -23,12 +44,85 @@ pub enum Message {
         success: bool,
         session_id: Option<String>,
         error: Option<String>,
+        #[serde(default)]
+        error_code: Option<String>, // Machine-readable reason, e.g. SESSION_LIMIT_EXCEEDED
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        resume_token: Option<String>, // Rebinds to the session after a dropped connection
+    },
+    /// Sent after Hello on a new connection to rebind to a session the old one was in
+    ResumeSession {
+        session_id: String,
+        resume_token: String,
+    },
+    /// Sent to the other participants when one rebinds after a dropped connection
+    ParticipantResumed {
+        session_id: String,
+        device_id: String,
+    },
+    /// Clients measure round-trip time by the timestamp echoed back in the Pong
+    Ping {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        timestamp: Option<u64>,
+    },
+    Pong {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        timestamp: Option<u64>,
     },
-    Ping,
-    Pong,
     Disconnect {
         reason: Option<String>,
     },
+    /// Invite another device into the sender's session
+    InviteParticipant {
+        device_id: String,
+        role: ParticipantRole,
+    },
+    /// Sent to the invited device
+    SessionInvitation {
+        session_id: String,
+        host: String,
+        invited_by: String,
+        role: ParticipantRole,
+    },
+    JoinSession {
+        session_id: String,
+    },
+    /// Hand control to another viewer, or take it away with `None`
+    TransferControl {
+        device_id: Option<String>,
+    },
+    /// Sent to every participant when someone joins, leaves or control changes
+    ParticipantsChanged {
+        session_id: String,
+        participants: Vec<ParticipantInfo>,
+    },
+    /// An invitation or control transfer was refused
+    SessionRequestFailed {
+        error: String,
+    },
+    /// Sent after the Hello acknowledgment: UDP and TCP port of the rendezvous service
+    /// that reports a client's public address, for direct connection candidates
+    RendezvousInfo {
+        port: u16,
+    },
+    /// A participant's traffic moved onto a direct link to its peer, or back to the relay
+    PathChanged {
+        session_id: String,
+        direct: bool,
+    },
+    /// End-to-end key agreement between two participants, delivered whatever their roles.
+    /// Goes to every other participant when `to` is empty. Key fields are not read here.
+    KeyExchange {
+        session_id: String,
+        device_id: String,
+        #[serde(default)]
+        to: Option<String>,
+    },
+    /// A participant's key for its session traffic, sealed for one other participant
+    SenderKey {
+        session_id: String,
+        device_id: String,
+        to: String,
+    },
     // All other messages are relayed as-is
     Relay {
         data: Vec<u8>,
@@ -38,19 +132,100 @@ pub enum Message {
 pub struct Client {
     pub device_id: String,
     pub platform: String,
+    pub identity: Option<ClientIdentity>,
     pub tx: mpsc::UnboundedSender<WsMessage>,
 }
 
+#[derive(Debug, Clone)]
 pub struct Session {
     pub id: String,
-    pub client_a: String,
-    pub client_b: String,
+    pub origin_node: String, // Node that opened the session; it records it and holds its slot
+    pub roster: Roster,
     pub created_at: std::time::Instant,
+    pub record: Option<SessionRecord>, // Set on the node that persists this session
+    pub tenant_id: Option<Uuid>, // Tenant of the initiating user, for limits and billing
+    pub holds_slot: bool, // Counts against the tenant's concurrent session limit on this node
+    pub last_activity: Arc<AtomicU64>, // Milliseconds after `created_at` of the last relayed message
+    pub direct: bool, // The peers reported a direct link, so no traffic passes through the relay
+    pub resume_tokens: HashMap<String, String>, // Device ID to the token it resumes the session with
+    pub held: HashMap<String, Hold>, // Participants whose connection dropped, by device ID
+}
+
+/// A participant kept in its session after its connection dropped
+#[derive(Debug, Clone)]
+pub struct Hold {
+    pub since: std::time::Instant,
+    pub reason: String, // Told to the others if the session ends because it never comes back
+}
+
+impl Session {
+    fn touch(&self) {
+        let elapsed = self.created_at.elapsed().as_millis() as u64;
+        self.last_activity.fetch_max(elapsed, Ordering::Relaxed);
+    }
+
+    fn idle_for(&self) -> Duration {
+        let last_activity = Duration::from_millis(self.last_activity.load(Ordering::Relaxed));
+        self.created_at.elapsed().saturating_sub(last_activity)
+    }
+}
+
+/// WebSocket ping settings used to detect dead connections
+#[derive(Debug, Clone, Copy)]
+pub struct Keepalive {
+    pub interval: Duration,
+    pub max_missed: u32, // Evict the client after this many unanswered pings in a row
+}
+
+impl Default for Keepalive {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(15),
+            max_missed: 3,
+        }
+    }
+}
+
+/// Where a device's message goes and what to charge it to
+#[derive(Debug, Clone)]
+pub struct Route {
+    pub session_id: String,
+    pub recipients: Vec<Participant>,
+    pub direction: Direction,
+    pub tenant_id: Option<Uuid>,
+}
+
+/// A live session as listed to administrators
+#[derive(Debug, Clone, Serialize)]
+pub struct SessionInfo {
+    pub session_id: String,
+    pub participants: Vec<Participant>,
+    pub invited: Vec<Participant>,
+    pub tenant_id: Option<Uuid>,
+    pub duration_secs: u64,
+    pub bytes_relayed: u64, // Relayed through this node
+    pub direct: bool,
+}
+
+/// Where a device is connected within the cluster
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Location {
+    Local,
+    Remote(String), // Node ID
 }
 
 pub struct SessionManager {
     clients: Arc<RwLock<HashMap<String, Client>>>,
     sessions: Arc<RwLock<HashMap<String, Session>>>,
+    metrics: RelayMetrics,
+    cluster: Option<Arc<Cluster>>,
+    auth: Option<Arc<JwtManager>>,
+    store: Option<SessionStore>,
+    limits: Limits,
+    draining: AtomicBool,
+    keepalive: Keepalive,
+    rendezvous_port: Option<u16>,
+    resume_grace: Duration,
 }
 
 impl SessionManager {
@@ -58,51 +233,563 @@ impl SessionManager {
         Self {
             clients: Arc::new(RwLock::new(HashMap::new())),
             sessions: Arc::new(RwLock::new(HashMap::new())),
+            metrics: RelayMetrics::new(),
+            cluster: None,
+            auth: None,
+            store: None,
+            limits: Limits::new(None, None, None),
+            draining: AtomicBool::new(false),
+            keepalive: Keepalive::default(),
+            rendezvous_port: None,
+            resume_grace: DEFAULT_RESUME_GRACE,
         }
     }
 
-    pub async fn register_client(&self, device_id: String, platform: String, tx: mpsc::UnboundedSender<WsMessage>) {
+    pub fn with_keepalive(mut self, keepalive: Keepalive) -> Self {
+        self.keepalive = keepalive;
+        self
+    }
+
+    pub fn keepalive(&self) -> Keepalive {
+        self.keepalive
+    }
+
+    /// Hold sessions this long for participants whose connection dropped; zero ends
+    /// their part in the session right away
+    pub fn with_resume_grace(mut self, grace: Duration) -> Self {
+        self.resume_grace = grace;
+        self
+    }
+
+    pub fn resume_grace(&self) -> Duration {
+        self.resume_grace
+    }
+
+    /// Advertise the rendezvous service to clients after they say Hello
+    pub fn with_rendezvous_port(mut self, port: u16) -> Self {
+        self.rendezvous_port = Some(port);
+        self
+    }
+
+    /// Enforce tenant session limits and bandwidth caps
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    pub fn limits(&self) -> &Limits {
+        &self.limits
+    }
+
+    /// Session slots this node holds in the cluster-wide tenant limits
+    pub async fn held_session_slots(&self) -> Vec<(Uuid, String)> {
+        self.sessions
+            .read()
+            .await
+            .values()
+            .filter(|s| s.holds_slot)
+            .filter_map(|s| s.tenant_id.map(|tenant_id| (tenant_id, s.id.clone())))
+            .collect()
+    }
+
+    /// Share presence with other relay nodes and forward traffic between them
+    pub fn with_cluster(mut self, cluster: Arc<Cluster>) -> Self {
+        self.cluster = Some(cluster);
+        self
+    }
+
+    /// Accept access tokens in Hello to identify the signed-in user
+    pub fn with_auth(mut self, jwt_manager: Arc<JwtManager>) -> Self {
+        self.auth = Some(jwt_manager);
+        self
+    }
+
+    /// Record sessions of authenticated users in the database
+    pub fn with_store(mut self, store: SessionStore) -> Self {
+        self.store = Some(store);
+        self
+    }
+
+    /// Resolve the user behind an access token sent in Hello
+    pub fn authenticate(&self, token: &str) -> Result<ClientIdentity> {
+        let auth = self.auth.as_ref().context("Authentication is not configured")?;
+        let claims = auth.verify_access_token(token)?;
+
+        Ok(ClientIdentity {
+            user_id: claims.sub,
+            tenant_id: claims.tenant_id,
+        })
+    }
+
+    pub fn cluster(&self) -> Option<&Arc<Cluster>> {
+        self.cluster.as_ref()
+    }
+
+    /// This node's ID in session rosters
+    pub fn node_id(&self) -> &str {
+        self.cluster.as_ref().map(|c| c.node_id()).unwrap_or(LOCAL_NODE)
+    }
+
+    pub async fn local_device_ids(&self) -> Vec<String> {
+        self.clients.read().await.keys().cloned().collect()
+    }
+
+    pub fn metrics(&self) -> &RelayMetrics {
+        &self.metrics
+    }
+
+    /// Current counters together with the number of connected clients and active sessions
+    pub async fn metrics_snapshot(&self) -> MetricsSnapshot {
+        let connected_clients = self.clients.read().await.len();
+        // Sessions mirrored from other nodes are counted there, so cluster-wide sums add up
+        let node_id = self.node_id();
+        let active_sessions = self.sessions.read().await.values().filter(|s| s.origin_node == node_id).count();
+        self.metrics.snapshot(connected_clients, active_sessions)
+    }
+
+    pub async fn register_client(
+        &self,
+        device_id: String,
+        platform: String,
+        identity: Option<ClientIdentity>,
+        tx: mpsc::UnboundedSender<WsMessage>,
+    ) {
         let client = Client {
             device_id: device_id.clone(),
             platform,
+            identity,
             tx,
         };
 
         let mut clients = self.clients.write().await;
         clients.insert(device_id.clone(), client);
+        drop(clients);
         tracing::info!("Client registered: {}", device_id);
+
+        if let Some(cluster) = &self.cluster {
+            if let Err(e) = cluster.register_device(&device_id).await {
+                tracing::warn!("Failed to publish presence of {}: {}", device_id, e);
+            }
+        }
     }
 
-    pub async fn unregister_client(&self, device_id: &str) {
+    /// Remove a client; sessions it ends tell the remaining participants `reason`
+    pub async fn unregister_client(&self, device_id: &str, reason: &str) {
         let mut clients = self.clients.write().await;
         clients.remove(device_id);
+        drop(clients);
         tracing::info!("Client unregistered: {}", device_id);
 
-        // Remove any sessions involving this client
+        // Leave every session the client was in or invited to
+        let session_ids: Vec<String> = self.sessions
+            .read()
+            .await
+            .values()
+            .filter(|s| s.roster.contains(device_id))
+            .map(|s| s.id.clone())
+            .collect();
+        for session_id in session_ids {
+            self.leave_session(&session_id, device_id, reason).await;
+        }
+
+        if let Some(cluster) = &self.cluster {
+            if let Err(e) = cluster.unregister_device(device_id).await {
+                tracing::warn!("Failed to remove presence of {}: {}", device_id, e);
+            }
+        }
+    }
+
+    /// A client's connection ended without a Disconnect. Sessions it takes part in are
+    /// held for the resume grace period; it leaves them if it does not come back in time.
+    /// Nothing happens if the device has already reconnected on another connection.
+    pub async fn client_dropped(&self, device_id: &str, tx: &mpsc::UnboundedSender<WsMessage>, reason: &str) {
+        if self.resume_grace.is_zero() {
+            return self.unregister_client(device_id, reason).await;
+        }
+
+        let mut clients = self.clients.write().await;
+        if !clients.get(device_id).is_some_and(|c| c.tx.same_channel(tx)) {
+            return;
+        }
+        clients.remove(device_id);
+        drop(clients);
+        tracing::info!("Client dropped: {}, holding its sessions", device_id);
+
+        let mut invited_to = Vec::new();
+        for session in self.sessions.write().await.values_mut() {
+            if session.roster.get(device_id).is_some() {
+                session.held.insert(device_id.to_string(), Hold {
+                    since: std::time::Instant::now(),
+                    reason: reason.to_string(),
+                });
+            } else if session.roster.contains(device_id) {
+                invited_to.push(session.id.clone());
+            }
+        }
+        for session_id in invited_to {
+            self.leave_session(&session_id, device_id, reason).await;
+        }
+
+        if let Some(cluster) = &self.cluster {
+            if let Err(e) = cluster.unregister_device(device_id).await {
+                tracing::warn!("Failed to remove presence of {}: {}", device_id, e);
+            }
+        }
+    }
+
+    /// Rebind a reconnected client to a session it was in, and tell the other
+    /// participants so they send again what it may have missed
+    pub async fn resume_session(&self, device_id: &str, session_id: &str, token: &str) -> Result<()> {
         let mut sessions = self.sessions.write().await;
-        sessions.retain(|_, session| {
-            session.client_a != device_id && session.client_b != device_id
-        });
+        let session = sessions
+            .get_mut(session_id)
+            .filter(|s| s.roster.get(device_id).is_some())
+            .filter(|s| s.resume_tokens.get(device_id).is_some_and(|t| t == token))
+            .ok_or(Error::SessionNotResumable)?;
+
+        session.held.remove(device_id);
+        let node = self.node_id().to_string();
+        let moved = session
+            .roster
+            .participants
+            .iter_mut()
+            .find(|p| p.device_id == device_id)
+            .is_some_and(|p| std::mem::replace(&mut p.node, node.clone()) != node);
+        let session = session.clone();
+        drop(sessions);
+
+        tracing::info!("{} resumed session {}", device_id, session_id);
+        if moved {
+            self.sync_session(&session).await?;
+        }
+
+        let resumed = Message::ParticipantResumed {
+            session_id: session_id.to_string(),
+            device_id: device_id.to_string(),
+        };
+        let resumed = WsMessage::Text(serde_json::to_string(&resumed)?);
+        for participant in session.roster.participants.iter().filter(|p| p.device_id != device_id) {
+            if let Err(e) = self.deliver("relay", participant, resumed.clone()).await {
+                tracing::warn!("Failed to notify {}: {}", participant.device_id, e);
+            }
+        }
+        Ok(())
+    }
+
+    /// The token `device_id` resumes a session with after its connection drops
+    pub async fn resume_token(&self, session_id: &str, device_id: &str) -> Option<String> {
+        self.sessions.read().await.get(session_id)?.resume_tokens.get(device_id).cloned()
+    }
+
+    /// Remove participants held for longer than the resume grace period from their
+    /// sessions, ending the sessions that cannot go on without them
+    pub async fn end_expired_holds(&self) -> usize {
+        let expired: Vec<(String, String, String)> = self
+            .sessions
+            .read()
+            .await
+            .values()
+            .flat_map(|s| {
+                s.held
+                    .iter()
+                    .filter(|(_, hold)| hold.since.elapsed() >= self.resume_grace)
+                    .map(|(device_id, hold)| (s.id.clone(), device_id.clone(), hold.reason.clone()))
+            })
+            .collect();
+
+        for (session_id, device_id, reason) in &expired {
+            tracing::info!("{} did not resume session {} in time", device_id, session_id);
+            self.leave_session(session_id, device_id, reason).await;
+        }
+        expired.len()
     }
 
+    /// Open a session in which `client_b` shares its screen and `client_a` controls it
     pub async fn create_session(&self, client_a: String, client_b: String) -> Result<String> {
+        if self.is_draining() {
+            return Err(Error::Session("Relay node is draining".to_string()).into());
+        }
+
+        // Traffic is routed by sender, so a device can only be in one session at a time
+        if let Some(busy) = in_session(&*self.sessions.read().await, [&client_a, &client_b]) {
+            return Err(Error::Session(format!("{} is already in a session", busy)).into());
+        }
+
         let session_id = uuid::Uuid::new_v4().to_string();
 
+        let host_node = match self.locate(&client_b).await {
+            Some(Location::Remote(node)) => node,
+            _ => self.node_id().to_string(),
+        };
+
+        // A guest connecting to a signed-in host counts against the host's tenant
+        let (identity, host_identity) = {
+            let clients = self.clients.read().await;
+            let identity_of = |device_id: &str| clients.get(device_id).and_then(|c| c.identity);
+            (identity_of(&client_a), identity_of(&client_b))
+        };
+        let tenant_id = identity.or(host_identity).map(|i| i.tenant_id);
+        let record = match (identity, &self.store) {
+            (Some(identity), Some(_)) => Some(SessionRecord {
+                session_id: Uuid::parse_str(&session_id)?,
+                tenant_id: identity.tenant_id,
+                initiator_user_id: identity.user_id,
+                initiator_device: client_a.clone(),
+                target_device: client_b.clone(),
+                joined: Vec::new(),
+            }),
+            _ => None,
+        };
+
+        // A limit that cannot be checked refuses the session rather than lifting the limit
+        let limit = match tenant_id {
+            Some(tenant_id) => match self.limits.max_concurrent_sessions(tenant_id).await {
+                Ok(limit) => limit,
+                Err(e) => {
+                    tracing::error!("Session limit check failed, refusing session: {:#}", e);
+                    return Err(Error::Internal("Session limit unavailable".to_string()).into());
+                }
+            },
+            None => None,
+        };
+
+        // With a cluster the slot is taken atomically in Redis, so the limit holds across nodes
+        if let (Some(cluster), Some(tenant_id), Some(limit)) = (&self.cluster, tenant_id, limit) {
+            match cluster.try_acquire_session(tenant_id, &session_id, limit).await {
+                Ok(true) => {}
+                Ok(false) => return Err(Error::SessionLimitExceeded.into()),
+                Err(e) => {
+                    tracing::error!("Session limit check failed, refusing session: {}", e);
+                    return Err(Error::Internal("Session limit unavailable".to_string()).into());
+                }
+            }
+        }
+
         let session = Session {
             id: session_id.clone(),
-            client_a: client_a.clone(),
-            client_b: client_b.clone(),
+            origin_node: self.node_id().to_string(),
+            roster: Roster::new(
+                Participant {
+                    device_id: client_b.clone(),
+                    role: ParticipantRole::Host,
+                    node: host_node,
+                },
+                Participant {
+                    device_id: client_a.clone(),
+                    role: ParticipantRole::Controller,
+                    node: self.node_id().to_string(),
+                },
+            ),
             created_at: std::time::Instant::now(),
+            record: record.clone(),
+            tenant_id,
+            holds_slot: tenant_id.is_some() && limit.is_some(),
+            last_activity: Arc::new(AtomicU64::new(0)),
+            direct: false,
+            resume_tokens: HashMap::from([
+                (client_a.clone(), new_resume_token()),
+                (client_b.clone(), new_resume_token()),
+            ]),
+            held: HashMap::new(),
         };
 
         let mut sessions = self.sessions.write().await;
-        sessions.insert(session_id.clone(), session);
+        if let Some(busy) = in_session(&sessions, [&client_a, &client_b]) {
+            drop(sessions);
+            if let (true, Some(tenant_id), Some(cluster)) = (session.holds_slot, tenant_id, &self.cluster) {
+                if let Err(e) = cluster.release_session(tenant_id, &session_id).await {
+                    tracing::warn!("Failed to release session slot {}: {}", session_id, e);
+                }
+            }
+            return Err(Error::Session(format!("{} is already in a session", busy)).into());
+        }
+        if let (None, Some(tenant_id), Some(limit)) = (&self.cluster, tenant_id, limit) {
+            let active = sessions
+                .values()
+                .filter(|s| s.holds_slot && s.tenant_id == Some(tenant_id))
+                .count();
+            if active >= limit as usize {
+                return Err(Error::SessionLimitExceeded.into());
+            }
+        }
+        sessions.insert(session_id.clone(), session.clone());
+        drop(sessions);
+
+        self.metrics.session_started();
+        self.limits.session_opened(&session_id, tenant_id);
+        if let (Some(store), Some(record)) = (&self.store, record) {
+            store.session_started(record);
+        }
+
+        // Nodes of the other participants keep their own copy for routing
+        if let Err(e) = self.sync_session(&session).await {
+            if let Some(session) = self.sessions.write().await.remove(&session_id) {
+                self.session_ended(&session);
+            }
+            return Err(e);
+        }
 
         tracing::info!("Session created: {} ({} <-> {})", session_id, client_a, client_b);
 
         Ok(session_id)
     }
 
+    /// Invite `invitee` into the session of `from`, who must be its host or controller
+    pub async fn invite(&self, from: &str, invitee: &str, role: ParticipantRole) -> Result<()> {
+        let node = match self.locate(invitee).await {
+            Some(Location::Local) => self.node_id().to_string(),
+            Some(Location::Remote(node)) => node,
+            None => anyhow::bail!("Device {} is not online", invitee),
+        };
+        let participant = Participant {
+            device_id: invitee.to_string(),
+            role,
+            node,
+        };
+
+        let mut sessions = self.sessions.write().await;
+        let session = sessions
+            .values_mut()
+            .find(|s| s.roster.get(from).is_some())
+            .context("Not in a session")?;
+        session.roster.invite(from, participant.clone())?;
+        let session = session.clone();
+        drop(sessions);
+
+        // The invitee's node needs the session to accept the join
+        self.sync_session(&session).await?;
+
+        let invitation = Message::SessionInvitation {
+            session_id: session.id.clone(),
+            host: session.roster.host().map(|p| p.device_id.clone()).unwrap_or_default(),
+            invited_by: from.to_string(),
+            role,
+        };
+        self.deliver(from, &participant, WsMessage::Text(serde_json::to_string(&invitation)?)).await?;
+
+        tracing::info!("{} invited {} to session {} as {:?}", from, invitee, session.id, role);
+        Ok(())
+    }
+
+    /// Accept an invitation to a session
+    pub async fn join_session(&self, device_id: &str, session_id: &str) -> Result<ParticipantRole> {
+        let mut sessions = self.sessions.write().await;
+        if in_session(&sessions, [device_id]).is_some() {
+            return Err(Error::Session(format!("{} is already in a session", device_id)).into());
+        }
+        let session = sessions.get_mut(session_id).context("Session not found")?;
+        let role = session.roster.join(device_id)?;
+        if let Some(record) = &mut session.record {
+            record.note_joined(&session.roster);
+        }
+        session.resume_tokens.insert(device_id.to_string(), new_resume_token());
+        let session = session.clone();
+        drop(sessions);
+
+        tracing::info!("{} joined session {} as {:?}", device_id, session_id, role);
+        self.session_changed(&session).await;
+        Ok(role)
+    }
+
+    /// Hand control of the sender's session to another viewer, or to nobody
+    pub async fn transfer_control(&self, from: &str, to: Option<&str>) -> Result<()> {
+        let mut sessions = self.sessions.write().await;
+        let session = sessions
+            .values_mut()
+            .find(|s| s.roster.get(from).is_some())
+            .context("Not in a session")?;
+        session.roster.transfer_control(from, to)?;
+        let session = session.clone();
+        drop(sessions);
+
+        tracing::info!("{} passed control of session {} to {:?}", from, session.id, to);
+        self.session_changed(&session).await;
+        Ok(())
+    }
+
+    /// Remove a device from a session, ending it if the host left or no viewers remain
+    async fn leave_session(&self, session_id: &str, device_id: &str, reason: &str) {
+        let mut sessions = self.sessions.write().await;
+        let Some(session) = sessions.get_mut(session_id) else {
+            return;
+        };
+        session.resume_tokens.remove(device_id);
+        session.held.remove(device_id);
+
+        match session.roster.remove(device_id) {
+            Removal::NotPresent => {}
+            Removal::Left => {
+                let session = session.clone();
+                drop(sessions);
+                tracing::info!("{} left session {}", device_id, session_id);
+                self.session_changed(&session).await;
+            }
+            Removal::SessionEnded => {
+                if let Some(session) = sessions.remove(session_id) {
+                    drop(sessions);
+                    self.close_session(session, reason, true).await;
+                }
+            }
+        }
+    }
+
+    /// Tell the participants and the other nodes about a session's new roster
+    async fn session_changed(&self, session: &Session) {
+        let update = Message::ParticipantsChanged {
+            session_id: session.id.clone(),
+            participants: session.roster.info(),
+        };
+        let update = WsMessage::Text(serde_json::to_string(&update).unwrap());
+        for participant in &session.roster.participants {
+            if let Err(e) = self.deliver("relay", participant, update.clone()).await {
+                tracing::warn!("Failed to notify {}: {}", participant.device_id, e);
+            }
+        }
+
+        if let Err(e) = self.sync_session(session).await {
+            tracing::warn!("Failed to sync session {}: {}", session.id, e);
+        }
+
+        // Nothing left to route here once the last local participant is gone
+        if !self.is_involved(&session.origin_node, &session.roster) {
+            if let Some(session) = self.sessions.write().await.remove(&session.id) {
+                self.session_ended(&session);
+            }
+        }
+    }
+
+    /// Send a session's state to the other nodes that route its traffic
+    async fn sync_session(&self, session: &Session) -> Result<()> {
+        let Some(cluster) = &self.cluster else {
+            return Ok(());
+        };
+
+        for node in self.remote_nodes(session) {
+            let event = ClusterEvent::SessionSync {
+                session_id: session.id.clone(),
+                origin_node: session.origin_node.clone(),
+                roster: session.roster.clone(),
+                tenant_id: session.tenant_id,
+                resume_tokens: session.resume_tokens.clone(),
+            };
+            cluster.publish(&node, event, Vec::new()).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Other nodes with a participant, an invitee or the record of a session
+    fn remote_nodes(&self, session: &Session) -> BTreeSet<String> {
+        let mut nodes: BTreeSet<String> = session.roster.nodes().into_iter().map(str::to_string).collect();
+        nodes.insert(session.origin_node.clone());
+        nodes.remove(self.node_id());
+        nodes
+    }
+
+    fn is_involved(&self, origin_node: &str, roster: &Roster) -> bool {
+        origin_node == self.node_id() || roster.nodes().contains(self.node_id())
+    }
+
     pub async fn relay_message(&self, from: &str, to: &str, message: WsMessage) -> Result<()> {
         let clients = self.clients.read().await;
 
@@ -110,43 +797,462 @@ impl SessionManager {
             client.tx.send(message)
                 .map_err(|_| anyhow::anyhow!("Failed to send message to {}", to))?;
             tracing::debug!("Relayed message from {} to {}", from, to);
+            return Ok(());
+        }
+        drop(clients);
+
+        match self.locate(to).await {
+            Some(Location::Remote(node)) => self.forward(&node, from, to, message).await,
+            _ => {
+                tracing::warn!("Target client not found: {}", to);
+                Ok(())
+            }
+        }
+    }
+
+    /// Hand a message to a participant, on this node or another
+    async fn deliver(&self, from: &str, to: &Participant, message: WsMessage) -> Result<()> {
+        if to.node == self.node_id() {
+            self.relay_message(from, &to.device_id, message).await
         } else {
-            tracing::warn!("Target client not found: {}", to);
+            self.forward(&to.node, from, &to.device_id, message).await
         }
+    }
+
+    /// Hand a message to the node that `to` is connected to
+    async fn forward(&self, node: &str, from: &str, to: &str, message: WsMessage) -> Result<()> {
+        let Some(cluster) = &self.cluster else {
+            anyhow::bail!("Clustering is not enabled");
+        };
+
+        let (binary, payload) = match message {
+            WsMessage::Text(text) => (false, text.into_bytes()),
+            WsMessage::Binary(data) => (true, data),
+            _ => return Ok(()),
+        };
+
+        let event = ClusterEvent::Deliver {
+            from: from.to_string(),
+            to: to.to_string(),
+            binary,
+        };
+        cluster.publish(node, event, payload).await?;
+        tracing::debug!("Forwarded message from {} to {} via node {}", from, to, node);
 
         Ok(())
     }
 
-    pub async fn get_peer(&self, device_id: &str) -> Option<String> {
-        let sessions = self.sessions.read().await;
+    /// Apply an event sent by another relay node
+    pub async fn handle_cluster_event(&self, envelope: Envelope) {
+        match envelope.event {
+            ClusterEvent::SessionSync { session_id, origin_node, roster, tenant_id, resume_tokens } => {
+                let mut sessions = self.sessions.write().await;
+
+                if !self.is_involved(&origin_node, &roster) {
+                    if let Some(session) = sessions.remove(&session_id) {
+                        self.session_ended(&session);
+                    }
+                    return;
+                }
+
+                if let Some(session) = sessions.get_mut(&session_id) {
+                    // A held participant that resumed on another node is no longer held here
+                    let node_id = self.node_id();
+                    session.held.retain(|device_id, _| roster.get(device_id).is_some_and(|p| p.node == node_id));
+                    if let Some(record) = &mut session.record {
+                        record.note_joined(&roster);
+                    }
+                    session.roster = roster;
+                    session.resume_tokens = resume_tokens;
+                    return;
+                }
+
+                tracing::info!("Session joined: {} (opened on {})", session_id, origin_node);
+                let session = Session {
+                    id: session_id.clone(),
+                    origin_node,
+                    roster,
+                    created_at: std::time::Instant::now(),
+                    record: None, // Persisted by the origin node
+                    tenant_id,
+                    holds_slot: false,
+                    last_activity: Arc::new(AtomicU64::new(0)),
+                    direct: false,
+                    resume_tokens,
+                    held: HashMap::new(),
+                };
+                sessions.insert(session_id.clone(), session);
+                // Counted in metrics and against the tenant by the origin node only, or it would be counted once per node
+                self.limits.session_opened(&session_id, None);
+            }
+
+            ClusterEvent::SessionClosed { session_id } => {
+                if let Some(session) = self.sessions.write().await.remove(&session_id) {
+                    self.session_ended(&session);
+                    tracing::info!("Session closed by node {}: {}", envelope.from_node, session_id);
+                }
+            }
+
+            ClusterEvent::Deliver { from, to, binary } => {
+                let message = if binary {
+                    WsMessage::Binary(envelope.payload)
+                } else {
+                    match String::from_utf8(envelope.payload) {
+                        Ok(text) => WsMessage::Text(text),
+                        Err(_) => {
+                            tracing::warn!("Dropping forwarded message with invalid text from {}", from);
+                            return;
+                        }
+                    }
+                };
+
+                // Traffic from participants on other nodes keeps the session active here too
+                if let Some(session) = self.sessions.read().await.values().find(|s| s.roster.get(&from).is_some()) {
+                    session.touch();
+                }
+
+                let clients = self.clients.read().await;
+                match clients.get(&to) {
+                    Some(client) => {
+                        let _ = client.tx.send(message);
+                    }
+                    None => tracing::warn!("Forwarded message for unknown client: {}", to),
+                }
+            }
+        }
+    }
+
+    /// Refuse new sessions while existing ones finish, e.g. before taking the node down
+    pub fn set_draining(&self, draining: bool) {
+        self.draining.store(draining, Ordering::Relaxed);
+        tracing::info!("Drain mode {}", if draining { "enabled" } else { "disabled" });
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+
+    /// Record whether a participant's session traffic now bypasses the relay. Direct
+    /// sessions are exempt from the idle timeout since the relay no longer sees activity.
+    pub async fn set_direct(&self, device_id: &str, session_id: &str, direct: bool) {
+        let mut sessions = self.sessions.write().await;
+        let Some(session) = sessions.get_mut(session_id).filter(|s| s.roster.contains(device_id)) else {
+            return;
+        };
+        if session.direct != direct {
+            let path = if direct { "direct" } else { "relayed" };
+            tracing::info!("Session {} is now {} (reported by {})", session_id, path, device_id);
+            session.direct = direct;
+        }
+        if !direct {
+            session.touch();
+        }
+    }
+
+    /// Deliver a key agreement message from `from` to another participant of the same
+    /// session, or to all of them. Unlike session traffic this ignores roles, since
+    /// observers need keys too; the relay cannot use the keys itself.
+    pub async fn deliver_key_message(&self, from: &str, session_id: &str, to: Option<&str>, message: WsMessage) {
+        let recipients: Vec<Participant> = {
+            let sessions = self.sessions.read().await;
+            let Some(session) = sessions.get(session_id).filter(|s| s.roster.contains(from)) else {
+                tracing::debug!("Dropped key message from {}: not in session {}", from, session_id);
+                return;
+            };
+            session
+                .roster
+                .participants
+                .iter()
+                .filter(|p| p.device_id != from && to.is_none_or(|to| p.device_id == to))
+                .cloned()
+                .collect()
+        };
+
+        for recipient in &recipients {
+            if let Err(e) = self.deliver(from, recipient, message.clone()).await {
+                tracing::warn!("Failed to deliver key message to {}: {}", recipient.device_id, e);
+            }
+        }
+    }
+
+    pub async fn list_sessions(&self) -> Vec<SessionInfo> {
+        self.sessions
+            .read()
+            .await
+            .values()
+            .map(|s| SessionInfo {
+                session_id: s.id.clone(),
+                participants: s.roster.participants.clone(),
+                invited: s.roster.invited.clone(),
+                tenant_id: s.tenant_id,
+                duration_secs: s.created_at.elapsed().as_secs(),
+                bytes_relayed: self.limits.session_bytes(&s.id),
+                direct: s.direct,
+            })
+            .collect()
+    }
+
+    /// End a session and send `Disconnect` with `reason` to every participant.
+    /// Returns false if no such session is active on this node.
+    pub async fn terminate_session(&self, session_id: &str, reason: &str) -> bool {
+        let Some(session) = self.sessions.write().await.remove(session_id) else {
+            return false;
+        };
+
+        self.close_session(session, reason, true).await;
+        true
+    }
+
+    /// Tell every connected client the relay is going away and end all sessions
+    pub async fn shutdown(&self, reason: &str) {
+        self.draining.store(true, Ordering::Relaxed);
+
+        let sessions: Vec<Session> = self.sessions.write().await.drain().map(|(_, s)| s).collect();
+        for session in sessions {
+            self.close_session(session, reason, false).await;
+        }
+
+        let clients: Vec<Client> = self.clients.write().await.drain().map(|(_, c)| c).collect();
+        for client in &clients {
+            let _ = client.tx.send(disconnect_message(reason));
+            let _ = client.tx.send(WsMessage::Close(None));
+        }
+
+        if let Some(cluster) = &self.cluster {
+            for client in &clients {
+                if let Err(e) = cluster.unregister_device(&client.device_id).await {
+                    tracing::warn!("Failed to remove presence of {}: {}", client.device_id, e);
+                }
+            }
+        }
+
+        tracing::info!("Disconnected {} clients: {}", clients.len(), reason);
+    }
+
+    /// End sessions that have relayed nothing for `timeout`. Only the host's node
+    /// sees all of a session's input and media, so only it makes the call.
+    pub async fn end_idle_sessions(&self, timeout: Duration) -> usize {
+        let mut sessions = self.sessions.write().await;
+        let idle: Vec<String> = sessions
+            .values()
+            .filter(|s| !s.direct && s.idle_for() >= timeout)
+            .filter(|s| s.roster.host().is_some_and(|h| h.node == self.node_id()))
+            .map(|s| s.id.clone())
+            .collect();
+        let idle: Vec<Session> = idle.iter().filter_map(|id| sessions.remove(id)).collect();
+        drop(sessions);
+
+        let count = idle.len();
+        let reason = match timeout.as_secs() {
+            secs if secs >= 60 => format!("Session ended after {} minutes without activity", secs / 60),
+            secs => format!("Session ended after {} seconds without activity", secs),
+        };
+        for session in idle {
+            self.close_session(session, &reason, true).await;
+        }
+        count
+    }
+
+    /// Notify the participants of a removed session and the other nodes holding it
+    async fn close_session(&self, session: Session, reason: &str, notify_local: bool) {
+        self.session_ended(&session);
+
+        for participant in &session.roster.participants {
+            if participant.node == self.node_id() && !notify_local {
+                continue;
+            }
+            if let Err(e) = self.deliver("relay", participant, disconnect_message(reason)).await {
+                tracing::warn!("Failed to notify {}: {}", participant.device_id, e);
+            }
+        }
+
+        if let Some(cluster) = &self.cluster {
+            for node in self.remote_nodes(&session) {
+                let event = ClusterEvent::SessionClosed { session_id: session.id.clone() };
+                if let Err(e) = cluster.publish(&node, event, Vec::new()).await {
+                    tracing::warn!("Failed to notify node {}: {}", node, e);
+                }
+            }
+        }
+
+        tracing::info!("Session terminated: {} ({})", session.id, reason);
+    }
+
+    /// Bookkeeping for a session that was just removed
+    fn session_ended(&self, session: &Session) {
+        let duration = session.created_at.elapsed();
+        let origin = session.origin_node == self.node_id();
+        if origin {
+            self.metrics.session_ended(duration);
+        }
+        let billed_to = session.tenant_id.filter(|_| origin);
+        let bytes_relayed = self.limits.session_closed(&session.id, billed_to);
+
+        if let (Some(store), Some(record)) = (&self.store, &session.record) {
+            store.session_ended(record.clone(), duration, bytes_relayed);
+        }
+
+        // Free the tenant's slot without holding up the caller
+        if let (true, Some(tenant_id), Some(cluster)) = (session.holds_slot, session.tenant_id, &self.cluster) {
+            let cluster = cluster.clone();
+            let session_id = session.id.clone();
+            tokio::spawn(async move {
+                if let Err(e) = cluster.release_session(tenant_id, &session_id).await {
+                    tracing::warn!("Failed to release session slot {}: {}", session_id, e);
+                }
+            });
+        }
+    }
+
+    /// Forward a message to the participants its sender's role allows, counting it.
+    /// Waits here when the session or its tenant is over its bandwidth cap, which
+    /// stops reading from the sender until it is back within the limit.
+    pub async fn relay_to_peer(&self, from: &str, message: WsMessage) -> Result<()> {
+        let Some(route) = self.route(from, &message).await else {
+            return Ok(());
+        };
+
+        if route.recipients.is_empty() {
+            tracing::debug!("Dropped message from {}: its role has no recipients", from);
+            return Ok(());
+        }
 
-        for session in sessions.values() {
-            if session.client_a == device_id {
-                return Some(session.client_b.clone());
-            } else if session.client_b == device_id {
-                return Some(session.client_a.clone());
+        let bytes = message.len();
+        let wait = self.limits.reserve(&route.session_id, route.tenant_id, bytes * route.recipients.len());
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+
+        for recipient in &route.recipients {
+            if let Err(e) = self.deliver(from, recipient, message.clone()).await {
+                tracing::warn!("Failed to relay message to {}: {}", recipient.device_id, e);
+                continue;
             }
+            self.metrics.message_relayed(route.direction, bytes);
         }
 
-        None
+        Ok(())
+    }
+
+    /// Who receives a message from `device_id`, given its role in its session
+    pub async fn route(&self, device_id: &str, message: &WsMessage) -> Option<Route> {
+        let sessions = self.sessions.read().await;
+        let session = sessions.values().find(|s| s.roster.get(device_id).is_some())?;
+
+        let from_host = session.roster.host().is_some_and(|h| h.device_id == device_id);
+        let mut recipients = session.roster.recipients(device_id, from_host && is_media(message));
+        // Held participants get what they missed from their peers once they resume
+        recipients.retain(|p| !session.held.contains_key(&p.device_id));
+        if !recipients.is_empty() {
+            session.touch();
+        }
+
+        Some(Route {
+            session_id: session.id.clone(),
+            recipients: recipients.into_iter().cloned().collect(),
+            direction: if from_host { Direction::TargetToInitiator } else { Direction::InitiatorToTarget },
+            tenant_id: session.tenant_id,
+        })
+    }
+
+    pub async fn locate(&self, device_id: &str) -> Option<Location> {
+        if self.clients.read().await.contains_key(device_id) {
+            return Some(Location::Local);
+        }
+
+        let cluster = self.cluster.as_ref()?;
+        match cluster.locate(device_id).await {
+            Ok(Some(node)) if node != cluster.node_id() => Some(Location::Remote(node)),
+            Ok(_) => None,
+            Err(e) => {
+                tracing::warn!("Presence lookup failed for {}: {}", device_id, e);
+                None
+            }
+        }
     }
 
     pub async fn client_exists(&self, device_id: &str) -> bool {
-        let clients = self.clients.read().await;
-        clients.contains_key(device_id)
+        self.locate(device_id).await.is_some()
+    }
+}
+
+/// Screen content the host sends to every viewer, as opposed to replies meant for the controller
+fn is_media(message: &WsMessage) -> bool {
+    #[derive(Deserialize)]
+    struct MessageType {
+        #[serde(rename = "type")]
+        kind: String,
+    }
+
+    match message {
+        WsMessage::Binary(_) => true,
+        WsMessage::Text(text) => serde_json::from_str::<MessageType>(text)
+            .is_ok_and(|m| MEDIA_TYPES.contains(&m.kind.as_str())),
+        _ => false,
     }
 }
 
-pub async fn handle_client(
-    socket: TcpStream,
+/// The first of `devices` that already takes part in one of `sessions`
+fn in_session<'a, const N: usize>(sessions: &HashMap<String, Session>, devices: [&'a str; N]) -> Option<&'a str> {
+    devices
+        .into_iter()
+        .find(|device_id| sessions.values().any(|s| s.roster.get(device_id).is_some()))
+}
+
+fn new_resume_token() -> String {
+    format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple())
+}
+
+fn disconnect_message(reason: &str) -> WsMessage {
+    let message = Message::Disconnect { reason: Some(reason.to_string()) };
+    WsMessage::Text(serde_json::to_string(&message).unwrap())
+}
+
+pub async fn handle_client<S>(
+    socket: S,
     addr: std::net::SocketAddr,
     manager: Arc<SessionManager>,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    manager.metrics().connection_opened();
+
+    let ws_stream = match accept_async(socket).await {
+        Ok(ws_stream) => ws_stream,
+        Err(e) => {
+            manager.metrics().handshake_failed();
+            return Err(anyhow::anyhow!("WebSocket handshake failed: {}", e));
+        }
+    };
+
+    let (ws_write, ws_read) = ws_stream.split();
+    serve_client(ws_write, ws_read, addr, manager).await
+}
+
+/// Serve a client connected over QUIC; its streams carry the same messages as a WebSocket
+pub async fn handle_quic_client(
+    connection: quinn::Connection,
+    manager: Arc<SessionManager>,
 ) -> Result<()> {
-    let ws_stream = accept_async(socket).await
-        .map_err(|e| anyhow::anyhow!("WebSocket handshake failed: {}", e))?;
+    manager.metrics().connection_opened();
 
-    let (mut ws_write, mut ws_read) = ws_stream.split();
+    let addr = connection.remote_address();
+    let (sink, stream) = quic::split(connection);
+    serve_client(sink, stream, addr, manager).await
+}
 
+async fn serve_client<W, R>(
+    mut ws_write: W,
+    mut ws_read: R,
+    addr: std::net::SocketAddr,
+    manager: Arc<SessionManager>,
+) -> Result<()>
+where
+    W: Sink<WsMessage> + Unpin + Send + 'static,
+    W::Error: std::fmt::Display,
+    R: Stream<Item = Result<WsMessage, WsError>> + Unpin,
+{
     // Create channel for outgoing messages
     let (tx, mut rx) = mpsc::unbounded_channel::<WsMessage>();
 
@@ -163,25 +1269,75 @@ pub async fn handle_client(
         }
     });
 
+    // Ping on an interval; any frame from the client counts as an answer
+    let keepalive = manager.keepalive();
+    let mut ping_timer = tokio::time::interval_at(tokio::time::Instant::now() + keepalive.interval, keepalive.interval);
+    let mut missed_pings = 0;
+    let mut disconnect_reason = "Peer disconnected";
+    let mut left = false; // Said goodbye, rather than the connection dropping
+
     // Handle incoming messages
-    while let Some(msg) = ws_read.next().await {
+    loop {
+        let msg = tokio::select! {
+            msg = ws_read.next() => match msg {
+                Some(msg) => msg,
+                None => break,
+            },
+            _ = ping_timer.tick() => {
+                if missed_pings >= keepalive.max_missed {
+                    tracing::warn!("Evicting {:?} ({}): missed {} pings", device_id, addr, missed_pings);
+                    disconnect_reason = "Peer connection timed out";
+                    break;
+                }
+                missed_pings += 1;
+                let _ = tx.send(WsMessage::Ping(Vec::new()));
+                continue;
+            }
+        };
+        missed_pings = 0;
+
         match msg {
             Ok(WsMessage::Text(text)) => {
                 match serde_json::from_str::<Message>(&text) {
-                    Ok(Message::Hello { device_id: id, platform, capabilities }) => {
+                    Ok(Message::Hello { device_id: id, platform, capabilities, auth_token }) => {
+                        // Signed-in users are identified; guests connect anonymously
+                        let identity = match auth_token.as_deref().map(|t| manager.authenticate(t)) {
+                            Some(Ok(identity)) => Some(identity),
+                            Some(Err(e)) => {
+                                tracing::warn!("Rejected Hello from {} ({}): {}", id, addr, e);
+                                let error = serde_json::to_string(&Message::ConnectResponse {
+                                    success: false,
+                                    session_id: None,
+                                    error: Some("Invalid auth token".to_string()),
+                                    error_code: None,
+                                    resume_token: None,
+                                }).unwrap();
+                                let _ = tx.send(WsMessage::Text(error));
+                                continue;
+                            }
+                            None => None,
+                        };
+
                         device_id = Some(id.clone());
-                        manager.register_client(id.clone(), platform, tx.clone()).await;
+                        manager.register_client(id.clone(), platform, identity, tx.clone()).await;
                         authenticated = true;
 
-                        tracing::info!("Client authenticated: {} from {}", id, addr);
+                        tracing::info!("Client authenticated: {} from {} (user: {:?})", id, addr, identity.map(|i| i.user_id));
 
                         // Send acknowledgment
                         let ack = serde_json::to_string(&Message::ConnectResponse {
                             success: true,
                             session_id: Some(id.clone()),
                             error: None,
+                            error_code: None,
+                            resume_token: None,
                         }).unwrap();
                         let _ = tx.send(WsMessage::Text(ack));
+
+                        if let Some(port) = manager.rendezvous_port {
+                            let info = serde_json::to_string(&Message::RendezvousInfo { port }).unwrap();
+                            let _ = tx.send(WsMessage::Text(info));
+                        }
                     }
 
                     Ok(Message::ConnectRequest { target_id, auth_token }) => {
@@ -190,6 +1346,8 @@ pub async fn handle_client(
                                 success: false,
                                 session_id: None,
                                 error: Some("Not authenticated".to_string()),
+                                error_code: None,
+                                resume_token: None,
                             }).unwrap();
                             let _ = tx.send(WsMessage::Text(error));
                             continue;
@@ -203,6 +1361,8 @@ pub async fn handle_client(
                                 success: false,
                                 session_id: None,
                                 error: Some("Target device not found".to_string()),
+                                error_code: None,
+                                resume_token: None,
                             }).unwrap();
                             let _ = tx.send(WsMessage::Text(error));
                             continue;
@@ -211,63 +1371,166 @@ pub async fn handle_client(
                         // Create session
                         match manager.create_session(from_id.clone(), target_id.clone()).await {
                             Ok(session_id) => {
-                                let response = serde_json::to_string(&Message::ConnectResponse {
+                                // Each side gets its own resume token
+                                let response = |resume_token: Option<String>| serde_json::to_string(&Message::ConnectResponse {
                                     success: true,
                                     session_id: Some(session_id.clone()),
                                     error: None,
+                                    error_code: None,
+                                    resume_token,
                                 }).unwrap();
-                                let _ = tx.send(WsMessage::Text(response.clone()));
+                                let _ = tx.send(WsMessage::Text(response(manager.resume_token(&session_id, from_id).await)));
 
                                 // Notify target
                                 let _ = manager.relay_message(
                                     from_id,
                                     &target_id,
-                                    WsMessage::Text(response),
+                                    WsMessage::Text(response(manager.resume_token(&session_id, &target_id).await)),
                                 ).await;
                             }
                             Err(e) => {
+                                let error_code = e.downcast_ref::<Error>().map(|e| e.error_code().to_string());
                                 let error = serde_json::to_string(&Message::ConnectResponse {
                                     success: false,
                                     session_id: None,
                                     error: Some(format!("Failed to create session: {}", e)),
+                                    error_code,
+                                    resume_token: None,
                                 }).unwrap();
                                 let _ = tx.send(WsMessage::Text(error));
                             }
                         }
                     }
 
-                    Ok(Message::Ping) => {
-                        let pong = serde_json::to_string(&Message::Pong).unwrap();
+                    Ok(Message::ResumeSession { session_id, resume_token }) => {
+                        let Some(ref dev_id) = device_id else {
+                            continue;
+                        };
+
+                        let response = match manager.resume_session(dev_id, &session_id, &resume_token).await {
+                            Ok(()) => Message::ConnectResponse {
+                                success: true,
+                                session_id: Some(session_id),
+                                error: None,
+                                error_code: None,
+                                resume_token: Some(resume_token),
+                            },
+                            Err(e) => {
+                                tracing::info!("{} could not resume session {}: {}", dev_id, session_id, e);
+                                Message::ConnectResponse {
+                                    success: false,
+                                    session_id: None,
+                                    error: Some(e.to_string()),
+                                    error_code: e.downcast_ref::<Error>().map(|e| e.error_code().to_string()),
+                                    resume_token: None,
+                                }
+                            }
+                        };
+                        let _ = tx.send(WsMessage::Text(serde_json::to_string(&response).unwrap()));
+                    }
+
+                    Ok(Message::InviteParticipant { device_id: invitee, role }) => {
+                        let Some(ref dev_id) = device_id else {
+                            continue;
+                        };
+
+                        if let Err(e) = manager.invite(dev_id, &invitee, role).await {
+                            let error = serde_json::to_string(&Message::SessionRequestFailed {
+                                error: format!("Failed to invite {}: {}", invitee, e),
+                            }).unwrap();
+                            let _ = tx.send(WsMessage::Text(error));
+                        }
+                    }
+
+                    Ok(Message::JoinSession { session_id }) => {
+                        let Some(ref dev_id) = device_id else {
+                            continue;
+                        };
+
+                        let response = match manager.join_session(dev_id, &session_id).await {
+                            Ok(_) => Message::ConnectResponse {
+                                success: true,
+                                resume_token: manager.resume_token(&session_id, dev_id).await,
+                                session_id: Some(session_id),
+                                error: None,
+                                error_code: None,
+                            },
+                            Err(e) => Message::ConnectResponse {
+                                success: false,
+                                session_id: None,
+                                error: Some(format!("Failed to join session: {}", e)),
+                                error_code: None,
+                                resume_token: None,
+                            },
+                        };
+                        let _ = tx.send(WsMessage::Text(serde_json::to_string(&response).unwrap()));
+                    }
+
+                    Ok(Message::TransferControl { device_id: to }) => {
+                        let Some(ref dev_id) = device_id else {
+                            continue;
+                        };
+
+                        if let Err(e) = manager.transfer_control(dev_id, to.as_deref()).await {
+                            let error = serde_json::to_string(&Message::SessionRequestFailed {
+                                error: format!("Failed to transfer control: {}", e),
+                            }).unwrap();
+                            let _ = tx.send(WsMessage::Text(error));
+                        }
+                    }
+
+                    Ok(Message::KeyExchange { session_id, device_id: sender, to }) => {
+                        if let Some(ref dev_id) = device_id {
+                            if *dev_id == sender {
+                                manager.deliver_key_message(dev_id, &session_id, to.as_deref(), WsMessage::Text(text)).await;
+                            }
+                        }
+                    }
+
+                    Ok(Message::SenderKey { session_id, device_id: sender, to }) => {
+                        if let Some(ref dev_id) = device_id {
+                            if *dev_id == sender {
+                                manager.deliver_key_message(dev_id, &session_id, Some(&to), WsMessage::Text(text)).await;
+                            }
+                        }
+                    }
+
+                    Ok(Message::PathChanged { session_id, direct }) => {
+                        if let Some(ref dev_id) = device_id {
+                            manager.set_direct(dev_id, &session_id, direct).await;
+                        }
+                    }
+
+                    Ok(Message::Ping { timestamp }) => {
+                        let pong = serde_json::to_string(&Message::Pong { timestamp }).unwrap();
                         let _ = tx.send(WsMessage::Text(pong));
                     }
 
                     Ok(Message::Disconnect { .. }) => {
                         tracing::info!("Client requested disconnect: {:?}", device_id);
+                        left = true;
                         break;
                     }
 
                     Ok(_) | Err(_) => {
-                        // Relay all other messages to peer
+                        // Relay all other messages to the participants the sender's role allows
                         if let Some(ref dev_id) = device_id {
-                            if let Some(peer_id) = manager.get_peer(dev_id).await {
-                                let _ = manager.relay_message(dev_id, &peer_id, WsMessage::Text(text)).await;
-                            }
+                            let _ = manager.relay_to_peer(dev_id, WsMessage::Text(text)).await;
                         }
                     }
                 }
             }
 
             Ok(WsMessage::Binary(data)) => {
-                // Relay binary messages (video frames, etc.) to peer
+                // Relay binary messages (video frames, etc.) like text ones
                 if let Some(ref dev_id) = device_id {
-                    if let Some(peer_id) = manager.get_peer(dev_id).await {
-                        let _ = manager.relay_message(dev_id, &peer_id, WsMessage::Binary(data)).await;
-                    }
+                    let _ = manager.relay_to_peer(dev_id, WsMessage::Binary(data)).await;
                 }
             }
 
             Ok(WsMessage::Close(_)) => {
                 tracing::info!("Client closed connection: {:?}", device_id);
+                left = true;
                 break;
             }
 
@@ -284,12 +1547,406 @@ pub async fn handle_client(
         }
     }
 
-    // Cleanup
+    // Cleanup; a dropped connection can come back and resume its sessions
     if let Some(dev_id) = device_id {
-        manager.unregister_client(&dev_id).await;
+        if left {
+            manager.unregister_client(&dev_id, disconnect_reason).await;
+        } else {
+            manager.client_dropped(&dev_id, &tx, disconnect_reason).await;
+        }
     }
 
     send_task.abort();
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn connect(manager: &SessionManager, device_id: &str) -> mpsc::UnboundedReceiver<WsMessage> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        manager.register_client(device_id.to_string(), "linux".to_string(), None, tx).await;
+        rx
+    }
+
+    #[tokio::test]
+    async fn test_terminate_session_and_drain() -> Result<()> {
+        let manager = SessionManager::new();
+        let mut rx_a = connect(&manager, "111").await;
+        let mut rx_b = connect(&manager, "222").await;
+
+        let session_id = manager.create_session("111".to_string(), "222".to_string()).await?;
+        assert_eq!(manager.list_sessions().await.len(), 1);
+
+        assert!(manager.terminate_session(&session_id, "Policy violation").await);
+        assert!(!manager.terminate_session(&session_id, "Policy violation").await);
+        assert!(manager.list_sessions().await.is_empty());
+
+        for rx in [&mut rx_a, &mut rx_b] {
+            let Some(WsMessage::Text(text)) = rx.recv().await else {
+                panic!("Expected a Disconnect message");
+            };
+            let Message::Disconnect { reason } = serde_json::from_str(&text)? else {
+                panic!("Expected a Disconnect message, got {}", text);
+            };
+            assert_eq!(reason.as_deref(), Some("Policy violation"));
+        }
+
+        manager.set_draining(true);
+        assert!(manager.create_session("111".to_string(), "222".to_string()).await.is_err());
+        manager.set_draining(false);
+        assert!(manager.create_session("111".to_string(), "222".to_string()).await.is_ok());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_idle_sessions_end() -> Result<()> {
+        let manager = SessionManager::new();
+        let _viewer = connect(&manager, "111").await;
+        let mut host = connect(&manager, "222").await;
+        manager.create_session("111".to_string(), "222".to_string()).await?;
+
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        let input = r#"{"type":"MouseMove","x":1,"y":1}"#;
+        manager.relay_to_peer("111", WsMessage::Text(input.to_string())).await?;
+        assert_eq!(manager.end_idle_sessions(Duration::from_millis(20)).await, 0);
+
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        assert_eq!(manager.end_idle_sessions(Duration::from_millis(20)).await, 1);
+
+        host.recv().await;
+        let Some(Message::Disconnect { reason }) = next_message(&mut host) else {
+            panic!("Expected a Disconnect message");
+        };
+        assert!(reason.is_some_and(|r| r.contains("without activity")));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_direct_sessions_are_not_idle() -> Result<()> {
+        let manager = SessionManager::new();
+        let _viewer = connect(&manager, "111").await;
+        let _host = connect(&manager, "222").await;
+        let session_id = manager.create_session("111".to_string(), "222".to_string()).await?;
+
+        // Only participants can change the path
+        manager.set_direct("333", &session_id, true).await;
+        assert!(!manager.list_sessions().await[0].direct);
+        manager.set_direct("111", &session_id, true).await;
+        assert!(manager.list_sessions().await[0].direct);
+
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        assert_eq!(manager.end_idle_sessions(Duration::from_millis(20)).await, 0);
+
+        // Falling back to the relay restarts the idle clock
+        manager.set_direct("222", &session_id, false).await;
+        assert_eq!(manager.end_idle_sessions(Duration::from_millis(20)).await, 0);
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        assert_eq!(manager.end_idle_sessions(Duration::from_millis(20)).await, 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_unresponsive_client_is_evicted() -> Result<()> {
+        let manager = Arc::new(SessionManager::new().with_keepalive(Keepalive {
+            interval: Duration::from_millis(20),
+            max_missed: 2,
+        }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+
+        let server_manager = manager.clone();
+        let server = tokio::spawn(async move {
+            let (socket, peer) = listener.accept().await?;
+            handle_client(socket, peer, server_manager).await
+        });
+
+        let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{}", addr)).await?;
+        let hello = r#"{"type":"Hello","device_id":"111","platform":"linux","capabilities":[]}"#;
+        ws.send(WsMessage::Text(hello.to_string())).await?;
+
+        // The client never reads, so the relay's pings go unanswered
+        tokio::time::timeout(Duration::from_secs(2), server).await???;
+        assert!(!manager.client_exists("111").await);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_busy_host_refuses_second_connect() -> Result<()> {
+        let manager = Arc::new(SessionManager::new());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+
+        let server_manager = manager.clone();
+        tokio::spawn(async move {
+            while let Ok((socket, peer)) = listener.accept().await {
+                tokio::spawn(handle_client(socket, peer, server_manager.clone()));
+            }
+        });
+
+        let mut clients = Vec::new();
+        for device_id in ["host", "first", "second"] {
+            let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{}", addr)).await?;
+            let hello = format!(r#"{{"type":"Hello","device_id":"{}","platform":"linux","capabilities":[]}}"#, device_id);
+            ws.send(WsMessage::Text(hello)).await?;
+            clients.push(ws);
+        }
+        for ws in &mut clients {
+            assert!(matches!(next_response(ws).await?, Message::ConnectResponse { success: true, .. }));
+        }
+
+        let connect = WsMessage::Text(r#"{"type":"ConnectRequest","target_id":"host"}"#.to_string());
+        clients[1].send(connect.clone()).await?;
+        let Message::ConnectResponse { success: true, session_id: Some(session_id), .. } = next_response(&mut clients[1]).await? else {
+            panic!("Expected the first viewer to connect");
+        };
+        next_response(&mut clients[0]).await?;
+
+        clients[2].send(connect).await?;
+        let Message::ConnectResponse { success: false, error_code, .. } = next_response(&mut clients[2]).await? else {
+            panic!("Expected the busy host to be refused");
+        };
+        assert_eq!(error_code.as_deref(), Some("SESSION_ERROR"));
+
+        // The host stays in its one session, and a viewer cannot join a second
+        let sessions = manager.list_sessions().await;
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].session_id, session_id);
+        assert!(manager.create_session("second".to_string(), "first".to_string()).await.is_err());
+        assert!(manager.join_session("host", &session_id).await.is_err());
+        Ok(())
+    }
+
+    /// The next message a test client is sent, skipping pings
+    async fn next_response<S>(ws: &mut S) -> Result<Message>
+    where
+        S: Stream<Item = Result<WsMessage, WsError>> + Unpin,
+    {
+        loop {
+            let msg = tokio::time::timeout(Duration::from_secs(2), ws.next()).await?.context("Connection closed")??;
+            if let WsMessage::Text(text) = msg {
+                return Ok(serde_json::from_str(&text)?);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_mirrored_sessions_are_billed_once() -> Result<()> {
+        let manager = SessionManager::new();
+        let tenant_id = Uuid::new_v4();
+        let participant = |device_id: &str, role, node: &str| Participant {
+            device_id: device_id.to_string(),
+            role,
+            node: node.to_string(),
+        };
+        let sync = ClusterEvent::SessionSync {
+            session_id: "s1".to_string(),
+            origin_node: "node-a".to_string(),
+            roster: Roster::new(
+                participant("host", ParticipantRole::Host, LOCAL_NODE),
+                participant("viewer", ParticipantRole::Controller, "node-a"),
+            ),
+            tenant_id: Some(tenant_id),
+            resume_tokens: HashMap::new(),
+        };
+        manager.handle_cluster_event(Envelope { from_node: "node-a".to_string(), event: sync, payload: Vec::new() }).await;
+        assert_eq!(manager.list_sessions().await.len(), 1);
+        let metrics = manager.metrics_snapshot().await;
+        assert_eq!((metrics.active_sessions, metrics.sessions_total), (0, 0));
+
+        // Bytes are counted where they are relayed, the session where it was opened
+        let _host = connect(&manager, "host").await;
+        let frame = r#"{"type":"VideoFrame","data":[],"width":1,"height":1,"timestamp":0}"#;
+        manager.relay_to_peer("host", WsMessage::Text(frame.to_string())).await.ok();
+        let usage = manager.limits().usage();
+        assert_eq!((usage[&tenant_id].sessions_started, usage[&tenant_id].active_sessions), (0, 0));
+        assert!(usage[&tenant_id].bytes_relayed > 0);
+
+        let closed = ClusterEvent::SessionClosed { session_id: "s1".to_string() };
+        manager.handle_cluster_event(Envelope { from_node: "node-a".to_string(), event: closed, payload: Vec::new() }).await;
+        assert_eq!(manager.limits().usage()[&tenant_id].active_sessions, 0);
+        assert_eq!(manager.metrics_snapshot().await.session_duration.count, 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_guest_counts_against_host_tenant() -> Result<()> {
+        let tenant_id = Uuid::new_v4();
+        let identity = ClientIdentity { user_id: Uuid::new_v4(), tenant_id };
+        let (tx, _host) = mpsc::unbounded_channel();
+
+        let manager = SessionManager::new();
+        manager.register_client("222".to_string(), "linux".to_string(), Some(identity), tx.clone()).await;
+        let _guest = connect(&manager, "111").await;
+        manager.create_session("111".to_string(), "222".to_string()).await?;
+        assert_eq!(manager.list_sessions().await[0].tenant_id, Some(tenant_id));
+
+        // A limit that cannot be read refuses the session instead of allowing it
+        let pool = sqlx::postgres::PgPoolOptions::new()
+            .acquire_timeout(Duration::from_millis(200))
+            .connect_lazy("postgres://scrdesk@127.0.0.1:1/scrdesk")?;
+        let manager = SessionManager::new().with_limits(Limits::new(Some(pool), None, None));
+        manager.register_client("222".to_string(), "linux".to_string(), Some(identity), tx).await;
+        let _guest = connect(&manager, "111").await;
+        let err = manager.create_session("111".to_string(), "222".to_string()).await.unwrap_err();
+        assert_eq!(err.downcast_ref::<Error>().map(|e| e.error_code()), Some("INTERNAL_ERROR"));
+        assert!(manager.list_sessions().await.is_empty());
+        Ok(())
+    }
+
+    fn next_message(rx: &mut mpsc::UnboundedReceiver<WsMessage>) -> Option<Message> {
+        match rx.try_recv() {
+            Ok(WsMessage::Text(text)) => serde_json::from_str(&text).ok(),
+            _ => None,
+        }
+    }
+
+    fn drain(rx: &mut mpsc::UnboundedReceiver<WsMessage>) {
+        while rx.try_recv().is_ok() {}
+    }
+
+    #[tokio::test]
+    async fn test_observer_joins_and_takes_control() -> Result<()> {
+        let manager = SessionManager::new();
+        let mut host = connect(&manager, "host").await;
+        let mut senior = connect(&manager, "senior").await;
+        let mut junior = connect(&manager, "junior").await;
+
+        let session_id = manager.create_session("senior".to_string(), "host".to_string()).await?;
+        manager.invite("senior", "junior", ParticipantRole::Observer).await?;
+        let Some(Message::SessionInvitation { session_id: invited_to, host: host_id, .. }) = next_message(&mut junior) else {
+            panic!("Expected an invitation");
+        };
+        assert_eq!((invited_to.as_str(), host_id.as_str()), (session_id.as_str(), "host"));
+
+        manager.join_session("junior", &session_id).await?;
+        for rx in [&mut host, &mut senior, &mut junior] {
+            drain(rx);
+        }
+
+        // Video reaches both viewers, replies only the controller
+        let frame = r#"{"type":"VideoFrame","data":[],"width":1,"height":1,"timestamp":0}"#;
+        manager.relay_to_peer("host", WsMessage::Text(frame.to_string())).await?;
+        manager.relay_to_peer("host", WsMessage::Text(r#"{"type":"Pong"}"#.to_string())).await?;
+        assert!(senior.try_recv().is_ok() && senior.try_recv().is_ok());
+        assert!(junior.try_recv().is_ok() && junior.try_recv().is_err());
+
+        // Observers cannot send input until they are given control
+        let input = r#"{"type":"MouseMove","x":1,"y":1}"#;
+        manager.relay_to_peer("junior", WsMessage::Text(input.to_string())).await?;
+        assert!(host.try_recv().is_err());
+
+        assert!(manager.transfer_control("junior", Some("junior")).await.is_err());
+        manager.transfer_control("senior", Some("junior")).await?;
+        drain(&mut host);
+        manager.relay_to_peer("junior", WsMessage::Text(input.to_string())).await?;
+        manager.relay_to_peer("senior", WsMessage::Text(input.to_string())).await?;
+        assert!(host.try_recv().is_ok() && host.try_recv().is_err());
+
+        // The session outlives a viewer leaving, but not the host
+        manager.unregister_client("senior", "Peer disconnected").await;
+        assert_eq!(manager.list_sessions().await.len(), 1);
+        manager.unregister_client("host", "Peer disconnected").await;
+        assert!(manager.list_sessions().await.is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_key_messages_reach_observers() -> Result<()> {
+        let manager = SessionManager::new();
+        let mut host = connect(&manager, "host").await;
+        let mut senior = connect(&manager, "senior").await;
+        let mut junior = connect(&manager, "junior").await;
+        let mut outsider = connect(&manager, "outsider").await;
+
+        let session_id = manager.create_session("senior".to_string(), "host".to_string()).await?;
+        manager.invite("senior", "junior", ParticipantRole::Observer).await?;
+        manager.join_session("junior", &session_id).await?;
+        for rx in [&mut host, &mut senior, &mut junior] {
+            drain(rx);
+        }
+
+        // An observer's announcement goes to everyone else, although its role has no recipients
+        let announce = WsMessage::Text(format!(
+            r#"{{"type":"KeyExchange","session_id":"{}","device_id":"junior","static_key":"aa","ephemeral_key":"bb"}}"#,
+            session_id
+        ));
+        manager.deliver_key_message("junior", &session_id, None, announce).await;
+        assert!(host.try_recv().is_ok() && senior.try_recv().is_ok());
+        assert!(junior.try_recv().is_err());
+
+        // Replies and sender keys go to one participant
+        let reply = WsMessage::Text("{}".to_string());
+        manager.deliver_key_message("host", &session_id, Some("junior"), reply.clone()).await;
+        assert!(junior.try_recv().is_ok() && senior.try_recv().is_err());
+
+        // Devices outside the session can neither send nor be reached
+        manager.deliver_key_message("outsider", &session_id, Some("host"), reply.clone()).await;
+        manager.deliver_key_message("host", &session_id, Some("outsider"), reply).await;
+        assert!(host.try_recv().is_err() && outsider.try_recv().is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_dropped_client_resumes_session() -> Result<()> {
+        let manager = SessionManager::new();
+        let (viewer_tx, _viewer) = mpsc::unbounded_channel();
+        manager.register_client("111".to_string(), "linux".to_string(), None, viewer_tx.clone()).await;
+        let mut host = connect(&manager, "222").await;
+        let session_id = manager.create_session("111".to_string(), "222".to_string()).await?;
+        let token = manager.resume_token(&session_id, "111").await.unwrap();
+        assert_ne!(Some(&token), manager.resume_token(&session_id, "222").await.as_ref());
+
+        // The session survives the drop; traffic for the viewer is not routed meanwhile
+        manager.client_dropped("111", &viewer_tx, "Peer connection timed out").await;
+        assert!(!manager.client_exists("111").await);
+        assert_eq!(manager.list_sessions().await.len(), 1);
+        assert_eq!(manager.end_expired_holds().await, 0);
+        let frame = WsMessage::Text(r#"{"type":"VideoFrame"}"#.to_string());
+        assert!(manager.route("222", &frame).await.is_some_and(|r| r.recipients.is_empty()));
+
+        let mut viewer = connect(&manager, "111").await;
+        assert!(manager.resume_session("111", &session_id, "wrong").await.is_err());
+        assert!(manager.resume_session("222", &session_id, &token).await.is_err());
+        manager.resume_session("111", &session_id, &token).await?;
+        let Some(Message::ParticipantResumed { device_id, .. }) = next_message(&mut host) else {
+            panic!("Expected the host to hear about the resumption");
+        };
+        assert_eq!(device_id, "111");
+        manager.relay_to_peer("222", frame).await?;
+        assert!(viewer.try_recv().is_ok());
+
+        // A drop noticed after the client reconnected leaves the new connection alone
+        manager.client_dropped("111", &viewer_tx, "Peer connection timed out").await;
+        assert!(manager.client_exists("111").await);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_held_session_ends_after_grace() -> Result<()> {
+        let manager = SessionManager::new().with_resume_grace(Duration::from_millis(20));
+        let (viewer_tx, _viewer) = mpsc::unbounded_channel();
+        manager.register_client("111".to_string(), "linux".to_string(), None, viewer_tx.clone()).await;
+        let mut host = connect(&manager, "222").await;
+        let session_id = manager.create_session("111".to_string(), "222".to_string()).await?;
+        let token = manager.resume_token(&session_id, "111").await.unwrap();
+
+        manager.client_dropped("111", &viewer_tx, "Peer connection timed out").await;
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        assert_eq!(manager.end_expired_holds().await, 1);
+        assert!(manager.list_sessions().await.is_empty());
+        let Some(Message::Disconnect { reason }) = next_message(&mut host) else {
+            panic!("Expected a Disconnect message");
+        };
+        assert_eq!(reason.as_deref(), Some("Peer connection timed out"));
+
+        let _viewer = connect(&manager, "111").await;
+        let error = manager.resume_session("111", &session_id, &token).await.unwrap_err();
+        assert!(matches!(error.downcast_ref::<Error>(), Some(Error::SessionNotResumable)));
+        Ok(())
+    }
+}
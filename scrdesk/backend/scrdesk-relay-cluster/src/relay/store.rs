@@ -0,0 +1,233 @@
+use super::participants::Roster;
+use anyhow::Result;
+use scrdesk_shared::models::audit::AuditAction;
+use serde_json::{json, Value};
+use sqlx::PgPool;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use uuid::Uuid;
+
+/// Who a client authenticated as in its Hello
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientIdentity {
+    pub user_id: Uuid,
+    pub tenant_id: Uuid,
+}
+
+/// What gets written to the `sessions` table for one relay session
+#[derive(Debug, Clone)]
+pub struct SessionRecord {
+    pub session_id: Uuid,
+    pub tenant_id: Uuid,
+    pub initiator_user_id: Uuid,
+    pub initiator_device: String, // Client-facing device IDs, resolved within the tenant
+    pub target_device: String,
+    pub joined: Vec<String>, // Devices that joined later, kept in the row's metadata when it ends
+}
+
+impl SessionRecord {
+    /// Note the devices in a roster that were not part of the session yet
+    pub fn note_joined(&mut self, roster: &Roster) {
+        for participant in &roster.participants {
+            let device_id = &participant.device_id;
+            if *device_id != self.initiator_device && *device_id != self.target_device && !self.joined.contains(device_id) {
+                self.joined.push(device_id.clone());
+            }
+        }
+    }
+}
+
+enum StoreEvent {
+    Started(SessionRecord),
+    Ended(SessionRecord, Duration, u64), // Duration and bytes relayed
+}
+
+/// Writes session history and audit entries to Postgres.
+///
+/// Writes go through a single background task so they never hold up relayed
+/// traffic, and the end of a session is always recorded after its start.
+pub struct SessionStore {
+    tx: mpsc::UnboundedSender<StoreEvent>,
+}
+
+impl SessionStore {
+    pub fn start(pool: PgPool, relay_server: String) -> Self {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+
+        tokio::spawn(async move {
+            while let Some(event) = rx.recv().await {
+                let result = match &event {
+                    StoreEvent::Started(record) => insert_session(&pool, &relay_server, record).await,
+                    StoreEvent::Ended(record, duration, bytes) => finish_session(&pool, record, *duration, *bytes).await,
+                };
+
+                if let Err(e) = result {
+                    let session_id = match &event {
+                        StoreEvent::Started(record) | StoreEvent::Ended(record, ..) => record.session_id,
+                    };
+                    tracing::error!("Failed to persist session {}: {}", session_id, e);
+                }
+            }
+        });
+
+        Self { tx }
+    }
+
+    pub fn session_started(&self, record: SessionRecord) {
+        let _ = self.tx.send(StoreEvent::Started(record));
+    }
+
+    pub fn session_ended(&self, record: SessionRecord, duration: Duration, bytes_relayed: u64) {
+        let _ = self.tx.send(StoreEvent::Ended(record, duration, bytes_relayed));
+    }
+}
+
+async fn insert_session(pool: &PgPool, relay_server: &str, record: &SessionRecord) -> Result<()> {
+    let mut tx = pool.begin().await?;
+
+    // Both devices must be registered in the initiator's tenant
+    let devices: Vec<(Uuid, String)> = sqlx::query_as(
+        "SELECT id, device_id FROM devices WHERE tenant_id = $1 AND device_id IN ($2, $3)"
+    )
+    .bind(record.tenant_id)
+    .bind(&record.initiator_device)
+    .bind(&record.target_device)
+    .fetch_all(&mut *tx)
+    .await?;
+
+    let find = |device_id: &str| devices.iter().find(|(_, d)| d == device_id).map(|(id, _)| *id);
+    let (Some(initiator), Some(target)) = (find(&record.initiator_device), find(&record.target_device)) else {
+        tracing::warn!(
+            "Session {} not recorded: devices {} / {} are not registered in tenant {}",
+            record.session_id, record.initiator_device, record.target_device, record.tenant_id
+        );
+        return Ok(());
+    };
+
+    sqlx::query(
+        "INSERT INTO sessions (id, tenant_id, initiator_device_id, target_device_id, initiator_user_id, relay_server)
+         VALUES ($1, $2, $3, $4, $5, $6)"
+    )
+    .bind(record.session_id)
+    .bind(record.tenant_id)
+    .bind(initiator)
+    .bind(target)
+    .bind(record.initiator_user_id)
+    .bind(relay_server)
+    .execute(&mut *tx)
+    .await?;
+
+    insert_audit_log(&mut tx, record, AuditAction::SessionStarted, started_metadata(record)).await?;
+
+    tx.commit().await?;
+    Ok(())
+}
+
+async fn finish_session(pool: &PgPool, record: &SessionRecord, duration: Duration, bytes_relayed: u64) -> Result<()> {
+    let mut tx = pool.begin().await?;
+
+    let metadata = ended_metadata(record, bytes_relayed);
+    let updated = sqlx::query(
+        "UPDATE sessions
+         SET ended_at = NOW(), duration_seconds = $2, metadata = metadata || $3::jsonb
+         WHERE id = $1 AND ended_at IS NULL"
+    )
+    .bind(record.session_id)
+    .bind(duration.as_secs().min(i32::MAX as u64) as i32)
+    .bind(metadata.to_string())
+    .execute(&mut *tx)
+    .await?;
+
+    // Nothing to close if the start was never recorded
+    if updated.rows_affected() == 0 {
+        return Ok(());
+    }
+
+    insert_audit_log(&mut tx, record, AuditAction::SessionEnded, metadata).await?;
+
+    tx.commit().await?;
+    Ok(())
+}
+
+async fn insert_audit_log(
+    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    record: &SessionRecord,
+    action: AuditAction,
+    metadata: Value,
+) -> Result<()> {
+    sqlx::query(
+        "INSERT INTO audit_logs (tenant_id, user_id, action, resource_type, resource_id, metadata)
+         VALUES ($1, $2, $3, $4, $5, $6::jsonb)"
+    )
+    .bind(record.tenant_id)
+    .bind(record.initiator_user_id)
+    .bind(action)
+    .bind("session")
+    .bind(record.session_id)
+    .bind(metadata.to_string())
+    .execute(&mut **tx)
+    .await?;
+
+    Ok(())
+}
+
+fn started_metadata(record: &SessionRecord) -> Value {
+    json!({
+        "initiator_device": record.initiator_device,
+        "target_device": record.target_device,
+    })
+}
+
+fn ended_metadata(record: &SessionRecord, bytes_relayed: u64) -> Value {
+    json!({
+        "bytes_relayed": bytes_relayed.min(i64::MAX as u64),
+        "participants": record.joined,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::relay::participants::{Participant, ParticipantRole};
+
+    fn participant(device_id: &str, role: ParticipantRole) -> Participant {
+        Participant { device_id: device_id.to_string(), role, node: "node-a".to_string() }
+    }
+
+    #[test]
+    fn test_record_keeps_everyone_who_joined() -> Result<()> {
+        let mut record = SessionRecord {
+            session_id: Uuid::new_v4(),
+            tenant_id: Uuid::new_v4(),
+            initiator_user_id: Uuid::new_v4(),
+            initiator_device: "viewer".to_string(),
+            target_device: "host".to_string(),
+            joined: Vec::new(),
+        };
+        assert_eq!(
+            started_metadata(&record),
+            json!({ "initiator_device": "viewer", "target_device": "host" })
+        );
+
+        let mut roster = Roster::new(
+            participant("host", ParticipantRole::Host),
+            participant("viewer", ParticipantRole::Controller),
+        );
+        roster.invite("host", participant("observer", ParticipantRole::Observer))?;
+        record.note_joined(&roster);
+        assert!(record.joined.is_empty()); // Invited is not joined
+
+        roster.join("observer")?;
+        record.note_joined(&roster);
+        record.note_joined(&roster);
+        roster.remove("observer");
+        record.note_joined(&roster);
+
+        // A participant that left before the end is still recorded, once
+        assert_eq!(
+            ended_metadata(&record, u64::MAX),
+            json!({ "bytes_relayed": i64::MAX, "participants": ["observer"] })
+        );
+        Ok(())
+    }
+}
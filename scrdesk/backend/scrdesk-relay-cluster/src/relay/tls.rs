@@ -0,0 +1,206 @@
+use anyhow::{anyhow, Context, Result};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls::ServerConfig;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio_rustls::TlsAcceptor;
+
+/// Serves the certificate from a PEM file pair and picks up new files when they change,
+/// so certificates can be renewed without restarting the relay
+#[derive(Debug)]
+pub struct CertificateResolver {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    current: RwLock<Loaded>,
+}
+
+#[derive(Debug)]
+struct Loaded {
+    key: Arc<CertifiedKey>,
+    modified: (SystemTime, SystemTime),
+}
+
+impl CertificateResolver {
+    pub fn load(cert_path: impl Into<PathBuf>, key_path: impl Into<PathBuf>) -> Result<Self> {
+        let cert_path = cert_path.into();
+        let key_path = key_path.into();
+        let current = load_files(&cert_path, &key_path)?;
+
+        Ok(Self {
+            cert_path,
+            key_path,
+            current: RwLock::new(current),
+        })
+    }
+
+    /// Reload the files if either has been modified. Returns true if a new certificate
+    /// was loaded; on error the previous certificate stays in use.
+    pub fn reload_if_changed(&self) -> Result<bool> {
+        let modified = (modified(&self.cert_path)?, modified(&self.key_path)?);
+        if self.current.read().unwrap().modified == modified {
+            return Ok(false);
+        }
+
+        let loaded = load_files(&self.cert_path, &self.key_path)?;
+        *self.current.write().unwrap() = loaded;
+        Ok(true)
+    }
+}
+
+impl ResolvesServerCert for CertificateResolver {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().key.clone())
+    }
+}
+
+/// Server config serving the resolver's certificate with the given ALPN protocols
+pub fn server_config(resolver: Arc<CertificateResolver>, alpn: &[&[u8]]) -> Result<ServerConfig> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let mut config = ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_cert_resolver(resolver);
+    config.alpn_protocols = alpn.iter().map(|p| p.to_vec()).collect();
+    Ok(config)
+}
+
+pub fn acceptor(resolver: Arc<CertificateResolver>) -> Result<TlsAcceptor> {
+    Ok(TlsAcceptor::from(Arc::new(server_config(resolver, &[])?)))
+}
+
+/// Check the certificate files for changes every `interval`
+pub fn start_reloader(resolver: Arc<CertificateResolver>, interval: Duration) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(interval);
+        loop {
+            interval.tick().await;
+            match resolver.reload_if_changed() {
+                Ok(true) => tracing::info!("Reloaded TLS certificate from {}", resolver.cert_path.display()),
+                Ok(false) => {}
+                Err(e) => tracing::warn!("TLS certificate reload failed, keeping the current one: {:#}", e),
+            }
+        }
+    });
+}
+
+fn load_files(cert_path: &Path, key_path: &Path) -> Result<Loaded> {
+    // Read the timestamps first so a write during loading is picked up next time
+    let modified = (modified(cert_path)?, modified(key_path)?);
+
+    let mut reader = BufReader::new(open(cert_path)?);
+    let certs = rustls_pemfile::certs(&mut reader)
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("Invalid certificate file {}", cert_path.display()))?;
+    if certs.is_empty() {
+        return Err(anyhow!("No certificates in {}", cert_path.display()));
+    }
+
+    let mut reader = BufReader::new(open(key_path)?);
+    let key = rustls_pemfile::private_key(&mut reader)
+        .with_context(|| format!("Invalid key file {}", key_path.display()))?
+        .ok_or_else(|| anyhow!("No private key in {}", key_path.display()))?;
+    let signing_key = rustls::crypto::ring::sign::any_supported_type(&key)
+        .map_err(|e| anyhow!("Unsupported private key in {}: {}", key_path.display(), e))?;
+
+    Ok(Loaded {
+        key: Arc::new(CertifiedKey::new(certs, signing_key)),
+        modified,
+    })
+}
+
+fn open(path: &Path) -> Result<File> {
+    File::open(path).with_context(|| format!("Failed to open {}", path.display()))
+}
+
+fn modified(path: &Path) -> Result<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|m| m.modified())
+        .with_context(|| format!("Failed to read {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustls::pki_types::{CertificateDer, ServerName};
+    use rustls::{ClientConfig, RootCertStore};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_rustls::TlsConnector;
+
+    const TESTDATA: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata");
+
+    fn install(dir: &Path, name: &str, modified: SystemTime) -> Result<()> {
+        for ext in ["crt", "key"] {
+            let target = dir.join(format!("relay.{}", ext));
+            std::fs::copy(format!("{}/{}.{}", TESTDATA, name, ext), &target)?;
+            File::options().write(true).open(&target)?.set_modified(modified)?;
+        }
+        Ok(())
+    }
+
+    fn certificate(name: &str) -> Result<CertificateDer<'static>> {
+        let mut reader = BufReader::new(File::open(format!("{}/{}.crt", TESTDATA, name))?);
+        let certificate = rustls_pemfile::certs(&mut reader).next().context("No certificate")??;
+        Ok(certificate)
+    }
+
+    /// Handshake with a client trusting only `trusted`; returns whether it succeeded
+    async fn handshake(acceptor: &TlsAcceptor, trusted: CertificateDer<'static>) -> Result<bool> {
+        let mut roots = RootCertStore::empty();
+        roots.add(trusted)?;
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let config = ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let connector = TlsConnector::from(Arc::new(config));
+
+        let (client, server) = tokio::io::duplex(16 * 1024);
+        let acceptor = acceptor.clone();
+        let server = tokio::spawn(async move {
+            let mut stream = acceptor.accept(server).await?;
+            stream.write_all(b"ok").await?;
+            stream.flush().await?;
+            anyhow::Ok(())
+        });
+
+        let result = async {
+            let mut stream = connector.connect(ServerName::try_from("localhost")?, client).await?;
+            let mut reply = [0u8; 2];
+            stream.read_exact(&mut reply).await?;
+            anyhow::Ok(reply == *b"ok")
+        }
+        .await;
+        server.abort();
+        Ok(result.unwrap_or(false))
+    }
+
+    #[tokio::test]
+    async fn test_certificate_reload() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("scrdesk-relay-tls-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir)?;
+        let start = SystemTime::now() - Duration::from_secs(60);
+        install(&dir, "relay-a", start)?;
+
+        let resolver = Arc::new(CertificateResolver::load(dir.join("relay.crt"), dir.join("relay.key"))?);
+        let acceptor = acceptor(resolver.clone())?;
+        assert!(handshake(&acceptor, certificate("relay-a")?).await?);
+        assert!(!handshake(&acceptor, certificate("relay-b")?).await?);
+        assert!(!resolver.reload_if_changed()?);
+
+        // A broken file keeps the current certificate in use
+        std::fs::write(dir.join("relay.key"), "not a key")?;
+        assert!(resolver.reload_if_changed().is_err());
+        assert!(handshake(&acceptor, certificate("relay-a")?).await?);
+
+        install(&dir, "relay-b", start + Duration::from_secs(30))?;
+        assert!(resolver.reload_if_changed()?);
+        assert!(handshake(&acceptor, certificate("relay-b")?).await?);
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
@@ -41,6 +41,83 @@ pub struct StripeConfig {
     pub webhook_secret: String,
 }
 
+#[derive(Debug, Clone, Deserialize)]
+pub struct RelayConfig {
+    #[serde(default = "default_relay_port")]
+    pub port: u16,
+    pub node_id: Option<String>, // Generated at startup if not set
+    #[serde(default = "default_presence_ttl")]
+    pub presence_ttl_secs: u64,
+    pub session_bandwidth_kbps: Option<u64>, // Per-session relay cap, unlimited if not set
+    pub tenant_bandwidth_kbps: Option<u64>,  // Per-tenant cap on each relay node
+    #[serde(default = "default_ping_interval")]
+    pub ping_interval_secs: u64,
+    #[serde(default = "default_max_missed_pings")]
+    pub max_missed_pings: u32, // Clients are evicted after missing this many pings in a row
+    #[serde(default = "default_idle_timeout")]
+    pub idle_timeout_secs: u64, // End sessions without input or media for this long; 0 disables
+    #[serde(default = "default_resume_grace")]
+    pub resume_grace_secs: u64, // Hold a session this long for a participant whose connection dropped
+    pub tls_cert_path: Option<String>, // PEM certificate chain; the relay serves wss:// when set
+    pub tls_key_path: Option<String>,
+    #[serde(default = "default_quic_enabled")]
+    pub quic_enabled: bool, // Also accept QUIC on the relay port (UDP) when TLS is configured
+    #[serde(default = "default_rendezvous_port")]
+    pub rendezvous_port: u16, // Tells clients their public address for direct connections; 0 disables
+}
+
+fn default_relay_port() -> u16 {
+    21117
+}
+
+fn default_presence_ttl() -> u64 {
+    30
+}
+
+fn default_ping_interval() -> u64 {
+    15
+}
+
+fn default_max_missed_pings() -> u32 {
+    3
+}
+
+fn default_idle_timeout() -> u64 {
+    1800
+}
+
+fn default_resume_grace() -> u64 {
+    30
+}
+
+fn default_quic_enabled() -> bool {
+    true
+}
+
+fn default_rendezvous_port() -> u16 {
+    21118
+}
+
+impl Default for RelayConfig {
+    fn default() -> Self {
+        Self {
+            port: default_relay_port(),
+            node_id: None,
+            presence_ttl_secs: default_presence_ttl(),
+            session_bandwidth_kbps: None,
+            tenant_bandwidth_kbps: None,
+            ping_interval_secs: default_ping_interval(),
+            max_missed_pings: default_max_missed_pings(),
+            idle_timeout_secs: default_idle_timeout(),
+            resume_grace_secs: default_resume_grace(),
+            tls_cert_path: None,
+            tls_key_path: None,
+            quic_enabled: default_quic_enabled(),
+            rendezvous_port: default_rendezvous_port(),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct Config {
     pub database: DatabaseConfig,
@@ -49,6 +126,8 @@ pub struct Config {
     pub jwt: JwtConfig,
     pub s3: Option<S3Config>,
     pub stripe: Option<StripeConfig>,
+    #[serde(default)]
+    pub relay: RelayConfig,
 }
 
 impl Config {
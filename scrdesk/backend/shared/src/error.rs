@@ -40,6 +40,12 @@ pub enum Error {
     #[error("Device limit exceeded")]
     DeviceLimitExceeded,
 
+    #[error("Concurrent session limit exceeded")]
+    SessionLimitExceeded,
+
+    #[error("Session can no longer be resumed")]
+    SessionNotResumable,
+
     #[error("Session error: {0}")]
     Session(String),
 
@@ -69,6 +75,8 @@ impl Error {
             Error::Jwt(_) => 401,
             Error::Tenant(_) => 403,
             Error::DeviceLimitExceeded => 429,
+            Error::SessionLimitExceeded => 429,
+            Error::SessionNotResumable => 410,
             Error::Session(_) => 400,
             Error::PolicyViolation(_) => 403,
             Error::Billing(_) => 402,
@@ -89,6 +97,8 @@ impl Error {
             Error::Jwt(_) => "JWT_ERROR",
             Error::Tenant(_) => "TENANT_ERROR",
             Error::DeviceLimitExceeded => "DEVICE_LIMIT_EXCEEDED",
+            Error::SessionLimitExceeded => "SESSION_LIMIT_EXCEEDED",
+            Error::SessionNotResumable => "SESSION_NOT_RESUMABLE",
             Error::Session(_) => "SESSION_ERROR",
             Error::PolicyViolation(_) => "POLICY_VIOLATION",
             Error::Billing(_) => "BILLING_ERROR",
@@ -25,6 +25,7 @@ pub enum AuditAction {
     TenantCreated,
     TenantUpdated,
     SettingsChanged,
+    DlpMatch,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
@@ -4,6 +4,7 @@ use sqlx::FromRow;
 use uuid::Uuid;
 
 use super::{PolicyId, TenantId};
+use crate::error::{Error, Result};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PolicyRules {
@@ -16,6 +17,120 @@ pub struct PolicyRules {
     pub allowed_days: Option<Vec<u8>>, // [1,2,3,4,5] (Monday-Friday)
     pub ip_whitelist: Option<Vec<String>>,
     pub ip_blacklist: Option<Vec<String>>,
+    #[serde(default)]
+    pub redaction_zones: Vec<RedactionZone>, // Masked on hosts before frames are sent
+    #[serde(default)]
+    pub require_watermark: bool, // Viewers must watermark the remote screen
+    #[serde(default)]
+    pub dlp_rules: Vec<DlpRule>, // Inspect what leaves hosts through the clipboard and file transfers
+}
+
+impl PolicyRules {
+    /// Reject rules that hosts could not apply as written; checked when a policy is saved
+    pub fn check(&self) -> Result<()> {
+        for zone in &self.redaction_zones {
+            zone.check()?;
+        }
+        for rule in &self.dlp_rules {
+            rule.check()?;
+        }
+        Ok(())
+    }
+}
+
+impl DlpRule {
+    /// A rule must compile on hosts; one that does not would let data through
+    pub fn check(&self) -> Result<()> {
+        let invalid = |reason: String| Err(Error::Validation(format!("DLP rule {:?}: {}", self.name, reason)));
+        let blank = |values: &[String]| values.iter().all(|v| v.trim().is_empty());
+
+        if self.name.trim().is_empty() {
+            return invalid("a name is required".to_string());
+        }
+        match &self.detector {
+            DlpDetector::Regex { pattern } => {
+                if let Err(e) = regex::Regex::new(pattern) {
+                    return invalid(format!("invalid pattern: {}", e));
+                }
+            }
+            DlpDetector::Keywords { words } if blank(words) => return invalid("no keywords".to_string()),
+            DlpDetector::Extension { extensions } if blank(extensions) => return invalid("no extensions".to_string()),
+            DlpDetector::MimeType { types } if blank(types) => return invalid("no content types".to_string()),
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+/// A data loss prevention rule: what to look for in outgoing data, and what to do
+/// when it is found. Every hit is reported as an audit event.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DlpRule {
+    pub name: String,
+    pub detector: DlpDetector,
+    pub action: DlpAction,
+    #[serde(default)]
+    pub channels: Vec<DlpChannel>, // All of them when empty
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum DlpDetector {
+    Regex { pattern: String },
+    /// Whole words, ignoring case
+    Keywords { words: Vec<String> },
+    /// Card numbers that pass the Luhn check
+    CreditCard,
+    /// IBANs that pass the mod-97 check
+    Iban,
+    /// Well-known API token formats and private keys
+    ApiKey,
+    /// File extensions, without the dot
+    Extension { extensions: Vec<String> },
+    /// Content types of files and clipboard images, e.g. `application/pdf` or `image/*`
+    MimeType { types: Vec<String> },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DlpAction {
+    Block,
+    Redact, // Matches in clipboard text are replaced; files cannot be, so they are blocked
+    AllowAndAudit,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DlpChannel {
+    Clipboard,
+    FileTransfer,
+}
+
+/// An area of a host's screen that viewers never see
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RedactionZone {
+    /// A fixed rectangle, in screen pixels
+    Rect { x: i32, y: i32, width: u32, height: u32 },
+    /// Every window whose title or class contains these, ignoring case (X11 hosts)
+    Window { title: Option<String>, class: Option<String> },
+}
+
+impl RedactionZone {
+    /// A rectangle must be non-empty and end within the i32 screen coordinates
+    pub fn check(&self) -> Result<()> {
+        if let RedactionZone::Rect { x, y, width, height } = *self {
+            let right = x as i64 + width as i64;
+            let bottom = y as i64 + height as i64;
+            if width == 0 || height == 0 || right > i32::MAX as i64 || bottom > i32::MAX as i64 {
+                return Err(Error::Validation(format!(
+                    "Redaction zone {}x{} at {},{} is out of bounds",
+                    width, height, x, y
+                )));
+            }
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
@@ -55,3 +170,30 @@ impl From<Policy> for PolicyResponse {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redaction_zone_bounds() {
+        let rect = |x, y, width, height| RedactionZone::Rect { x, y, width, height };
+        assert!(rect(0, 0, 1920, 1080).check().is_ok());
+        assert!(rect(-100, -100, 200, 200).check().is_ok());
+        assert!(rect(0, 0, 0, 1080).check().is_err());
+        assert!(rect(0, 0, u32::MAX, u32::MAX).check().is_err());
+        assert!(rect(i32::MAX, 0, 1, 1).check().is_err());
+        assert!(RedactionZone::Window { title: None, class: Some("keepassxc".to_string()) }.check().is_ok());
+    }
+
+    #[test]
+    fn test_dlp_rules_must_compile() {
+        let rule = |detector| DlpRule { name: "Secrets".to_string(), detector, action: DlpAction::Block, channels: Vec::new() };
+        assert!(rule(DlpDetector::Regex { pattern: r"(?i)top secret".to_string() }).check().is_ok());
+        assert!(rule(DlpDetector::CreditCard).check().is_ok());
+        assert!(rule(DlpDetector::Regex { pattern: "(".to_string() }).check().is_err());
+        assert!(rule(DlpDetector::Keywords { words: vec![" ".to_string()] }).check().is_err());
+        assert!(rule(DlpDetector::Extension { extensions: Vec::new() }).check().is_err());
+        assert!(DlpRule { name: String::new(), ..rule(DlpDetector::Iban) }.check().is_err());
+    }
+}
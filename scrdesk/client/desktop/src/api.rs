@@ -3,6 +3,8 @@ use reqwest::Client;
 use serde::{Deserialize, Serialize};
 use std::sync::Arc;
 use tokio::sync::Mutex;
+use crate::capture::redaction::RedactionZone;
+use crate::dlp::{DlpAction, DlpChannel, DlpRule};
 
 #[derive(Debug, Clone)]
 pub struct ApiClient {
@@ -39,6 +41,7 @@ pub struct RegisterDeviceRequest {
     pub os_type: String,
     pub os_version: String,
     pub hostname: String,
+    pub public_key: String, // X25519 identity key in hex, see network::e2e::DeviceKey
 }
 
 #[derive(Debug, Deserialize)]
@@ -57,6 +60,53 @@ pub struct Device {
     pub approved: bool,
 }
 
+/// Connection quality of a finished session, see network::stats
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SessionQualityReport {
+    pub duration_secs: f64,
+    pub frames_sent: u64,
+    pub frames_received: u64,
+    pub frames_dropped: u64,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub avg_rtt_ms: Option<f64>,
+    pub avg_jitter_ms: Option<f64>,
+    pub avg_input_latency_ms: Option<f64>,
+    pub redactions: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct WatermarkPolicyResponse {
+    required: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct DlpRulesResponse {
+    rules: Vec<DlpRule>,
+}
+
+/// A data loss prevention hit, without the data that matched
+#[derive(Debug, Serialize)]
+pub struct DlpEvent {
+    pub device_id: String,
+    pub channel: DlpChannel,
+    pub rule: String,
+    pub detector: String,
+    pub action: DlpAction,
+    pub matches: u32,
+    pub filename: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RedactionZonesResponse {
+    zones: Vec<RedactionZone>,
+}
+
+#[derive(Debug, Deserialize)]
+struct DeviceKeyResponse {
+    public_key: String,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct DeviceListResponse {
     pub devices: Vec<Device>,
@@ -176,6 +226,156 @@ impl ApiClient {
         Ok(())
     }
 
+    /// The key a device registered with the device manager, or None if the device is
+    /// unknown or not approved
+    pub async fn device_public_key(&self, device_id: &str) -> Result<Option<String>> {
+        let url = format!("{}/api/v1/devices/keys/{}", self.base_url, device_id);
+
+        let token = self.token.lock().await.clone()
+            .context("Not authenticated")?;
+
+        let response = self.client
+            .get(&url)
+            .header("Authorization", format!("Bearer {}", token))
+            .send()
+            .await
+            .context("Failed to look up device key")?;
+
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        if !response.status().is_success() {
+            let status = response.status();
+            anyhow::bail!("Device key lookup failed: {}", status);
+        }
+
+        let key: DeviceKeyResponse = response.json().await
+            .context("Failed to parse device key response")?;
+
+        Ok(Some(key.public_key))
+    }
+
+    /// Record what this device measured over a session with the session
+    pub async fn report_session_quality(&self, device_id: &str, session_id: &str, report: &SessionQualityReport) -> Result<()> {
+        let url = format!("{}/api/v1/devices/{}/sessions/{}/quality", self.base_url, device_id, session_id);
+
+        let token = self.token.lock().await.clone()
+            .context("Not authenticated")?;
+
+        let response = self.client
+            .post(&url)
+            .header("Authorization", format!("Bearer {}", token))
+            .json(report)
+            .send()
+            .await
+            .context("Failed to send quality report")?;
+
+        if !response.status().is_success() {
+            let status = response.status();
+            anyhow::bail!("Quality report failed: {}", status);
+        }
+
+        Ok(())
+    }
+
+    /// The redaction zones the policies of this device's groups ask for
+    pub async fn redaction_zones(&self, device_id: &str) -> Result<Vec<RedactionZone>> {
+        let url = format!("{}/api/v1/policies/devices/{}/redactions", self.base_url, device_id);
+
+        let token = self.token.lock().await.clone()
+            .context("Not authenticated")?;
+
+        let response = self.client
+            .get(&url)
+            .header("Authorization", format!("Bearer {}", token))
+            .send()
+            .await
+            .context("Failed to fetch redaction zones")?;
+
+        if !response.status().is_success() {
+            let status = response.status();
+            anyhow::bail!("Redaction zone lookup failed: {}", status);
+        }
+
+        let zones: RedactionZonesResponse = response.json().await
+            .context("Failed to parse redaction zones")?;
+
+        Ok(zones.zones)
+    }
+
+    /// Whether a policy of this device's groups makes it watermark remote screens
+    pub async fn watermark_required(&self, device_id: &str) -> Result<bool> {
+        let url = format!("{}/api/v1/policies/devices/{}/watermark", self.base_url, device_id);
+
+        let token = self.token.lock().await.clone()
+            .context("Not authenticated")?;
+
+        let response = self.client
+            .get(&url)
+            .header("Authorization", format!("Bearer {}", token))
+            .send()
+            .await
+            .context("Failed to fetch watermark policy")?;
+
+        if !response.status().is_success() {
+            let status = response.status();
+            anyhow::bail!("Watermark policy lookup failed: {}", status);
+        }
+
+        let policy: WatermarkPolicyResponse = response.json().await
+            .context("Failed to parse watermark policy")?;
+
+        Ok(policy.required)
+    }
+
+    /// The data loss prevention rules of the policies of this device's groups
+    pub async fn dlp_rules(&self, device_id: &str) -> Result<Vec<DlpRule>> {
+        let url = format!("{}/api/v1/policies/devices/{}/dlp", self.base_url, device_id);
+
+        let token = self.token.lock().await.clone()
+            .context("Not authenticated")?;
+
+        let response = self.client
+            .get(&url)
+            .header("Authorization", format!("Bearer {}", token))
+            .send()
+            .await
+            .context("Failed to fetch DLP rules")?;
+
+        if !response.status().is_success() {
+            let status = response.status();
+            anyhow::bail!("DLP rule lookup failed: {}", status);
+        }
+
+        let rules: DlpRulesResponse = response.json().await
+            .context("Failed to parse DLP rules")?;
+
+        Ok(rules.rules)
+    }
+
+    /// Record a data loss prevention hit in the audit log
+    pub async fn report_dlp_event(&self, event: &DlpEvent) -> Result<()> {
+        let url = format!("{}/api/v1/audit-logs/dlp", self.base_url);
+
+        let token = self.token.lock().await.clone()
+            .context("Not authenticated")?;
+
+        let response = self.client
+            .post(&url)
+            .header("Authorization", format!("Bearer {}", token))
+            .json(event)
+            .send()
+            .await
+            .context("Failed to send DLP event")?;
+
+        if !response.status().is_success() {
+            let status = response.status();
+            anyhow::bail!("DLP event report failed: {}", status);
+        }
+
+        Ok(())
+    }
+
     pub fn set_token(&self, token: String) {
         let token_clone = Arc::clone(&self.token);
         tokio::spawn(async move {
@@ -183,6 +383,10 @@ impl ApiClient {
         });
     }
 
+    pub async fn token(&self) -> Option<String> {
+        self.token.lock().await.clone()
+    }
+
     pub async fn is_authenticated(&self) -> bool {
         self.token.lock().await.is_some()
     }
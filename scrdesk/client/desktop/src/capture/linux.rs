@@ -1,20 +1,224 @@
-use super::{Frame, ScreenCapture};
-use anyhow::Result;
+use super::{virtual_display, CaptureArea, Frame, Rect, ScreenCapture, WindowInfo};
+use anyhow::{Context, Result};
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int, c_uchar, c_uint, c_ulong};
+use std::time::{SystemTime, UNIX_EPOCH};
+use x11::xlib::{
+    Atom, Display, Drawable, False, IsViewable, LSBFirst, Window, XAllPlanes, XCloseDisplay, XDefaultRootWindow,
+    XDestroyImage, XErrorEvent, XFetchName, XFree, XGetImage, XGetInputFocus, XGetWindowAttributes, XGetWindowProperty, XInternAtom,
+    XOpenDisplay, XQueryTree, XSetErrorHandler, XTranslateCoordinates, XWindowAttributes, ZPixmap, XA_STRING, XA_WINDOW,
+};
 
+/// Captures the X display in `DISPLAY`, a virtual one included: the whole screen, one
+/// top-level window or a region of the screen
 pub struct LinuxCapturer {
+    display: *mut Display,
+    root: Window,
     width: u32,
     height: u32,
     running: bool,
+    area: CaptureArea,
+    bounds: Option<Rect>, // Of the last frame, unless it was the whole screen
 }
 
+// The connection is only used through `&mut self`, one thread at a time
+unsafe impl Send for LinuxCapturer {}
+
 impl LinuxCapturer {
     pub fn new() -> Result<Self> {
-        // TODO: Implement X11/Wayland capture
-        Ok(Self {
-            width: 1920,
-            height: 1080,
+        let display = unsafe { XOpenDisplay(std::ptr::null()) };
+        if display.is_null() {
+            anyhow::bail!("Cannot open X display {:?}", std::env::var("DISPLAY").unwrap_or_default());
+        }
+        unsafe {
+            // Xlib exits the process on errors by default, e.g. when the screen is
+            // resized while it is being read or a shared window closes
+            XSetErrorHandler(Some(log_x_error));
+        }
+
+        let root = unsafe { XDefaultRootWindow(display) };
+        let mut capturer = Self {
+            display,
+            root,
+            width: 0,
+            height: 0,
             running: false,
-        })
+            area: CaptureArea::FullScreen,
+            bounds: None,
+        };
+        (capturer.width, capturer.height) = capturer.screen_size()?;
+        Ok(capturer)
+    }
+
+    fn screen_size(&self) -> Result<(u32, u32)> {
+        let mut attributes: XWindowAttributes = unsafe { std::mem::zeroed() };
+        if unsafe { XGetWindowAttributes(self.display, self.root, &mut attributes) } == 0 {
+            anyhow::bail!("Failed to read the size of the X screen");
+        }
+        Ok((attributes.width as u32, attributes.height as u32))
+    }
+
+    /// Where a window is on the screen, or None if it is gone or not shown
+    fn window_bounds(&self, window: Window) -> Option<Rect> {
+        let mut attributes: XWindowAttributes = unsafe { std::mem::zeroed() };
+        if unsafe { XGetWindowAttributes(self.display, window, &mut attributes) } == 0 || attributes.map_state != IsViewable {
+            return None;
+        }
+        let (mut x, mut y, mut child) = (0, 0, 0);
+        if unsafe { XTranslateCoordinates(self.display, window, self.root, 0, 0, &mut x, &mut y, &mut child) } == 0 {
+            return None;
+        }
+        Some(Rect { x, y, width: attributes.width as u32, height: attributes.height as u32 })
+    }
+
+    /// Whether the focus is on a window or one of its descendants, e.g. a toolkit's focus proxy
+    fn has_focus(&self, window: Window) -> bool {
+        let (mut focus, mut revert_to) = (0, 0);
+        unsafe { XGetInputFocus(self.display, &mut focus, &mut revert_to) };
+
+        // 0 is None and 1 is PointerRoot: no window has the focus to itself
+        let mut current = focus;
+        while current > 1 && current != self.root {
+            if current == window {
+                return true;
+            }
+            let (mut root, mut parent) = (0, 0);
+            let mut children: *mut Window = std::ptr::null_mut();
+            let mut count: c_uint = 0;
+            if unsafe { XQueryTree(self.display, current, &mut root, &mut parent, &mut children, &mut count) } == 0 {
+                return false;
+            }
+            if !children.is_null() {
+                unsafe { XFree(children.cast()) };
+            }
+            current = parent;
+        }
+        false
+    }
+
+    /// The part of the screen to capture and where to read it from
+    fn source(&self) -> Result<(Drawable, Rect, Option<Rect>)> {
+        let screen = Rect { x: 0, y: 0, width: self.width, height: self.height };
+        match self.area {
+            CaptureArea::FullScreen => Ok((self.root, screen, None)),
+            CaptureArea::Region(region) => {
+                let visible = region.intersect(&screen).context("The shared region is outside the screen")?;
+                Ok((self.root, visible, Some(visible)))
+            }
+            CaptureArea::Window(id) => {
+                // Read from the window itself, so windows on top of it are not shared;
+                // without a compositor, covered parts show whatever the server has
+                let window: Window = id;
+                let bounds = self.window_bounds(window).context("The shared window was closed or minimized")?;
+                let visible = bounds.intersect(&screen).context("The shared window is off the screen")?;
+                let within = Rect { x: visible.x - bounds.x, y: visible.y - bounds.y, ..visible };
+                Ok((window, within, Some(visible)))
+            }
+        }
+    }
+
+    fn grab(&self, drawable: Drawable, rect: Rect) -> Result<Vec<u8>> {
+        unsafe {
+            let image = XGetImage(self.display, drawable, rect.x, rect.y, rect.width, rect.height, XAllPlanes(), ZPixmap);
+            if image.is_null() {
+                anyhow::bail!("Failed to capture the X screen");
+            }
+            let image_ref = &*image;
+            let converted = if image_ref.bits_per_pixel == 32 {
+                let len = image_ref.bytes_per_line as usize * image_ref.height as usize;
+                let pixels = std::slice::from_raw_parts(image_ref.data as *const u8, len);
+                Some(to_rgba(
+                    pixels,
+                    image_ref.width as u32,
+                    image_ref.height as u32,
+                    image_ref.bytes_per_line as usize,
+                    [image_ref.red_mask, image_ref.green_mask, image_ref.blue_mask].map(u64::from),
+                    image_ref.byte_order == LSBFirst,
+                ))
+            } else {
+                None
+            };
+            let bits_per_pixel = image_ref.bits_per_pixel;
+            XDestroyImage(image);
+            converted.with_context(|| format!("Unsupported X screen format: {} bits per pixel", bits_per_pixel))
+        }
+    }
+
+    /// A window property as raw bytes, with its format (8, 16 or 32 bits per item)
+    fn property(&self, window: Window, name: &str, kind: Atom) -> Option<(c_int, Vec<u8>)> {
+        let name = CString::new(name).ok()?;
+        unsafe {
+            let atom = XInternAtom(self.display, name.as_ptr(), False);
+            let (mut actual_kind, mut format, mut items, mut remaining) = (0, 0, 0, 0);
+            let mut data: *mut c_uchar = std::ptr::null_mut();
+            let status = XGetWindowProperty(
+                self.display, window, atom, 0, i32::MAX as i64 / 4, False, kind,
+                &mut actual_kind, &mut format, &mut items, &mut remaining, &mut data,
+            );
+            if status != 0 || data.is_null() {
+                return None;
+            }
+            // 32-bit items come as longs
+            let item_size = match format {
+                8 => 1,
+                16 => std::mem::size_of::<std::os::raw::c_short>(),
+                _ => std::mem::size_of::<c_ulong>(),
+            };
+            let bytes = std::slice::from_raw_parts(data, items as usize * item_size).to_vec();
+            XFree(data.cast());
+            (actual_kind == kind).then_some((format, bytes))
+        }
+    }
+
+    /// The application windows, as the window manager lists them, or else the
+    /// children of the root window
+    fn top_level_windows(&self) -> Vec<Window> {
+        if let Some((32, bytes)) = self.property(self.root, "_NET_CLIENT_LIST", XA_WINDOW) {
+            return bytes
+                .chunks_exact(std::mem::size_of::<c_ulong>())
+                .map(|item| c_ulong::from_ne_bytes(item.try_into().unwrap()) as Window)
+                .collect();
+        }
+
+        unsafe {
+            let (mut root, mut parent) = (0, 0);
+            let mut children: *mut Window = std::ptr::null_mut();
+            let mut count: c_uint = 0;
+            if XQueryTree(self.display, self.root, &mut root, &mut parent, &mut children, &mut count) == 0 || children.is_null() {
+                return Vec::new();
+            }
+            let windows = std::slice::from_raw_parts(children, count as usize).to_vec();
+            XFree(children.cast());
+            windows
+        }
+    }
+
+    /// The class part of WM_CLASS, e.g. `KeePassXC`, or else its instance part
+    fn window_class(&self, window: Window) -> String {
+        let Some((8, bytes)) = self.property(window, "WM_CLASS", XA_STRING) else {
+            return String::new();
+        };
+        let mut parts = bytes.split(|&b| b == 0).filter(|part| !part.is_empty());
+        let instance = parts.next().unwrap_or_default();
+        String::from_utf8_lossy(parts.next().unwrap_or(instance)).into_owned()
+    }
+
+    fn window_title(&self, window: Window) -> Option<String> {
+        let utf8_name = CString::new("UTF8_STRING").unwrap();
+        let utf8 = unsafe { XInternAtom(self.display, utf8_name.as_ptr(), False) };
+        if let Some((8, bytes)) = self.property(window, "_NET_WM_NAME", utf8) {
+            return Some(String::from_utf8_lossy(&bytes).into_owned());
+        }
+
+        unsafe {
+            let mut name: *mut c_char = std::ptr::null_mut();
+            if XFetchName(self.display, window, &mut name) == 0 || name.is_null() {
+                return None;
+            }
+            let title = CStr::from_ptr(name).to_string_lossy().into_owned();
+            XFree(name.cast());
+            Some(title)
+        }
     }
 }
 
@@ -26,7 +230,25 @@ impl ScreenCapture for LinuxCapturer {
     }
 
     fn capture_frame(&mut self) -> Result<Frame> {
-        anyhow::bail!("Linux screen capture not implemented yet")
+        // The screen may have been resized since the last frame
+        (self.width, self.height) = self.screen_size()?;
+
+        let (drawable, rect, bounds) = self.source()?;
+        let data = self.grab(drawable, rect)?;
+        self.bounds = bounds;
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64;
+
+        Ok(Frame {
+            data,
+            width: rect.width,
+            height: rect.height,
+            stride: (rect.width * 4) as usize,
+            timestamp,
+        })
     }
 
     fn stop(&mut self) {
@@ -37,4 +259,106 @@ impl ScreenCapture for LinuxCapturer {
     fn get_dimensions(&self) -> (u32, u32) {
         (self.width, self.height)
     }
+
+    fn set_resolution(&mut self, width: u32, height: u32) -> Result<()> {
+        let display = virtual_display::current()
+            .context("Only a virtual display can be resized remotely")?;
+        display.resize(width, height)
+    }
+
+    fn list_windows(&self) -> Result<Vec<WindowInfo>> {
+        Ok(self
+            .top_level_windows()
+            .into_iter()
+            .filter_map(|window| {
+                let bounds = self.window_bounds(window)?;
+                let title = self.window_title(window).unwrap_or_default();
+                let class = self.window_class(window);
+                if title.trim().is_empty() && class.is_empty() {
+                    return None;
+                }
+                Some(WindowInfo { id: window, title, class, bounds })
+            })
+            .collect())
+    }
+
+    fn set_area(&mut self, area: CaptureArea) -> Result<()> {
+        let previous = std::mem::replace(&mut self.area, area);
+        match self.source() {
+            Ok((_, _, bounds)) => self.bounds = bounds,
+            Err(e) => {
+                self.area = previous;
+                return Err(e);
+            }
+        }
+        tracing::info!("Sharing {:?}", area);
+        Ok(())
+    }
+
+    fn capture_bounds(&self) -> Option<Rect> {
+        self.bounds
+    }
+
+    fn keyboard_in_area(&self) -> bool {
+        match self.area {
+            CaptureArea::Window(id) => self.has_focus(id),
+            CaptureArea::FullScreen | CaptureArea::Region(_) => true,
+        }
+    }
+}
+
+impl Drop for LinuxCapturer {
+    fn drop(&mut self) {
+        unsafe {
+            XCloseDisplay(self.display);
+        }
+    }
+}
+
+unsafe extern "C" fn log_x_error(_display: *mut Display, event: *mut XErrorEvent) -> c_int {
+    let event = &*event;
+    tracing::warn!("X error {} (request {}.{})", event.error_code, event.request_code, event.minor_code);
+    0
+}
+
+/// Convert 32-bit pixels with the given channel masks to RGBA
+fn to_rgba(pixels: &[u8], width: u32, height: u32, bytes_per_line: usize, masks: [u64; 3], little_endian: bool) -> Vec<u8> {
+    let channel = |pixel: u32, mask: u64| {
+        let mask = mask as u32;
+        if mask == 0 {
+            0
+        } else {
+            ((pixel & mask) >> mask.trailing_zeros()) as u8
+        }
+    };
+
+    let mut rgba = Vec::with_capacity(width as usize * height as usize * 4);
+    for row in pixels.chunks(bytes_per_line).take(height as usize) {
+        for bytes in row.chunks_exact(4).take(width as usize) {
+            let bytes = [bytes[0], bytes[1], bytes[2], bytes[3]];
+            let pixel = if little_endian { u32::from_le_bytes(bytes) } else { u32::from_be_bytes(bytes) };
+            rgba.extend_from_slice(&[channel(pixel, masks[0]), channel(pixel, masks[1]), channel(pixel, masks[2]), 255]);
+        }
+    }
+    rgba
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_rgba() {
+        // Two BGRX pixels and two bytes of row padding, as a little-endian server sends them
+        let pixels = [0x30, 0x20, 0x10, 0x00, 0xff, 0x00, 0x80, 0x00, 0xaa, 0xaa];
+        let masks = [0xff0000, 0x00ff00, 0x0000ff];
+        assert_eq!(
+            to_rgba(&pixels, 2, 1, 10, masks, true),
+            vec![0x10, 0x20, 0x30, 255, 0x80, 0x00, 0xff, 255]
+        );
+        assert_eq!(
+            to_rgba(&[0x00, 0x10, 0x20, 0x30], 1, 1, 4, masks, false),
+            vec![0x10, 0x20, 0x30, 255]
+        );
+    }
 }
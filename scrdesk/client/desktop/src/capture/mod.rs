@@ -7,6 +7,10 @@ mod macos;
 mod windows;
 #[cfg(target_os = "linux")]
 mod linux;
+pub mod redaction;
+pub mod synthetic;
+#[cfg(target_os = "linux")]
+pub mod virtual_display;
 
 pub struct Frame {
     pub data: Vec<u8>,
@@ -16,11 +20,107 @@ pub struct Frame {
     pub timestamp: u64,
 }
 
+impl Frame {
+    /// Paint a rectangle of the frame, in frame pixels, with one RGBA color
+    pub fn fill(&mut self, rect: Rect, rgba: [u8; 4]) {
+        let Some(rect) = rect.intersect(&Rect { x: 0, y: 0, width: self.width, height: self.height }) else {
+            return;
+        };
+        for row in rect.y as usize..(rect.y as usize + rect.height as usize) {
+            let start = row * self.stride + rect.x as usize * 4;
+            for pixel in self.data[start..start + rect.width as usize * 4].chunks_exact_mut(4) {
+                pixel.copy_from_slice(&rgba);
+            }
+        }
+    }
+}
+
+/// A rectangle on the screen, in pixels
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rect {
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Rect {
+    /// The part of this rectangle inside `other`, if any
+    pub fn intersect(&self, other: &Rect) -> Option<Rect> {
+        // In i64, so a huge rectangle (e.g. a redaction zone covering everything) cannot wrap
+        let left = self.x.max(other.x);
+        let top = self.y.max(other.y);
+        let right = (self.x as i64 + self.width as i64).min(other.x as i64 + other.width as i64);
+        let bottom = (self.y as i64 + self.height as i64).min(other.y as i64 + other.height as i64);
+        if right <= left as i64 || bottom <= top as i64 {
+            return None;
+        }
+        let width = (right - left as i64).min(u32::MAX as i64) as u32;
+        let height = (bottom - top as i64).min(u32::MAX as i64) as u32;
+        Some(Rect { x: left, y: top, width, height })
+    }
+
+    /// The screen position of a point given relative to this rectangle, kept inside it
+    pub fn to_screen(self, x: i32, y: i32) -> (i32, i32) {
+        let max_x = (self.width as i32 - 1).max(0);
+        let max_y = (self.height as i32 - 1).max(0);
+        (self.x + x.clamp(0, max_x), self.y + y.clamp(0, max_y))
+    }
+}
+
+/// A top-level window that can be shared on its own
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WindowInfo {
+    pub id: u64,
+    pub title: String,
+    pub class: String, // WM_CLASS on X11
+    pub bounds: Rect, // Where it is on the screen when listed
+}
+
+/// The part of the screen that is shared
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CaptureArea {
+    #[default]
+    FullScreen,
+    Window(u64),  // Follows the window as it moves or resizes
+    Region(Rect), // Fixed, in screen coordinates
+}
+
 pub trait ScreenCapture: Send {
     fn start(&mut self) -> Result<()>;
     fn capture_frame(&mut self) -> Result<Frame>;
     fn stop(&mut self);
     fn get_dimensions(&self) -> (u32, u32);
+
+    /// Change the resolution of the captured screen, where the capturer controls it
+    fn set_resolution(&mut self, width: u32, height: u32) -> Result<()> {
+        anyhow::bail!("This screen cannot be resized to {}x{} remotely", width, height)
+    }
+
+    /// Top-level windows that can be shared on their own, where the platform lists them
+    fn list_windows(&self) -> Result<Vec<WindowInfo>> {
+        Ok(Vec::new())
+    }
+
+    /// Share only one window or a region instead of the whole screen
+    fn set_area(&mut self, area: CaptureArea) -> Result<()> {
+        match area {
+            CaptureArea::FullScreen => Ok(()),
+            _ => anyhow::bail!("Only the whole screen can be shared on this platform"),
+        }
+    }
+
+    /// Where on the screen the last frame was taken from, unless it was the whole screen.
+    /// Input from the viewer is relative to it and kept inside it.
+    fn capture_bounds(&self) -> Option<Rect> {
+        None
+    }
+
+    /// Whether keys typed now reach what is shared. A shared window must have the
+    /// focus; a region cannot confine the keyboard, so it is always true then.
+    fn keyboard_in_area(&self) -> bool {
+        true
+    }
 }
 
 pub fn create_capturer() -> Result<Box<dyn ScreenCapture>> {
@@ -44,3 +144,24 @@ pub fn create_capturer() -> Result<Box<dyn ScreenCapture>> {
         anyhow::bail!("Unsupported platform for screen capture")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rect_intersect_and_to_screen() {
+        let screen = Rect { x: 0, y: 0, width: 1920, height: 1080 };
+        let window = Rect { x: 1800, y: -20, width: 400, height: 300 };
+        assert_eq!(window.intersect(&screen), Some(Rect { x: 1800, y: 0, width: 120, height: 280 }));
+        assert_eq!(Rect { x: 1920, y: 0, width: 10, height: 10 }.intersect(&screen), None);
+        let everything = Rect { x: -100, y: 0, width: u32::MAX, height: u32::MAX };
+        assert_eq!(everything.intersect(&screen), Some(screen));
+        assert_eq!(Rect { x: i32::MAX, y: i32::MAX, width: u32::MAX, height: 1 }.intersect(&screen), None);
+
+        let region = Rect { x: 100, y: 200, width: 640, height: 480 };
+        assert_eq!(region.to_screen(10, 20), (110, 220));
+        assert_eq!(region.to_screen(-5, 5000), (100, 679));
+        assert_eq!(Rect { x: 7, y: 7, width: 0, height: 0 }.to_screen(3, 3), (7, 7));
+    }
+}
@@ -0,0 +1,220 @@
+//! Privacy redaction: areas of the host's screen that viewers never see.
+//!
+//! Zones are fixed rectangles or windows matched by title or class (X11 hosts). They
+//! come from `SCRDESK_REDACT` on the host and from the policies of its groups, and
+//! are filled in every frame right after it is captured, before it is encoded or sent.
+//! If the windows cannot be listed while a window zone is set, the whole frame is
+//! filled rather than risk showing one.
+//!
+//! `SCRDESK_REDACT` holds zones separated by `;`: `rect=X,Y,WxH`, `title=TEXT` or
+//! `class=TEXT`, e.g. `rect=0,0,400x300;class=keepassxc;title=Patient record`.
+
+use super::{Frame, Rect, WindowInfo};
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+
+const FILL: [u8; 4] = [0, 0, 0, 255];
+
+/// An area of the screen that is never sent, as policies describe it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RedactionZone {
+    Rect { x: i32, y: i32, width: u32, height: u32 },
+    /// Every window whose title or class contains these, ignoring case; both must match if both are given
+    Window { title: Option<String>, class: Option<String> },
+}
+
+impl RedactionZone {
+    fn matches(&self, window: &WindowInfo) -> bool {
+        let RedactionZone::Window { title, class } = self else {
+            return false;
+        };
+        let contains = |value: &str, pattern: &Option<String>| {
+            pattern.as_ref().map(|pattern| value.to_lowercase().contains(&pattern.to_lowercase()))
+        };
+        match (contains(&window.title, title), contains(&window.class, class)) {
+            (None, None) => false,
+            (title, class) => title.unwrap_or(true) && class.unwrap_or(true),
+        }
+    }
+}
+
+/// Parse zones in the form of `SCRDESK_REDACT`
+pub fn parse_zones(spec: &str) -> Result<Vec<RedactionZone>> {
+    let mut zones = Vec::new();
+    for zone in spec.split(';').map(str::trim).filter(|zone| !zone.is_empty()) {
+        let (kind, value) = zone.split_once('=').with_context(|| format!("Expected KIND=VALUE in {:?}", zone))?;
+        let value = value.trim();
+        zones.push(match kind.trim() {
+            "rect" => parse_rect(value).with_context(|| format!("Expected rect=X,Y,WxH in {:?}", zone))?,
+            "title" if !value.is_empty() => RedactionZone::Window { title: Some(value.to_string()), class: None },
+            "class" if !value.is_empty() => RedactionZone::Window { title: None, class: Some(value.to_string()) },
+            _ => bail!("Invalid redaction zone {:?}, expected rect, title or class", zone),
+        });
+    }
+    Ok(zones)
+}
+
+fn parse_rect(value: &str) -> Option<RedactionZone> {
+    let mut parts = value.splitn(3, ',');
+    let x = parts.next()?.trim().parse().ok()?;
+    let y = parts.next()?.trim().parse().ok()?;
+    let (width, height) = parts.next()?.trim().split_once('x')?;
+    let (width, height) = (width.parse().ok()?, height.parse().ok()?);
+    (width > 0 && height > 0).then_some(RedactionZone::Rect { x, y, width, height })
+}
+
+#[derive(Default)]
+struct Zones {
+    local: Vec<RedactionZone>,
+    policy: Vec<RedactionZone>,
+    policy_loaded: bool, // The policy's zones were fetched at least once
+}
+
+/// The zones in force on this host; clones share them, so the policy can replace its
+/// zones while the capture loop applies them
+#[derive(Clone, Default)]
+pub struct Redactor {
+    zones: Arc<Mutex<Zones>>,
+}
+
+impl Redactor {
+    /// With the local zones from `SCRDESK_REDACT`
+    pub fn from_env() -> Result<Self> {
+        let local = match std::env::var("SCRDESK_REDACT") {
+            Ok(spec) => parse_zones(&spec).context("Invalid SCRDESK_REDACT")?,
+            Err(_) => Vec::new(),
+        };
+        Ok(Self::with_zones(local))
+    }
+
+    pub fn with_zones(local: Vec<RedactionZone>) -> Self {
+        Self { zones: Arc::new(Mutex::new(Zones { local, ..Zones::default() })) }
+    }
+
+    /// Replace the zones the policy asks for
+    pub fn set_policy_zones(&self, zones: Vec<RedactionZone>) {
+        let mut current = self.zones.lock().unwrap();
+        current.policy_loaded = true;
+        if current.policy != zones {
+            tracing::info!("Policy redaction zones: {:?}", zones);
+            current.policy = zones;
+        }
+    }
+
+    /// Whether the policy's zones were fetched yet; a signed-in host shares nothing before
+    pub fn policy_loaded(&self) -> bool {
+        self.zones.lock().unwrap().policy_loaded
+    }
+
+    pub fn zones(&self) -> Vec<RedactionZone> {
+        let zones = self.zones.lock().unwrap();
+        zones.local.iter().chain(&zones.policy).cloned().collect()
+    }
+
+    /// Fill the zones in a frame taken from `origin` on the screen, or from the whole
+    /// screen if None. `windows` is only listed when a window zone is set. Returns how
+    /// many areas were filled.
+    pub fn redact(&self, frame: &mut Frame, origin: Option<Rect>, windows: impl FnOnce() -> Result<Vec<WindowInfo>>) -> usize {
+        let zones = self.zones();
+        if zones.is_empty() {
+            return 0;
+        }
+        let origin = origin.unwrap_or(Rect { x: 0, y: 0, width: frame.width, height: frame.height });
+        let whole = Rect { x: 0, y: 0, width: frame.width, height: frame.height };
+
+        let windows = if zones.iter().any(|zone| matches!(zone, RedactionZone::Window { .. })) {
+            match windows() {
+                Ok(windows) => windows,
+                Err(e) => {
+                    tracing::warn!("Cannot list windows to redact, hiding the whole frame: {:#}", e);
+                    frame.fill(whole, FILL);
+                    return 1;
+                }
+            }
+        } else {
+            Vec::new()
+        };
+
+        let mut areas = Vec::new();
+        for zone in &zones {
+            match zone {
+                RedactionZone::Rect { x, y, width, height } => {
+                    areas.push(Rect { x: *x, y: *y, width: *width, height: *height });
+                }
+                RedactionZone::Window { .. } => {
+                    areas.extend(windows.iter().filter(|window| zone.matches(window)).map(|window| window.bounds));
+                }
+            }
+        }
+
+        let mut filled = 0;
+        for area in areas {
+            if let Some(visible) = area.intersect(&origin) {
+                frame.fill(Rect { x: visible.x - origin.x, y: visible.y - origin.y, ..visible }, FILL);
+                filled += 1;
+            }
+        }
+        filled
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn window(title: &str, class: &str, bounds: Rect) -> WindowInfo {
+        WindowInfo { id: 1, title: title.to_string(), class: class.to_string(), bounds }
+    }
+
+    #[test]
+    fn test_parse_zones() {
+        let zones = parse_zones(" rect=10,-20,300x200; class=KeePassXC ;title=Patient record;").unwrap();
+        assert_eq!(
+            zones,
+            vec![
+                RedactionZone::Rect { x: 10, y: -20, width: 300, height: 200 },
+                RedactionZone::Window { title: None, class: Some("KeePassXC".to_string()) },
+                RedactionZone::Window { title: Some("Patient record".to_string()), class: None },
+            ]
+        );
+        assert!(parse_zones("").unwrap().is_empty());
+        assert!(parse_zones("rect=10,10,0x5").is_err());
+        assert!(parse_zones("title=").is_err());
+        assert!(parse_zones("screen=1").is_err());
+
+        // As policies send them
+        let json = r#"[{"type":"rect","x":0,"y":0,"width":5,"height":5},{"type":"window","title":null,"class":"1password"}]"#;
+        let zones: Vec<RedactionZone> = serde_json::from_str(json).unwrap();
+        assert_eq!(zones[1], RedactionZone::Window { title: None, class: Some("1password".to_string()) });
+    }
+
+    #[test]
+    fn test_redact_fills_zones_in_frame_coordinates() {
+        let redactor = Redactor::with_zones(vec![
+            RedactionZone::Rect { x: 0, y: 0, width: 2, height: 1 },
+            RedactionZone::Window { title: Some("vault".to_string()), class: None },
+        ]);
+        assert!(!redactor.policy_loaded());
+        redactor.set_policy_zones(vec![RedactionZone::Window { title: None, class: Some("Chart".to_string()) }]);
+        assert!(redactor.policy_loaded());
+
+        // A 4x2 region at (1, 0) of the screen
+        let mut frame = Frame { data: vec![9; 4 * 2 * 4], width: 4, height: 2, stride: 16, timestamp: 0 };
+        let origin = Some(Rect { x: 1, y: 0, width: 4, height: 2 });
+        let windows = vec![
+            window("My Vault", "keepassxc", Rect { x: 4, y: 1, width: 10, height: 10 }),
+            window("Patient chart", "Firefox", Rect { x: 1, y: 1, width: 1, height: 1 }),
+        ];
+        assert_eq!(redactor.redact(&mut frame, origin, || Ok(windows)), 2);
+
+        let masked: Vec<bool> = frame.data.chunks(4).map(|pixel| pixel == FILL).collect();
+        assert_eq!(masked, vec![true, false, false, false, false, false, false, true]);
+
+        // Listing the windows failed: nothing is shown
+        let mut frame = Frame { data: vec![9; 16], width: 2, height: 2, stride: 8, timestamp: 0 };
+        assert_eq!(redactor.redact(&mut frame, None, || bail!("no display")), 1);
+        assert!(frame.data.chunks(4).all(|pixel| pixel == FILL));
+    }
+}
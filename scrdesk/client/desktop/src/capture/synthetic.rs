@@ -0,0 +1,103 @@
+//! A capturer that draws a test pattern instead of reading the screen, for headless
+//! tests. Frame `n` is always the same image and carries `n * FRAME_INTERVAL` as its
+//! timestamp, so a receiver can check each frame it gets against `render`.
+
+use super::{Frame, ScreenCapture};
+use anyhow::Result;
+use std::time::Duration;
+
+/// Time between the frames of the pattern, as in the capture loop
+pub const FRAME_INTERVAL: Duration = Duration::from_millis(33);
+
+const BAR_WIDTH: u32 = 16;
+
+pub struct SyntheticCapturer {
+    width: u32,
+    height: u32,
+    next_frame: u64,
+    running: bool,
+}
+
+impl SyntheticCapturer {
+    pub fn new(width: u32, height: u32) -> Self {
+        Self { width, height, next_frame: 0, running: false }
+    }
+
+    /// RGBA pixels of frame `index`: a gradient under a bar that moves right each frame,
+    /// with the blue channel counting frames
+    pub fn render(width: u32, height: u32, index: u64) -> Vec<u8> {
+        let bar = (index * 4 % width.max(1) as u64) as u32;
+        let mut data = Vec::with_capacity(width as usize * height as usize * 4);
+        for y in 0..height {
+            for x in 0..width {
+                if x.wrapping_sub(bar) < BAR_WIDTH {
+                    data.extend_from_slice(&[255, 255, 255, 255]);
+                } else {
+                    let r = (x * 255 / width.max(1)) as u8;
+                    let g = (y * 255 / height.max(1)) as u8;
+                    data.extend_from_slice(&[r, g, index as u8, 255]);
+                }
+            }
+        }
+        data
+    }
+
+    /// Which frame a timestamp belongs to
+    pub fn frame_index(timestamp: u64) -> u64 {
+        timestamp / FRAME_INTERVAL.as_millis() as u64
+    }
+}
+
+impl ScreenCapture for SyntheticCapturer {
+    fn start(&mut self) -> Result<()> {
+        tracing::info!("Starting synthetic capture: {}x{}", self.width, self.height);
+        self.running = true;
+        Ok(())
+    }
+
+    fn capture_frame(&mut self) -> Result<Frame> {
+        let index = self.next_frame;
+        self.next_frame += 1;
+        Ok(Frame {
+            data: Self::render(self.width, self.height, index),
+            width: self.width,
+            height: self.height,
+            stride: self.width as usize * 4,
+            timestamp: index * FRAME_INTERVAL.as_millis() as u64,
+        })
+    }
+
+    fn stop(&mut self) {
+        tracing::info!("Stopping synthetic capture");
+        self.running = false;
+    }
+
+    fn get_dimensions(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frames_are_repeatable_and_move() {
+        let mut capturer = SyntheticCapturer::new(64, 8);
+        capturer.start().unwrap();
+        let first = capturer.capture_frame().unwrap();
+        let second = capturer.capture_frame().unwrap();
+
+        assert_eq!((first.width, first.height, first.stride), (64, 8, 256));
+        assert_eq!(first.data.len(), 64 * 8 * 4);
+        assert_eq!(SyntheticCapturer::frame_index(second.timestamp), 1);
+        assert_eq!(second.data, SyntheticCapturer::render(64, 8, 1));
+        assert_ne!(first.data, second.data);
+
+        // The bar starts at the left edge and moves four pixels a frame
+        assert_eq!(first.data[..4], [255, 255, 255, 255]);
+        let later = SyntheticCapturer::render(64, 8, 8);
+        assert_ne!(later[..4], [255, 255, 255, 255]);
+        assert_eq!(later[32 * 4..33 * 4], [255, 255, 255, 255]);
+    }
+}
@@ -0,0 +1,335 @@
+//! A virtual X display for Linux hosts without one, e.g. servers with no X session.
+//!
+//! When the host starts without a usable display, it starts an Xvfb (or Xorg with the
+//! dummy driver, "Xdummy") on a free display number, optionally runs a session command
+//! on it, and points `DISPLAY` at it, so the UI, the capturer and the input simulator
+//! all use it. The display lives as long as the `VirtualDisplay` and is resized with
+//! RandR when the viewer asks for another resolution.
+//!
+//! Configured with `SCRDESK_VIRTUAL_DISPLAY` (`auto`, the default, `always` or `off`),
+//! `SCRDESK_VIRTUAL_DISPLAY_SIZE` (e.g. `1920x1080`), `SCRDESK_VIRTUAL_DISPLAY_SERVER`
+//! (`xvfb` or `xdummy`) and `SCRDESK_VIRTUAL_SESSION` (a shell command, e.g. `openbox-session`).
+
+use anyhow::{bail, ensure, Context, Result};
+use std::ffi::CString;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+use x11::xlib::{XCloseDisplay, XOpenDisplay};
+
+const DEFAULT_SIZE: (u32, u32) = (1920, 1080);
+const MIN_SIZE: (u32, u32) = (640, 480);
+const MAX_SIZE: (u32, u32) = (7680, 4320);
+const FIRST_DISPLAY: u32 = 99; // Clear of the displays of real sessions
+const START_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    Auto,   // Only without a usable display
+    Always, // Even next to a real session, e.g. to keep it private
+    Off,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Server {
+    Xvfb,
+    Xdummy,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct VirtualDisplayConfig {
+    pub mode: Mode,
+    pub server: Server,
+    pub size: (u32, u32),
+    pub session: Option<String>,
+}
+
+impl Default for VirtualDisplayConfig {
+    fn default() -> Self {
+        Self { mode: Mode::Auto, server: Server::Xvfb, size: DEFAULT_SIZE, session: None }
+    }
+}
+
+impl VirtualDisplayConfig {
+    pub fn from_env() -> Result<Self> {
+        let var = |name: &str| std::env::var(name).ok().map(|v| v.trim().to_string()).filter(|v| !v.is_empty());
+        let mut config = Self::default();
+        if let Some(mode) = var("SCRDESK_VIRTUAL_DISPLAY") {
+            config.mode = match mode.to_lowercase().as_str() {
+                "auto" => Mode::Auto,
+                "always" | "1" | "true" => Mode::Always,
+                "off" | "0" | "false" => Mode::Off,
+                other => bail!("Invalid SCRDESK_VIRTUAL_DISPLAY {:?}, expected auto, always or off", other),
+            };
+        }
+        if let Some(server) = var("SCRDESK_VIRTUAL_DISPLAY_SERVER") {
+            config.server = match server.to_lowercase().as_str() {
+                "xvfb" => Server::Xvfb,
+                "xdummy" | "dummy" => Server::Xdummy,
+                other => bail!("Invalid SCRDESK_VIRTUAL_DISPLAY_SERVER {:?}, expected xvfb or xdummy", other),
+            };
+        }
+        if let Some(size) = var("SCRDESK_VIRTUAL_DISPLAY_SIZE") {
+            config.size = parse_size(&size).context("Invalid SCRDESK_VIRTUAL_DISPLAY_SIZE")?;
+        }
+        config.session = var("SCRDESK_VIRTUAL_SESSION");
+        Ok(config)
+    }
+}
+
+/// Parse `WIDTHxHEIGHT`, within the sizes a virtual display supports
+pub fn parse_size(size: &str) -> Result<(u32, u32)> {
+    let (width, height) = size.split_once(['x', 'X']).with_context(|| format!("Expected WIDTHxHEIGHT, got {:?}", size))?;
+    let size = (width.trim().parse()?, height.trim().parse()?);
+    check_size(size)?;
+    Ok(size)
+}
+
+fn check_size((width, height): (u32, u32)) -> Result<()> {
+    ensure!(
+        (MIN_SIZE.0..=MAX_SIZE.0).contains(&width) && (MIN_SIZE.1..=MAX_SIZE.1).contains(&height),
+        "{}x{} is outside {}x{} to {}x{}",
+        width, height, MIN_SIZE.0, MIN_SIZE.1, MAX_SIZE.0, MAX_SIZE.1
+    );
+    Ok(())
+}
+
+/// Whether `DISPLAY` names an X display we can connect to
+pub fn display_usable() -> bool {
+    std::env::var("DISPLAY").ok().filter(|d| !d.is_empty()).is_some_and(|d| can_open(&d))
+}
+
+fn can_open(display: &str) -> bool {
+    let Ok(name) = CString::new(display) else {
+        return false;
+    };
+    unsafe {
+        let connection = XOpenDisplay(name.as_ptr());
+        if connection.is_null() {
+            return false;
+        }
+        XCloseDisplay(connection);
+    }
+    true
+}
+
+/// The virtual display this process owns, for the capturer to resize
+static CURRENT: Mutex<Option<Weak<VirtualDisplay>>> = Mutex::new(None);
+
+pub fn current() -> Option<Arc<VirtualDisplay>> {
+    CURRENT.lock().unwrap().as_ref().and_then(Weak::upgrade)
+}
+
+/// Start a virtual display if the configuration asks for one, and point `DISPLAY` at
+/// it. Call before any other thread starts; the display stops when the result is dropped.
+pub fn start_from_env() -> Option<Arc<VirtualDisplay>> {
+    let config = match VirtualDisplayConfig::from_env() {
+        Ok(config) => config,
+        Err(e) => {
+            tracing::error!("Not starting a virtual display: {:#}", e);
+            return None;
+        }
+    };
+    match config.mode {
+        Mode::Off => return None,
+        // A Wayland session has no X display, but it is still the user's screen
+        Mode::Auto if display_usable() || std::env::var_os("WAYLAND_DISPLAY").is_some() => return None,
+        _ => {}
+    }
+
+    tracing::info!("No usable display, starting a virtual one");
+    match VirtualDisplay::start(&config) {
+        Ok(display) => {
+            std::env::set_var("DISPLAY", display.name());
+            let display = Arc::new(display);
+            *CURRENT.lock().unwrap() = Some(Arc::downgrade(&display));
+            Some(display)
+        }
+        Err(e) => {
+            tracing::error!("Failed to start a virtual display: {:#}", e);
+            None
+        }
+    }
+}
+
+pub struct VirtualDisplay {
+    name: String,
+    server: Child,
+    session: Option<Child>,
+    config_file: Option<PathBuf>, // Written for Xdummy
+}
+
+impl VirtualDisplay {
+    pub fn start(config: &VirtualDisplayConfig) -> Result<Self> {
+        check_size(config.size)?;
+        let number = free_display(Path::new("/tmp"))?;
+        let name = format!(":{}", number);
+        let (width, height) = config.size;
+
+        let mut config_file = None;
+        let mut command = match config.server {
+            Server::Xvfb => {
+                // RandR cannot grow Xvfb past the screen it started with
+                let mut command = Command::new("Xvfb");
+                command.args([&name, "-screen", "0", &format!("{}x{}x24", MAX_SIZE.0, MAX_SIZE.1)]);
+                command
+            }
+            Server::Xdummy => {
+                let path = std::env::temp_dir().join(format!("scrdesk-xdummy-{}.conf", number));
+                std::fs::write(&path, xdummy_config()).context("Failed to write the Xdummy configuration")?;
+                let mut command = Command::new("Xorg");
+                command.arg(&name).arg("-config").arg(&path).arg("-logfile").arg(path.with_extension("log"));
+                config_file = Some(path);
+                command
+            }
+        };
+        let server = command
+            .args(["-nolisten", "tcp", "-noreset", "+extension", "RANDR"])
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .spawn()
+            .with_context(|| format!("Failed to run the {:?} server, is it installed?", config.server))?;
+
+        let mut started = Self { name, server, session: None, config_file };
+        started.wait_ready()?;
+        tracing::info!("Virtual display {} running ({:?}, {}x{})", started.name, config.server, width, height);
+
+        // Both servers come up at the largest size, so viewers can pick any size later
+        started.resize(width, height)?;
+
+        if let Some(session) = &config.session {
+            let child = Command::new("sh")
+                .args(["-c", session])
+                .env("DISPLAY", &started.name)
+                .stdin(Stdio::null())
+                .spawn()
+                .with_context(|| format!("Failed to start the session {:?}", session))?;
+            tracing::info!("Started session on {}: {}", started.name, session);
+            started.session = Some(child);
+        }
+        Ok(started)
+    }
+
+    fn wait_ready(&mut self) -> Result<()> {
+        let deadline = Instant::now() + START_TIMEOUT;
+        while !can_open(&self.name) {
+            if let Some(status) = self.server.try_wait()? {
+                bail!("Display server exited with {}", status);
+            }
+            ensure!(Instant::now() < deadline, "Display {} did not come up within {:?}", self.name, START_TIMEOUT);
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        Ok(())
+    }
+
+    /// The display name, e.g. `:99`
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Change the screen size, e.g. when the viewer asks for its own resolution
+    pub fn resize(&self, width: u32, height: u32) -> Result<()> {
+        check_size((width, height))?;
+        let output = Command::new("xrandr")
+            .args(["-d", &self.name, "--fb", &format!("{}x{}", width, height)])
+            .output()
+            .context("Failed to run xrandr, is it installed?")?;
+        ensure!(
+            output.status.success(),
+            "xrandr could not resize {}: {}",
+            self.name,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+        tracing::info!("Virtual display {} resized to {}x{}", self.name, width, height);
+        Ok(())
+    }
+}
+
+impl Drop for VirtualDisplay {
+    fn drop(&mut self) {
+        // Clients of the display go when it does
+        if let Some(session) = self.session.as_mut() {
+            let _ = session.kill();
+            let _ = session.wait();
+        }
+        let _ = self.server.kill();
+        let _ = self.server.wait();
+        if let Some(path) = &self.config_file {
+            let _ = std::fs::remove_file(path);
+        }
+        tracing::info!("Virtual display {} stopped", self.name);
+    }
+}
+
+/// The first display number from `FIRST_DISPLAY` that no X server holds, going by the
+/// lock files and sockets under `tmp`
+fn free_display(tmp: &Path) -> Result<u32> {
+    (FIRST_DISPLAY..FIRST_DISPLAY + 100)
+        .find(|n| !tmp.join(format!(".X{}-lock", n)).exists() && !tmp.join(format!(".X11-unix/X{}", n)).exists())
+        .context("No free X display number")
+}
+
+/// Xorg with the dummy driver, able to grow to the largest supported size
+fn xdummy_config() -> String {
+    format!(
+        r#"Section "Device"
+    Identifier "scrdesk-dummy"
+    Driver "dummy"
+    VideoRam {video_ram}
+EndSection
+
+Section "Monitor"
+    Identifier "scrdesk-monitor"
+    HorizSync 5.0 - 1000.0
+    VertRefresh 5.0 - 200.0
+EndSection
+
+Section "Screen"
+    Identifier "scrdesk-screen"
+    Device "scrdesk-dummy"
+    Monitor "scrdesk-monitor"
+    DefaultDepth 24
+    SubSection "Display"
+        Depth 24
+        Virtual {width} {height}
+    EndSubSection
+EndSection
+"#,
+        video_ram = MAX_SIZE.0 * MAX_SIZE.1 * 4 / 1024 + 1024,
+        width = MAX_SIZE.0,
+        height = MAX_SIZE.1,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("1920x1080").unwrap(), (1920, 1080));
+        assert_eq!(parse_size(" 1280 X 720 ").unwrap(), (1280, 720));
+        assert!(parse_size("1920").is_err());
+        assert!(parse_size("100x100").is_err());
+        assert!(parse_size("10000x10000").is_err());
+    }
+
+    #[test]
+    fn test_free_display_skips_taken_numbers() {
+        let tmp = tempfile::tempdir().unwrap();
+        std::fs::create_dir(tmp.path().join(".X11-unix")).unwrap();
+        assert_eq!(free_display(tmp.path()).unwrap(), FIRST_DISPLAY);
+
+        std::fs::write(tmp.path().join(format!(".X{}-lock", FIRST_DISPLAY)), "").unwrap();
+        std::fs::write(tmp.path().join(format!(".X11-unix/X{}", FIRST_DISPLAY + 1)), "").unwrap();
+        assert_eq!(free_display(tmp.path()).unwrap(), FIRST_DISPLAY + 2);
+    }
+
+    #[test]
+    fn test_xdummy_config_fits_the_largest_size() {
+        let config = xdummy_config();
+        assert!(config.contains(r#"Driver "dummy""#));
+        assert!(config.contains(&format!("Virtual {} {}", MAX_SIZE.0, MAX_SIZE.1)));
+    }
+}
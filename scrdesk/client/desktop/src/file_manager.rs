@@ -0,0 +1,324 @@
+use crate::protocol::{FileEntry, Message};
+use eframe::egui;
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+/// Remote side of the file manager, updated from incoming protocol messages
+#[derive(Debug)]
+pub struct RemoteBrowserState {
+    pub path: String,
+    pub entries: Vec<FileEntry>,
+    pub pending: HashSet<String>,
+    pub last_error: Option<String>,
+    pub needs_refresh: bool,
+}
+
+impl Default for RemoteBrowserState {
+    fn default() -> Self {
+        Self {
+            path: "/".to_string(),
+            entries: Vec::new(),
+            pending: HashSet::new(),
+            last_error: None,
+            needs_refresh: true,
+        }
+    }
+}
+
+impl RemoteBrowserState {
+    /// Apply a file browser response. Returns false if the message is not one.
+    pub fn apply_response(&mut self, message: &Message) -> bool {
+        match message {
+            Message::DirectoryListing { request_id, path, entries } => {
+                self.pending.remove(request_id);
+                self.path = path.clone();
+                self.entries = entries.clone();
+                self.last_error = None;
+            }
+            Message::PathStat { request_id, entry } => {
+                self.pending.remove(request_id);
+                if let Some(existing) = self.entries.iter_mut().find(|e| e.path == entry.path) {
+                    *existing = entry.clone();
+                }
+            }
+            Message::FileOperationResult { request_id, success, error } => {
+                self.pending.remove(request_id);
+                if *success {
+                    self.needs_refresh = true;
+                } else {
+                    self.last_error = error.clone();
+                }
+            }
+            _ => return false,
+        }
+        true
+    }
+}
+
+/// Something the file manager wants the app to do on its behalf
+pub enum FileManagerAction {
+    Send(Message),
+    Upload(PathBuf),
+    SyncUpload(PathBuf), // Send only the differences to the remote copy
+}
+
+struct LocalEntry {
+    name: String,
+    path: PathBuf,
+    is_dir: bool,
+    size: u64,
+}
+
+/// Two-pane (local / remote) file manager view
+pub struct FileManagerView {
+    local_dir: PathBuf,
+    local_entries: Vec<LocalEntry>,
+    local_selected: Option<PathBuf>,
+    remote: Arc<Mutex<RemoteBrowserState>>,
+    remote_selected: Option<FileEntry>,
+    name_input: String,
+    confirm_delete: bool,
+}
+
+impl FileManagerView {
+    pub fn new(remote: Arc<Mutex<RemoteBrowserState>>) -> Self {
+        let local_dir = std::env::current_dir().unwrap_or_default();
+        let mut view = Self {
+            local_dir,
+            local_entries: Vec::new(),
+            local_selected: None,
+            remote,
+            remote_selected: None,
+            name_input: String::new(),
+            confirm_delete: false,
+        };
+        view.refresh_local();
+        view
+    }
+
+    fn refresh_local(&mut self) {
+        self.local_entries.clear();
+        self.local_selected = None;
+
+        let Ok(read_dir) = std::fs::read_dir(&self.local_dir) else {
+            tracing::warn!("Failed to read local directory: {}", self.local_dir.display());
+            return;
+        };
+
+        for item in read_dir.flatten() {
+            let Ok(metadata) = item.metadata() else { continue };
+            self.local_entries.push(LocalEntry {
+                name: item.file_name().to_string_lossy().to_string(),
+                path: item.path(),
+                is_dir: metadata.is_dir(),
+                size: metadata.len(),
+            });
+        }
+
+        self.local_entries.sort_by(|a, b| b.is_dir.cmp(&a.is_dir).then_with(|| a.name.cmp(&b.name)));
+    }
+
+    fn request(remote: &mut RemoteBrowserState, build: impl FnOnce(String) -> Message) -> FileManagerAction {
+        let request_id = uuid::Uuid::new_v4().to_string();
+        remote.pending.insert(request_id.clone());
+        FileManagerAction::Send(build(request_id))
+    }
+
+    pub fn show(&mut self, ui: &mut egui::Ui) -> Vec<FileManagerAction> {
+        let mut actions = Vec::new();
+        let remote_state = self.remote.clone();
+        let mut remote = remote_state.blocking_lock();
+
+        if remote.needs_refresh {
+            remote.needs_refresh = false;
+            let path = remote.path.clone();
+            actions.push(Self::request(&mut remote, |request_id| Message::ListDirectory { request_id, path }));
+        }
+
+        ui.columns(2, |columns| {
+            // Local pane
+            columns[0].vertical(|ui| {
+                ui.strong("This Computer");
+                ui.horizontal(|ui| {
+                    if ui.button("⬆").on_hover_text("Parent directory").clicked() {
+                        if let Some(parent) = self.local_dir.parent() {
+                            self.local_dir = parent.to_path_buf();
+                            self.refresh_local();
+                        }
+                    }
+                    ui.label(egui::RichText::new(self.local_dir.display().to_string()).monospace());
+                });
+                ui.separator();
+
+                let mut open_dir = None;
+                egui::ScrollArea::vertical()
+                    .id_source("local_pane")
+                    .max_height(ui.available_height() - 40.0)
+                    .show(ui, |ui| {
+                        for entry in &self.local_entries {
+                            let icon = if entry.is_dir { "📁" } else { "📄" };
+                            let selected = self.local_selected.as_ref() == Some(&entry.path);
+                            let label = format!("{} {}  {}", icon, entry.name, format_size(entry.size, entry.is_dir));
+                            let response = ui.selectable_label(selected, label);
+                            if response.clicked() {
+                                self.local_selected = Some(entry.path.clone());
+                            }
+                            if response.double_clicked() && entry.is_dir {
+                                open_dir = Some(entry.path.clone());
+                            }
+                        }
+                    });
+                if let Some(dir) = open_dir {
+                    self.local_dir = dir;
+                    self.refresh_local();
+                }
+
+                ui.separator();
+                ui.horizontal(|ui| {
+                    let can_upload = self.local_selected.as_ref().map(|p| p.is_file()).unwrap_or(false);
+                    if ui.add_enabled(can_upload, egui::Button::new("Upload ➡")).clicked() {
+                        if let Some(path) = self.local_selected.clone() {
+                            actions.push(FileManagerAction::Upload(path));
+                        }
+                    }
+                    if ui.add_enabled(can_upload, egui::Button::new("Sync ➡"))
+                        .on_hover_text("Send only the parts that differ from the remote copy")
+                        .clicked()
+                    {
+                        if let Some(path) = self.local_selected.clone() {
+                            actions.push(FileManagerAction::SyncUpload(path));
+                        }
+                    }
+                    if ui.button("⟳").on_hover_text("Refresh").clicked() {
+                        self.refresh_local();
+                    }
+                });
+            });
+
+            // Remote pane
+            columns[1].vertical(|ui| {
+                ui.strong("Remote Computer");
+                ui.horizontal(|ui| {
+                    if ui.button("⬆").on_hover_text("Parent directory").clicked() {
+                        let parent = parent_virtual(&remote.path);
+                        self.remote_selected = None;
+                        actions.push(Self::request(&mut remote, |request_id| Message::ListDirectory { request_id, path: parent }));
+                    }
+                    ui.label(egui::RichText::new(remote.path.clone()).monospace());
+                    if !remote.pending.is_empty() {
+                        ui.spinner();
+                    }
+                });
+                ui.separator();
+
+                let mut open_dir = None;
+                egui::ScrollArea::vertical()
+                    .id_source("remote_pane")
+                    .max_height(ui.available_height() - 70.0)
+                    .show(ui, |ui| {
+                        for entry in &remote.entries {
+                            let icon = if entry.is_dir { "📁" } else { "📄" };
+                            let selected = self.remote_selected.as_ref().map(|s| s.path == entry.path).unwrap_or(false);
+                            let label = format!("{} {}  {}", icon, entry.name, format_size(entry.size, entry.is_dir));
+                            let response = ui.selectable_label(selected, label);
+                            if response.clicked() {
+                                self.remote_selected = Some(entry.clone());
+                                self.confirm_delete = false;
+                            }
+                            if response.double_clicked() && entry.is_dir {
+                                open_dir = Some(entry.path.clone());
+                            }
+                        }
+                    });
+                if let Some(path) = open_dir {
+                    self.remote_selected = None;
+                    actions.push(Self::request(&mut remote, |request_id| Message::ListDirectory { request_id, path }));
+                }
+
+                if let Some(error) = &remote.last_error {
+                    ui.colored_label(egui::Color32::RED, format!("❌ {}", error));
+                }
+
+                ui.separator();
+                ui.horizontal(|ui| {
+                    ui.add(egui::TextEdit::singleline(&mut self.name_input)
+                        .hint_text("Name")
+                        .desired_width(140.0));
+
+                    let has_name = !self.name_input.trim().is_empty() && !self.name_input.contains(['/', '\\']);
+                    if ui.add_enabled(has_name, egui::Button::new("New Folder")).clicked() {
+                        let path = format!("{}/{}", remote.path.trim_end_matches('/'), self.name_input.trim());
+                        self.name_input.clear();
+                        actions.push(Self::request(&mut remote, |request_id| Message::CreateDirectory { request_id, path }));
+                    }
+
+                    if let Some(selected) = self.remote_selected.clone() {
+                        if ui.add_enabled(has_name, egui::Button::new("Rename")).clicked() {
+                            let to = format!("{}/{}", parent_virtual(&selected.path).trim_end_matches('/'), self.name_input.trim());
+                            self.name_input.clear();
+                            self.remote_selected = None;
+                            actions.push(Self::request(&mut remote, |request_id| Message::RenamePath {
+                                request_id,
+                                from: selected.path.clone(),
+                                to,
+                            }));
+                        }
+                    }
+                });
+
+                ui.horizontal(|ui| {
+                    let selected = self.remote_selected.clone();
+                    let can_download = selected.as_ref().map(|e| !e.is_dir).unwrap_or(false);
+                    if ui.add_enabled(can_download, egui::Button::new("⬅ Download")).clicked() {
+                        if let Some(entry) = &selected {
+                            let path = entry.path.clone();
+                            actions.push(Self::request(&mut remote, |request_id| Message::DownloadPath { request_id, path }));
+                        }
+                    }
+
+                    let delete_text = if self.confirm_delete { "Confirm Delete" } else { "Delete" };
+                    if ui.add_enabled(selected.is_some(), egui::Button::new(delete_text)).clicked() {
+                        if !self.confirm_delete {
+                            self.confirm_delete = true;
+                        } else if let Some(entry) = selected {
+                            self.confirm_delete = false;
+                            self.remote_selected = None;
+                            actions.push(Self::request(&mut remote, |request_id| Message::DeletePath {
+                                request_id,
+                                path: entry.path,
+                                recursive: entry.is_dir,
+                            }));
+                        }
+                    }
+
+                    if ui.button("⟳").on_hover_text("Refresh").clicked() {
+                        remote.needs_refresh = true;
+                    }
+                });
+            });
+        });
+
+        actions
+    }
+}
+
+fn parent_virtual(path: &str) -> String {
+    match path.trim_end_matches('/').rsplit_once('/') {
+        Some((parent, _)) if !parent.is_empty() => parent.to_string(),
+        _ => "/".to_string(),
+    }
+}
+
+fn format_size(size: u64, is_dir: bool) -> String {
+    if is_dir {
+        return String::new();
+    }
+    match size {
+        s if s >= 1024 * 1024 * 1024 => format!("{:.1} GB", s as f64 / (1024.0 * 1024.0 * 1024.0)),
+        s if s >= 1024 * 1024 => format!("{:.1} MB", s as f64 / (1024.0 * 1024.0)),
+        s if s >= 1024 => format!("{:.1} KB", s as f64 / 1024.0),
+        s => format!("{} B", s),
+    }
+}
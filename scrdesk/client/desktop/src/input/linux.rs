@@ -1,61 +1,128 @@
 use super::InputSimulator;
 use crate::protocol::{KeyModifiers, MouseButton};
 use anyhow::Result;
+use std::ffi::CString;
+use std::sync::Mutex;
+use x11::xlib::{CurrentTime, Display, XCloseDisplay, XFlush, XKeysymToKeycode, XOpenDisplay, XStringToKeysym};
+use x11::xtest::{XTestFakeButtonEvent, XTestFakeKeyEvent, XTestFakeMotionEvent, XTestQueryExtension};
 
+/// Injects input into the X display in `DISPLAY` through the XTest extension
 pub struct LinuxSimulator {
-    // TODO: Add X11 Display connection or Wayland connection
+    display: Mutex<*mut Display>,
 }
 
+// Every use of the connection goes through the mutex
+unsafe impl Send for LinuxSimulator {}
+
 impl LinuxSimulator {
     pub fn new() -> Result<Self> {
-        // TODO: Initialize X11 or Wayland connection
-        // For now, return stub implementation
-        Ok(Self {})
+        let display = unsafe { XOpenDisplay(std::ptr::null()) };
+        if display.is_null() {
+            anyhow::bail!("Cannot open X display {:?}", std::env::var("DISPLAY").unwrap_or_default());
+        }
+
+        let (mut event_base, mut error_base, mut major, mut minor) = (0, 0, 0, 0);
+        if unsafe { XTestQueryExtension(display, &mut event_base, &mut error_base, &mut major, &mut minor) } == 0 {
+            unsafe { XCloseDisplay(display) };
+            anyhow::bail!("The X server does not support the XTest extension");
+        }
+
+        Ok(Self { display: Mutex::new(display) })
+    }
+
+    /// Run `f` on the connection and send what it queued
+    fn with_display(&self, f: impl FnOnce(*mut Display) -> Result<()>) -> Result<()> {
+        let display = self.display.lock().unwrap();
+        let result = f(*display);
+        unsafe { XFlush(*display) };
+        result
+    }
+
+    fn key_event(display: *mut Display, keysym_name: &str, pressed: bool) -> Result<()> {
+        let name = CString::new(keysym_name)?;
+        let keycode = unsafe {
+            let keysym = XStringToKeysym(name.as_ptr());
+            if keysym == 0 {
+                anyhow::bail!("Unknown key: {}", keysym_name);
+            }
+            XKeysymToKeycode(display, keysym)
+        };
+        if keycode == 0 {
+            anyhow::bail!("No key on the keyboard produces {}", keysym_name);
+        }
+        unsafe { XTestFakeKeyEvent(display, keycode as u32, pressed as i32, CurrentTime) };
+        Ok(())
     }
 }
 
 impl InputSimulator for LinuxSimulator {
     fn simulate_mouse_move(&self, x: i32, y: i32) -> Result<()> {
-        // TODO: Implement X11 XWarpPointer or Wayland equivalent
-        tracing::warn!("Linux mouse move not implemented: ({}, {})", x, y);
-        anyhow::bail!("Linux input simulation not implemented yet")
+        self.with_display(|display| {
+            unsafe { XTestFakeMotionEvent(display, -1, x, y, CurrentTime) };
+            Ok(())
+        })
     }
 
     fn simulate_mouse_button(&self, button: MouseButton, pressed: bool) -> Result<()> {
-        // TODO: Implement X11 XTestFakeButtonEvent or Wayland equivalent
-        tracing::warn!(
-            "Linux mouse button not implemented: {:?} {}",
-            button,
-            if pressed { "pressed" } else { "released" }
-        );
-        anyhow::bail!("Linux input simulation not implemented yet")
+        self.with_display(|display| {
+            unsafe { XTestFakeButtonEvent(display, map_mouse_button(button), pressed as i32, CurrentTime) };
+            Ok(())
+        })
     }
 
     fn simulate_mouse_scroll(&self, delta_x: i32, delta_y: i32) -> Result<()> {
-        // TODO: Implement X11 scroll events or Wayland equivalent
-        tracing::warn!("Linux mouse scroll not implemented: ({}, {})", delta_x, delta_y);
-        anyhow::bail!("Linux input simulation not implemented yet")
+        // X scrolls by clicks of buttons 4 and 5 (up, down) and 6 and 7 (left, right)
+        self.with_display(|display| {
+            let vertical = if delta_y > 0 { 4 } else { 5 };
+            let horizontal = if delta_x > 0 { 7 } else { 6 };
+            for (button, clicks) in [(vertical, delta_y.unsigned_abs()), (horizontal, delta_x.unsigned_abs())] {
+                for _ in 0..clicks {
+                    unsafe {
+                        XTestFakeButtonEvent(display, button, 1, CurrentTime);
+                        XTestFakeButtonEvent(display, button, 0, CurrentTime);
+                    }
+                }
+            }
+            Ok(())
+        })
     }
 
     fn simulate_key(&self, key: &str, pressed: bool, modifiers: KeyModifiers) -> Result<()> {
-        // TODO: Implement X11 XTestFakeKeyEvent or Wayland equivalent
-        tracing::warn!(
-            "Linux keyboard not implemented: {} {} (shift:{} ctrl:{} alt:{} meta:{})",
-            key,
-            if pressed { "pressed" } else { "released" },
-            modifiers.shift,
-            modifiers.ctrl,
-            modifiers.alt,
-            modifiers.meta
-        );
-        anyhow::bail!("Linux input simulation not implemented yet")
+        let held = [
+            (modifiers.shift, "Shift_L"),
+            (modifiers.ctrl, "Control_L"),
+            (modifiers.alt, "Alt_L"),
+            (modifiers.meta, "Super_L"),
+        ];
+
+        self.with_display(|display| {
+            // Press modifiers first if key is being pressed
+            if pressed {
+                for (_, modifier) in held.iter().filter(|(on, _)| *on) {
+                    Self::key_event(display, modifier, true)?;
+                }
+            }
+
+            Self::key_event(display, &keysym_name(key), pressed)?;
+
+            // Release modifiers if key is being released
+            if !pressed {
+                for (_, modifier) in held.iter().filter(|(on, _)| *on) {
+                    Self::key_event(display, modifier, false)?;
+                }
+            }
+            Ok(())
+        })
     }
 }
 
-// TODO: Implement these functions for X11
-/*
-use x11::xlib::{Display, XOpenDisplay, XCloseDisplay, XFlush};
-use x11::xtest::{XTestFakeMotionEvent, XTestFakeButtonEvent, XTestFakeKeyEvent};
+impl Drop for LinuxSimulator {
+    fn drop(&mut self) {
+        unsafe {
+            XCloseDisplay(*self.display.get_mut().unwrap());
+        }
+    }
+}
 
 fn map_mouse_button(button: MouseButton) -> u32 {
     match button {
@@ -67,9 +134,69 @@ fn map_mouse_button(button: MouseButton) -> u32 {
     }
 }
 
-fn map_key_to_keycode(display: *mut Display, key: &str) -> Option<u32> {
-    // Use XStringToKeysym and XKeysymToKeycode
-    // Mapping for common keys
-    None
+/// The X keysym name for a key as the controller names it; anything else is taken to
+/// be a keysym name already, e.g. `F5`
+fn keysym_name(key: &str) -> String {
+    let lower = key.to_lowercase();
+    let name = match lower.as_str() {
+        // Special keys
+        "space" => "space",
+        "return" | "enter" => "Return",
+        "tab" => "Tab",
+        "escape" | "esc" => "Escape",
+        "backspace" => "BackSpace",
+        "delete" => "Delete",
+
+        // Arrow keys
+        "left" => "Left",
+        "right" => "Right",
+        "up" => "Up",
+        "down" => "Down",
+
+        // Navigation keys
+        "home" => "Home",
+        "end" => "End",
+        "pageup" => "Prior",
+        "pagedown" => "Next",
+
+        // Modifier keys
+        "shift" => "Shift_L",
+        "control" | "ctrl" => "Control_L",
+        "alt" | "option" => "Alt_L",
+        "command" | "windows" | "win" | "meta" => "Super_L",
+
+        // Punctuation
+        ";" => "semicolon",
+        "=" => "equal",
+        "," => "comma",
+        "-" => "minus",
+        "." => "period",
+        "/" => "slash",
+        "`" => "grave",
+        "[" => "bracketleft",
+        "\\" => "backslash",
+        "]" => "bracketright",
+        "'" => "apostrophe",
+
+        // Letters and digits are their own keysyms
+        single if single.len() == 1 && single.chars().all(|c| c.is_ascii_alphanumeric()) => single,
+        _ => key,
+    };
+    name.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keysym_names() {
+        assert_eq!(keysym_name("A"), "a");
+        assert_eq!(keysym_name("7"), "7");
+        assert_eq!(keysym_name("Enter"), "Return");
+        assert_eq!(keysym_name("backspace"), "BackSpace");
+        assert_eq!(keysym_name("PageDown"), "Next");
+        assert_eq!(keysym_name("["), "bracketleft");
+        assert_eq!(keysym_name("F5"), "F5");
+    }
 }
-*/
@@ -1,5 +1,8 @@
 use anyhow::Result;
-use crate::protocol::{KeyModifiers, MouseButton};
+use std::collections::HashSet;
+use std::sync::Mutex;
+use crate::capture::Rect;
+use crate::protocol::{KeyModifiers, Message, MouseButton};
 
 #[cfg(target_os = "macos")]
 mod macos;
@@ -7,6 +10,7 @@ mod macos;
 mod windows;
 #[cfg(target_os = "linux")]
 mod linux;
+pub mod recording;
 
 pub trait InputSimulator: Send {
     fn simulate_mouse_move(&self, x: i32, y: i32) -> Result<()>;
@@ -15,6 +19,75 @@ pub trait InputSimulator: Send {
     fn simulate_key(&self, key: &str, pressed: bool, modifiers: KeyModifiers) -> Result<()>;
 }
 
+/// Apply an input message from the controller; other messages are ignored
+pub fn apply(simulator: &dyn InputSimulator, message: &Message) -> Result<()> {
+    match message {
+        Message::MouseMove { x, y } => simulator.simulate_mouse_move(*x, *y),
+        Message::MouseButton { button, pressed } => simulator.simulate_mouse_button(*button, *pressed),
+        Message::MouseScroll { delta_x, delta_y } => simulator.simulate_mouse_scroll(*delta_x, *delta_y),
+        Message::KeyboardEvent { key, pressed, modifiers } => simulator.simulate_key(key, *pressed, *modifiers),
+        _ => Ok(()),
+    }
+}
+
+/// Keeps a controller's pointer inside the shared window or region. Positions are
+/// relative to the area and kept inside it, and clicks and scrolls go where the
+/// controller last pointed, even if the local user moved the pointer away since.
+/// Key presses are dropped while they would not reach the shared area, e.g. while
+/// another window than the shared one has the focus; releases of keys that were
+/// pressed still go through, so none is left held down.
+#[derive(Default)]
+pub struct InputClip {
+    pointer: Mutex<Option<(i32, i32)>>, // Last position the controller moved to, relative to the area
+    held: Mutex<HashSet<String>>,       // Keys pressed and not released yet
+}
+
+impl InputClip {
+    /// Apply an input message for a controller who sees `area`, or the whole screen if
+    /// None. `keyboard_in_area` tells whether keys typed now reach that area.
+    pub fn apply(&self, simulator: &dyn InputSimulator, message: &Message, area: Option<Rect>, keyboard_in_area: bool) -> Result<()> {
+        if let Message::KeyboardEvent { key, pressed, .. } = message {
+            // Outside the shared window only releases of keys pressed inside it get through;
+            // a repeated press would bring its modifiers along to the focused window
+            let mut held = self.held.lock().unwrap();
+            let was_held = held.remove(key);
+            if !keyboard_in_area && (*pressed || !was_held) {
+                tracing::debug!("Dropping key {} outside the shared window", key);
+                if *pressed && was_held {
+                    held.insert(key.clone());
+                }
+                return Ok(());
+            }
+            if *pressed {
+                held.insert(key.clone());
+            }
+            return apply(simulator, message);
+        }
+
+        let Some(area) = area else {
+            return apply(simulator, message);
+        };
+        let mut pointer = self.pointer.lock().unwrap();
+        match message {
+            Message::MouseMove { x, y } => {
+                *pointer = Some((*x, *y));
+                let (x, y) = area.to_screen(*x, *y);
+                simulator.simulate_mouse_move(x, y)
+            }
+            Message::MouseButton { .. } | Message::MouseScroll { .. } => {
+                // Nothing to aim at before the first move
+                let Some((x, y)) = *pointer else {
+                    return Ok(());
+                };
+                let (x, y) = area.to_screen(x, y);
+                simulator.simulate_mouse_move(x, y)?;
+                apply(simulator, message)
+            }
+            _ => apply(simulator, message),
+        }
+    }
+}
+
 pub fn create_simulator() -> Result<Box<dyn InputSimulator>> {
     #[cfg(target_os = "macos")]
     {
@@ -36,3 +109,73 @@ pub fn create_simulator() -> Result<Box<dyn InputSimulator>> {
         anyhow::bail!("Unsupported platform for input simulation")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use recording::{InputEvent, RecordingSimulator};
+
+    #[test]
+    fn test_clip_keeps_pointer_inside_area() {
+        let recorder = RecordingSimulator::new();
+        let clip = InputClip::default();
+        let area = Some(Rect { x: 100, y: 50, width: 200, height: 100 });
+
+        let click = Message::MouseButton { button: MouseButton::Left, pressed: true };
+        clip.apply(&recorder, &click, area, true).unwrap(); // No position yet
+        clip.apply(&recorder, &Message::MouseMove { x: 10, y: 500 }, area, true).unwrap();
+        clip.apply(&recorder, &click, area, true).unwrap();
+        clip.apply(&recorder, &Message::MouseMove { x: 10, y: 10 }, None, true).unwrap();
+
+        assert_eq!(
+            recorder.events(),
+            vec![
+                InputEvent::MouseMove { x: 110, y: 149 },
+                InputEvent::MouseMove { x: 110, y: 149 },
+                InputEvent::MouseButton { button: MouseButton::Left, pressed: true },
+                InputEvent::MouseMove { x: 10, y: 10 },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_clip_drops_keys_outside_shared_window() {
+        let recorder = RecordingSimulator::new();
+        let clip = InputClip::default();
+        let area = Some(Rect { x: 100, y: 50, width: 200, height: 100 });
+        let key = |key: &str, pressed: bool| Message::KeyboardEvent {
+            key: key.to_string(),
+            pressed,
+            modifiers: KeyModifiers::default(),
+        };
+
+        // Another window has the focus: Alt+Tab never reaches it
+        clip.apply(&recorder, &key("Alt", true), area, false).unwrap();
+        clip.apply(&recorder, &key("Tab", true), area, false).unwrap();
+        clip.apply(&recorder, &key("Tab", false), area, false).unwrap();
+        clip.apply(&recorder, &key("Alt", false), area, false).unwrap();
+        assert!(recorder.events().is_empty());
+
+        // A key pressed in the shared window is released even after the focus moved away
+        clip.apply(&recorder, &key("a", true), area, true).unwrap();
+        clip.apply(&recorder, &key("a", false), area, false).unwrap();
+        clip.apply(&recorder, &key("b", true), area, false).unwrap();
+
+        // A key held since the focus moved away is not pressed again, only released
+        let ctrl = Message::KeyboardEvent {
+            key: "w".to_string(),
+            pressed: true,
+            modifiers: KeyModifiers { ctrl: true, ..KeyModifiers::default() },
+        };
+        clip.apply(&recorder, &key("w", true), area, true).unwrap();
+        clip.apply(&recorder, &ctrl, area, false).unwrap();
+        clip.apply(&recorder, &key("w", false), area, false).unwrap();
+        clip.apply(&recorder, &key("w", false), area, false).unwrap();
+
+        let event = |key: &str, pressed: bool| InputEvent::Key { key: key.to_string(), pressed, modifiers: KeyModifiers::default() };
+        assert_eq!(
+            recorder.events(),
+            vec![event("a", true), event("a", false), event("w", true), event("w", false)]
+        );
+    }
+}
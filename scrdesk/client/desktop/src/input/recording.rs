@@ -0,0 +1,89 @@
+//! An input simulator that records what it is asked to do instead of doing it, for
+//! headless tests.
+
+use super::InputSimulator;
+use crate::protocol::{KeyModifiers, MouseButton};
+use anyhow::Result;
+use std::sync::{Arc, Mutex};
+
+/// One call made to the simulator
+#[derive(Debug, Clone, PartialEq)]
+pub enum InputEvent {
+    MouseMove { x: i32, y: i32 },
+    MouseButton { button: MouseButton, pressed: bool },
+    MouseScroll { delta_x: i32, delta_y: i32 },
+    Key { key: String, pressed: bool, modifiers: KeyModifiers },
+}
+
+/// Clones share one log, so a test can keep a clone and read what the session injected
+#[derive(Clone, Default)]
+pub struct RecordingSimulator {
+    events: Arc<Mutex<Vec<InputEvent>>>,
+}
+
+impl RecordingSimulator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The events recorded so far, oldest first
+    pub fn events(&self) -> Vec<InputEvent> {
+        self.events.lock().unwrap().clone()
+    }
+
+    fn record(&self, event: InputEvent) -> Result<()> {
+        tracing::debug!("Recorded input: {:?}", event);
+        self.events.lock().unwrap().push(event);
+        Ok(())
+    }
+}
+
+impl InputSimulator for RecordingSimulator {
+    fn simulate_mouse_move(&self, x: i32, y: i32) -> Result<()> {
+        self.record(InputEvent::MouseMove { x, y })
+    }
+
+    fn simulate_mouse_button(&self, button: MouseButton, pressed: bool) -> Result<()> {
+        self.record(InputEvent::MouseButton { button, pressed })
+    }
+
+    fn simulate_mouse_scroll(&self, delta_x: i32, delta_y: i32) -> Result<()> {
+        self.record(InputEvent::MouseScroll { delta_x, delta_y })
+    }
+
+    fn simulate_key(&self, key: &str, pressed: bool, modifiers: KeyModifiers) -> Result<()> {
+        self.record(InputEvent::Key { key: key.to_string(), pressed, modifiers })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::apply;
+    use crate::protocol::Message;
+
+    #[test]
+    fn test_records_applied_input_in_order() {
+        let simulator = RecordingSimulator::new();
+        let log = simulator.clone();
+        let modifiers = KeyModifiers { ctrl: true, ..Default::default() };
+
+        for message in [
+            Message::MouseMove { x: 10, y: 20 },
+            Message::MouseButton { button: MouseButton::Left, pressed: true },
+            Message::ClipboardUpdate { content: String::new(), mime_type: "text/plain".to_string() },
+            Message::KeyboardEvent { key: "c".to_string(), pressed: true, modifiers },
+        ] {
+            apply(&simulator, &message).unwrap();
+        }
+
+        assert_eq!(
+            log.events(),
+            vec![
+                InputEvent::MouseMove { x: 10, y: 20 },
+                InputEvent::MouseButton { button: MouseButton::Left, pressed: true },
+                InputEvent::Key { key: "c".to_string(), pressed: true, modifiers },
+            ]
+        );
+    }
+}
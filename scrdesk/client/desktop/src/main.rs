@@ -9,20 +9,34 @@ mod input;
 mod transfer;
 mod clipboard;
 mod network;
+mod file_manager;
+mod participants;
+mod sim;
+mod watermark;
+mod dlp;
 
 use api::{ApiClient, RegisterDeviceRequest};
 use connection::{ConnectionManager, ConnectionState};
 use eframe::egui;
 use std::sync::Arc;
-use std::time::{SystemTime, UNIX_EPOCH};
-use tokio::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::{Mutex, Notify};
 
 // Remote desktop modules
-use capture::ScreenCapture;
-use input::InputSimulator;
-use transfer::FileTransferManager;
+use capture::{CaptureArea, Rect, ScreenCapture, WindowInfo};
+use capture::redaction::Redactor;
+use dlp::Dlp;
+use input::{InputClip, InputSimulator};
+use transfer::{ConflictPolicy, ConflictResolution, DeltaStart, DownloadPolicy, DownloadStart, FileTransferManager, ResolvedDownload};
+use transfer::browser::FileBrowserHost;
+use transfer::delta::{self, DeltaOp, FileSignature};
+use transfer::scheduler::{TransferScheduler, DEFAULT_WINDOW_SIZE};
+use file_manager::{FileManagerAction, FileManagerView, RemoteBrowserState};
+use participants::{ParticipantsView, SessionParticipants};
 use clipboard::ClipboardMonitor;
-use network::{NetworkConnection, ConnectionManager as NetConnectionManager};
+use network::queue::OutgoingQueue;
+use network::{DirectStatus, NetworkConnection, RelayEndpoint, SecurityStatus, SendStatus, SessionStats, ConnectionManager as NetConnectionManager};
 use protocol::Message;
 
 fn main() -> Result<(), eframe::Error> {
@@ -76,6 +90,18 @@ fn main() -> Result<(), eframe::Error> {
     #[cfg(debug_assertions)]
     tracing_subscriber::fmt::init();
 
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("p2p-check") {
+        std::process::exit(run_p2p_check(&args[1..]));
+    }
+    if args.first().map(String::as_str) == Some("sim") {
+        std::process::exit(sim::run_sim(&args[1..]));
+    }
+
+    // Headless Linux hosts get a virtual display, which the UI runs on as well
+    #[cfg(target_os = "linux")]
+    let _virtual_display = capture::virtual_display::start_from_env();
+
     let options = eframe::NativeOptions {
         viewport: egui::ViewportBuilder::default()
             .with_inner_size([1000.0, 750.0])
@@ -103,6 +129,67 @@ fn main() -> Result<(), eframe::Error> {
     )
 }
 
+/// How long `p2p-check` waits for the session to go direct
+const P2P_CHECK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// `scrdesk p2p-check <device-id> [<target-id>]`: connect to the relay from the
+/// environment without the UI, optionally open a session to `target-id`, and report
+/// whether the session gets a direct path. Exits with 0 if it does.
+fn run_p2p_check(args: &[String]) -> i32 {
+    let Some(device_id) = args.first() else {
+        eprintln!("Usage: scrdesk p2p-check <device-id> [<target-id>]");
+        return 2;
+    };
+
+    let runtime = match tokio::runtime::Runtime::new() {
+        Ok(runtime) => runtime,
+        Err(e) => {
+            eprintln!("Failed to create Tokio runtime: {}", e);
+            return 2;
+        }
+    };
+
+    runtime.block_on(async {
+        let connection = match RelayEndpoint::from_env() {
+            Ok(relay) => NetworkConnection::connect(relay, device_id.clone(), None).await,
+            Err(e) => Err(e),
+        };
+        let connection = match connection {
+            Ok(connection) => connection,
+            Err(e) => {
+                eprintln!("Failed to connect to the relay: {:#}", e);
+                return 2;
+            }
+        };
+
+        if let Some(target_id) = args.get(1) {
+            let request = Message::ConnectRequest { target_id: target_id.clone(), auth_token: None };
+            if let Err(e) = connection.send(request).await {
+                eprintln!("Failed to request a session: {:#}", e);
+                return 2;
+            }
+        }
+
+        let deadline = tokio::time::Instant::now() + P2P_CHECK_TIMEOUT;
+        while tokio::time::Instant::now() < deadline {
+            if let Some(path) = connection.direct_path() {
+                println!("direct {}", path);
+                // Give the peer time to see the link before the session ends
+                tokio::time::sleep(Duration::from_secs(2)).await;
+                return 0;
+            }
+            if let Ok(Some(Message::ConnectResponse { success: false, error, .. })) =
+                tokio::time::timeout(Duration::from_millis(100), connection.recv()).await
+            {
+                eprintln!("Session failed: {}", error.unwrap_or_default());
+                return 2;
+            }
+        }
+        println!("relayed");
+        1
+    })
+}
+
 #[cfg(windows)]
 fn show_error_dialog(message: &str) {
     unsafe {
@@ -168,19 +255,46 @@ struct ScrDeskApp {
 
     // Remote desktop components
     net_connection: Arc<Mutex<Option<NetConnectionManager>>>,
+    direct_status: DirectStatus, // Whether the session bypasses the relay
+    security_status: SecurityStatus, // End-to-end encryption and security codes
+    session_stats: SessionStats, // Round-trip time, frame rates and latency of the session
+    show_stats_overlay: bool,
     screen_capturer: Arc<Mutex<Option<Box<dyn ScreenCapture>>>>,
     input_simulator: Arc<Mutex<Option<Box<dyn InputSimulator>>>>,
+    input_clip: Arc<InputClip>, // Keeps the controller inside the shared window or region
     file_transfer: Arc<Mutex<Option<FileTransferManager>>>,
     clipboard_monitor: Arc<Mutex<Option<ClipboardMonitor>>>,
+    file_browser_host: Arc<Mutex<Option<FileBrowserHost>>>,
+    transfer_scheduler: Arc<Mutex<TransferScheduler>>,
+    upload_notify: Arc<Notify>,
+
+    // Remote file manager state
+    remote_browser: Arc<Mutex<RemoteBrowserState>>,
+    file_manager: Option<FileManagerView>,
+    show_file_manager: bool,
+
+    // Other participants of the session and pending invitations
+    session_participants: Arc<Mutex<SessionParticipants>>,
+    participants_view: ParticipantsView,
 
     // Remote screen state
     remote_screen_texture: Option<egui::TextureHandle>,
     remote_screen_size: (u32, u32),
+    latest_frame: Arc<Mutex<Option<(u32, u32, Vec<u8>)>>>, // Arrived, not shown yet
+    watermark_enabled: bool,
+    watermark_required: Arc<AtomicBool>, // By a policy of this device's groups
+    requested_resolution: Option<(u32, u32)>, // Asked of the host, which follows on a virtual display
     is_streaming: bool,
     remote_device_id: String,
 
     // Screen capture state
     is_capturing: bool,
+    share_area: CaptureArea,
+    shareable_windows: Vec<WindowInfo>,
+    share_region: Rect, // Edited while sharing a region
+    redactor: Redactor, // Areas that are never sent
+    redaction_error: Option<String>, // Invalid local zones; nothing is shared until fixed
+    dlp: Dlp, // Inspects what leaves through the clipboard and file transfers
     capture_fps: f32,
     last_frame_time: std::time::Instant,
 }
@@ -202,12 +316,27 @@ const TEXT_PRIMARY: egui::Color32 = egui::Color32::from_rgb(17, 24, 39);
 const TEXT_SECONDARY: egui::Color32 = egui::Color32::from_rgb(107, 114, 128);   // gray-500
 const SUCCESS_COLOR: egui::Color32 = egui::Color32::from_rgb(34, 197, 94);      // green-500
 
+/// How often the host asks for the redaction zones of its policies
+const REDACTION_REFRESH: Duration = Duration::from_secs(60); // Also the watermark and DLP rules
+/// How soon it asks again while it has no zones from the policies yet
+const REDACTION_RETRY: Duration = Duration::from_secs(2);
+
+/// Screen sizes a viewer can ask the host for
+const RESOLUTIONS: [(u32, u32); 4] = [(1280, 720), (1600, 900), (1920, 1080), (2560, 1440)];
+
 impl ScrDeskApp {
     fn new(_cc: &eframe::CreationContext<'_>, runtime: tokio::runtime::Runtime) -> Self {
         let server_url = "http://72.61.138.218:8000".to_string();
         let api_client = Arc::new(ApiClient::new(server_url.clone()));
         let relay_server = "72.61.138.218:21117".to_string();
         let connection_manager = Arc::new(ConnectionManager::new(relay_server));
+        let (redactor, redaction_error) = match Redactor::from_env() {
+            Ok(redactor) => (redactor, None),
+            Err(e) => {
+                tracing::error!("{:#}", e);
+                (Redactor::default(), Some(format!("{:#}", e)))
+            }
+        };
 
         Self {
             runtime,
@@ -235,19 +364,47 @@ impl ScrDeskApp {
 
             // Remote desktop components (initialized on demand)
             net_connection: Arc::new(Mutex::new(None)),
+            direct_status: DirectStatus::default(),
+            security_status: SecurityStatus::default(),
+            session_stats: SessionStats::default(),
+            show_stats_overlay: false,
             screen_capturer: Arc::new(Mutex::new(None)),
             input_simulator: Arc::new(Mutex::new(None)),
+            input_clip: Arc::new(InputClip::default()),
             file_transfer: Arc::new(Mutex::new(None)),
             clipboard_monitor: Arc::new(Mutex::new(None)),
+            file_browser_host: Arc::new(Mutex::new(None)),
+            transfer_scheduler: Arc::new(Mutex::new(TransferScheduler::new(
+                DEFAULT_WINDOW_SIZE,
+                transfer_bandwidth_limit(),
+            ))),
+            upload_notify: Arc::new(Notify::new()),
+
+            // Remote file manager state
+            remote_browser: Arc::new(Mutex::new(RemoteBrowserState::default())),
+            file_manager: None,
+            show_file_manager: false,
+            session_participants: Arc::new(Mutex::new(SessionParticipants::default())),
+            participants_view: ParticipantsView::new(),
 
             // Remote screen state
             remote_screen_texture: None,
             remote_screen_size: (1920, 1080),
+            latest_frame: Arc::new(Mutex::new(None)),
+            watermark_enabled: watermark::enabled_from_env(),
+            watermark_required: Arc::new(AtomicBool::new(false)),
+            requested_resolution: None,
             is_streaming: false,
             remote_device_id: String::new(),
 
             // Screen capture state
             is_capturing: false,
+            share_area: CaptureArea::FullScreen,
+            shareable_windows: Vec::new(),
+            share_region: Rect { x: 0, y: 0, width: 1280, height: 720 },
+            redactor,
+            redaction_error,
+            dlp: Dlp::default(),
             capture_fps: 0.0,
             last_frame_time: std::time::Instant::now(),
         }
@@ -259,24 +416,50 @@ impl ScrDeskApp {
             return;
         }
 
+        if let Some(e) = &self.redaction_error {
+            self.error_message = Some(format!("Not sharing until the redaction zones are fixed: {}", e));
+            return;
+        }
+
         self.is_capturing = true;
         let screen_capturer = self.screen_capturer.clone();
+        let redactor = self.redactor.clone();
+        let api_client = Arc::clone(&self.api_client);
+        let session_stats = self.session_stats.clone();
         let net_connection = self.net_connection.clone();
         let ctx_clone = ctx.clone();
 
         self.runtime.spawn(async move {
             let mut frame_count = 0;
             let mut last_fps_update = std::time::Instant::now();
+            let mut holding = false;
 
             loop {
                 tokio::time::sleep(tokio::time::Duration::from_millis(33)).await; // ~30 FPS
 
+                // A signed-in host shares nothing until it knows which zones its policies hide
+                if !redactor.policy_loaded() && api_client.is_authenticated().await {
+                    if !holding {
+                        tracing::info!("Holding screen capture until the policy's redaction zones are loaded");
+                        holding = true;
+                    }
+                    continue;
+                }
+                holding = false;
+
                 // Capture frame
                 let frame_data = {
                     let mut capturer = screen_capturer.lock().await;
                     if let Some(cap) = capturer.as_mut() {
                         match cap.capture_frame() {
-                            Ok(frame) => Some(frame),
+                            Ok(mut frame) => {
+                                // Masked before the frame goes anywhere
+                                let redacted = redactor.redact(&mut frame, cap.capture_bounds(), || cap.list_windows());
+                                if redacted > 0 {
+                                    session_stats.redacted(redacted);
+                                }
+                                Some(frame)
+                            }
                             Err(e) => {
                                 tracing::error!("Failed to capture frame: {}", e);
                                 None
@@ -289,20 +472,30 @@ impl ScrDeskApp {
 
                 if let Some(frame) = frame_data {
                     // Send frame to remote
+                    let mut congested = false;
                     if let Some(manager) = net_connection.lock().await.as_ref() {
                         let msg = Message::VideoFrame {
                             data: frame.data,
                             width: frame.width,
                             height: frame.height,
                             timestamp: frame.timestamp,
-                            is_keyframe: frame_count % 30 == 0, // Keyframe every 30 frames
+                            // Keyframe every 30 frames, and whenever a viewer has to pick the stream up again
+                            is_keyframe: manager.take_keyframe_request() || frame_count % 30 == 0,
+                            after_input: false, // Set by the connection for the first frame after input
                         };
 
-                        if let Err(e) = manager.send(msg).await {
-                            tracing::error!("Failed to send video frame: {}", e);
+                        match manager.send(msg).await {
+                            Ok(status) => congested = status == SendStatus::Congested,
+                            Err(e) => tracing::error!("Failed to send video frame: {}", e),
                         }
                     }
 
+                    // The connection is behind; skip a frame rather than queue more
+                    if congested {
+                        tracing::debug!("Video queue full, skipping a frame");
+                        tokio::time::sleep(tokio::time::Duration::from_millis(33)).await;
+                    }
+
                     frame_count += 1;
 
                     // Update FPS counter
@@ -320,6 +513,83 @@ impl ScrDeskApp {
         tracing::info!("Screen capture started");
     }
 
+    // Show the latest frame that arrived, watermarked first if it has to be
+    fn update_remote_screen_texture(&mut self, ctx: &egui::Context) {
+        let Some((width, height, mut data)) = self.latest_frame.blocking_lock().take() else {
+            return;
+        };
+
+        if self.watermark_enabled || self.watermark_required.load(Ordering::Relaxed) {
+            let viewer = match &self.user_info {
+                Some(user) => user.email.clone(),
+                None => format!("guest {}", self.guest_connection_id),
+            };
+            let session_id = self.session_stats.session_id().unwrap_or_else(|| self.remote_device_id.clone());
+            watermark::Watermark::now(&viewer, &session_id).burn(&mut data, width, height);
+        }
+
+        let image = egui::ColorImage::from_rgba_unmultiplied([width as usize, height as usize], &data);
+        match &mut self.remote_screen_texture {
+            Some(texture) => texture.set(image, egui::TextureOptions::LINEAR),
+            None => self.remote_screen_texture = Some(ctx.load_texture("remote_screen", image, egui::TextureOptions::LINEAR)),
+        }
+        self.remote_screen_size = (width, height);
+    }
+
+    // Pick what to share; the controller can only see and point inside it
+    fn share_area_picker(&mut self, ui: &mut egui::Ui) {
+        let selected = match self.share_area {
+            CaptureArea::FullScreen => "🖥 Full screen".to_string(),
+            CaptureArea::Window(id) => self.shareable_windows.iter()
+                .find(|window| window.id == id)
+                .map_or("🗔 Window".to_string(), |window| format!("🗔 {}", window.title.chars().take(30).collect::<String>())),
+            CaptureArea::Region(region) => format!("⬚ {}x{} at {},{}", region.width, region.height, region.x, region.y),
+        };
+
+        let mut area = self.share_area;
+        let response = egui::ComboBox::from_id_source("share_area")
+            .selected_text(selected)
+            .width(220.0)
+            .show_ui(ui, |ui| {
+                ui.selectable_value(&mut area, CaptureArea::FullScreen, "🖥 Full screen");
+                for window in &self.shareable_windows {
+                    let name = if window.title.trim().is_empty() { &window.class } else { &window.title };
+                    ui.selectable_value(&mut area, CaptureArea::Window(window.id), format!("🗔 {}", name));
+                }
+                ui.selectable_value(&mut area, CaptureArea::Region(self.share_region), "⬚ Region");
+            });
+
+        // Windows come and go; list them again whenever the list is opened
+        if response.response.clicked() {
+            self.shareable_windows = self.screen_capturer.blocking_lock().as_ref()
+                .and_then(|cap| cap.list_windows().ok())
+                .unwrap_or_default();
+        }
+
+        if let CaptureArea::Region(_) = area {
+            let mut region = self.share_region;
+            ui.add(egui::DragValue::new(&mut region.x).prefix("x ").clamp_range(0..=7680));
+            ui.add(egui::DragValue::new(&mut region.y).prefix("y ").clamp_range(0..=4320));
+            ui.add(egui::DragValue::new(&mut region.width).prefix("w ").clamp_range(16..=7680));
+            ui.add(egui::DragValue::new(&mut region.height).prefix("h ").clamp_range(16..=4320));
+            if region != self.share_region {
+                self.share_region = region;
+                area = CaptureArea::Region(region);
+            }
+        }
+
+        if area != self.share_area {
+            let applied = match self.screen_capturer.blocking_lock().as_mut() {
+                Some(cap) => cap.set_area(area),
+                None => Err(anyhow::anyhow!("Screen capture is not available")),
+            };
+            match applied {
+                Ok(()) => self.share_area = area,
+                Err(e) => self.error_message = Some(format!("Cannot share that: {:#}", e)),
+            }
+        }
+    }
+
     // Stop screen capture
     fn stop_screen_capture(&mut self) {
         self.is_capturing = false;
@@ -331,11 +601,26 @@ impl ScrDeskApp {
         let runtime_handle = self.runtime.handle().clone();
         let net_connection = self.net_connection.clone();
         let device_id = self.guest_connection_id.clone();
+        let api_client = Arc::clone(&self.api_client);
+        let mut manager = NetConnectionManager::new().with_key_directory(Arc::clone(&self.api_client));
+        if quality_reports_enabled() {
+            manager = manager.with_quality_reports(Arc::clone(&self.api_client));
+        }
+        self.direct_status = manager.direct_status();
+        self.security_status = manager.security_status();
+        self.session_stats = manager.session_stats();
 
-        // Initialize network connection
+        // Initialize network connection; signed-in users identify themselves to the relay
         self.runtime.spawn(async move {
-            let mut manager = NetConnectionManager::new();
-            if let Err(e) = manager.connect(device_id).await {
+            let relay = match RelayEndpoint::from_env() {
+                Ok(relay) => relay,
+                Err(e) => {
+                    tracing::error!("Invalid relay configuration: {}", e);
+                    return;
+                }
+            };
+            let auth_token = api_client.token().await;
+            if let Err(e) = manager.connect(relay, device_id, auth_token).await {
                 tracing::error!("Failed to connect: {}", e);
             } else {
                 *net_connection.lock().await = Some(manager);
@@ -343,6 +628,34 @@ impl ScrDeskApp {
             }
         });
 
+        // Keep the redaction zones, the watermark rule and the DLP rules of the policies current while signed in
+        self.dlp = Dlp::with_reporter(Arc::clone(&self.api_client), self.guest_connection_id.clone(), runtime_handle);
+        let redactor = self.redactor.clone();
+        let watermark_required = self.watermark_required.clone();
+        let dlp = self.dlp.clone();
+        let api_client = Arc::clone(&self.api_client);
+        let device_id = self.guest_connection_id.clone();
+        self.runtime.spawn(async move {
+            loop {
+                if api_client.is_authenticated().await {
+                    match api_client.redaction_zones(&device_id).await {
+                        Ok(zones) => redactor.set_policy_zones(zones),
+                        Err(e) => tracing::warn!("Failed to fetch redaction zones, keeping the last ones: {:#}", e),
+                    }
+                    match api_client.watermark_required(&device_id).await {
+                        Ok(required) => watermark_required.store(required, Ordering::Relaxed),
+                        Err(e) => tracing::warn!("Failed to fetch the watermark policy, keeping the last one: {:#}", e),
+                    }
+                    match api_client.dlp_rules(&device_id).await {
+                        Ok(rules) => dlp.set_rules(rules),
+                        Err(e) => tracing::warn!("Failed to fetch DLP rules, keeping the last ones: {:#}", e),
+                    }
+                }
+                let refresh = if redactor.policy_loaded() { REDACTION_REFRESH } else { REDACTION_RETRY };
+                tokio::time::sleep(refresh).await;
+            }
+        });
+
         // Initialize screen capturer
         let capturer = capture::create_capturer();
         if let Ok(cap) = capturer {
@@ -361,16 +674,46 @@ impl ScrDeskApp {
         let downloads_dir = std::env::current_dir()
             .unwrap_or_default()
             .join("downloads");
-        if let Ok(ft) = FileTransferManager::new(downloads_dir) {
+        let download_policy = DownloadPolicy {
+            conflict: ConflictPolicy::Ask,
+            ..Default::default()
+        };
+        if let Ok(mut ft) = FileTransferManager::with_policy(downloads_dir.clone(), download_policy) {
+            ft.set_dlp(self.dlp.clone());
             *self.file_transfer.blocking_lock() = Some(ft);
             tracing::info!("File transfer manager initialized");
         }
 
+        // Start the upload pump (flow-controlled, shared by all transfers)
+        let net_connection = self.net_connection.clone();
+        let file_transfer = self.file_transfer.clone();
+        let scheduler = self.transfer_scheduler.clone();
+        let notify = self.upload_notify.clone();
+        self.runtime.spawn(async move {
+            upload_pump(net_connection, file_transfer, scheduler, notify).await;
+        });
+
+        // Initialize remote file browser (host side)
+        match FileBrowserHost::from_env(downloads_dir) {
+            Ok(host) => {
+                *self.file_browser_host.blocking_lock() = Some(host);
+                tracing::info!("File browser initialized");
+            }
+            Err(e) => {
+                tracing::error!("Failed to initialize file browser: {}", e);
+            }
+        }
+
         // Initialize clipboard monitor
         let ctx_clone = ctx.clone();
         let net_conn = self.net_connection.clone();
+        let dlp = self.dlp.clone();
 
         if let Ok(monitor) = ClipboardMonitor::new(move |content| {
+            // Blocked content is not sent at all
+            let Some(content) = dlp.check_clipboard(&content) else {
+                return;
+            };
             let net_conn = net_conn.clone();
             let ctx = ctx_clone.clone();
 
@@ -418,17 +761,30 @@ impl ScrDeskApp {
     fn handle_incoming_messages(&mut self, ctx: &egui::Context) {
         let net_connection = self.net_connection.clone();
         let input_simulator = self.input_simulator.clone();
+        let screen_capturer = self.screen_capturer.clone();
+        let input_clip = self.input_clip.clone();
+        let latest_frame = self.latest_frame.clone();
         let file_transfer = self.file_transfer.clone();
         let clipboard_monitor = self.clipboard_monitor.clone();
+        let file_browser_host = self.file_browser_host.clone();
+        let remote_browser = self.remote_browser.clone();
+        let transfer_scheduler = self.transfer_scheduler.clone();
+        let upload_notify = self.upload_notify.clone();
+        let session_participants = self.session_participants.clone();
         let ctx_clone = ctx.clone();
 
         self.runtime.spawn(async move {
             if let Some(manager) = net_connection.lock().await.as_ref() {
                 if let Some(msg) = manager.recv().await {
                     match msg {
-                        Message::ConnectResponse { success, session_id, error } => {
+                        Message::ConnectResponse { success, session_id, error, error_code, .. } => {
                             if success {
                                 tracing::info!("Connected! Session: {:?}", session_id);
+                                if let Some(ft) = file_transfer.lock().await.as_mut() {
+                                    ft.reset_session_quota();
+                                }
+                            } else if error_code.as_deref() == Some("SESSION_LIMIT_EXCEEDED") {
+                                tracing::error!("Connection refused: your organization has reached its concurrent session limit");
                             } else {
                                 tracing::error!("Connection failed: {:?}", error);
                             }
@@ -436,45 +792,254 @@ impl ScrDeskApp {
                         }
 
                         Message::VideoFrame { data, width, height, .. } => {
-                            // Store frame data for rendering
-                            // Frame data is RGBA format, can be directly used with egui
+                            // RGBA; the UI thread turns the latest one into the texture
                             tracing::debug!("Received video frame: {}x{} ({} bytes)", width, height, data.len());
-
-                            // We'll update the texture in the UI thread
-                            // For now, just log it
-                            // TODO: Convert to egui::ColorImage and update texture
+                            if data.len() == width as usize * height as usize * 4 {
+                                *latest_frame.lock().await = Some((width, height, data));
+                                ctx_clone.request_repaint();
+                            }
                         }
 
-                        Message::MouseMove { x, y } => {
+                        message if message.is_input() => {
+                            // Relative to the shared window or region, if only that is shared
+                            let (area, keyboard_in_area) = match screen_capturer.lock().await.as_ref() {
+                                Some(cap) => (cap.capture_bounds(), cap.keyboard_in_area()),
+                                None => (None, true),
+                            };
                             if let Some(sim) = input_simulator.lock().await.as_ref() {
-                                let _ = sim.simulate_mouse_move(x, y);
+                                let _ = input_clip.apply(sim.as_ref(), &message, area, keyboard_in_area);
                             }
                         }
 
-                        Message::MouseButton { button, pressed } => {
-                            if let Some(sim) = input_simulator.lock().await.as_ref() {
-                                let _ = sim.simulate_mouse_button(button, pressed);
+                        Message::SetResolution { width, height } => {
+                            let resized = match screen_capturer.lock().await.as_mut() {
+                                Some(cap) => cap.set_resolution(width, height),
+                                None => Err(anyhow::anyhow!("Screen capture is not available")),
+                            };
+                            match resized {
+                                Ok(()) => tracing::info!("Screen resized to {}x{} for the viewer", width, height),
+                                Err(e) => tracing::warn!("Cannot change the resolution to {}x{}: {:#}", width, height, e),
                             }
                         }
 
-                        Message::MouseScroll { delta_x, delta_y } => {
-                            if let Some(sim) = input_simulator.lock().await.as_ref() {
-                                let _ = sim.simulate_mouse_scroll(delta_x, delta_y);
+                        Message::FileTransferRequest { transfer_id, filename, filesize, .. } => {
+                            let started = match file_transfer.lock().await.as_mut() {
+                                Some(ft) => ft.start_download(transfer_id.clone(), filename, filesize),
+                                None => Err(anyhow::anyhow!("File transfer is not available")),
+                            };
+
+                            let response = match started {
+                                Ok(DownloadStart::Started) => Some(Message::FileTransferResponse {
+                                    transfer_id,
+                                    accepted: true,
+                                    reason: None,
+                                }),
+                                // Answered once the user resolves the name conflict
+                                Ok(DownloadStart::NeedsConfirmation { existing }) => {
+                                    tracing::info!("Download waiting for confirmation: {}", existing.display());
+                                    None
+                                }
+                                Err(e) => {
+                                    tracing::error!("Rejected incoming file: {}", e);
+                                    Some(Message::FileTransferResponse {
+                                        transfer_id,
+                                        accepted: false,
+                                        reason: Some(e.to_string()),
+                                    })
+                                }
+                            };
+
+                            if let Some(response) = response {
+                                let _ = manager.send(response).await;
                             }
+                            ctx_clone.request_repaint();
                         }
 
-                        Message::KeyboardEvent { key, pressed, modifiers } => {
-                            if let Some(sim) = input_simulator.lock().await.as_ref() {
-                                let _ = sim.simulate_key(&key, pressed, modifiers);
+                        Message::FileTransferResponse { transfer_id, accepted, reason } => {
+                            if !accepted {
+                                tracing::warn!("Transfer {} rejected by peer: {:?}", transfer_id, reason);
+                                if let Some(ft) = file_transfer.lock().await.as_mut() {
+                                    ft.mark_failed(&transfer_id, reason.unwrap_or_else(|| "Rejected".to_string()));
+                                }
                             }
+                            transfer_scheduler.lock().await.on_response(&transfer_id, accepted);
+                            upload_notify.notify_one();
                         }
 
                         Message::FileChunk { transfer_id, chunk_index, data } => {
+                            let written = match file_transfer.lock().await.as_mut() {
+                                Some(ft) => ft.write_chunk(&transfer_id, chunk_index, data).map_err(|e| {
+                                    ft.mark_failed(&transfer_id, e.to_string());
+                                    e
+                                }),
+                                None => Err(anyhow::anyhow!("File transfer is not available")),
+                            };
+
+                            // Acknowledge so the sender can advance its window, or abort the transfer
+                            let reply = match written {
+                                Ok(()) => Message::FileChunkAck { transfer_id, chunk_index },
+                                Err(e) => {
+                                    tracing::error!("Failed to write chunk for {}: {}", transfer_id, e);
+                                    Message::FileTransferComplete { transfer_id, success: false, checksum: None }
+                                }
+                            };
+                            let _ = manager.send(reply).await;
+                        }
+
+                        Message::FileChunkAck { transfer_id, chunk_index } => {
+                            transfer_scheduler.lock().await.on_ack(&transfer_id, chunk_index);
+                            upload_notify.notify_one();
+                        }
+
+                        Message::FileTransferComplete { transfer_id, success, checksum } => {
+                            // The receiver may be aborting one of our uploads
+                            if !success {
+                                transfer_scheduler.lock().await.on_failed(&transfer_id);
+                                upload_notify.notify_one();
+                            }
+
                             if let Some(ft) = file_transfer.lock().await.as_mut() {
-                                let _ = ft.write_chunk(&transfer_id, chunk_index, data);
+                                let is_download = ft
+                                    .get_transfer(&transfer_id)
+                                    .map(|t| t.info.direction == transfer::TransferDirection::Download)
+                                    .unwrap_or(false);
+
+                                if is_download && success {
+                                    if let Err(e) = ft.finish_download(&transfer_id, checksum.as_deref()) {
+                                        tracing::error!("Download {} failed verification: {}", transfer_id, e);
+                                    }
+                                } else if is_download {
+                                    ft.mark_failed(&transfer_id, "Sender aborted the transfer".to_string());
+                                } else if !success {
+                                    ft.mark_failed(&transfer_id, "Receiver aborted the transfer".to_string());
+                                }
+                            }
+
+                            tracing::info!("Transfer {} finished (success: {})", transfer_id, success);
+                            remote_browser.lock().await.needs_refresh = true;
+                            ctx_clone.request_repaint();
+                        }
+
+                        Message::DeltaSyncRequest { transfer_id, filename, filesize } => {
+                            // Hashing the existing copy may take a while for large files
+                            let started = tokio::task::block_in_place(|| {
+                                match file_transfer.blocking_lock().as_mut() {
+                                    Some(ft) => ft.start_delta_download(transfer_id.clone(), filename, filesize),
+                                    None => Err(anyhow::anyhow!("File transfer is not available")),
+                                }
+                            });
+
+                            let response = match started {
+                                Ok(DeltaStart::Started(signature)) => Some(Message::DeltaSignature { transfer_id, signature }),
+                                // Answered once the user resolves the name conflict
+                                Ok(DeltaStart::NeedsConfirmation { existing }) => {
+                                    tracing::info!("Delta sync waiting for confirmation: {}", existing.display());
+                                    None
+                                }
+                                Err(e) => {
+                                    tracing::error!("Rejected delta sync: {}", e);
+                                    Some(Message::FileTransferResponse {
+                                        transfer_id,
+                                        accepted: false,
+                                        reason: Some(e.to_string()),
+                                    })
+                                }
+                            };
+                            if let Some(response) = response {
+                                let _ = manager.send(response).await;
+                            }
+                            ctx_clone.request_repaint();
+                        }
+
+                        Message::DeltaSignature { transfer_id, signature } => {
+                            let net_connection = net_connection.clone();
+                            let file_transfer = file_transfer.clone();
+                            let scheduler = transfer_scheduler.clone();
+                            tokio::spawn(send_delta(net_connection, file_transfer, scheduler, transfer_id, signature));
+                        }
+
+                        Message::FileDelta { transfer_id, ops } => {
+                            let applied = tokio::task::block_in_place(|| {
+                                match file_transfer.blocking_lock().as_mut() {
+                                    Some(ft) => ft.apply_delta(&transfer_id, &ops).map_err(|e| {
+                                        ft.mark_failed(&transfer_id, e.to_string());
+                                        e
+                                    }),
+                                    None => Err(anyhow::anyhow!("File transfer is not available")),
+                                }
+                            });
+
+                            if let Err(e) = applied {
+                                tracing::error!("Failed to apply delta for {}: {}", transfer_id, e);
+                                let _ = manager.send(Message::FileTransferComplete {
+                                    transfer_id,
+                                    success: false,
+                                    checksum: None,
+                                }).await;
                             }
                         }
 
+                        Message::ListDirectory { .. }
+                        | Message::StatPath { .. }
+                        | Message::CreateDirectory { .. }
+                        | Message::RenamePath { .. }
+                        | Message::DeletePath { .. } => {
+                            let response = match file_browser_host.lock().await.as_ref() {
+                                Some(host) => host.handle_request(&msg),
+                                None => None,
+                            };
+
+                            if let Some(response) = response {
+                                let _ = manager.send(response).await;
+                            }
+                        }
+
+                        Message::DownloadPath { request_id, path } => {
+                            let resolved = match file_browser_host.lock().await.as_ref() {
+                                Some(host) => host.resolve_download(&path),
+                                None => Err(anyhow::anyhow!("File browser is not available")),
+                            };
+
+                            // Data loss prevention reads the whole file before it may go out
+                            let started = match resolved {
+                                Ok(file_path) => tokio::task::block_in_place(|| {
+                                    match file_transfer.blocking_lock().as_mut() {
+                                        Some(ft) => ft.start_upload_with_id(request_id.clone(), file_path),
+                                        None => Err(anyhow::anyhow!("File transfer is not available")),
+                                    }
+                                }),
+                                Err(e) => Err(e),
+                            };
+
+                            match started {
+                                Ok(info) => {
+                                    let _ = manager.send(Message::FileOperationResult {
+                                        request_id,
+                                        success: true,
+                                        error: None,
+                                    }).await;
+
+                                    // Chunks follow once the requester accepts the transfer
+                                    transfer_scheduler.lock().await.add(info.transfer_id.clone());
+                                    let _ = manager.send(transfer_request(&info, protocol::TransferDirection::Download)).await;
+                                }
+                                Err(e) => {
+                                    let _ = manager.send(Message::FileOperationResult {
+                                        request_id,
+                                        success: false,
+                                        error: Some(e.to_string()),
+                                    }).await;
+                                }
+                            }
+                        }
+
+                        Message::DirectoryListing { .. }
+                        | Message::PathStat { .. }
+                        | Message::FileOperationResult { .. } => {
+                            remote_browser.lock().await.apply_response(&msg);
+                            ctx_clone.request_repaint();
+                        }
+
                         Message::ClipboardUpdate { content, .. } => {
                             if let Some(monitor) = clipboard_monitor.lock().await.as_mut() {
                                 // TODO: Parse and set clipboard content
@@ -482,6 +1047,19 @@ impl ScrDeskApp {
                             }
                         }
 
+                        Message::ParticipantsChanged { .. }
+                        | Message::SessionInvitation { .. }
+                        | Message::SessionRequestFailed { .. } => {
+                            session_participants.lock().await.apply(&msg);
+                            ctx_clone.request_repaint();
+                        }
+
+                        Message::Disconnect { reason } => {
+                            tracing::info!("Session ended by relay: {}", reason.as_deref().unwrap_or("no reason given"));
+                            session_participants.lock().await.clear();
+                            ctx_clone.request_repaint();
+                        }
+
                         _ => {
                             tracing::debug!("Received message: {:?}", msg);
                         }
@@ -491,6 +1069,153 @@ impl ScrDeskApp {
         });
     }
 
+    // Ask the user what to do with incoming files that clash with existing ones
+    fn render_conflict_dialog(&mut self, ctx: &egui::Context) {
+        let pending: Vec<_> = match self.file_transfer.blocking_lock().as_ref() {
+            Some(ft) => ft.pending_downloads().into_iter().cloned().collect(),
+            None => return,
+        };
+
+        let Some(download) = pending.into_iter().next() else {
+            return;
+        };
+
+        let mut resolution = None;
+        egui::Window::new("File already exists")
+            .collapsible(false)
+            .resizable(false)
+            .anchor(egui::Align2::CENTER_CENTER, egui::vec2(0.0, 0.0))
+            .show(ctx, |ui| {
+                ui.label(format!(
+                    "The remote computer is sending \"{}\" ({} bytes), but a file with this name already exists:",
+                    download.filename, download.total_size
+                ));
+                ui.label(egui::RichText::new(download.existing.display().to_string()).monospace());
+                ui.add_space(10.0);
+                ui.horizontal(|ui| {
+                    if ui.button("Keep Both").clicked() {
+                        resolution = Some(ConflictResolution::Rename);
+                    }
+                    if ui.button("Overwrite").clicked() {
+                        resolution = Some(ConflictResolution::Overwrite);
+                    }
+                    if ui.button("Reject").clicked() {
+                        resolution = Some(ConflictResolution::Reject);
+                    }
+                });
+            });
+
+        if let Some(resolution) = resolution {
+            let file_transfer = self.file_transfer.clone();
+            let net_connection = self.net_connection.clone();
+            let transfer_id = download.transfer_id;
+
+            self.runtime.spawn(async move {
+                // Accepting a delta sync hashes the existing copy
+                let result = match file_transfer.lock().await.as_mut() {
+                    Some(ft) => tokio::task::block_in_place(|| ft.resolve_conflict(&transfer_id, resolution)),
+                    None => return,
+                };
+
+                let response = match result {
+                    Ok(ResolvedDownload::Delta(signature)) => Message::DeltaSignature { transfer_id, signature },
+                    Ok(ResolvedDownload::Chunks) => Message::FileTransferResponse { transfer_id, accepted: true, reason: None },
+                    Ok(ResolvedDownload::Rejected) => Message::FileTransferResponse {
+                        transfer_id,
+                        accepted: false,
+                        reason: Some("Rejected by user".to_string()),
+                    },
+                    Err(e) => Message::FileTransferResponse { transfer_id, accepted: false, reason: Some(e.to_string()) },
+                };
+
+                if let Some(manager) = net_connection.lock().await.as_ref() {
+                    let _ = manager.send(response).await;
+                }
+            });
+        }
+    }
+
+    // Send invitations, joins and control changes to the relay
+    fn send_session_messages(&self, messages: Vec<Message>) {
+        for msg in messages {
+            let net_connection = self.net_connection.clone();
+            self.runtime.spawn(async move {
+                if let Some(manager) = net_connection.lock().await.as_ref() {
+                    if let Err(e) = manager.send(msg).await {
+                        tracing::error!("Failed to send session request: {}", e);
+                    }
+                }
+            });
+        }
+    }
+
+    // Carry out requests coming from the file manager view
+    fn handle_file_manager_actions(&mut self, actions: Vec<FileManagerAction>) {
+        for action in actions {
+            let net_connection = self.net_connection.clone();
+            let file_transfer = self.file_transfer.clone();
+            let scheduler = self.transfer_scheduler.clone();
+
+            match action {
+                FileManagerAction::Send(msg) => {
+                    self.runtime.spawn(async move {
+                        if let Some(manager) = net_connection.lock().await.as_ref() {
+                            if let Err(e) = manager.send(msg).await {
+                                tracing::error!("Failed to send file browser request: {}", e);
+                            }
+                        }
+                    });
+                }
+                FileManagerAction::Upload(path) => {
+                    self.runtime.spawn(async move {
+                        let started = tokio::task::block_in_place(|| match file_transfer.blocking_lock().as_mut() {
+                            Some(ft) => ft.start_upload(path),
+                            None => Err(anyhow::anyhow!("File transfer is not available")),
+                        });
+
+                        match started {
+                            Ok(info) => {
+                                scheduler.lock().await.add(info.transfer_id.clone());
+                                if let Some(manager) = net_connection.lock().await.as_ref() {
+                                    let request = transfer_request(&info, protocol::TransferDirection::Upload);
+                                    if let Err(e) = manager.send(request).await {
+                                        tracing::error!("Failed to send transfer request: {}", e);
+                                    }
+                                }
+                            }
+                            Err(e) => tracing::error!("Failed to start upload: {}", e),
+                        }
+                    });
+                }
+                FileManagerAction::SyncUpload(path) => {
+                    self.runtime.spawn(async move {
+                        let started = tokio::task::block_in_place(|| match file_transfer.blocking_lock().as_mut() {
+                            Some(ft) => ft.start_upload(path),
+                            None => Err(anyhow::anyhow!("File transfer is not available")),
+                        });
+
+                        match started {
+                            Ok(info) => {
+                                // The receiver answers with the signature of its copy
+                                if let Some(manager) = net_connection.lock().await.as_ref() {
+                                    let request = Message::DeltaSyncRequest {
+                                        transfer_id: info.transfer_id,
+                                        filename: info.filename,
+                                        filesize: info.total_size,
+                                    };
+                                    if let Err(e) = manager.send(request).await {
+                                        tracing::error!("Failed to send delta sync request: {}", e);
+                                    }
+                                }
+                            }
+                            Err(e) => tracing::error!("Failed to start sync: {}", e),
+                        }
+                    });
+                }
+            }
+        }
+    }
+
     fn handle_login(&mut self, ctx: &egui::Context) {
         if self.is_logging_in {
             return;
@@ -848,6 +1573,38 @@ impl ScrDeskApp {
         });
     }
 
+    // Round-trip time, frame rates and latency of the session
+    fn render_stats_overlay(&mut self, ctx: &egui::Context) {
+        let stats = self.session_stats.snapshot();
+        let ms = |value: Option<f64>| value.map_or_else(|| "–".to_string(), |v| format!("{:.0} ms", v));
+
+        egui::Window::new("📈 Connection Quality")
+            .open(&mut self.show_stats_overlay)
+            .anchor(egui::Align2::RIGHT_TOP, egui::vec2(-10.0, 60.0))
+            .resizable(false)
+            .collapsible(false)
+            .show(ctx, |ui| {
+                egui::Grid::new("session_stats").num_columns(2).striped(true).show(ui, |ui| {
+                    let rows = [
+                        ("Round-trip time", ms(stats.rtt_ms)),
+                        ("Jitter", ms(stats.jitter_ms)),
+                        ("FPS sent / received", format!("{:.1} / {:.1}", stats.fps_sent, stats.fps_received)),
+                        ("Bitrate sent", format!("{:.0} kbps", stats.kbps_sent)),
+                        ("Bitrate received", format!("{:.0} kbps", stats.kbps_received)),
+                        ("Dropped frames", stats.frames_dropped.to_string()),
+                        ("Send queue", stats.queue_depth.to_string()),
+                        ("Input latency", ms(stats.input_latency_ms)),
+                        ("Redacted areas", stats.redactions.to_string()),
+                    ];
+                    for (name, value) in rows {
+                        ui.label(egui::RichText::new(name).color(TEXT_SECONDARY));
+                        ui.label(value);
+                        ui.end_row();
+                    }
+                });
+            });
+    }
+
     fn render_connected_mode(&mut self, ui: &mut egui::Ui, ctx: &egui::Context) {
         ui.vertical_centered(|ui| {
             ui.add_space(20.0);
@@ -861,6 +1618,18 @@ impl ScrDeskApp {
                         .color(SUCCESS_COLOR)
                 );
 
+                // Direct or through the relay
+                match self.direct_status.path() {
+                    Some(path) => {
+                        ui.label(egui::RichText::new("⚡ Direct").color(SUCCESS_COLOR))
+                            .on_hover_text(format!("Peer-to-peer connection ({})", path));
+                    }
+                    None => {
+                        ui.label(egui::RichText::new("☁ Relayed").color(TEXT_SECONDARY))
+                            .on_hover_text("Traffic goes through the relay server");
+                    }
+                }
+
                 ui.add_space(20.0);
 
                 // Disconnect button
@@ -871,6 +1640,7 @@ impl ScrDeskApp {
                 .fill(egui::Color32::from_rgb(239, 68, 68)); // red-500
 
                 if ui.add(disconnect_btn).clicked() {
+                    self.session_participants.blocking_lock().clear();
                     self.stop_screen_capture();
                     self.is_streaming = false;
                     self.mode = AppMode::GuestMode;
@@ -900,18 +1670,122 @@ impl ScrDeskApp {
                         self.start_screen_capture(ctx);
                     }
                 }
+
+                ui.add_space(10.0);
+
+                // The whole screen, one window or a region
+                self.share_area_picker(ui);
+
+                ui.add_space(20.0);
+
+                // Toggle between remote screen and file manager
+                let files_btn_text = if self.show_file_manager {
+                    "🖥 Remote Screen"
+                } else {
+                    "📁 Files"
+                };
+
+                let files_btn = egui::Button::new(
+                    egui::RichText::new(files_btn_text)
+                        .color(egui::Color32::WHITE)
+                )
+                .fill(SECONDARY_COLOR);
+
+                if ui.add(files_btn).clicked() {
+                    self.show_file_manager = !self.show_file_manager;
+                }
+
+                ui.add_space(20.0);
+
+                // Connection quality overlay
+                if ui.selectable_label(self.show_stats_overlay, "📈 Stats").clicked() {
+                    self.show_stats_overlay = !self.show_stats_overlay;
+                }
+
+                ui.add_space(20.0);
+
+                // Screen size of the host
+                let mut requested = self.requested_resolution;
+                egui::ComboBox::from_id_source("remote_resolution")
+                    .selected_text(requested.map_or("🖵 Resolution".to_string(), |(w, h)| format!("🖵 {}x{}", w, h)))
+                    .show_ui(ui, |ui| {
+                        for size in RESOLUTIONS {
+                            ui.selectable_value(&mut requested, Some(size), format!("{}x{}", size.0, size.1));
+                        }
+                    });
+                if requested != self.requested_resolution {
+                    self.requested_resolution = requested;
+                    if let Some((width, height)) = requested {
+                        self.send_session_messages(vec![Message::SetResolution { width, height }]);
+                    }
+                }
+
+                ui.add_space(20.0);
+
+                // Forensic watermark; a policy can make it mandatory
+                let required = self.watermark_required.load(Ordering::Relaxed);
+                let toggle = ui.add_enabled(
+                    !required,
+                    egui::SelectableLabel::new(self.watermark_enabled || required, "💧 Watermark"),
+                );
+                if toggle.clicked() {
+                    self.watermark_enabled = !self.watermark_enabled;
+                }
+                toggle.on_disabled_hover_text("Required by your organization's policy");
             });
 
-            ui.add_space(20.0);
+            if self.show_stats_overlay {
+                self.render_stats_overlay(ctx);
+            }
+
+            // End-to-end encryption, with the codes users compare out of band
+            ui.add_space(6.0);
+            match (self.security_status.error(), self.security_status.peers()) {
+                (Some(error), _) => {
+                    ui.label(egui::RichText::new(format!("⚠ {}", error)).color(egui::Color32::from_rgb(239, 68, 68)));
+                }
+                (None, peers) if peers.is_empty() => {
+                    ui.label(egui::RichText::new("🔓 Setting up end-to-end encryption...").color(TEXT_SECONDARY));
+                }
+                (None, peers) => {
+                    for peer in peers {
+                        let text = format!(
+                            "🔒 End-to-end encrypted with {} · Security code: {}{}",
+                            peer.device_id,
+                            peer.code,
+                            if peer.verified { " · ✔ Registered key" } else { "" }
+                        );
+                        ui.label(egui::RichText::new(text).color(SUCCESS_COLOR))
+                            .on_hover_text("Compare this code with the other side, e.g. by phone. If it differs, someone is intercepting the session.");
+                    }
+                }
+            }
+
+            ui.add_space(10.0);
+
+            // Everyone in the session, with invite and control buttons for the host and controller
+            let session_participants = self.session_participants.clone();
+            let messages = self.participants_view.show(ui, &session_participants.blocking_lock(), &self.guest_connection_id);
+            self.send_session_messages(messages);
+
+            ui.add_space(10.0);
             ui.separator();
             ui.add_space(10.0);
 
+            if self.show_file_manager {
+                let remote_browser = self.remote_browser.clone();
+                let view = self.file_manager.get_or_insert_with(|| FileManagerView::new(remote_browser));
+                let actions = view.show(ui);
+                self.handle_file_manager_actions(actions);
+                return;
+            }
+
             // Remote screen display area
             ui.heading("Remote Screen");
             ui.add_space(10.0);
 
-            // Placeholder for remote screen
-            // TODO: Display actual remote screen texture
+            self.update_remote_screen_texture(ctx);
+
             let available_size = ui.available_size();
             let screen_rect = egui::Rect::from_min_size(
                 ui.cursor().min,
@@ -935,25 +1809,25 @@ impl ScrDeskApp {
                     egui::Stroke::new(2.0, PRIMARY_COLOR),
                 );
 
-                // Show "Waiting for remote screen..." text in center
-                let text = if self.remote_screen_texture.is_some() {
-                    "Remote Screen"
+                if let Some(texture) = &self.remote_screen_texture {
+                    // Fit the screen inside the area, keeping its shape
+                    let (width, height) = self.remote_screen_size;
+                    let scale = (screen_rect.width() / width as f32).min(screen_rect.height() / height as f32);
+                    let image_rect = egui::Rect::from_center_size(
+                        screen_rect.center(),
+                        egui::vec2(width as f32, height as f32) * scale,
+                    );
+                    let uv = egui::Rect::from_min_max(egui::pos2(0.0, 0.0), egui::pos2(1.0, 1.0));
+                    painter.image(texture.id(), image_rect, uv, egui::Color32::WHITE);
                 } else {
-                    "Waiting for remote screen..."
-                };
-
-                painter.text(
-                    screen_rect.center(),
-                    egui::Align2::CENTER_CENTER,
-                    text,
-                    egui::FontId::proportional(20.0),
-                    TEXT_SECONDARY,
-                );
-
-                // TODO: Render actual remote screen texture here
-                // if let Some(texture) = &self.remote_screen_texture {
-                //     ui.image(texture, screen_rect.size());
-                // }
+                    painter.text(
+                        screen_rect.center(),
+                        egui::Align2::CENTER_CENTER,
+                        "Waiting for remote screen...",
+                        egui::FontId::proportional(20.0),
+                        TEXT_SECONDARY,
+                    );
+                }
             });
 
             ui.add_space(10.0);
@@ -1015,6 +1889,18 @@ impl eframe::App for ScrDeskApp {
                     ui.label(egui::RichText::new(format!("📊 {}", self.status_message)).color(TEXT_SECONDARY));
                 }
             });
+
+            // Invitation to join someone else's session
+            let session_participants = self.session_participants.clone();
+            let mut session = session_participants.blocking_lock();
+            let host = session.invitation.as_ref().map(|i| i.host.clone());
+            if let Some(join) = participants::show_invitation(ui, &mut session) {
+                drop(session);
+                self.send_session_messages(vec![join]);
+                self.remote_device_id = host.unwrap_or_default();
+                self.status_message = format!("Joining session of {}...", self.remote_device_id);
+                self.mode = AppMode::Connected;
+            }
             ui.add_space(5.0);
         });
 
@@ -1048,6 +1934,8 @@ impl eframe::App for ScrDeskApp {
             }
         });
 
+        self.render_conflict_dialog(ctx);
+
         // Handle incoming messages when connected
         if self.is_streaming {
             self.handle_incoming_messages(ctx);
@@ -1062,3 +1950,199 @@ impl eframe::App for ScrDeskApp {
         ctx.request_repaint_after(std::time::Duration::from_millis(16)); // ~60 FPS
     }
 }
+
+fn transfer_request(info: &transfer::TransferInfo, direction: protocol::TransferDirection) -> Message {
+    Message::FileTransferRequest {
+        transfer_id: info.transfer_id.clone(),
+        filename: info.filename.clone(),
+        filesize: info.total_size,
+        direction,
+    }
+}
+
+/// Optional upload bandwidth cap from `SCRDESK_TRANSFER_BANDWIDTH_KBPS`
+fn transfer_bandwidth_limit() -> Option<u64> {
+    std::env::var("SCRDESK_TRANSFER_BANDWIDTH_KBPS")
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok())
+        .filter(|kbps| *kbps > 0)
+        .map(|kbps| kbps * 1024)
+}
+
+/// Whether to report each session's connection quality to the backend, from
+/// `SCRDESK_QUALITY_REPORTS`; off unless set to 1 or true
+fn quality_reports_enabled() -> bool {
+    matches!(std::env::var("SCRDESK_QUALITY_REPORTS").as_deref(), Ok("1" | "true"))
+}
+
+/// Send chunks for all accepted uploads, honouring each transfer's window and
+/// the bandwidth cap. Woken whenever an ack or response arrives.
+async fn upload_pump(
+    net_connection: Arc<Mutex<Option<NetConnectionManager>>>,
+    file_transfer: Arc<Mutex<Option<FileTransferManager>>>,
+    scheduler: Arc<Mutex<TransferScheduler>>,
+    notify: Arc<Notify>,
+) {
+    loop {
+        let finished = scheduler.lock().await.take_finished();
+        for (transfer_id, success) in finished {
+            let checksum = match file_transfer.lock().await.as_ref() {
+                Some(ft) => ft.get_transfer(&transfer_id).and_then(|t| t.checksum.clone()),
+                None => None,
+            };
+
+            if let Some(sender) = bulk_sender(&net_connection).await {
+                let _ = sender.push(Message::FileTransferComplete {
+                    transfer_id,
+                    success: success && checksum.is_some(),
+                    checksum,
+                }).await;
+            }
+        }
+
+        let next = scheduler.lock().await.next_ready();
+        let Some(transfer_id) = next else {
+            notify.notified().await;
+            continue;
+        };
+
+        let chunk = match file_transfer.lock().await.as_mut() {
+            Some(ft) => ft.read_next_chunk(&transfer_id),
+            None => Err(anyhow::anyhow!("File transfer is not available")),
+        };
+
+        match chunk {
+            Ok(Some((chunk_index, data))) => {
+                let delay = scheduler.lock().await.reserve_bandwidth(data.len());
+                if !delay.is_zero() {
+                    tokio::time::sleep(delay).await;
+                }
+
+                scheduler.lock().await.on_sent(&transfer_id, chunk_index);
+
+                let msg = Message::FileChunk {
+                    transfer_id: transfer_id.clone(),
+                    chunk_index,
+                    data,
+                };
+
+                let sent = match bulk_sender(&net_connection).await {
+                    Some(sender) => sender.push(msg).await,
+                    None => Err(anyhow::anyhow!("Not connected")),
+                };
+
+                if let Err(e) = sent {
+                    tracing::error!("Failed to send file chunk: {}", e);
+                    scheduler.lock().await.on_failed(&transfer_id);
+                }
+            }
+            Ok(None) => scheduler.lock().await.on_read_complete(&transfer_id),
+            Err(e) => {
+                tracing::error!("Failed to read file chunk: {}", e);
+                if let Some(ft) = file_transfer.lock().await.as_mut() {
+                    ft.mark_failed(&transfer_id, e.to_string());
+                }
+                scheduler.lock().await.on_failed(&transfer_id);
+            }
+        }
+    }
+}
+
+/// Send a file as a delta against the receiver's copy, in batches of at most
+/// `delta::BATCH_SIZE` literal bytes, followed by the checksum of the whole file.
+async fn send_delta(
+    net_connection: Arc<Mutex<Option<NetConnectionManager>>>,
+    file_transfer: Arc<Mutex<Option<FileTransferManager>>>,
+    scheduler: Arc<Mutex<TransferScheduler>>,
+    transfer_id: String,
+    signature: FileSignature,
+) {
+    let path = match file_transfer.lock().await.as_ref() {
+        Some(ft) => ft.get_transfer(&transfer_id).map(|t| t.file_path.clone()),
+        None => None,
+    };
+    let Some(path) = path else {
+        tracing::warn!("Delta signature for unknown transfer {}", transfer_id);
+        return;
+    };
+
+    // Hashing runs on a blocking thread; the channel keeps it from racing ahead of the network
+    let (batch_tx, mut batch_rx) = tokio::sync::mpsc::channel::<Vec<DeltaOp>>(2);
+    let worker = tokio::task::spawn_blocking(move || {
+        let mut batch = Vec::new();
+        let mut batch_bytes = 0;
+
+        let checksum = delta::compute_delta(&path, &signature, |op| {
+            batch_bytes += op.literal_len();
+            batch.push(op);
+            if batch_bytes >= delta::BATCH_SIZE {
+                batch_bytes = 0;
+                batch_tx
+                    .blocking_send(std::mem::take(&mut batch))
+                    .map_err(|_| anyhow::anyhow!("Delta transfer aborted"))?;
+            }
+            Ok(())
+        })?;
+
+        if !batch.is_empty() {
+            batch_tx
+                .blocking_send(batch)
+                .map_err(|_| anyhow::anyhow!("Delta transfer aborted"))?;
+        }
+
+        Ok::<_, anyhow::Error>(checksum)
+    });
+
+    while let Some(ops) = batch_rx.recv().await {
+        // Stop early if the receiver gave up
+        let aborted = match file_transfer.lock().await.as_ref() {
+            Some(ft) => ft.get_transfer(&transfer_id).map(|t| t.error.is_some()).unwrap_or(true),
+            None => true,
+        };
+        if aborted {
+            break;
+        }
+
+        let bytes: usize = ops.iter().map(|op| op.literal_len()).sum();
+        let delay = scheduler.lock().await.reserve_bandwidth(bytes);
+        if !delay.is_zero() {
+            tokio::time::sleep(delay).await;
+        }
+
+        let sent = match bulk_sender(&net_connection).await {
+            Some(sender) => sender.push(Message::FileDelta { transfer_id: transfer_id.clone(), ops }).await,
+            None => Err(anyhow::anyhow!("Not connected")),
+        };
+        if let Err(e) = sent {
+            tracing::error!("Failed to send file delta: {}", e);
+            break;
+        }
+    }
+    drop(batch_rx);
+
+    let checksum = match worker.await {
+        Ok(Ok(checksum)) => Some(checksum),
+        Ok(Err(e)) => {
+            tracing::error!("Delta sync of {} failed: {}", transfer_id, e);
+            None
+        }
+        Err(e) => {
+            tracing::error!("Delta worker panicked: {}", e);
+            None
+        }
+    };
+
+    if let Some(sender) = bulk_sender(&net_connection).await {
+        let _ = sender.push(Message::FileTransferComplete {
+            transfer_id,
+            success: checksum.is_some(),
+            checksum,
+        }).await;
+    }
+}
+
+/// The outgoing queues of the connection, taken out of the lock: the file transfer
+/// queue is small, and waiting for room in it must not hold up video and input
+async fn bulk_sender(net_connection: &Mutex<Option<NetConnectionManager>>) -> Option<OutgoingQueue> {
+    net_connection.lock().await.as_ref().and_then(|manager| manager.sender())
+}
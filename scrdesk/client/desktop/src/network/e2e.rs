@@ -0,0 +1,925 @@
+//! End-to-end encryption of session traffic between participants.
+//!
+//! Every device has a long-term X25519 key, registered with the device manager as its
+//! `public_key`. When a session starts, each participant announces that key and a fresh
+//! ephemeral key through the relay. Each pair of participants then mixes three
+//! Diffie-Hellman results (ephemeral with ephemeral, and each device key with the other
+//! side's ephemeral) into pairwise keys, which only the holders of both device keys can
+//! derive. Signed-in clients check the peer's key against the device manager. Both UIs
+//! also show a short code derived from the handshake, for users to compare out of band.
+//!
+//! Session traffic is sealed with ChaCha20-Poly1305 under a random key of the sender,
+//! which is handed to each peer under the pairwise key. Because all viewers share the
+//! sender key, the relay can still fan video out to a group. Sender keys are replaced
+//! whenever someone joins or leaves. The relay only sees ciphertext and the traffic
+//! class it needs for routing.
+
+use anyhow::{anyhow, bail, Context, Result};
+use crate::api::ApiClient;
+use crate::protocol::{Message, ParticipantInfo, SealedMessage};
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hkdf::Hkdf;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinSet;
+use tokio::time::Instant;
+use x25519_dalek::{PublicKey, StaticSecret};
+use super::tls;
+
+/// Path of the device key file; defaults to `device.key` in the working directory
+pub const DEVICE_KEY_ENV: &str = "SCRDESK_DEVICE_KEY";
+
+const PROTOCOL: &[u8] = b"scrdesk-e2e-v1";
+const NONCE_LEN: usize = 12;
+const KEY_EXCHANGE_TIMEOUT: Duration = Duration::from_secs(15);
+const MAX_HELD: usize = 256; // Outgoing messages waiting for keys
+const MAX_EARLY: usize = 64; // Incoming messages waiting for their sender key
+const REPLAY_WINDOW: u64 = 64;
+const RETAINED_KEYS: usize = 2; // Sender keys kept per peer, so traffic in flight survives a change
+
+/// The device's long-term key pair
+pub struct DeviceKey {
+    secret: StaticSecret,
+    public: PublicKey,
+}
+
+impl DeviceKey {
+    pub fn generate() -> Self {
+        Self::from_secret(StaticSecret::random())
+    }
+
+    /// Load the key from `SCRDESK_DEVICE_KEY` or `device.key`, creating it on first use
+    pub fn load_or_create() -> Result<Self> {
+        let path = match std::env::var_os(DEVICE_KEY_ENV) {
+            Some(path) => PathBuf::from(path),
+            None => std::env::current_dir()?.join("device.key"),
+        };
+        Self::load_or_create_at(&path)
+    }
+
+    fn load_or_create_at(path: &Path) -> Result<Self> {
+        if path.exists() {
+            let hex = std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to read device key {}", path.display()))?;
+            let secret = parse_key(&hex).with_context(|| format!("Invalid device key in {}", path.display()))?;
+            return Ok(Self::from_secret(StaticSecret::from(secret)));
+        }
+
+        let key = Self::generate();
+        write_private(path, &tls::to_hex(key.secret.as_bytes()))
+            .with_context(|| format!("Failed to write device key {}", path.display()))?;
+        tracing::info!("Created device key {}", path.display());
+        Ok(key)
+    }
+
+    fn from_secret(secret: StaticSecret) -> Self {
+        let public = PublicKey::from(&secret);
+        Self { secret, public }
+    }
+
+    /// The public key in hex, as registered with the device manager
+    pub fn public_hex(&self) -> String {
+        tls::to_hex(self.public.as_bytes())
+    }
+}
+
+#[cfg(unix)]
+fn write_private(path: &Path, contents: &str) -> std::io::Result<()> {
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
+    let mut file = std::fs::OpenOptions::new().write(true).create_new(true).mode(0o600).open(path)?;
+    file.write_all(contents.as_bytes())
+}
+
+#[cfg(not(unix))]
+fn write_private(path: &Path, contents: &str) -> std::io::Result<()> {
+    std::fs::write(path, contents)
+}
+
+fn parse_key(hex: &str) -> Result<[u8; 32]> {
+    tls::parse_pin(hex).map_err(|_| anyhow!("Expected 32 bytes in hex"))
+}
+
+/// What the UI shows about one peer
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerSecurity {
+    pub device_id: String,
+    pub code: String,   // Short authentication string, the same on both sides
+    pub verified: bool, // The peer's key matches the one registered with the device manager
+}
+
+#[derive(Debug, Default)]
+struct SecurityState {
+    peers: Vec<PeerSecurity>,
+    error: Option<String>,
+}
+
+/// End-to-end state of the current session, shared with the UI
+#[derive(Debug, Clone, Default)]
+pub struct SecurityStatus(Arc<std::sync::Mutex<SecurityState>>);
+
+impl SecurityStatus {
+    /// Peers whose traffic is end-to-end encrypted
+    pub fn peers(&self) -> Vec<PeerSecurity> {
+        self.0.lock().unwrap().peers.clone()
+    }
+
+    /// Why session traffic is being withheld, if it is
+    pub fn error(&self) -> Option<String> {
+        self.0.lock().unwrap().error.clone()
+    }
+
+    fn set_peer(&self, peer: PeerSecurity) {
+        let mut state = self.0.lock().unwrap();
+        state.peers.retain(|p| p.device_id != peer.device_id);
+        state.peers.push(peer);
+        state.error = None;
+    }
+
+    fn remove_peer(&self, device_id: &str) {
+        self.0.lock().unwrap().peers.retain(|p| p.device_id != device_id);
+    }
+
+    fn set_error(&self, error: String) {
+        self.0.lock().unwrap().error = Some(error);
+    }
+
+    fn clear(&self) {
+        *self.0.lock().unwrap() = SecurityState::default();
+    }
+}
+
+/// What a connection needs for end-to-end encryption
+#[derive(Clone)]
+pub struct E2eConfig {
+    pub identity: Arc<DeviceKey>,
+    pub directory: Option<Arc<ApiClient>>, // Where registered device keys are checked
+    pub status: SecurityStatus,
+}
+
+impl E2eConfig {
+    pub fn new(identity: DeviceKey) -> Self {
+        Self {
+            identity: Arc::new(identity),
+            directory: None,
+            status: SecurityStatus::default(),
+        }
+    }
+}
+
+/// A peer we completed the key exchange with
+struct Peer {
+    static_key: PublicKey,
+    ephemeral_key: PublicKey,
+    send: ChaCha20Poly1305, // Pairwise, for handing over our sender keys
+    recv: ChaCha20Poly1305,
+}
+
+/// Our key for session traffic
+struct SenderKey {
+    key_id: u32,
+    key: Key,
+    cipher: ChaCha20Poly1305,
+    counter: u64,
+}
+
+/// A peer's sender key, with the counters already seen under it
+struct ReceiverKey {
+    key_id: u32,
+    cipher: ChaCha20Poly1305,
+    highest: Option<u64>,
+    seen: u64, // Bit n set: counter `highest - n` was accepted
+}
+
+impl ReceiverKey {
+    /// Accept each counter once, allowing for reordering within the window
+    fn accept(&mut self, counter: u64) -> bool {
+        let Some(highest) = self.highest else {
+            self.highest = Some(counter);
+            self.seen = 1;
+            return true;
+        };
+
+        if counter > highest {
+            let shift = counter - highest;
+            self.seen = if shift >= REPLAY_WINDOW { 0 } else { self.seen << shift };
+            self.seen |= 1;
+            self.highest = Some(counter);
+            true
+        } else {
+            let age = highest - counter;
+            if age >= REPLAY_WINDOW || self.seen & (1 << age) != 0 {
+                return false;
+            }
+            self.seen |= 1 << age;
+            true
+        }
+    }
+}
+
+/// A peer's key exchange, once its registered key has been looked up
+struct Lookup {
+    session_id: String,
+    device_id: String,
+    static_key: PublicKey,
+    ephemeral_key: PublicKey,
+    registered: Result<Option<String>>,
+}
+
+/// Keys and sealing for the session of one connection
+pub struct E2eSession {
+    own_id: String,
+    config: E2eConfig,
+    session_id: Option<String>,
+    ephemeral: Option<StaticSecret>,
+    peers: HashMap<String, Peer>,
+    roster: Option<HashSet<String>>, // Other participants as of the last roster update
+    lookups: JoinSet<Lookup>,
+    sender: Option<SenderKey>,
+    receivers: HashMap<String, Vec<ReceiverKey>>,
+    held: VecDeque<Message>,
+    early: Vec<Message>,
+    deadline: Option<Instant>,
+}
+
+impl E2eSession {
+    pub fn new(own_id: String, config: E2eConfig) -> Self {
+        Self {
+            own_id,
+            config,
+            session_id: None,
+            ephemeral: None,
+            peers: HashMap::new(),
+            roster: None,
+            lookups: JoinSet::new(),
+            sender: None,
+            receivers: HashMap::new(),
+            held: VecDeque::new(),
+            early: Vec::new(),
+            deadline: None,
+        }
+    }
+
+    /// A session was opened or joined: announce our keys to the other participants
+    pub fn start(&mut self, session_id: &str) -> Vec<Message> {
+        if self.session_id.as_deref() == Some(session_id) {
+            return Vec::new();
+        }
+
+        self.reset();
+        self.session_id = Some(session_id.to_string());
+        self.deadline = Some(Instant::now() + KEY_EXCHANGE_TIMEOUT);
+        let ephemeral = StaticSecret::random();
+        let announcement = self.key_exchange_message(&ephemeral, None);
+        self.ephemeral = Some(ephemeral);
+        vec![announcement]
+    }
+
+    pub fn reset(&mut self) {
+        self.session_id = None;
+        self.ephemeral = None;
+        self.peers.clear();
+        self.roster = None;
+        self.lookups.abort_all();
+        self.sender = None;
+        self.receivers.clear();
+        self.held.clear();
+        self.early.clear();
+        self.deadline = None;
+        self.config.status.clear();
+    }
+
+    fn key_exchange_message(&self, ephemeral: &StaticSecret, to: Option<String>) -> Message {
+        Message::KeyExchange {
+            session_id: self.session_id.clone().unwrap_or_default(),
+            device_id: self.own_id.clone(),
+            to,
+            static_key: self.config.identity.public_hex(),
+            ephemeral_key: tls::to_hex(PublicKey::from(ephemeral).as_bytes()),
+        }
+    }
+
+    /// A peer's announcement or reply. Returns the messages to send.
+    pub fn receive_key_exchange(
+        &mut self,
+        session_id: &str,
+        device_id: &str,
+        to: Option<&str>,
+        static_key: &str,
+        ephemeral_key: &str,
+    ) -> Vec<Message> {
+        if device_id == self.own_id {
+            return Vec::new();
+        }
+
+        // The announcement may overtake the relay's response that opened the session
+        let mut outgoing = match self.session_id.as_deref() {
+            None => self.start(session_id),
+            Some(current) if current == session_id => Vec::new(),
+            Some(_) => {
+                tracing::debug!("Ignoring key exchange from {} for another session", device_id);
+                return Vec::new();
+            }
+        };
+
+        let (Ok(static_key), Ok(ephemeral_key)) = (parse_key(static_key), parse_key(ephemeral_key)) else {
+            tracing::warn!("Invalid key exchange from {}", device_id);
+            return outgoing;
+        };
+        let (static_key, ephemeral_key) = (PublicKey::from(static_key), PublicKey::from(ephemeral_key));
+
+        if let Some(peer) = self.peers.get(device_id) {
+            if peer.static_key == static_key && peer.ephemeral_key == ephemeral_key {
+                return outgoing; // The reply to an announcement we already answered
+            }
+        }
+
+        // Answer announcements with our keys, in case the peer missed ours
+        if to.is_none() {
+            if let Some(ephemeral) = &self.ephemeral {
+                outgoing.push(self.key_exchange_message(ephemeral, Some(device_id.to_string())));
+            }
+        }
+
+        let lookup = Lookup {
+            session_id: session_id.to_string(),
+            device_id: device_id.to_string(),
+            static_key,
+            ephemeral_key,
+            registered: Ok(None),
+        };
+        match self.config.directory.clone() {
+            Some(directory) => {
+                self.lookups.spawn(async move {
+                    let registered = if directory.is_authenticated().await {
+                        directory.device_public_key(&lookup.device_id).await
+                    } else {
+                        Ok(None)
+                    };
+                    Lookup { registered, ..lookup }
+                });
+            }
+            None => outgoing.extend(self.complete(lookup)),
+        }
+        outgoing
+    }
+
+    /// Finish the key exchange with a peer whose key was looked up
+    fn complete(&mut self, lookup: Lookup) -> Vec<Message> {
+        if self.session_id.as_deref() != Some(lookup.session_id.as_str()) {
+            return Vec::new();
+        }
+        let Some(ephemeral) = &self.ephemeral else {
+            return Vec::new();
+        };
+
+        let presented = tls::to_hex(lookup.static_key.as_bytes());
+        let verified = match &lookup.registered {
+            Ok(Some(registered)) if registered.trim().eq_ignore_ascii_case(&presented) => true,
+            Ok(Some(_)) => {
+                let error = format!(
+                    "The key of {} does not match the one registered with the device manager",
+                    lookup.device_id
+                );
+                tracing::error!("{}, not sending it any session traffic", error);
+                self.config.status.set_error(error);
+                return Vec::new();
+            }
+            Ok(None) => false,
+            Err(e) => {
+                tracing::warn!("Could not check the key of {}: {:#}", lookup.device_id, e);
+                false
+            }
+        };
+
+        let (peer, code) = match derive(
+            &self.config.identity,
+            &self.own_id,
+            ephemeral,
+            &lookup.device_id,
+            &lookup.static_key,
+            &lookup.ephemeral_key,
+            &lookup.session_id,
+        ) {
+            Ok(derived) => derived,
+            Err(e) => {
+                tracing::warn!("Key exchange with {} failed: {}", lookup.device_id, e);
+                return Vec::new();
+            }
+        };
+
+        tracing::info!(
+            "End-to-end encryption with {} established, security code {}{}",
+            lookup.device_id,
+            code,
+            if verified { ", key verified" } else { "" }
+        );
+        self.config.status.set_peer(PeerSecurity { device_id: lookup.device_id.clone(), code, verified });
+        // A new handshake means the peer restarted and lost our sender key
+        self.receivers.remove(&lookup.device_id);
+        self.peers.insert(lookup.device_id.clone(), peer);
+        self.deadline = None;
+
+        if self.sender.is_none() {
+            self.sender = Some(new_sender_key(0));
+        }
+        let mut outgoing: Vec<Message> = self.sender_key_message(&lookup.device_id).into_iter().collect();
+
+        // Anything held back until now can go out
+        while let Some(message) = self.held.pop_front() {
+            outgoing.extend(self.seal(message));
+        }
+        outgoing
+    }
+
+    /// Our current sender key, sealed for one peer
+    fn sender_key_message(&self, device_id: &str) -> Option<Message> {
+        let sender = self.sender.as_ref()?;
+        let peer = self.peers.get(device_id)?;
+        let session_id = self.session_id.clone()?;
+
+        let aad = sender_key_aad(&session_id, &self.own_id, device_id, sender.key_id);
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = peer.send.encrypt(&nonce, Payload { msg: sender.key.as_slice(), aad: &aad }).ok()?;
+
+        let mut sealed_key = nonce.to_vec();
+        sealed_key.extend(ciphertext);
+        Some(Message::SenderKey {
+            session_id,
+            device_id: self.own_id.clone(),
+            to: device_id.to_string(),
+            key_id: sender.key_id,
+            sealed_key,
+        })
+    }
+
+    /// A peer's sender key. Returns messages that were waiting for it, with their sender.
+    pub fn receive_sender_key(&mut self, session_id: &str, device_id: &str, key_id: u32, sealed_key: &[u8]) -> Vec<(String, Message)> {
+        if self.session_id.as_deref() != Some(session_id) || sealed_key.len() < NONCE_LEN {
+            return Vec::new();
+        }
+        let Some(peer) = self.peers.get(device_id) else {
+            tracing::debug!("Sender key from {} before the key exchange", device_id);
+            return Vec::new();
+        };
+
+        let aad = sender_key_aad(session_id, device_id, &self.own_id, key_id);
+        let (nonce, ciphertext) = sealed_key.split_at(NONCE_LEN);
+        let key = match peer.recv.decrypt(Nonce::from_slice(nonce), Payload { msg: ciphertext, aad: &aad }) {
+            Ok(key) if key.len() == 32 => key,
+            _ => {
+                tracing::warn!("Rejected a sender key from {}", device_id);
+                return Vec::new();
+            }
+        };
+
+        let keys = self.receivers.entry(device_id.to_string()).or_default();
+        keys.retain(|k| k.key_id != key_id);
+        keys.push(ReceiverKey {
+            key_id,
+            cipher: ChaCha20Poly1305::new(Key::from_slice(&key)),
+            highest: None,
+            seen: 0,
+        });
+        if keys.len() > RETAINED_KEYS {
+            keys.remove(0);
+        }
+
+        std::mem::take(&mut self.early).into_iter().flat_map(|sealed| self.open(sealed)).collect()
+    }
+
+    /// Seal session content for the other participants. Returns None while there are
+    /// no keys yet, holding the message back until there are.
+    pub fn seal(&mut self, message: Message) -> Option<Message> {
+        let session_id = self.session_id.clone()?;
+
+        let Some(sender) = self.sender.as_mut() else {
+            // Stale video is useless, everything else goes out once keys are agreed
+            if !matches!(message, Message::VideoFrame { .. }) && self.config.status.error().is_none() {
+                if self.held.len() == MAX_HELD {
+                    tracing::warn!("Too many messages waiting for end-to-end keys, dropping the oldest");
+                    self.held.pop_front();
+                }
+                self.held.push_back(message);
+            }
+            return None;
+        };
+
+        let plaintext = message.to_bytes().ok()?;
+        let class = class_of(&message);
+        let counter = sender.counter;
+        sender.counter += 1;
+
+        let aad = sealed_aad(class, &session_id, &self.own_id, sender.key_id);
+        let ciphertext = sender.cipher.encrypt(&counter_nonce(counter), Payload { msg: &plaintext, aad: &aad }).ok()?;
+        let sealed = SealedMessage {
+            sender: self.own_id.clone(),
+            key_id: sender.key_id,
+            counter,
+            ciphertext,
+        };
+        Some(match class {
+            SealedClass::Media => Message::SealedMedia(sealed),
+            SealedClass::Bulk => Message::SealedBulk(sealed),
+            SealedClass::Control => Message::Sealed(sealed),
+        })
+    }
+
+    /// Open a sealed message from a peer, returning it with its sender's device ID;
+    /// empty if it cannot be opened (yet)
+    pub fn open(&mut self, message: Message) -> Vec<(String, Message)> {
+        let (class, sealed) = match &message {
+            Message::Sealed(sealed) => (SealedClass::Control, sealed),
+            Message::SealedMedia(sealed) => (SealedClass::Media, sealed),
+            Message::SealedBulk(sealed) => (SealedClass::Bulk, sealed),
+            _ => return Vec::new(),
+        };
+        let Some(session_id) = self.session_id.as_deref() else {
+            return Vec::new();
+        };
+
+        let key = self
+            .receivers
+            .get_mut(&sealed.sender)
+            .and_then(|keys| keys.iter_mut().find(|k| k.key_id == sealed.key_id));
+        let Some(key) = key else {
+            // The sender key may still be on its way, except for stale video
+            if class != SealedClass::Media && self.early.len() < MAX_EARLY {
+                self.early.push(message);
+            }
+            return Vec::new();
+        };
+
+        let aad = sealed_aad(class, session_id, &sealed.sender, sealed.key_id);
+        let payload = Payload { msg: &sealed.ciphertext, aad: &aad };
+        let Ok(plaintext) = key.cipher.decrypt(&counter_nonce(sealed.counter), payload) else {
+            tracing::warn!("Dropping a message from {} that failed authentication", sealed.sender);
+            return Vec::new();
+        };
+        if !key.accept(sealed.counter) {
+            tracing::warn!("Dropping a replayed message from {}", sealed.sender);
+            return Vec::new();
+        }
+
+        match Message::from_bytes(&plaintext) {
+            Ok(inner) if inner.is_session_content() && class_of(&inner) == class => vec![(sealed.sender.clone(), inner)],
+            Ok(_) => {
+                tracing::warn!("Dropping a sealed message from {} of an unexpected type", sealed.sender);
+                Vec::new()
+            }
+            Err(e) => {
+                tracing::error!("Failed to parse sealed message: {}", e);
+                Vec::new()
+            }
+        }
+    }
+
+    /// Forget participants who left and replace our sender key whenever the roster
+    /// changes, so nobody reads traffic from outside their time in the session.
+    /// Returns the messages to send.
+    pub fn participants_changed(&mut self, session_id: &str, participants: &[ParticipantInfo]) -> Vec<Message> {
+        if self.session_id.as_deref() != Some(session_id) {
+            return Vec::new();
+        }
+
+        let roster: HashSet<String> = participants
+            .iter()
+            .map(|p| p.device_id.clone())
+            .filter(|id| *id != self.own_id)
+            .collect();
+        let known = self.roster.clone().unwrap_or_else(|| self.peers.keys().cloned().collect());
+        self.roster = Some(roster.clone());
+        if known == roster {
+            return Vec::new();
+        }
+
+        let departed: Vec<String> = self.peers.keys().filter(|id| !roster.contains(*id)).cloned().collect();
+        for device_id in departed {
+            self.peers.remove(&device_id);
+            self.receivers.remove(&device_id);
+            self.config.status.remove_peer(&device_id);
+        }
+
+        let Some(sender) = &self.sender else {
+            return Vec::new();
+        };
+        self.sender = Some(new_sender_key(sender.key_id.wrapping_add(1)));
+        self.peers.keys().filter_map(|id| self.sender_key_message(id)).collect()
+    }
+
+    /// Wait for a key lookup to finish or the key exchange to time out; never resolves
+    /// while there is neither. Returns the messages to send.
+    pub async fn next_event(&mut self) -> Vec<Message> {
+        let deadline = self.deadline;
+        let lookups = &mut self.lookups;
+        tokio::select! {
+            Some(result) = lookups.join_next() => match result {
+                Ok(lookup) => self.complete(lookup),
+                Err(_) => Vec::new(), // Aborted by a reset
+            },
+            _ = async {
+                match deadline {
+                    Some(deadline) => tokio::time::sleep_until(deadline).await,
+                    None => std::future::pending().await,
+                }
+            } => {
+                self.deadline = None;
+                self.held.clear();
+                let error = "The peer did not set up end-to-end encryption, session traffic is withheld";
+                tracing::warn!("{}", error);
+                self.config.status.set_error(error.to_string());
+                Vec::new()
+            }
+        }
+    }
+}
+
+/// How a sealed message is routed; part of the authenticated data so the relay cannot
+/// pass video off as input
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SealedClass {
+    Control,
+    Media,
+    Bulk,
+}
+
+fn class_of(message: &Message) -> SealedClass {
+    match message {
+        Message::VideoFrame { .. } => SealedClass::Media,
+        message if message.is_bulk() => SealedClass::Bulk,
+        _ => SealedClass::Control,
+    }
+}
+
+fn new_sender_key(key_id: u32) -> SenderKey {
+    let key = ChaCha20Poly1305::generate_key(&mut OsRng);
+    SenderKey {
+        key_id,
+        cipher: ChaCha20Poly1305::new(&key),
+        key,
+        counter: 0,
+    }
+}
+
+/// Each sender key encrypts every counter value at most once
+fn counter_nonce(counter: u64) -> Nonce {
+    let mut nonce = [0u8; NONCE_LEN];
+    nonce[4..].copy_from_slice(&counter.to_be_bytes());
+    Nonce::from(nonce)
+}
+
+fn sealed_aad(class: SealedClass, session_id: &str, sender: &str, key_id: u32) -> Vec<u8> {
+    let class = match class {
+        SealedClass::Control => "Sealed",
+        SealedClass::Media => "SealedMedia",
+        SealedClass::Bulk => "SealedBulk",
+    };
+    let mut aad = framed(&[PROTOCOL, class.as_bytes(), session_id.as_bytes(), sender.as_bytes()]);
+    aad.extend(key_id.to_be_bytes());
+    aad
+}
+
+fn sender_key_aad(session_id: &str, from: &str, to: &str, key_id: u32) -> Vec<u8> {
+    let mut aad = framed(&[PROTOCOL, b"SenderKey", session_id.as_bytes(), from.as_bytes(), to.as_bytes()]);
+    aad.extend(key_id.to_be_bytes());
+    aad
+}
+
+/// Length-prefixed concatenation, so field boundaries cannot be shifted
+fn framed(parts: &[&[u8]]) -> Vec<u8> {
+    let mut out = Vec::new();
+    for part in parts {
+        out.extend((part.len() as u32).to_be_bytes());
+        out.extend_from_slice(part);
+    }
+    out
+}
+
+/// Pairwise keys and the security code for a peer. Roles follow the device IDs, so
+/// both sides compute the same values without agreeing who started.
+fn derive(
+    identity: &DeviceKey,
+    own_id: &str,
+    ephemeral: &StaticSecret,
+    peer_id: &str,
+    peer_static: &PublicKey,
+    peer_ephemeral: &PublicKey,
+    session_id: &str,
+) -> Result<(Peer, String)> {
+    if own_id == peer_id {
+        bail!("Peer has our device ID");
+    }
+    let we_are_first = own_id < peer_id;
+
+    let ee = ephemeral.diffie_hellman(peer_ephemeral);
+    let own_static = identity.secret.diffie_hellman(peer_ephemeral);
+    let peer_static_dh = ephemeral.diffie_hellman(peer_static);
+    // DH(first's device key, second's ephemeral), then DH(first's ephemeral, second's device key)
+    let (se, es) = if we_are_first { (own_static, peer_static_dh) } else { (peer_static_dh, own_static) };
+    if ![&ee, &se, &es].iter().all(|dh| dh.was_contributory()) {
+        bail!("Peer sent a weak key");
+    }
+
+    let own_ephemeral = PublicKey::from(ephemeral);
+    let own = (own_id.as_bytes(), identity.public.as_bytes(), own_ephemeral.as_bytes());
+    let peer = (peer_id.as_bytes(), peer_static.as_bytes(), peer_ephemeral.as_bytes());
+    let (first, second) = if we_are_first { (own, peer) } else { (peer, own) };
+    let transcript = Sha256::digest(framed(&[
+        PROTOCOL,
+        session_id.as_bytes(),
+        first.0,
+        first.1,
+        first.2,
+        second.0,
+        second.1,
+        second.2,
+    ]));
+
+    let mut secret = Vec::with_capacity(96);
+    for dh in [&ee, &se, &es] {
+        secret.extend_from_slice(dh.as_bytes());
+    }
+    let hkdf = Hkdf::<Sha256>::new(Some(&transcript), &secret);
+    let expand = |info: &[u8], out: &mut [u8]| hkdf.expand(info, out).map_err(|_| anyhow!("Key derivation failed"));
+
+    let mut first_to_second = [0u8; 32];
+    let mut second_to_first = [0u8; 32];
+    let mut code = [0u8; 4];
+    expand(b"first to second", &mut first_to_second)?;
+    expand(b"second to first", &mut second_to_first)?;
+    expand(b"security code", &mut code)?;
+
+    let (send, recv) = if we_are_first { (first_to_second, second_to_first) } else { (second_to_first, first_to_second) };
+    let code = u32::from_be_bytes(code) % 1_000_000;
+    Ok((
+        Peer {
+            static_key: *peer_static,
+            ephemeral_key: *peer_ephemeral,
+            send: ChaCha20Poly1305::new(Key::from_slice(&send)),
+            recv: ChaCha20Poly1305::new(Key::from_slice(&recv)),
+        },
+        format!("{:03} {:03}", code / 1000, code % 1000),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::ParticipantRole;
+
+    fn session(id: &str) -> (E2eSession, SecurityStatus) {
+        let config = E2eConfig::new(DeviceKey::generate());
+        let status = config.status.clone();
+        (E2eSession::new(id.to_string(), config), status)
+    }
+
+    /// Deliver key messages addressed to `session`; returns what it sends in reply
+    fn deliver(session: &mut E2eSession, messages: &[Message]) -> Vec<Message> {
+        let mut outgoing = Vec::new();
+        for message in messages {
+            match message {
+                Message::KeyExchange { session_id, device_id, to, static_key, ephemeral_key }
+                    if to.as_deref().is_none_or(|to| to == session.own_id) =>
+                {
+                    outgoing.extend(session.receive_key_exchange(session_id, device_id, to.as_deref(), static_key, ephemeral_key));
+                }
+                Message::SenderKey { session_id, device_id, to, key_id, sealed_key } if *to == session.own_id => {
+                    assert!(session.receive_sender_key(session_id, device_id, *key_id, sealed_key).is_empty());
+                }
+                _ => {}
+            }
+        }
+        outgoing
+    }
+
+    /// Run the key exchange between two sessions until neither has more to say
+    fn handshake(a: &mut E2eSession, b: &mut E2eSession, session_id: &str) {
+        let mut to_b = a.start(session_id);
+        let mut to_a = b.start(session_id);
+        while !to_a.is_empty() || !to_b.is_empty() {
+            let from_a = deliver(a, &to_a);
+            let from_b = deliver(b, &to_b);
+            to_a = from_b;
+            to_b = from_a;
+        }
+    }
+
+    fn mouse_move(x: i32) -> Message {
+        Message::MouseMove { x, y: 0 }
+    }
+
+    #[test]
+    fn test_key_exchange_and_sealing() {
+        let (mut host, host_status) = session("111");
+        let (mut viewer, viewer_status) = session("222");
+
+        // Nothing leaves before the keys are agreed
+        host.start("s1");
+        assert!(host.seal(mouse_move(1)).is_none());
+        host.reset();
+
+        handshake(&mut host, &mut viewer, "s1");
+        let (host_peers, viewer_peers) = (host_status.peers(), viewer_status.peers());
+        assert_eq!(host_peers.len(), 1);
+        assert_eq!(host_peers[0].device_id, "222");
+        assert_eq!(host_peers[0].code, viewer_peers[0].code);
+        assert!(!host_peers[0].verified);
+
+        let sealed = viewer.seal(mouse_move(7)).unwrap();
+        let json = sealed.to_json().unwrap();
+        assert!(json.starts_with(r#"{"type":"Sealed","#) && !json.contains("MouseMove"));
+        match host.open(sealed.clone()).as_slice() {
+            [(sender, Message::MouseMove { x: 7, .. })] if sender == "222" => {}
+            other => panic!("Unexpected {:?}", other),
+        }
+
+        // Replays and tampering are rejected
+        assert!(host.open(sealed.clone()).is_empty());
+        let Message::Sealed(mut tampered) = viewer.seal(mouse_move(8)).unwrap() else {
+            panic!("Expected a sealed message");
+        };
+        tampered.ciphertext[0] ^= 1;
+        assert!(host.open(Message::Sealed(tampered)).is_empty());
+
+        // Video keeps its class, so the relay can fan it out
+        let frame = Message::VideoFrame { data: vec![1, 2, 3], width: 1, height: 1, timestamp: 0, is_keyframe: true, after_input: false };
+        let sealed = host.seal(frame).unwrap();
+        assert!(matches!(sealed, Message::SealedMedia(_)));
+        let Message::SealedMedia(inner) = sealed else { unreachable!() };
+        assert!(viewer.open(Message::Sealed(inner)).is_empty(), "class is authenticated");
+    }
+
+    #[test]
+    fn test_messages_out_of_order_within_window() {
+        let (mut a, _) = session("111");
+        let (mut b, _) = session("222");
+        handshake(&mut a, &mut b, "s1");
+
+        let sealed: Vec<Message> = (0..5).map(|x| a.seal(mouse_move(x)).unwrap()).collect();
+        for index in [3, 0, 4, 1, 2] {
+            assert_eq!(b.open(sealed[index].clone()).len(), 1);
+        }
+    }
+
+    #[test]
+    fn test_interception_changes_the_code() {
+        let (mut a, a_status) = session("111");
+        let (mut b, b_status) = session("222");
+        let (mut relay_a, _) = session("222"); // The relay impersonates each side to the other
+        let (mut relay_b, _) = session("111");
+
+        handshake(&mut a, &mut relay_a, "s1");
+        handshake(&mut relay_b, &mut b, "s1");
+        assert_ne!(a_status.peers()[0].code, b_status.peers()[0].code);
+    }
+
+    #[test]
+    fn test_held_messages_and_rotation() {
+        let (mut host, _) = session("host");
+        let (mut viewer, _) = session("viewer");
+
+        // Input queued before the exchange goes out sealed once it completes
+        let to_host = viewer.start("s1");
+        assert!(viewer.seal(mouse_move(1)).is_none());
+        let to_viewer = host.start("s1");
+        let replies = deliver(&mut host, &to_host);
+        let mut from_viewer = deliver(&mut viewer, &to_viewer);
+        from_viewer.extend(deliver(&mut viewer, &replies));
+        assert!(deliver(&mut host, &from_viewer).is_empty());
+        let sealed: Vec<&Message> = from_viewer.iter().filter(|m| matches!(m, Message::Sealed(_))).collect();
+        assert_eq!(sealed.len(), 1);
+        assert_eq!(host.open(sealed[0].clone()).len(), 1);
+
+        // A new participant replaces the sender key; messages under it wait for the key
+        let roster = |ids: &[&str]| -> Vec<ParticipantInfo> {
+            ids.iter().map(|id| ParticipantInfo { device_id: id.to_string(), role: ParticipantRole::Observer }).collect()
+        };
+        let rotation = host.participants_changed("s1", &roster(&["host", "viewer", "observer"]));
+        assert_eq!(rotation.len(), 1);
+        let frame = host.seal(mouse_move(2)).unwrap();
+        assert!(viewer.open(frame).is_empty());
+        let Message::SenderKey { session_id, device_id, key_id, sealed_key, .. } = &rotation[0] else {
+            panic!("Expected a sender key");
+        };
+        assert_eq!(viewer.receive_sender_key(session_id, device_id, *key_id, sealed_key).len(), 1);
+
+        // Departed participants are forgotten
+        host.participants_changed("s1", &roster(&["host", "observer"]));
+        assert!(host.peers.is_empty());
+    }
+
+    #[test]
+    fn test_device_key_persists() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("device.key");
+        let created = DeviceKey::load_or_create_at(&path)?;
+        let loaded = DeviceKey::load_or_create_at(&path)?;
+        assert_eq!(created.public_hex(), loaded.public_hex());
+        assert_eq!(created.public_hex().len(), 64);
+        Ok(())
+    }
+}
@@ -0,0 +1,254 @@
+//! Simulated network conditions, to reproduce a poor connection on a good one.
+//!
+//! `Impaired` wraps a transport and passes each direction of its connections through
+//! a link that drops messages, holds each back for the latency plus random jitter, and
+//! sends no faster than the bandwidth limit. Messages keep their order, as over TCP.
+//! The randomness is seeded, so the same seed loses the same messages. Clients take
+//! the conditions from `SCRDESK_NET_IMPAIRMENT`, e.g. `latency=80,jitter=20,loss=0.01,kbps=2000`.
+
+use anyhow::{bail, Context, Result};
+use futures::future::BoxFuture;
+use futures::{SinkExt, StreamExt};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+use tokio_tungstenite::tungstenite::protocol::Message as WsMessage;
+use super::transport::{channel, MessageSink, MessageStream, Transport};
+
+pub const IMPAIRMENT_ENV: &str = "SCRDESK_NET_IMPAIRMENT";
+
+/// Conditions of each direction of a link
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Impairment {
+    pub latency: Duration, // One way
+    pub jitter: Duration,  // Up to this much is added to the latency
+    pub loss: f64,         // Share of messages dropped, from 0 to 1
+    pub bandwidth: Option<u64>, // Bytes per second
+    pub seed: u64,
+}
+
+impl Impairment {
+    /// Parse comma-separated `latency` and `jitter` in milliseconds, `loss` as a
+    /// share, `kbps` and `seed`
+    pub fn parse(spec: &str) -> Result<Self> {
+        let mut impairment = Self::default();
+        for part in spec.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            let (key, value) = part.split_once('=').with_context(|| format!("Expected key=value, got {:?}", part))?;
+            let number = || value.trim().parse::<f64>().ok().filter(|v| v.is_finite() && *v >= 0.0)
+                .with_context(|| format!("Invalid {}: {:?}", key, value));
+            match key.trim() {
+                "latency" => impairment.latency = Duration::from_secs_f64(number()? / 1000.0),
+                "jitter" => impairment.jitter = Duration::from_secs_f64(number()? / 1000.0),
+                "loss" => impairment.loss = number()?.min(1.0),
+                "kbps" => impairment.bandwidth = Some((number()? * 1000.0 / 8.0) as u64).filter(|b| *b > 0),
+                "seed" => impairment.seed = number()? as u64,
+                other => bail!("Unknown network impairment {:?}", other),
+            }
+        }
+        Ok(impairment)
+    }
+
+    /// The conditions set in `SCRDESK_NET_IMPAIRMENT`, if any
+    pub fn from_env() -> Option<Self> {
+        let spec = std::env::var(IMPAIRMENT_ENV).ok().filter(|s| !s.trim().is_empty())?;
+        match Self::parse(&spec) {
+            Ok(impairment) => Some(impairment),
+            Err(e) => {
+                tracing::error!("Ignoring {}: {:#}", IMPAIRMENT_ENV, e);
+                None
+            }
+        }
+    }
+}
+
+/// A transport whose connections go through simulated links
+pub struct Impaired<T> {
+    inner: T,
+    impairment: Impairment,
+    connections: u64,
+}
+
+impl<T: Transport> Impaired<T> {
+    pub fn new(inner: T, impairment: Impairment) -> Self {
+        tracing::warn!("Simulating network conditions: {:?}", impairment);
+        Self { inner, impairment, connections: 0 }
+    }
+}
+
+impl<T: Transport> Transport for Impaired<T> {
+    fn connect(&mut self) -> BoxFuture<'_, Result<(MessageSink, MessageStream)>> {
+        Box::pin(async move {
+            let (sink, stream) = self.inner.connect().await?;
+
+            // Each connection and direction draws its own, repeatable randomness
+            let seed = self.impairment.seed.wrapping_add(self.connections * 2);
+            self.connections += 1;
+
+            let (outgoing_sink, outgoing_stream) = channel();
+            let (incoming_sink, incoming_stream) = channel();
+            spawn_link(outgoing_stream, sink, self.impairment.clone(), seed);
+            spawn_link(stream, incoming_sink, self.impairment.clone(), seed + 1);
+            Ok((outgoing_sink, incoming_stream))
+        })
+    }
+
+    fn relay_host(&self) -> Option<String> {
+        self.inner.relay_host()
+    }
+}
+
+/// Carry messages from `source` to `sink` under the given conditions, until either
+/// end goes away
+fn spawn_link(mut source: MessageStream, mut sink: MessageSink, impairment: Impairment, seed: u64) {
+    let (queue_tx, mut queue_rx) = mpsc::unbounded_channel::<(Instant, WsMessage)>();
+
+    // Decide when each message arrives as it is sent...
+    tokio::spawn(async move {
+        let mut rng = SplitMix64(seed);
+        let mut link_free = Instant::now(); // When the bandwidth limit lets the next message start
+        let mut last_arrival = Instant::now();
+        while let Some(Ok(message)) = source.next().await {
+            let data = matches!(message, WsMessage::Text(_) | WsMessage::Binary(_));
+            if data && rng.next_f64() < impairment.loss {
+                continue;
+            }
+
+            let now = Instant::now();
+            let sent = match impairment.bandwidth {
+                Some(bandwidth) => {
+                    link_free = link_free.max(now) + Duration::from_secs_f64(message.len() as f64 / bandwidth as f64);
+                    link_free
+                }
+                None => now,
+            };
+            let jitter = impairment.jitter.mul_f64(rng.next_f64());
+            last_arrival = last_arrival.max(sent + impairment.latency + jitter);
+            if queue_tx.send((last_arrival, message)).is_err() {
+                break;
+            }
+        }
+    });
+
+    // ...and deliver it then
+    tokio::spawn(async move {
+        while let Some((arrival, message)) = queue_rx.recv().await {
+            tokio::time::sleep_until(arrival).await;
+            if sink.send(message).await.is_err() {
+                return;
+            }
+        }
+        let _ = sink.close().await;
+    });
+}
+
+/// Small seeded generator; the simulation needs repeatability, not quality
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in [0, 1)
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::transport::loopback::loopback;
+
+    fn text(n: usize) -> WsMessage {
+        WsMessage::Text(n.to_string())
+    }
+
+    async fn connect(impairment: Impairment) -> ((MessageSink, MessageStream), (MessageSink, MessageStream)) {
+        let (transport, mut listener) = loopback();
+        let mut transport = Impaired::new(transport, impairment);
+        let client = transport.connect().await.unwrap();
+        (client, listener.accept().await)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_latency_and_jitter_keep_order() {
+        let impairment = Impairment {
+            latency: Duration::from_millis(100),
+            jitter: Duration::from_millis(50),
+            ..Default::default()
+        };
+        let ((mut sink, _), (_, mut relay)) = connect(impairment).await;
+
+        let start = Instant::now();
+        for n in 0..20 {
+            sink.send(text(n)).await.unwrap();
+        }
+        for n in 0..20 {
+            assert_eq!(relay.next().await.unwrap().unwrap(), text(n));
+            let elapsed = start.elapsed();
+            assert!(elapsed >= Duration::from_millis(100) && elapsed <= Duration::from_millis(150), "{:?}", elapsed);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_bandwidth_limit() {
+        let impairment = Impairment { bandwidth: Some(10_000), ..Default::default() };
+        let ((_, mut client), (mut relay, _)) = connect(impairment).await;
+
+        // Ten messages of 1000 bytes take a second at 10 kB/s
+        let start = Instant::now();
+        for _ in 0..10 {
+            relay.send(WsMessage::Binary(vec![0; 1000])).await.unwrap();
+        }
+        for _ in 0..10 {
+            client.next().await.unwrap().unwrap();
+        }
+        assert_eq!(start.elapsed(), Duration::from_secs(1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_loss_is_repeatable() {
+        async fn delivered(seed: u64) -> Vec<WsMessage> {
+            let ((mut sink, _), (_, mut relay)) = connect(Impairment { loss: 0.3, seed, ..Default::default() }).await;
+            for n in 0..100 {
+                sink.send(text(n)).await.unwrap();
+            }
+            sink.send(WsMessage::Close(None)).await.unwrap();
+            drop(sink);
+            let mut delivered = Vec::new();
+            while let Some(Ok(message)) = relay.next().await {
+                delivered.push(message);
+            }
+            delivered
+        }
+
+        let first = delivered(7).await;
+        assert!((50..90).contains(&first.len()), "{} delivered", first.len());
+        assert_eq!(first.last(), Some(&WsMessage::Close(None)), "close frames are never lost");
+        assert_eq!(delivered(7).await, first);
+        assert_ne!(delivered(8).await, first);
+    }
+
+    #[test]
+    fn test_parse() {
+        let impairment = Impairment::parse("latency=80, jitter=20,loss=0.01,kbps=2000,seed=3").unwrap();
+        assert_eq!(
+            impairment,
+            Impairment {
+                latency: Duration::from_millis(80),
+                jitter: Duration::from_millis(20),
+                loss: 0.01,
+                bandwidth: Some(250_000),
+                seed: 3,
+            }
+        );
+        assert_eq!(Impairment::parse("").unwrap(), Impairment::default());
+        assert!(Impairment::parse("latency=-1").is_err());
+        assert!(Impairment::parse("delay=5").is_err());
+    }
+}
@@ -1,11 +1,35 @@
 use anyhow::{Context, Result};
+use crate::api::ApiClient;
 use crate::protocol::Message;
 use futures::{SinkExt, StreamExt};
 use std::sync::Arc;
 use tokio::sync::{mpsc, Mutex};
-use tokio_tungstenite::{connect_async, tungstenite::protocol::Message as WsMessage};
+use tokio_tungstenite::tungstenite::{protocol::Message as WsMessage, Error as WsError};
+
+pub mod e2e;
+pub mod impairment;
+pub mod p2p;
+pub mod queue;
+mod quic;
+pub mod resume;
+pub mod stats;
+mod tls;
+pub mod transport;
+
+pub use tls::RelayEndpoint;
+pub use e2e::{DeviceKey, E2eConfig, SecurityStatus};
+pub use p2p::DirectStatus;
+pub use queue::SendStatus;
+pub use resume::KeyframeRequest;
+pub use stats::SessionStats;
+use e2e::E2eSession;
+use p2p::{DirectEvent, DirectPath, RemotePeer};
+use queue::OutgoingQueue;
+use resume::ResumeState;
+use stats::STATS_INTERVAL;
+use impairment::{Impaired, Impairment};
+use transport::{MessageSink, RelayTransport, Transport};
 
-const RELAY_SERVER_URL: &str = "ws://72.61.138.218:21117";
 const RECONNECT_DELAY_SECS: u64 = 5;
 const MAX_RECONNECT_ATTEMPTS: u32 = 10;
 
@@ -20,34 +44,77 @@ pub enum ConnectionState {
 
 pub struct NetworkConnection {
     state: Arc<Mutex<ConnectionState>>,
-    outgoing_tx: mpsc::UnboundedSender<Message>,
+    outgoing: OutgoingQueue,
     incoming_rx: Arc<Mutex<mpsc::UnboundedReceiver<Message>>>,
+    direct_status: DirectStatus,
+    keyframe_request: KeyframeRequest,
 }
 
 impl NetworkConnection {
-    pub async fn connect(device_id: String) -> Result<Self> {
-        let (outgoing_tx, outgoing_rx) = mpsc::unbounded_channel::<Message>();
+    /// Connect with a throwaway device key and no key checks
+    pub async fn connect(relay: RelayEndpoint, device_id: String, auth_token: Option<String>) -> Result<Self> {
+        let e2e = E2eConfig::new(DeviceKey::generate());
+        Self::connect_with_status(relay, device_id, auth_token, DirectStatus::default(), e2e, SessionStats::default()).await
+    }
+
+    /// Connect, reporting the session's direct path to `direct_status`, its end-to-end
+    /// encryption to the status in `e2e` and its connection quality to `stats`. Network
+    /// conditions set in `SCRDESK_NET_IMPAIRMENT` are simulated on top.
+    pub async fn connect_with_status(
+        relay: RelayEndpoint,
+        device_id: String,
+        auth_token: Option<String>,
+        direct_status: DirectStatus,
+        e2e: E2eConfig,
+        stats: SessionStats,
+    ) -> Result<Self> {
+        let transport: Box<dyn Transport> = match Impairment::from_env() {
+            Some(impairment) => Box::new(Impaired::new(RelayTransport::new(relay), impairment)),
+            None => Box::new(RelayTransport::new(relay)),
+        };
+        Self::connect_with_transport(transport, device_id, auth_token, direct_status, e2e, stats).await
+    }
+
+    /// Connect over any transport, e.g. an in-process loopback in tests
+    pub async fn connect_with_transport(
+        transport: Box<dyn Transport>,
+        device_id: String,
+        auth_token: Option<String>,
+        direct_status: DirectStatus,
+        e2e: E2eConfig,
+        stats: SessionStats,
+    ) -> Result<Self> {
+        let keyframe_request = KeyframeRequest::default();
+        let outgoing = OutgoingQueue::new(keyframe_request.clone(), stats.clone());
+        let (relay_tx, relay_rx) = mpsc::unbounded_channel::<Message>(); // Signalling of the direct path
         let (incoming_tx, incoming_rx) = mpsc::unbounded_channel::<Message>();
 
         let state = Arc::new(Mutex::new(ConnectionState::Connecting));
 
         // Spawn connection task
         let state_clone = state.clone();
+        let direct = DirectPath::new(device_id.clone(), direct_status.clone());
+        let e2e = E2eSession::new(device_id.clone(), e2e);
+        let resume = ResumeState::new(device_id.clone(), keyframe_request.clone());
+        let task_outgoing = outgoing.clone();
         tokio::spawn(async move {
-            connection_task(device_id, outgoing_rx, incoming_tx, state_clone).await;
+            connection_task(transport, device_id, auth_token, task_outgoing, relay_rx, incoming_tx, state_clone, direct, e2e, resume, stats, relay_tx).await;
         });
 
         Ok(Self {
             state,
-            outgoing_tx,
+            outgoing,
             incoming_rx: Arc::new(Mutex::new(incoming_rx)),
+            direct_status,
+            keyframe_request,
         })
     }
 
-    pub async fn send(&self, message: Message) -> Result<()> {
-        self.outgoing_tx.send(message)
-            .context("Failed to send message")?;
-        Ok(())
+    /// Queue a message in the queue of its class. Waits while that queue is full, except
+    /// that video and mouse moves rather push out stale ones and report `Congested`.
+    pub async fn send(&self, message: Message) -> Result<SendStatus> {
+        self.outgoing.push(message).await
+            .context("Failed to send message")
     }
 
     pub async fn recv(&self) -> Option<Message> {
@@ -58,36 +125,52 @@ impl NetworkConnection {
         self.state.lock().await.clone()
     }
 
+    /// The direct link the session currently uses, or None while it is relayed
+    pub fn direct_path(&self) -> Option<String> {
+        self.direct_status.path()
+    }
+
+    /// Whether the next video frame should be a keyframe, e.g. after a viewer resumed
+    pub fn take_keyframe_request(&self) -> bool {
+        self.keyframe_request.take()
+    }
+
     pub async fn disconnect(&self) {
         let _ = self.send(Message::Disconnect { reason: Some("User disconnected".to_string()) }).await;
         *self.state.lock().await = ConnectionState::Disconnected;
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn connection_task(
+    mut transport: Box<dyn Transport>,
     device_id: String,
-    mut outgoing_rx: mpsc::UnboundedReceiver<Message>,
+    auth_token: Option<String>,
+    outgoing: OutgoingQueue,
+    mut relay_rx: mpsc::UnboundedReceiver<Message>,
     incoming_tx: mpsc::UnboundedSender<Message>,
     state: Arc<Mutex<ConnectionState>>,
+    mut direct: DirectPath,
+    mut e2e: E2eSession,
+    mut resume: ResumeState,
+    stats: SessionStats,
+    relay_tx: mpsc::UnboundedSender<Message>,
 ) {
     let mut reconnect_attempts = 0;
 
     loop {
-        tracing::info!("Connecting to relay server: {}", RELAY_SERVER_URL);
-
-        match connect_async(RELAY_SERVER_URL).await {
-            Ok((ws_stream, _)) => {
+        match transport.connect().await {
+            Ok((mut ws_write, mut ws_read)) => {
                 tracing::info!("Connected to relay server");
                 *state.lock().await = ConnectionState::Connected;
                 reconnect_attempts = 0;
 
-                let (mut ws_write, mut ws_read) = ws_stream.split();
-
                 // Send Hello message
                 let hello = Message::Hello {
                     device_id: device_id.clone(),
                     platform: std::env::consts::OS.to_string(),
                     capabilities: vec!["screen_capture".to_string(), "input_control".to_string()],
+                    auth_token: auth_token.clone(),
                 };
 
                 if let Ok(json) = hello.to_json() {
@@ -97,17 +180,60 @@ async fn connection_task(
                     }
                 }
 
-                // Main message loop
+                // Rebind to the session the relay held for us after the connection dropped
+                if let Some(Ok(json)) = resume.resume_message().map(|m| m.to_json()) {
+                    tracing::info!("Resuming session");
+                    if let Err(e) = ws_write.send(WsMessage::Text(json)).await {
+                        tracing::error!("Failed to resume session: {}", e);
+                        continue;
+                    }
+                }
+
+                // Main message loop. Outgoing messages are taken from the queues by
+                // class, so control and input never wait behind video or file data.
+                let mut stats_tick = tokio::time::interval(STATS_INTERVAL);
                 loop {
                     tokio::select! {
+                        biased;
+
                         // Outgoing messages
-                        Some(msg) = outgoing_rx.recv() => {
+                        mut msg = next_outgoing(&mut relay_rx, &outgoing) => {
+                            if matches!(msg, Message::Disconnect { .. }) {
+                                direct.reset();
+                                e2e.reset();
+                                resume.reset();
+                                stats.session_ended(&device_id);
+                            }
+                            let frame = stats.outgoing(&mut msg);
+                            let Some(msg) = resume.outgoing(msg) else {
+                                if frame.is_some() {
+                                    stats.frame_dropped();
+                                }
+                                continue;
+                            };
+                            // Session content only leaves sealed, once keys are agreed
+                            let msg = if msg.is_session_content() {
+                                match e2e.seal(msg) {
+                                    Some(sealed) => sealed,
+                                    None => {
+                                        if frame.is_some() {
+                                            stats.frame_dropped();
+                                        }
+                                        continue;
+                                    }
+                                }
+                            } else {
+                                msg
+                            };
                             match msg.to_json() {
                                 Ok(json) => {
-                                    if let Err(e) = ws_write.send(WsMessage::Text(json)).await {
+                                    if let Err(e) = send_routed(&mut ws_write, &mut direct, &msg, json).await {
                                         tracing::error!("Failed to send message: {}", e);
                                         break;
                                     }
+                                    if let Some(bytes) = frame {
+                                        stats.frame_sent(bytes);
+                                    }
                                 }
                                 Err(e) => {
                                     tracing::error!("Failed to serialize message: {}", e);
@@ -116,59 +242,220 @@ async fn connection_task(
                         }
 
                         // Incoming messages
-                        Some(msg) = ws_read.next() => {
-                            match msg {
-                                Ok(WsMessage::Text(text)) => {
-                                    match Message::from_json(&text) {
-                                        Ok(parsed) => {
-                                            // Handle ping/pong internally
-                                            if matches!(parsed, Message::Ping) {
-                                                if let Ok(json) = Message::Pong.to_json() {
-                                                    let _ = ws_write.send(WsMessage::Text(json)).await;
-                                                }
-                                                continue;
-                                            }
+                        msg = ws_read.next() => {
+                            let Some(msg) = msg else {
+                                tracing::info!("Connection to relay server ended");
+                                break;
+                            };
+                            let parsed = match msg {
+                                Ok(WsMessage::Text(text)) => Message::from_json(&text),
+                                Ok(WsMessage::Binary(data)) => Message::from_bytes(&data),
 
-                                            if incoming_tx.send(parsed).is_err() {
-                                                tracing::error!("Failed to forward message: receiver dropped");
-                                                break;
-                                            }
+                                Ok(WsMessage::Close(_)) => {
+                                    tracing::info!("WebSocket closed by server");
+                                    break;
+                                }
+
+                                Ok(WsMessage::Ping(_)) | Ok(WsMessage::Pong(_)) => {
+                                    // Handled by tungstenite
+                                    continue;
+                                }
+
+                                Err(e) => {
+                                    tracing::error!("WebSocket error: {}", e);
+                                    break;
+                                }
+
+                                _ => continue,
+                            };
+
+                            match parsed {
+                                Ok(parsed) => {
+                                    // Handle ping/pong internally
+                                    if let Message::Ping { timestamp } = parsed {
+                                        if let Ok(json) = (Message::Pong { timestamp }).to_json() {
+                                            let _ = ws_write.send(WsMessage::Text(json)).await;
                                         }
-                                        Err(e) => {
-                                            tracing::error!("Failed to parse message: {}", e);
+                                        continue;
+                                    }
+                                    if let Message::Pong { timestamp } = parsed {
+                                        if let Some(timestamp) = timestamp {
+                                            stats.pong(timestamp);
                                         }
+                                        continue;
+                                    }
+
+                                    if matches!(parsed, Message::Sealed(_) | Message::SealedMedia(_) | Message::SealedBulk(_)) {
+                                        if !forward_all(&incoming_tx, &mut resume, &stats, open_sealed(&mut e2e, &stats, parsed)) {
+                                            break;
+                                        }
+                                        continue;
                                     }
-                                }
 
-                                Ok(WsMessage::Binary(data)) => {
-                                    match Message::from_bytes(&data) {
-                                        Ok(parsed) => {
-                                            if incoming_tx.send(parsed).is_err() {
-                                                tracing::error!("Failed to forward message: receiver dropped");
+                                    let mut outgoing = Vec::new();
+                                    let mut forward = true;
+                                    match &parsed {
+                                        Message::RendezvousInfo { port } => {
+                                            if let Some(host) = transport.relay_host() {
+                                                direct.set_rendezvous((host, *port));
+                                            }
+                                            continue;
+                                        }
+                                        Message::PeerCandidates { session_id, device_id: peer, candidates, fingerprint } => {
+                                            match RemotePeer::new(peer.clone(), candidates.clone(), fingerprint) {
+                                                Ok(remote) => direct.add_remote(session_id.clone(), remote, relay_tx.clone()),
+                                                Err(e) => tracing::warn!("Invalid candidates from {}: {}", peer, e),
+                                            }
+                                            continue;
+                                        }
+                                        Message::KeyExchange { session_id, device_id: peer, to, static_key, ephemeral_key } => {
+                                            let replies = e2e.receive_key_exchange(session_id, peer, to.as_deref(), static_key, ephemeral_key);
+                                            if let Err(e) = send_all(&mut ws_write, &mut direct, replies).await {
+                                                tracing::error!("Failed to send message: {}", e);
+                                                break;
+                                            }
+                                            continue;
+                                        }
+                                        Message::SenderKey { session_id, device_id: peer, key_id, sealed_key, .. } => {
+                                            if !forward_all(&incoming_tx, &mut resume, &stats, e2e.receive_sender_key(session_id, peer, *key_id, sealed_key)) {
+                                                break;
+                                            }
+                                            continue;
+                                        }
+                                        // A session with the peer was opened; our own ID acknowledges Hello
+                                        Message::ConnectResponse { success: true, session_id: Some(session_id), resume_token, .. }
+                                            if *session_id != device_id => {
+                                            direct.start(session_id.clone(), relay_tx.clone());
+                                            outgoing = e2e.start(session_id);
+                                            if resume.is_resume_of(session_id) {
+                                                // Same session as before, with its keys; only resend what was lost
+                                                tracing::info!("Resumed session {}", session_id);
+                                                outgoing.extend(resume.resumed().into_iter().filter_map(|m| e2e.seal(m)));
+                                                forward = false;
+                                            } else {
+                                                resume.session_opened(session_id, resume_token.as_deref());
+                                                stats.session_ended(&device_id);
+                                                stats.session_started(session_id);
+                                            }
+                                        }
+                                        Message::ConnectResponse { success: false, error_code: Some(code), error, .. }
+                                            if code == "SESSION_NOT_RESUMABLE" => {
+                                            tracing::warn!("Could not resume the session: {}", error.as_deref().unwrap_or("no reason given"));
+                                            direct.reset();
+                                            e2e.reset();
+                                            resume.reset();
+                                            stats.session_ended(&device_id);
+                                            let ended = Message::Disconnect { reason: Some("The session ended while reconnecting".to_string()) };
+                                            if incoming_tx.send(ended).is_err() {
                                                 break;
                                             }
+                                            continue;
                                         }
-                                        Err(e) => {
-                                            tracing::error!("Failed to parse binary message: {}", e);
+                                        Message::ParticipantResumed { session_id, device_id: peer } => {
+                                            if resume.is_resume_of(session_id) {
+                                                tracing::info!("{} resumed the session", peer);
+                                                outgoing = resume.resumed().into_iter().filter_map(|m| e2e.seal(m)).collect();
+                                            }
+                                            forward = false;
                                         }
+                                        Message::ParticipantsChanged { session_id, participants } => {
+                                            let closed = direct.set_participants(session_id, participants.len());
+                                            fall_back_to_relay(&mut ws_write, closed).await;
+                                            outgoing = e2e.participants_changed(session_id, participants);
+                                            resume.participants_changed(participants);
+                                        }
+                                        Message::Disconnect { .. } => {
+                                            direct.reset();
+                                            e2e.reset();
+                                            resume.reset();
+                                            stats.session_ended(&device_id);
+                                        }
+                                        // The relay could forge anything it can read
+                                        message if message.is_session_content() => {
+                                            tracing::warn!("Dropping an unencrypted session message from the relay");
+                                            continue;
+                                        }
+                                        _ => {}
                                     }
-                                }
 
-                                Ok(WsMessage::Close(_)) => {
-                                    tracing::info!("WebSocket closed by server");
-                                    break;
+                                    if let Err(e) = send_all(&mut ws_write, &mut direct, outgoing).await {
+                                        tracing::error!("Failed to send message: {}", e);
+                                        break;
+                                    }
+                                    if forward && incoming_tx.send(parsed).is_err() {
+                                        tracing::error!("Failed to forward message: receiver dropped");
+                                        break;
+                                    }
                                 }
+                                Err(e) => {
+                                    tracing::error!("Failed to parse message: {}", e);
+                                }
+                            }
+                        }
 
-                                Ok(WsMessage::Ping(_)) | Ok(WsMessage::Pong(_)) => {
-                                    // Handled by tungstenite
+                        // Session traffic from the peer over the direct link
+                        event = direct.next_event() => match event {
+                            DirectEvent::Message(msg) => {
+                                let parsed = match msg {
+                                    Some(Ok(WsMessage::Text(text))) => Message::from_json(&text),
+                                    Some(Ok(WsMessage::Binary(data))) => Message::from_bytes(&data),
+                                    Some(Ok(WsMessage::Close(_))) | Some(Err(_)) | None => {
+                                        fall_back_to_relay(&mut ws_write, direct.close()).await;
+                                        continue;
+                                    }
+                                    Some(Ok(_)) => continue,
+                                };
+                                match parsed {
+                                    // The link is authenticated, but only to a key exchanged through the relay
+                                    Ok(parsed @ (Message::Sealed(_) | Message::SealedMedia(_) | Message::SealedBulk(_))) => {
+                                        if !forward_all(&incoming_tx, &mut resume, &stats, open_sealed(&mut e2e, &stats, parsed)) {
+                                            break;
+                                        }
+                                    }
+                                    Ok(_) => tracing::warn!("Dropping an unencrypted message from the direct link"),
+                                    Err(e) => tracing::error!("Failed to parse message: {}", e),
+                                }
+                            }
+                            DirectEvent::Negotiated(Ok(link)) => {
+                                tracing::info!("Direct connection to peer established ({})", link.path);
+                                direct.install(link);
+                                if let Some(session_id) = direct.session_id() {
+                                    let changed = Message::PathChanged { session_id: session_id.to_string(), direct: true };
+                                    if let Ok(json) = changed.to_json() {
+                                        let _ = ws_write.send(WsMessage::Text(json)).await;
+                                    }
                                 }
+                            }
+                            DirectEvent::Negotiated(Err(e)) => {
+                                tracing::info!("No direct connection to peer, staying relayed: {:#}", e);
+                            }
+                        },
 
-                                Err(e) => {
-                                    tracing::error!("WebSocket error: {}", e);
+                        // Key lookups finishing, or the key exchange timing out
+                        outgoing = e2e.next_event() => {
+                            if let Err(e) = send_all(&mut ws_write, &mut direct, outgoing).await {
+                                tracing::error!("Failed to send message: {}", e);
+                                break;
+                            }
+                        }
+
+                        // Acknowledging numbered control messages from peers
+                        acks = resume.next_ack() => {
+                            let acks = acks.into_iter().filter_map(|m| e2e.seal(m)).collect();
+                            if let Err(e) = send_all(&mut ws_write, &mut direct, acks).await {
+                                tracing::error!("Failed to send message: {}", e);
+                                break;
+                            }
+                        }
+
+                        // Round-trip time to the relay, and the rates of the interval that ended
+                        _ = stats_tick.tick() => {
+                            stats.tick(outgoing.len());
+                            if let Ok(json) = stats.ping().to_json() {
+                                if let Err(e) = ws_write.send(WsMessage::Text(json)).await {
+                                    tracing::error!("Failed to send ping: {}", e);
                                     break;
                                 }
-
-                                _ => {}
                             }
                         }
 
@@ -179,6 +466,13 @@ async fn connection_task(
                     }
                 }
 
+                // The relay holds a resumable session for a while, so its keys are kept for
+                // when we are back. The direct link is negotiated again either way.
+                direct.reset();
+                if !resume.is_resumable() {
+                    e2e.reset();
+                    stats.session_ended(&device_id);
+                }
                 tracing::warn!("Connection lost, will attempt to reconnect");
             }
 
@@ -189,6 +483,7 @@ async fn connection_task(
                 if reconnect_attempts >= MAX_RECONNECT_ATTEMPTS {
                     tracing::error!("Max reconnect attempts reached, giving up");
                     *state.lock().await = ConnectionState::Failed;
+                    outgoing.close();
                     break;
                 }
             }
@@ -200,11 +495,89 @@ async fn connection_task(
     }
 }
 
+/// The next message to send: signalling of the direct path first, then the queues
+async fn next_outgoing(relay_rx: &mut mpsc::UnboundedReceiver<Message>, outgoing: &OutgoingQueue) -> Message {
+    tokio::select! {
+        biased;
+        Some(message) = relay_rx.recv() => message,
+        message = outgoing.next() => message,
+    }
+}
+
+/// Send session traffic over the direct link if there is one, and everything else, or
+/// everything once the link fails, through the relay
+async fn send_routed(ws_write: &mut MessageSink, direct: &mut DirectPath, message: &Message, json: String) -> Result<(), WsError> {
+    if !message.is_for_relay() {
+        if let Some(link) = direct.link() {
+            if link.sink.send(WsMessage::Text(json.clone())).await.is_ok() {
+                return Ok(());
+            }
+            fall_back_to_relay(ws_write, direct.close()).await;
+        }
+    }
+    ws_write.send(WsMessage::Text(json)).await
+}
+
+/// Send what the end-to-end layer produced: keys through the relay, sealed traffic
+/// by whichever path the session uses
+async fn send_all(ws_write: &mut MessageSink, direct: &mut DirectPath, messages: Vec<Message>) -> Result<(), WsError> {
+    for message in messages {
+        match message.to_json() {
+            Ok(json) => send_routed(ws_write, direct, &message, json).await?,
+            Err(e) => tracing::error!("Failed to serialize message: {}", e),
+        }
+    }
+    Ok(())
+}
+
+/// Open sealed traffic from a peer, counting video that could not be opened as dropped
+fn open_sealed(e2e: &mut E2eSession, stats: &SessionStats, sealed: Message) -> Vec<(String, Message)> {
+    let media = matches!(sealed, Message::SealedMedia(_));
+    let opened = e2e.open(sealed);
+    if media && opened.is_empty() {
+        stats.frame_dropped();
+    }
+    opened
+}
+
+/// Hand opened messages to the application, in order and without the numbering of
+/// control messages; false once it has gone away
+fn forward_all(
+    incoming_tx: &mpsc::UnboundedSender<Message>,
+    resume: &mut ResumeState,
+    stats: &SessionStats,
+    opened: Vec<(String, Message)>,
+) -> bool {
+    for message in opened.into_iter().filter_map(|(sender, message)| resume.incoming(&sender, message)) {
+        stats.incoming(&message);
+        if incoming_tx.send(message).is_err() {
+            tracing::error!("Failed to forward message: receiver dropped");
+            return false;
+        }
+    }
+    true
+}
+
+/// Tell the relay that a session's traffic is coming back through it
+async fn fall_back_to_relay(ws_write: &mut MessageSink, closed: Option<String>) {
+    let Some(session_id) = closed else {
+        return;
+    };
+    tracing::warn!("Direct connection to peer lost, falling back to the relay");
+    if let Ok(json) = (Message::PathChanged { session_id, direct: false }).to_json() {
+        let _ = ws_write.send(WsMessage::Text(json)).await;
+    }
+}
+
 /// Connection manager for handling sessions
 pub struct ConnectionManager {
     connection: Option<NetworkConnection>,
     session_id: Option<String>,
     remote_id: Option<String>,
+    direct_status: DirectStatus,
+    security_status: SecurityStatus,
+    session_stats: SessionStats,
+    key_directory: Option<Arc<ApiClient>>,
 }
 
 impl ConnectionManager {
@@ -213,11 +586,59 @@ impl ConnectionManager {
             connection: None,
             session_id: None,
             remote_id: None,
+            direct_status: DirectStatus::default(),
+            security_status: SecurityStatus::default(),
+            session_stats: SessionStats::default(),
+            key_directory: None,
         }
     }
 
-    pub async fn connect(&mut self, device_id: String) -> Result<()> {
-        let conn = NetworkConnection::connect(device_id).await?;
+    /// Check peers' keys against the ones registered with the device manager while
+    /// the API client is signed in
+    pub fn with_key_directory(mut self, api_client: Arc<ApiClient>) -> Self {
+        self.key_directory = Some(api_client);
+        self
+    }
+
+    /// Report each session's connection quality to the backend when it ends
+    pub fn with_quality_reports(mut self, api_client: Arc<ApiClient>) -> Self {
+        self.session_stats = self.session_stats.with_reports(api_client);
+        self
+    }
+
+    /// Whether the session runs over a direct link; stays valid across reconnects
+    pub fn direct_status(&self) -> DirectStatus {
+        self.direct_status.clone()
+    }
+
+    /// The session's end-to-end encryption; stays valid across reconnects
+    pub fn security_status(&self) -> SecurityStatus {
+        self.security_status.clone()
+    }
+
+    /// The session's connection quality; stays valid across reconnects
+    pub fn session_stats(&self) -> SessionStats {
+        self.session_stats.clone()
+    }
+
+    pub async fn connect(&mut self, relay: RelayEndpoint, device_id: String, auth_token: Option<String>) -> Result<()> {
+        let identity = DeviceKey::load_or_create().unwrap_or_else(|e| {
+            tracing::warn!("Using a temporary device key: {:#}", e);
+            DeviceKey::generate()
+        });
+        let e2e = E2eConfig {
+            identity: Arc::new(identity),
+            directory: self.key_directory.clone(),
+            status: self.security_status.clone(),
+        };
+        let conn = NetworkConnection::connect_with_status(
+            relay,
+            device_id,
+            auth_token,
+            self.direct_status.clone(),
+            e2e,
+            self.session_stats.clone(),
+        ).await?;
         self.connection = Some(conn);
         Ok(())
     }
@@ -235,14 +656,17 @@ impl ConnectionManager {
         Ok(())
     }
 
-    pub async fn send(&self, message: Message) -> Result<()> {
-        if let Some(conn) = &self.connection {
-            conn.send(message).await?;
-        } else {
-            anyhow::bail!("Not connected");
+    pub async fn send(&self, message: Message) -> Result<SendStatus> {
+        match &self.connection {
+            Some(conn) => conn.send(message).await,
+            None => anyhow::bail!("Not connected"),
         }
+    }
 
-        Ok(())
+    /// A handle on the outgoing queues, for senders that may wait for room (file
+    /// transfers) without holding on to the manager meanwhile
+    pub fn sender(&self) -> Option<OutgoingQueue> {
+        self.connection.as_ref().map(|conn| conn.outgoing.clone())
     }
 
     pub async fn recv(&self) -> Option<Message> {
@@ -253,6 +677,11 @@ impl ConnectionManager {
         }
     }
 
+    /// Whether the next video frame should be a keyframe
+    pub fn take_keyframe_request(&self) -> bool {
+        self.connection.as_ref().is_some_and(|conn| conn.take_keyframe_request())
+    }
+
     pub async fn get_state(&self) -> ConnectionState {
         if let Some(conn) = &self.connection {
             conn.get_state().await
@@ -291,15 +720,59 @@ impl ConnectionManager {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use transport::loopback::loopback;
+    use transport::MessageStream;
+
+    async fn connect(transport: impl Transport, stats: SessionStats) -> NetworkConnection {
+        let e2e = E2eConfig::new(DeviceKey::generate());
+        NetworkConnection::connect_with_transport(Box::new(transport), "device".to_string(), None, DirectStatus::default(), e2e, stats)
+            .await
+            .unwrap()
+    }
 
-    #[tokio::test]
-    async fn test_connection_state() {
-        let device_id = "test-device-123".to_string();
-
-        // Note: This will fail without actual relay server
-        let result = NetworkConnection::connect(device_id).await;
+    async fn next_message(stream: &mut MessageStream) -> Message {
+        match stream.next().await {
+            Some(Ok(WsMessage::Text(text))) => Message::from_json(&text).unwrap(),
+            other => panic!("Expected a message, got {:?}", other),
+        }
+    }
 
-        // Just verify it returns something
-        assert!(result.is_ok() || result.is_err());
+    #[tokio::test(start_paused = true)]
+    async fn test_connection_state() {
+        let (transport, mut relay) = loopback();
+        let stats = SessionStats::default();
+        let connection = connect(transport, stats.clone()).await;
+
+        let (mut sink, mut stream) = relay.accept().await;
+        assert!(matches!(next_message(&mut stream).await, Message::Hello { .. }));
+        assert_eq!(connection.get_state().await, ConnectionState::Connected);
+
+        // Echoing the client's pings gives the round-trip time; the relay's are answered
+        let Message::Ping { timestamp } = next_message(&mut stream).await else {
+            panic!("Expected a ping");
+        };
+        tokio::time::sleep(std::time::Duration::from_millis(40)).await;
+        sink.send(WsMessage::Text((Message::Pong { timestamp }).to_json().unwrap())).await.unwrap();
+        sink.send(WsMessage::Text((Message::Ping { timestamp: Some(7) }).to_json().unwrap())).await.unwrap();
+        assert!(matches!(next_message(&mut stream).await, Message::Pong { timestamp: Some(7) }));
+        assert_eq!(stats.snapshot().rtt_ms, Some(40.0));
+
+        // A dropped connection is opened again after a delay
+        drop((sink, stream));
+        while connection.get_state().await != ConnectionState::Reconnecting {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        let (_, mut stream) = relay.accept().await;
+        assert!(matches!(next_message(&mut stream).await, Message::Hello { .. }));
+        assert_eq!(connection.get_state().await, ConnectionState::Connected);
+
+        // Messages from the application go out in order
+        connection.send(Message::Disconnect { reason: None }).await.unwrap();
+        loop {
+            match next_message(&mut stream).await {
+                Message::Ping { .. } => continue,
+                message => break assert!(matches!(message, Message::Disconnect { reason: None })),
+            }
+        }
     }
 }
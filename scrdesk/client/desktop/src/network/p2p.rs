@@ -0,0 +1,653 @@
+//! Direct connections between the two peers of a session, with the relay as rendezvous.
+//!
+//! Each side gathers candidate addresses for one UDP socket and one TCP port: its LAN
+//! addresses, plus the public addresses the relay's rendezvous service observes. The
+//! candidates and a fingerprint of a throwaway certificate are exchanged through the
+//! relay. Both sides then send UDP probes to every candidate to open their NATs and
+//! run QUIC over the punched socket, or failing that connect to each other's TCP
+//! candidates at the same time (simultaneous open) and run TLS and WebSocket on top.
+//! Both links are mutually authenticated with the exchanged fingerprints, and the
+//! side with the lower device ID takes the server role. When no direct path works in
+//! time the session simply stays relayed.
+
+use anyhow::{anyhow, bail, Context, Result};
+use crate::protocol::{Candidate, CandidateKind, CandidateProtocol, Message};
+use futures::stream::FuturesUnordered;
+use futures::future::BoxFuture;
+use futures::{Future, FutureExt, StreamExt};
+use quinn::crypto::rustls::{QuicClientConfig, QuicServerConfig};
+use quinn::{Endpoint, EndpointConfig, TokioRuntime};
+use rustls::pki_types::ServerName;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::net::{TcpListener, TcpSocket, TcpStream, UdpSocket};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+use tokio_tungstenite::tungstenite::{Error as WsError, Message as WsMessage};
+use super::tls::{self, PeerIdentity};
+use super::quic;
+use super::transport::{MessageSink, MessageStream};
+
+pub const ALPN: &[u8] = b"scrdesk-direct";
+
+const WHOAMI: &[u8] = b"SCRDESK-WHOAMI"; // Must match the relay's rendezvous service
+const WHOAMI_REPLY: &str = "SCRDESK-YOUARE ";
+const PROBE: &str = "SCRDESK-PUNCH";
+const PEER_NAME: &str = "scrdesk-peer";
+
+const DISCOVERY_TIMEOUT: Duration = Duration::from_secs(1);
+const CANDIDATES_TIMEOUT: Duration = Duration::from_secs(10); // Wait for the peer's candidates
+const UDP_TIMEOUT: Duration = Duration::from_secs(4);
+const TCP_TIMEOUT: Duration = Duration::from_secs(4);
+const PROBE_INTERVAL: Duration = Duration::from_millis(100);
+const TCP_RETRY_INTERVAL: Duration = Duration::from_millis(200);
+const LAN_GRACE: Duration = Duration::from_millis(200); // Wait this long for a LAN path to beat a public one
+
+/// Where to ask for our public address: the relay host and its rendezvous port
+pub type Rendezvous = (String, u16);
+
+/// The peer's side of the negotiation, as received through the relay
+#[derive(Debug, Clone)]
+pub struct RemotePeer {
+    pub device_id: String,
+    pub candidates: Vec<Candidate>,
+    pub fingerprint: [u8; 32],
+}
+
+impl RemotePeer {
+    pub fn new(device_id: String, candidates: Vec<Candidate>, fingerprint: &str) -> Result<Self> {
+        Ok(Self {
+            device_id,
+            candidates,
+            fingerprint: tls::parse_pin(fingerprint)?,
+        })
+    }
+
+    fn addresses(&self, protocol: CandidateProtocol) -> Vec<SocketAddr> {
+        // LAN candidates first, so they are probed first
+        let mut candidates: Vec<&Candidate> = self.candidates.iter().filter(|c| c.protocol == protocol).collect();
+        candidates.sort_by_key(|c| c.kind != CandidateKind::Lan);
+        candidates.iter().map(|c| c.address).collect()
+    }
+
+    fn is_lan(&self, address: SocketAddr) -> bool {
+        self.candidates.iter().any(|c| c.kind == CandidateKind::Lan && c.address == address)
+    }
+}
+
+/// An established direct link to the peer
+pub struct DirectLink {
+    pub sink: MessageSink,
+    pub stream: MessageStream,
+    pub path: String, // e.g. "UDP 192.168.1.20:50123", for logs and the UI
+}
+
+/// The path of the current direct link, shared with the UI; None while relayed
+#[derive(Debug, Clone, Default)]
+pub struct DirectStatus(Arc<std::sync::Mutex<Option<String>>>);
+
+impl DirectStatus {
+    pub fn path(&self) -> Option<String> {
+        self.0.lock().unwrap().clone()
+    }
+
+    fn set(&self, path: Option<String>) {
+        *self.0.lock().unwrap() = path;
+    }
+}
+
+/// Sockets and certificate for one connection attempt
+pub struct LocalPeer {
+    identity: PeerIdentity,
+    udp: UdpSocket,
+    tcp: TcpListener,
+    pub candidates: Vec<Candidate>,
+}
+
+impl LocalPeer {
+    /// Bind the sockets and gather candidates, asking the rendezvous service for our
+    /// public addresses if there is one
+    pub async fn gather(rendezvous: Option<&Rendezvous>) -> Result<Self> {
+        let rendezvous = match rendezvous {
+            Some((host, port)) => tokio::net::lookup_host((host.as_str(), *port)).await?.find(|a| a.is_ipv4()),
+            None => None,
+        };
+
+        let udp = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+        let tcp_socket = reusable_socket()?;
+        tcp_socket.bind((Ipv4Addr::UNSPECIFIED, 0).into())?;
+        let tcp = tcp_socket.listen(16)?;
+        let udp_port = udp.local_addr()?.port();
+        let tcp_port = tcp.local_addr()?.port();
+
+        let mut candidates = Vec::new();
+        for ip in lan_addresses(rendezvous) {
+            candidates.push(candidate(CandidateProtocol::Udp, CandidateKind::Lan, SocketAddr::new(ip, udp_port)));
+            candidates.push(candidate(CandidateProtocol::Tcp, CandidateKind::Lan, SocketAddr::new(ip, tcp_port)));
+        }
+
+        if let Some(rendezvous) = rendezvous {
+            match tokio::time::timeout(DISCOVERY_TIMEOUT, public_udp_address(&udp, rendezvous)).await {
+                Ok(Ok(address)) => candidates.push(candidate(CandidateProtocol::Udp, CandidateKind::Public, address)),
+                Ok(Err(e)) => tracing::debug!("UDP address discovery failed: {:#}", e),
+                Err(_) => tracing::debug!("UDP address discovery timed out"),
+            }
+            match tokio::time::timeout(DISCOVERY_TIMEOUT, public_tcp_address(tcp_port, rendezvous)).await {
+                Ok(Ok(address)) => candidates.push(candidate(CandidateProtocol::Tcp, CandidateKind::Public, address)),
+                Ok(Err(e)) => tracing::debug!("TCP address discovery failed: {:#}", e),
+                Err(_) => tracing::debug!("TCP address discovery timed out"),
+            }
+        }
+        candidates.dedup_by_key(|c| (c.protocol, c.address));
+
+        Ok(Self {
+            identity: PeerIdentity::generate()?,
+            udp,
+            tcp,
+            candidates,
+        })
+    }
+
+    pub fn fingerprint(&self) -> String {
+        tls::to_hex(&self.identity.fingerprint)
+    }
+
+    /// Try UDP, then TCP. `own_id` and the peer's device ID decide the roles.
+    pub async fn connect(self, own_id: &str, session_id: &str, remote: &RemotePeer) -> Result<DirectLink> {
+        let server = own_id < remote.device_id.as_str();
+        let probe = format!("{} {} {}", PROBE, session_id, own_id);
+        let expected = format!("{} {} {}", PROBE, session_id, remote.device_id);
+
+        let udp = udp_link(self.udp, &self.identity, server, probe.as_bytes(), expected.as_bytes(), remote);
+        match tokio::time::timeout(UDP_TIMEOUT, udp).await {
+            Ok(Ok(link)) => return Ok(link),
+            Ok(Err(e)) => tracing::debug!("Direct UDP path failed: {:#}", e),
+            Err(_) => tracing::debug!("Direct UDP path timed out"),
+        }
+
+        tokio::time::timeout(TCP_TIMEOUT, tcp_link(self.tcp, &self.identity, server, remote))
+            .await
+            .map_err(|_| anyhow!("No direct path to the peer"))?
+    }
+}
+
+/// Gather candidates, send them to the peer through the relay and connect once the
+/// peer's candidates arrive
+pub async fn negotiate(
+    own_id: String,
+    session_id: String,
+    rendezvous: Option<Rendezvous>,
+    relay_tx: mpsc::UnboundedSender<Message>,
+    mut remote_rx: mpsc::UnboundedReceiver<RemotePeer>,
+) -> Result<DirectLink> {
+    let local = LocalPeer::gather(rendezvous.as_ref()).await?;
+    relay_tx.send(Message::PeerCandidates {
+        session_id: session_id.clone(),
+        device_id: own_id.clone(),
+        candidates: local.candidates.clone(),
+        fingerprint: local.fingerprint(),
+    })?;
+
+    let remote = tokio::time::timeout(CANDIDATES_TIMEOUT, remote_rx.recv())
+        .await
+        .context("Peer sent no candidates")?
+        .context("Negotiation cancelled")?;
+    local.connect(&own_id, &session_id, &remote).await
+}
+
+pub enum DirectEvent {
+    Message(Option<Result<WsMessage, WsError>>), // None when the link closed
+    Negotiated(Result<DirectLink>),
+}
+
+/// The direct path of the connection's current session: the running negotiation and,
+/// once it succeeds, the link. Session traffic takes the link while there is one.
+pub struct DirectPath {
+    own_id: String,
+    status: DirectStatus,
+    rendezvous: Option<Rendezvous>,
+    session_id: Option<String>,
+    group_session: Option<String>, // Session known to have more than two participants
+    remote_tx: Option<mpsc::UnboundedSender<RemotePeer>>,
+    negotiation: Option<JoinHandle<Result<DirectLink>>>,
+    link: Option<DirectLink>,
+}
+
+impl DirectPath {
+    pub fn new(own_id: String, status: DirectStatus) -> Self {
+        Self {
+            own_id,
+            status,
+            rendezvous: None,
+            session_id: None,
+            group_session: None,
+            remote_tx: None,
+            negotiation: None,
+            link: None,
+        }
+    }
+
+    pub fn set_rendezvous(&mut self, rendezvous: Rendezvous) {
+        self.rendezvous = Some(rendezvous);
+    }
+
+    pub fn session_id(&self) -> Option<&str> {
+        self.session_id.as_deref()
+    }
+
+    /// Start negotiating a direct link for a newly established session
+    pub fn start(&mut self, session_id: String, relay_tx: mpsc::UnboundedSender<Message>) {
+        self.reset();
+        if self.group_session.as_deref() == Some(session_id.as_str()) {
+            return;
+        }
+        tracing::debug!("Negotiating a direct connection for session {}", session_id);
+        let (remote_tx, remote_rx) = mpsc::unbounded_channel();
+        self.negotiation = Some(tokio::spawn(negotiate(
+            self.own_id.clone(),
+            session_id.clone(),
+            self.rendezvous.clone(),
+            relay_tx,
+            remote_rx,
+        )));
+        self.session_id = Some(session_id);
+        self.remote_tx = Some(remote_tx);
+    }
+
+    /// Hand the peer's candidates to the negotiation, starting one if the peer got
+    /// there first
+    pub fn add_remote(&mut self, session_id: String, remote: RemotePeer, relay_tx: mpsc::UnboundedSender<Message>) {
+        if self.session_id.as_deref() != Some(session_id.as_str()) {
+            self.start(session_id, relay_tx);
+        }
+        if let Some(remote_tx) = &self.remote_tx {
+            let _ = remote_tx.send(remote);
+        }
+    }
+
+    /// Direct links are only for two-party sessions: give up on a session once more
+    /// participants join. Returns the session ID if its link was closed.
+    pub fn set_participants(&mut self, session_id: &str, count: usize) -> Option<String> {
+        if count <= 2 {
+            return None;
+        }
+        self.group_session = Some(session_id.to_string());
+        if self.session_id.as_deref() == Some(session_id) {
+            return self.reset();
+        }
+        None
+    }
+
+    /// The next message from the link or the outcome of the running negotiation;
+    /// never resolves while there is neither
+    pub async fn next_event(&mut self) -> DirectEvent {
+        let link = self.link.as_mut();
+        let negotiation = self.negotiation.as_mut();
+        let event = tokio::select! {
+            message = async {
+                match link {
+                    Some(link) => link.stream.next().await,
+                    None => std::future::pending().await,
+                }
+            } => DirectEvent::Message(message),
+            result = async {
+                match negotiation {
+                    Some(negotiation) => negotiation.await,
+                    None => std::future::pending().await,
+                }
+            } => DirectEvent::Negotiated(result.unwrap_or_else(|e| Err(e.into()))),
+        };
+
+        if let DirectEvent::Negotiated(_) = event {
+            self.negotiation = None;
+            self.remote_tx = None;
+        }
+        event
+    }
+
+    pub fn install(&mut self, link: DirectLink) {
+        self.status.set(Some(link.path.clone()));
+        self.link = Some(link);
+    }
+
+    pub fn link(&mut self) -> Option<&mut DirectLink> {
+        self.link.as_mut()
+    }
+
+    /// Stop using the link, returning the session ID if there was one
+    pub fn close(&mut self) -> Option<String> {
+        self.link.take()?;
+        self.status.set(None);
+        self.session_id.clone()
+    }
+
+    /// Forget the session: cancel any negotiation and close the link
+    pub fn reset(&mut self) -> Option<String> {
+        if let Some(negotiation) = self.negotiation.take() {
+            negotiation.abort();
+        }
+        self.remote_tx = None;
+        let closed = self.close();
+        self.session_id = None;
+        closed
+    }
+}
+
+impl Drop for DirectPath {
+    fn drop(&mut self) {
+        self.reset();
+    }
+}
+
+fn candidate(protocol: CandidateProtocol, kind: CandidateKind, address: SocketAddr) -> Candidate {
+    Candidate { protocol, kind, address }
+}
+
+/// A TCP socket that can share its port with the listener, for simultaneous open
+fn reusable_socket() -> Result<TcpSocket> {
+    let socket = TcpSocket::new_v4()?;
+    socket.set_reuseaddr(true)?;
+    #[cfg(unix)]
+    socket.set_reuseport(true)?;
+    Ok(socket)
+}
+
+/// Loopback, for peers on the same machine, and the address of the interface that
+/// routes towards the relay (or any address if there is no relay)
+fn lan_addresses(rendezvous: Option<SocketAddr>) -> Vec<IpAddr> {
+    let mut addresses = vec![IpAddr::V4(Ipv4Addr::LOCALHOST)];
+    let target = rendezvous.unwrap_or_else(|| SocketAddr::from(([192, 0, 2, 1], 9)));
+
+    // Connecting a UDP socket sends nothing but picks the outgoing interface
+    let routed = std::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))
+        .and_then(|socket| socket.connect(target).and_then(|_| socket.local_addr()));
+    match routed {
+        Ok(local) if !local.ip().is_unspecified() && !local.ip().is_loopback() => addresses.push(local.ip()),
+        Ok(_) => {}
+        Err(e) => tracing::debug!("No route for LAN address discovery: {}", e),
+    }
+    addresses
+}
+
+async fn public_udp_address(socket: &UdpSocket, rendezvous: SocketAddr) -> Result<SocketAddr> {
+    let mut buf = [0u8; 128];
+    loop {
+        socket.send_to(WHOAMI, rendezvous).await?;
+        let reply = tokio::time::timeout(DISCOVERY_TIMEOUT / 4, socket.recv_from(&mut buf)).await;
+        let Ok(Ok((len, from))) = reply else {
+            continue;
+        };
+        if from != rendezvous {
+            continue;
+        }
+        if let Some(address) = std::str::from_utf8(&buf[..len]).ok().and_then(|r| r.strip_prefix(WHOAMI_REPLY)) {
+            return Ok(address.parse()?);
+        }
+    }
+}
+
+/// Ask from the TCP candidate port, so the observed port is the NAT mapping for it
+async fn public_tcp_address(port: u16, rendezvous: SocketAddr) -> Result<SocketAddr> {
+    let socket = reusable_socket()?;
+    socket.bind((Ipv4Addr::UNSPECIFIED, port).into())?;
+    let stream = socket.connect(rendezvous).await?;
+
+    let mut reply = String::new();
+    BufReader::new(stream).read_line(&mut reply).await?;
+    let address = reply.trim().strip_prefix(WHOAMI_REPLY).context("Unexpected rendezvous reply")?;
+    Ok(address.parse()?)
+}
+
+/// Send probes to the peer's UDP candidates until one of the peer's probes arrives,
+/// and return the address it came from. A LAN address wins over a public one that
+/// answers shortly before it.
+async fn punch(socket: &UdpSocket, probe: &[u8], expected: &[u8], remote: &RemotePeer) -> Result<SocketAddr> {
+    let targets = remote.addresses(CandidateProtocol::Udp);
+    if targets.is_empty() {
+        bail!("Peer has no UDP candidates");
+    }
+
+    let mut interval = tokio::time::interval(PROBE_INTERVAL);
+    let mut buf = [0u8; 256];
+    let mut found: Option<(SocketAddr, tokio::time::Instant)> = None;
+    loop {
+        let grace = async {
+            match found {
+                Some((_, deadline)) => tokio::time::sleep_until(deadline).await,
+                None => std::future::pending().await,
+            }
+        };
+
+        tokio::select! {
+            _ = interval.tick() => {
+                for target in &targets {
+                    // Unreachable candidates are expected; keep probing the rest
+                    let _ = socket.send_to(probe, target).await;
+                }
+            }
+            received = socket.recv_from(&mut buf) => {
+                let Ok((len, from)) = received else {
+                    continue;
+                };
+                if &buf[..len] != expected {
+                    continue;
+                }
+                if remote.is_lan(from) {
+                    return Ok(from);
+                }
+                found.get_or_insert((from, tokio::time::Instant::now() + LAN_GRACE));
+            }
+            _ = grace => return Ok(found.unwrap().0),
+        }
+    }
+}
+
+async fn udp_link(
+    socket: UdpSocket,
+    identity: &PeerIdentity,
+    server: bool,
+    probe: &[u8],
+    expected: &[u8],
+    remote: &RemotePeer,
+) -> Result<DirectLink> {
+    let address = punch(&socket, probe, expected, remote).await?;
+    tracing::debug!("UDP probe from the peer arrived from {}", address);
+    // The peer may not have heard from us yet, e.g. when its probe was already waiting
+    // before we sent our first one, and it keeps waiting until it does
+    for _ in 0..3 {
+        socket.send_to(probe, address).await?;
+    }
+
+    let socket = socket.into_std()?;
+    let connection = if server {
+        let crypto = identity.server_config(remote.fingerprint, &[ALPN])?;
+        let mut config = quinn::ServerConfig::with_crypto(Arc::new(QuicServerConfig::try_from(crypto)?));
+        config.transport_config(Arc::new(quic::transport_config()));
+        let endpoint = Endpoint::new(EndpointConfig::default(), Some(config), socket, Arc::new(TokioRuntime))?;
+
+        // Anyone else knocking fails the pinned handshake; keep waiting for the peer
+        loop {
+            let incoming = endpoint.accept().await.context("QUIC endpoint closed")?;
+            match incoming.await {
+                Ok(connection) => break connection,
+                Err(e) => tracing::debug!("Rejected direct QUIC connection: {}", e),
+            }
+        }
+    } else {
+        let crypto = identity.client_config(remote.fingerprint, &[ALPN])?;
+        let mut config = quinn::ClientConfig::new(Arc::new(QuicClientConfig::try_from(crypto)?));
+        config.transport_config(Arc::new(quic::transport_config()));
+        let endpoint = Endpoint::new(EndpointConfig::default(), None, socket, Arc::new(TokioRuntime))?;
+        endpoint.connect_with(config, address, PEER_NAME)?.await?
+    };
+
+    let (sink, stream) = quic::split(connection);
+    Ok(DirectLink {
+        sink,
+        stream: Box::pin(stream),
+        path: format!("UDP {}", address),
+    })
+}
+
+/// Connect to every TCP candidate from our listening port while also accepting on it.
+/// Behind NATs the crossing attempts open a single connection; without NATs each side
+/// may end up with its own. Every connection gets a TLS handshake in the agreed roles,
+/// and the client upgrades only the first one to complete to WebSocket, so the server
+/// ends up using the same one.
+async fn tcp_link(listener: TcpListener, identity: &PeerIdentity, server: bool, remote: &RemotePeer) -> Result<DirectLink> {
+    let targets = remote.addresses(CandidateProtocol::Tcp);
+    if targets.is_empty() {
+        bail!("Peer has no TCP candidates");
+    }
+
+    if server {
+        let acceptor = TlsAcceptor::from(Arc::new(identity.server_config(remote.fingerprint, &[])?));
+        let acceptor = &acceptor;
+        race(&listener, &targets, |stream, address| async move {
+            let tls = acceptor.accept(stream).await?;
+            let (sink, stream) = tokio_tungstenite::accept_async(tls).await?.split();
+            Ok(link(Box::pin(sink), Box::pin(stream), address))
+        })
+        .await
+    } else {
+        let connector = TlsConnector::from(Arc::new(identity.client_config(remote.fingerprint, &[])?));
+        let connector = &connector;
+        let (tls, address) = race(&listener, &targets, |stream, address| async move {
+            Ok((connector.connect(ServerName::try_from(PEER_NAME)?, stream).await?, address))
+        })
+        .await?;
+
+        let url = format!("ws://{}/", PEER_NAME);
+        let (ws_stream, _) = tokio_tungstenite::client_async(url, tls).await?;
+        let (sink, stream) = ws_stream.split();
+        Ok(link(Box::pin(sink), Box::pin(stream), address))
+    }
+}
+
+/// Run `secure` on every connection we make or accept and return the first success
+async fn race<'a, T, F, Fut>(listener: &'a TcpListener, targets: &[SocketAddr], secure: F) -> Result<T>
+where
+    F: Fn(TcpStream, SocketAddr) -> Fut + Copy + Send + Sync + 'a,
+    Fut: Future<Output = Result<T>> + Send + 'a,
+    T: 'a,
+{
+    let port = listener.local_addr()?.port();
+    let mut attempts: FuturesUnordered<BoxFuture<'a, Result<T>>> = FuturesUnordered::new();
+    for &address in targets {
+        attempts.push(async move { secure(dial(port, address).await?, address).await }.boxed());
+    }
+
+    let mut last_error = None;
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                if let Ok((stream, address)) = accepted {
+                    attempts.push(secure(stream, address).boxed());
+                }
+            }
+            Some(result) = attempts.next() => match result {
+                Ok(secured) => return Ok(secured),
+                Err(e) => last_error = Some(e),
+            },
+            else => return Err(last_error.unwrap_or_else(|| anyhow!("No TCP connection to the peer"))),
+        }
+    }
+}
+
+/// Keep connecting until the peer's NAT lets us through
+async fn dial(port: u16, address: SocketAddr) -> Result<TcpStream> {
+    loop {
+        let socket = reusable_socket()?;
+        socket.bind((Ipv4Addr::UNSPECIFIED, port).into())?;
+        match socket.connect(address).await {
+            Ok(stream) => {
+                stream.set_nodelay(true)?;
+                return Ok(stream);
+            }
+            Err(e) => {
+                // Also expected when the peer's connection already took this address pair
+                tracing::trace!("Direct TCP connect to {} failed: {}", address, e);
+                tokio::time::sleep(TCP_RETRY_INTERVAL).await;
+            }
+        }
+    }
+}
+
+fn link(sink: MessageSink, stream: MessageStream, address: SocketAddr) -> DirectLink {
+    DirectLink {
+        sink,
+        stream,
+        path: format!("TCP {}", address),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::SinkExt;
+
+    fn remote(device_id: &str, local: &LocalPeer, protocol: Option<CandidateProtocol>) -> Result<RemotePeer> {
+        let candidates = local.candidates.iter()
+            .filter(|c| protocol.is_none_or(|p| c.protocol == p))
+            .copied()
+            .collect();
+        RemotePeer::new(device_id.to_string(), candidates, &local.fingerprint())
+    }
+
+    /// Connect two local peers offering only `protocol` candidates, or all of them
+    async fn connect_pair(protocol: Option<CandidateProtocol>) -> Result<(DirectLink, DirectLink)> {
+        let a = LocalPeer::gather(None).await?;
+        let b = LocalPeer::gather(None).await?;
+        let for_a = remote("222", &b, protocol)?;
+        let for_b = remote("111", &a, protocol)?;
+
+        let (a, b) = tokio::join!(a.connect("111", "session", &for_a), b.connect("222", "session", &for_b));
+        Ok((a?, b?))
+    }
+
+    async fn exchange(a: &mut DirectLink, b: &mut DirectLink) -> Result<()> {
+        let ping = WsMessage::Text(r#"{"type":"MouseMove","x":1,"y":2}"#.to_string());
+        a.sink.send(ping.clone()).await?;
+        let received = tokio::time::timeout(Duration::from_secs(5), b.stream.next()).await?;
+        assert_eq!(received.context("Link closed")??, ping);
+
+        let pong = WsMessage::Text(r#"{"type":"ClipboardUpdate","content":"x","mime_type":"text/plain"}"#.to_string());
+        b.sink.send(pong.clone()).await?;
+        let received = tokio::time::timeout(Duration::from_secs(5), a.stream.next()).await?;
+        assert_eq!(received.context("Link closed")??, pong);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_direct_udp_link() -> Result<()> {
+        let (mut a, mut b) = connect_pair(None).await?;
+        assert!(a.path.starts_with("UDP 127.0.0.1:"), "{}", a.path);
+        assert!(b.path.starts_with("UDP 127.0.0.1:"), "{}", b.path);
+        exchange(&mut a, &mut b).await
+    }
+
+    #[tokio::test]
+    async fn test_direct_tcp_link() -> Result<()> {
+        let (mut a, mut b) = connect_pair(Some(CandidateProtocol::Tcp)).await?;
+        assert!(a.path.starts_with("TCP "), "{}", a.path);
+        assert!(b.path.starts_with("TCP "), "{}", b.path);
+        exchange(&mut a, &mut b).await
+    }
+
+    #[tokio::test]
+    async fn test_wrong_fingerprint_is_rejected() -> Result<()> {
+        let a = LocalPeer::gather(None).await?;
+        let b = LocalPeer::gather(None).await?;
+        let for_a = remote("222", &b, None)?;
+        let mut for_b = remote("111", &a, None)?;
+        for_b.fingerprint[0] ^= 0xff;
+
+        let (a, b) = tokio::join!(a.connect("111", "session", &for_a), b.connect("222", "session", &for_b));
+        assert!(a.is_err());
+        assert!(b.is_err());
+        Ok(())
+    }
+}
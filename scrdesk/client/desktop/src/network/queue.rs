@@ -0,0 +1,361 @@
+//! Outgoing messages, queued by class so a file chunk or a large frame never holds up
+//! a keystroke.
+//!
+//! Each class has a bounded queue and the connection task takes from them by weighted
+//! round robin, so every class gets a share of the connection while interactive traffic
+//! gets most of it. Input and cursor moves keep their relative order. When a queue is
+//! full, stale video and mouse moves give way to new ones: the oldest frame is dropped,
+//! and a move is dropped once a later move supersedes it. Other classes make the sender
+//! wait for room.
+
+use anyhow::{bail, Result};
+use crate::protocol::Message;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+use super::resume::KeyframeRequest;
+use super::stats::SessionStats;
+
+/// What a message is, for how it is queued
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrafficClass {
+    Control,
+    Input,
+    Cursor,
+    Video,
+    Bulk,
+}
+
+const CLASSES: [TrafficClass; 5] = [
+    TrafficClass::Control,
+    TrafficClass::Input,
+    TrafficClass::Cursor,
+    TrafficClass::Video,
+    TrafficClass::Bulk,
+];
+
+impl TrafficClass {
+    pub fn of(message: &Message) -> Self {
+        match message {
+            Message::MouseMove { .. } => Self::Cursor,
+            message if message.is_input() => Self::Input,
+            Message::VideoFrame { .. } => Self::Video,
+            message if message.is_bulk() => Self::Bulk,
+            _ => Self::Control,
+        }
+    }
+
+    fn capacity(self) -> usize {
+        match self {
+            Self::Control => 256,
+            Self::Input => 128,
+            Self::Cursor => 32,
+            Self::Video => 3, // A frame older than that is not worth sending
+            Self::Bulk => 4,
+        }
+    }
+
+    /// Messages taken from the class per round while others are waiting too
+    fn weight(self) -> u32 {
+        match self {
+            Self::Control => 8,
+            Self::Input => 8,
+            Self::Cursor => 4,
+            Self::Video => 2,
+            Self::Bulk => 1,
+        }
+    }
+}
+
+/// How a message was queued
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SendStatus {
+    Queued,
+    Congested, // Queued, but stale video or mouse moves were dropped to make room
+}
+
+struct Queues {
+    queues: [VecDeque<(u64, Message)>; 5], // By class, with the order they were queued in
+    credits: [u32; 5],
+    next_seq: u64,
+    closed: bool,
+}
+
+impl Queues {
+    fn new() -> Self {
+        Self {
+            queues: Default::default(),
+            credits: CLASSES.map(TrafficClass::weight),
+            next_seq: 0,
+            closed: false,
+        }
+    }
+
+    fn queue(&mut self, class: TrafficClass) -> &mut VecDeque<(u64, Message)> {
+        &mut self.queues[class as usize]
+    }
+
+    /// Queue the message if there is room or stale messages to make room, else hand it back
+    fn try_push(&mut self, message: Message, keyframe: &KeyframeRequest, stats: &SessionStats) -> Result<SendStatus, Message> {
+        let class = TrafficClass::of(&message);
+        let mut status = SendStatus::Queued;
+        if self.queue(class).len() >= class.capacity() {
+            match class {
+                TrafficClass::Video => {
+                    if let Some((_, Message::VideoFrame { is_keyframe: true, .. })) = self.queue(class).pop_front() {
+                        keyframe.set();
+                    }
+                    stats.frame_dropped();
+                }
+                TrafficClass::Cursor => match self.superseded_move() {
+                    Some(index) => {
+                        self.queue(class).remove(index);
+                    }
+                    None => return Err(message),
+                },
+                _ => return Err(message),
+            }
+            status = SendStatus::Congested;
+        }
+
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.queue(class).push_back((seq, message));
+        Ok(status)
+    }
+
+    /// The oldest queued move that a later one supersedes, with no other input between
+    fn superseded_move(&self) -> Option<usize> {
+        let moves = &self.queues[TrafficClass::Cursor as usize];
+        let input = &self.queues[TrafficClass::Input as usize];
+        (0..moves.len()).find(|&i| {
+            let (seq, next) = (moves[i].0, moves.get(i + 1).map_or(self.next_seq, |(next, _)| *next));
+            !input.iter().any(|(between, _)| *between > seq && *between < next)
+        })
+    }
+
+    /// The next message to send, by weighted round robin over the classes
+    fn pop(&mut self) -> Option<Message> {
+        if self.queues.iter().all(VecDeque::is_empty) {
+            return None;
+        }
+        loop {
+            if let Some(class) = CLASSES
+                .into_iter()
+                .find(|class| self.credits[*class as usize] > 0 && !self.queues[*class as usize].is_empty())
+            {
+                self.credits[class as usize] -= 1;
+                return self.take(class);
+            }
+            self.credits = CLASSES.map(TrafficClass::weight);
+        }
+    }
+
+    fn take(&mut self, class: TrafficClass) -> Option<Message> {
+        let class = match class {
+            // Whichever of input and cursor moves was queued first goes first
+            TrafficClass::Input | TrafficClass::Cursor => {
+                let head = |queue: &VecDeque<(u64, Message)>| queue.front().map_or(u64::MAX, |(seq, _)| *seq);
+                if head(self.queue(TrafficClass::Input)) < head(self.queue(TrafficClass::Cursor)) {
+                    TrafficClass::Input
+                } else {
+                    TrafficClass::Cursor
+                }
+            }
+            class => class,
+        };
+        self.queue(class).pop_front().map(|(_, message)| message)
+    }
+
+    fn len(&self) -> usize {
+        self.queues.iter().map(VecDeque::len).sum()
+    }
+}
+
+/// The outgoing queues of a connection, shared by the senders and the connection task
+#[derive(Clone)]
+pub struct OutgoingQueue {
+    queues: Arc<Mutex<Queues>>,
+    ready: Arc<Notify>, // Something was queued
+    room: Arc<Notify>,  // Something was taken, or the queue closed
+    keyframe: KeyframeRequest,
+    stats: SessionStats,
+}
+
+impl OutgoingQueue {
+    /// Dropped keyframes are requested again through `keyframe`, and dropped frames
+    /// counted in `stats`
+    pub fn new(keyframe: KeyframeRequest, stats: SessionStats) -> Self {
+        Self {
+            queues: Arc::new(Mutex::new(Queues::new())),
+            ready: Arc::new(Notify::new()),
+            room: Arc::new(Notify::new()),
+            keyframe,
+            stats,
+        }
+    }
+
+    /// Queue a message, waiting for room unless stale messages can give way
+    pub async fn push(&self, mut message: Message) -> Result<SendStatus> {
+        loop {
+            let room = self.room.notified();
+            tokio::pin!(room);
+            room.as_mut().enable();
+
+            {
+                let mut queues = self.queues.lock().unwrap();
+                if queues.closed {
+                    bail!("Connection closed");
+                }
+                match queues.try_push(message, &self.keyframe, &self.stats) {
+                    Ok(status) => {
+                        self.ready.notify_one();
+                        return Ok(status);
+                    }
+                    Err(returned) => message = returned,
+                }
+            }
+
+            room.await;
+        }
+    }
+
+    /// The next message to send; safe to cancel
+    pub async fn next(&self) -> Message {
+        loop {
+            let ready = self.ready.notified();
+            tokio::pin!(ready);
+            ready.as_mut().enable();
+
+            if let Some(message) = self.queues.lock().unwrap().pop() {
+                self.room.notify_waiters();
+                return message;
+            }
+
+            ready.await;
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.queues.lock().unwrap().len()
+    }
+
+    /// Fail queued and future sends; the connection has given up
+    pub fn close(&self) {
+        let mut queues = self.queues.lock().unwrap();
+        queues.closed = true;
+        queues.queues.iter_mut().for_each(VecDeque::clear);
+        self.room.notify_waiters();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn queue() -> (OutgoingQueue, KeyframeRequest, SessionStats) {
+        let (keyframe, stats) = (KeyframeRequest::default(), SessionStats::default());
+        (OutgoingQueue::new(keyframe.clone(), stats.clone()), keyframe, stats)
+    }
+
+    fn frame(timestamp: u64, is_keyframe: bool) -> Message {
+        Message::VideoFrame { data: Vec::new(), width: 1, height: 1, timestamp, is_keyframe, after_input: false }
+    }
+
+    fn chunk(chunk_index: u64) -> Message {
+        Message::FileChunk { transfer_id: "t".to_string(), chunk_index, data: Vec::new() }
+    }
+
+    fn click() -> Message {
+        Message::MouseButton { button: crate::protocol::MouseButton::Left, pressed: true }
+    }
+
+    fn drain(queue: &OutgoingQueue) -> Vec<Message> {
+        let mut messages = Vec::new();
+        while let Some(message) = queue.queues.lock().unwrap().pop() {
+            messages.push(message);
+        }
+        messages
+    }
+
+    #[tokio::test]
+    async fn test_weighted_classes() {
+        let (queue, _, _) = queue();
+        for i in 0..4 {
+            queue.push(chunk(i)).await.unwrap();
+        }
+        for _ in 0..20 {
+            queue.push(Message::Ping { timestamp: None }).await.unwrap();
+        }
+
+        // Control goes first, but file data still gets its share
+        let order: Vec<TrafficClass> = drain(&queue).iter().map(TrafficClass::of).collect();
+        let first_chunk = order.iter().position(|class| *class == TrafficClass::Bulk).unwrap();
+        assert_eq!(first_chunk, 8);
+        assert_eq!(order.iter().filter(|class| **class == TrafficClass::Bulk).count(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_stale_video_gives_way() {
+        let (queue, keyframe, stats) = queue();
+        for timestamp in 0..3 {
+            assert_eq!(queue.push(frame(timestamp, timestamp == 0)).await.unwrap(), SendStatus::Queued);
+        }
+
+        // The dropped frame was a keyframe, so the next capture must be one
+        assert_eq!(queue.push(frame(3, false)).await.unwrap(), SendStatus::Congested);
+        assert!(keyframe.take());
+        assert_eq!(stats.snapshot().frames_dropped, 1);
+
+        let timestamps: Vec<u64> = drain(&queue)
+            .into_iter()
+            .map(|m| match m {
+                Message::VideoFrame { timestamp, .. } => timestamp,
+                _ => unreachable!(),
+            })
+            .collect();
+        assert_eq!(timestamps, vec![1, 2, 3]);
+    }
+
+    #[tokio::test]
+    async fn test_mouse_moves_coalesce_around_clicks() {
+        let (queue, _, _) = queue();
+        let capacity = TrafficClass::Cursor.capacity() as i32;
+        queue.push(Message::MouseMove { x: 0, y: 0 }).await.unwrap();
+        queue.push(click()).await.unwrap();
+        for x in 1..capacity {
+            queue.push(Message::MouseMove { x, y: 0 }).await.unwrap();
+        }
+
+        // The move before the click stays; the oldest one after it gives way
+        assert_eq!(queue.push(Message::MouseMove { x: 99, y: 0 }).await.unwrap(), SendStatus::Congested);
+        let sent = drain(&queue);
+        assert!(matches!(sent[..3], [Message::MouseMove { x: 0, .. }, Message::MouseButton { .. }, Message::MouseMove { x: 2, .. }]));
+        assert!(matches!(sent.last(), Some(Message::MouseMove { x: 99, .. })));
+        assert_eq!(sent.len(), capacity as usize + 1);
+    }
+
+    #[tokio::test]
+    async fn test_full_queue_makes_sender_wait() {
+        let (queue, _, _) = queue();
+        for i in 0..TrafficClass::Bulk.capacity() as u64 {
+            queue.push(chunk(i)).await.unwrap();
+        }
+
+        let waiting = tokio::spawn({
+            let queue = queue.clone();
+            async move { queue.push(chunk(99)).await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!waiting.is_finished());
+
+        assert!(matches!(queue.next().await, Message::FileChunk { chunk_index: 0, .. }));
+        assert_eq!(waiting.await.unwrap().unwrap(), SendStatus::Queued);
+
+        // Once closed, senders are told instead of waiting forever
+        queue.close();
+        assert!(queue.push(chunk(100)).await.is_err());
+        assert_eq!(queue.len(), 0);
+    }
+}
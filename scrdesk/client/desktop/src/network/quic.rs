@@ -0,0 +1,434 @@
+//! QUIC transport to the relay. The framing must match the relay's QUIC listener.
+//!
+//! Each side sends on unidirectional streams so that a lost packet only stalls
+//! the traffic that needs it: control and input share one high-priority stream,
+//! file data has its own stream, and every video frame travels on a fresh stream
+//! so a frame that is still being retransmitted never holds up the next one.
+//! Messages are framed as a kind byte, a big-endian u32 length and the payload.
+
+use anyhow::{bail, Context as _, Result};
+use futures::{Sink, Stream, StreamExt};
+use quinn::crypto::rustls::QuicClientConfig;
+use quinn::{Connection, ConnectionError, Endpoint, RecvStream, SendStream, TransportConfig, VarInt};
+use serde::Deserialize;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::sync::mpsc;
+use tokio_tungstenite::tungstenite::{Error as WsError, Message as WsMessage};
+use super::RelayEndpoint;
+
+pub const ALPN: &[u8] = b"scrdesk-relay";
+
+const MAX_FRAME_LEN: usize = 64 * 1024 * 1024;
+const MAX_UNI_STREAMS: u32 = 256; // Video frames in flight per connection
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(10); // Keeps NAT mappings open
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(3); // Give up quickly when UDP is blocked
+const BULK_QUEUE_CAPACITY: usize = 4;
+const MEDIA_TYPES: &[&str] = &["VideoFrame", "SealedMedia"];
+const BULK_TYPES: &[&str] = &["FileChunk", "FileDelta", "SealedBulk"]; // Same as Message::is_bulk
+
+/// Which stream a message travels on; sent as the first byte of each stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum StreamKind {
+    Control = 0,
+    Media = 1,
+    Bulk = 2,
+}
+
+impl StreamKind {
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(Self::Control),
+            1 => Some(Self::Media),
+            2 => Some(Self::Bulk),
+            _ => None,
+        }
+    }
+
+    /// Higher values are sent first when the connection is congested
+    fn priority(self) -> i32 {
+        match self {
+            Self::Control => 2,
+            Self::Media => 1,
+            Self::Bulk => 0,
+        }
+    }
+}
+
+/// Connect to the relay's QUIC endpoint, on the same port as its WebSocket listener
+pub async fn connect(relay: &RelayEndpoint) -> Result<(QuicSink, QuicStream)> {
+    let (host, port) = relay.host_and_port()?;
+    let addr = tokio::net::lookup_host((host.as_str(), port))
+        .await?
+        .next()
+        .context("Relay host did not resolve")?;
+
+    let crypto = relay.client_config(&[ALPN])?;
+    let mut config = quinn::ClientConfig::new(Arc::new(QuicClientConfig::try_from(crypto)?));
+    config.transport_config(Arc::new(transport_config()));
+
+    let bind_addr = if addr.is_ipv6() { "[::]:0" } else { "0.0.0.0:0" };
+    let endpoint = Endpoint::client(bind_addr.parse()?)?;
+    let connecting = endpoint.connect_with(config, addr, &host)?;
+    let connection = tokio::time::timeout(CONNECT_TIMEOUT, connecting)
+        .await
+        .context("QUIC handshake timed out")??;
+    Ok(split(connection))
+}
+
+pub(super) fn transport_config() -> TransportConfig {
+    let mut transport = TransportConfig::default();
+    transport.max_concurrent_uni_streams(VarInt::from_u32(MAX_UNI_STREAMS));
+    transport.max_concurrent_bidi_streams(VarInt::from_u32(0));
+    transport.max_idle_timeout(IDLE_TIMEOUT.try_into().ok());
+    transport.keep_alive_interval(Some(KEEP_ALIVE_INTERVAL));
+    transport
+}
+
+pub type QuicSink = Pin<Box<dyn Sink<WsMessage, Error = WsError> + Send>>;
+
+/// Incoming messages from all of a connection's streams. Dropping it closes the connection.
+pub struct QuicStream {
+    connection: Connection,
+    incoming: mpsc::UnboundedReceiver<Result<WsMessage, WsError>>,
+}
+
+impl Stream for QuicStream {
+    type Item = Result<WsMessage, WsError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.incoming.poll_recv(cx)
+    }
+}
+
+impl Drop for QuicStream {
+    fn drop(&mut self) {
+        self.connection.close(VarInt::from_u32(0), b"closed");
+    }
+}
+
+/// Split a connection into a sink and a stream of WebSocket messages, so it can be
+/// used like a WebSocket connection. Pings are answered automatically.
+pub fn split(connection: Connection) -> (QuicSink, QuicStream) {
+    let (control_tx, mut control_rx) = mpsc::unbounded_channel();
+    let (bulk_tx, mut bulk_rx) = mpsc::channel(BULK_QUEUE_CAPACITY);
+    let (incoming_tx, incoming_rx) = mpsc::unbounded_channel();
+
+    let control_messages = futures::stream::poll_fn(move |cx| control_rx.poll_recv(cx));
+    let bulk_messages = futures::stream::poll_fn(move |cx| bulk_rx.poll_recv(cx));
+    tokio::spawn(write_stream(connection.clone(), StreamKind::Control, control_messages));
+    tokio::spawn(write_stream(connection.clone(), StreamKind::Bulk, bulk_messages));
+    tokio::spawn(read_streams(connection.clone(), incoming_tx, control_tx.clone()));
+
+    let writer = Writer {
+        connection: connection.clone(),
+        control: control_tx,
+        bulk: bulk_tx,
+        media_sequence: 0,
+    };
+    let sink = futures::sink::unfold(writer, |mut writer, message| async move {
+        writer.send(message).await?;
+        Ok::<_, WsError>(writer)
+    });
+
+    let stream = QuicStream {
+        connection,
+        incoming: incoming_rx,
+    };
+    (Box::pin(sink), stream)
+}
+
+struct Writer {
+    connection: Connection,
+    control: mpsc::UnboundedSender<WsMessage>,
+    bulk: mpsc::Sender<WsMessage>,
+    media_sequence: u64,
+}
+
+impl Writer {
+    async fn send(&mut self, message: WsMessage) -> Result<(), WsError> {
+        match classify(&message) {
+            StreamKind::Control => self.control.send(message).map_err(|_| closed()),
+            StreamKind::Bulk => self.bulk.send(message).await.map_err(|_| closed()),
+            StreamKind::Media => {
+                self.media_sequence += 1;
+                let connection = self.connection.clone();
+                let sequence = self.media_sequence;
+                tokio::spawn(async move {
+                    if let Err(e) = send_media(&connection, sequence, &message).await {
+                        tracing::debug!("Failed to send video frame: {}", e);
+                    }
+                });
+                Ok(())
+            }
+        }
+    }
+}
+
+fn classify(message: &WsMessage) -> StreamKind {
+    #[derive(Deserialize)]
+    struct MessageType {
+        #[serde(rename = "type")]
+        kind: String,
+    }
+
+    let kind = match message {
+        WsMessage::Binary(_) => return StreamKind::Media,
+        WsMessage::Text(text) => match serde_json::from_str::<MessageType>(text) {
+            Ok(message) => message.kind,
+            Err(_) => return StreamKind::Control,
+        },
+        _ => return StreamKind::Control,
+    };
+
+    if MEDIA_TYPES.contains(&kind.as_str()) {
+        StreamKind::Media
+    } else if BULK_TYPES.contains(&kind.as_str()) {
+        StreamKind::Bulk
+    } else {
+        StreamKind::Control
+    }
+}
+
+async fn open(connection: &Connection, kind: StreamKind) -> Result<SendStream> {
+    let mut stream = connection.open_uni().await?;
+    stream.set_priority(kind.priority())?;
+    stream.write_all(&[kind as u8]).await?;
+    Ok(stream)
+}
+
+/// Send one video frame on its own stream, tagged with a sequence number so the
+/// receiver can drop frames that arrive after a newer one
+async fn send_media(connection: &Connection, sequence: u64, message: &WsMessage) -> Result<()> {
+    let mut stream = open(connection, StreamKind::Media).await?;
+    let mut buf = sequence.to_be_bytes().to_vec();
+    encode_frame(&mut buf, message)?;
+    stream.write_all(&buf).await?;
+    stream.finish()?;
+    Ok(())
+}
+
+async fn write_stream(connection: Connection, kind: StreamKind, mut messages: impl Stream<Item = WsMessage> + Unpin) {
+    let mut stream = None;
+    while let Some(message) = messages.next().await {
+        let result = async {
+            if stream.is_none() {
+                stream = Some(open(&connection, kind).await?);
+            }
+            let mut buf = Vec::new();
+            encode_frame(&mut buf, &message)?;
+            stream.as_mut().unwrap().write_all(&buf).await?;
+            anyhow::Ok(())
+        }
+        .await;
+
+        if let Err(e) = result {
+            tracing::debug!("QUIC {:?} stream closed: {}", kind, e);
+            return;
+        }
+        if let WsMessage::Close(_) = message {
+            connection.close(VarInt::from_u32(0), b"closed");
+            return;
+        }
+    }
+}
+
+async fn read_streams(
+    connection: Connection,
+    incoming: mpsc::UnboundedSender<Result<WsMessage, WsError>>,
+    control: mpsc::UnboundedSender<WsMessage>,
+) {
+    let latest_media = Arc::new(AtomicU64::new(0));
+    loop {
+        match connection.accept_uni().await {
+            Ok(stream) => {
+                let incoming = incoming.clone();
+                let control = control.clone();
+                let latest_media = latest_media.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = read_stream(stream, &incoming, &control, &latest_media).await {
+                        tracing::debug!("QUIC stream failed: {}", e);
+                    }
+                });
+            }
+            Err(ConnectionError::ApplicationClosed(_) | ConnectionError::LocallyClosed) => return,
+            Err(e) => {
+                let _ = incoming.send(Err(WsError::Io(io::Error::other(e))));
+                return;
+            }
+        }
+    }
+}
+
+async fn read_stream(
+    mut stream: RecvStream,
+    incoming: &mpsc::UnboundedSender<Result<WsMessage, WsError>>,
+    control: &mpsc::UnboundedSender<WsMessage>,
+    latest_media: &AtomicU64,
+) -> Result<()> {
+    let Some(kind) = StreamKind::from_u8(stream.read_u8().await?) else {
+        bail!("Unknown stream kind");
+    };
+
+    if kind == StreamKind::Media {
+        let sequence = stream.read_u64().await?;
+        if let Some(message) = read_frame(&mut stream).await? {
+            if latest_media.fetch_max(sequence, Ordering::Relaxed) < sequence {
+                let _ = incoming.send(Ok(message));
+            }
+        }
+        return Ok(());
+    }
+
+    while let Some(message) = read_frame(&mut stream).await? {
+        if let WsMessage::Ping(data) = &message {
+            let _ = control.send(WsMessage::Pong(data.clone()));
+        }
+        if incoming.send(Ok(message)).is_err() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+fn encode_frame(buf: &mut Vec<u8>, message: &WsMessage) -> Result<()> {
+    let (kind, payload): (u8, &[u8]) = match message {
+        WsMessage::Text(text) => (0, text.as_bytes()),
+        WsMessage::Binary(data) => (1, data),
+        WsMessage::Ping(data) => (2, data),
+        WsMessage::Pong(data) => (3, data),
+        WsMessage::Close(_) => (4, &[]),
+        WsMessage::Frame(_) => bail!("Raw frames cannot be sent over QUIC"),
+    };
+
+    buf.push(kind);
+    buf.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    buf.extend_from_slice(payload);
+    Ok(())
+}
+
+/// Read the next frame, or None at the end of the stream
+async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Option<WsMessage>> {
+    let kind = match reader.read_u8().await {
+        Ok(kind) => kind,
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+
+    let len = reader.read_u32().await? as usize;
+    if len > MAX_FRAME_LEN {
+        bail!("Frame of {} bytes exceeds the limit", len);
+    }
+    let mut payload = vec![0u8; len];
+    reader.read_exact(&mut payload).await?;
+
+    let message = match kind {
+        0 => WsMessage::Text(String::from_utf8(payload)?),
+        1 => WsMessage::Binary(payload),
+        2 => WsMessage::Ping(payload),
+        3 => WsMessage::Pong(payload),
+        4 => WsMessage::Close(None),
+        _ => bail!("Unknown frame kind {}", kind),
+    };
+    Ok(Some(message))
+}
+
+fn closed() -> WsError {
+    WsError::ConnectionClosed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::tls::tests::{CERTIFICATE, FINGERPRINT, KEY};
+    use futures::SinkExt;
+    use quinn::crypto::rustls::QuicServerConfig;
+
+    fn server_endpoint() -> Result<Endpoint> {
+        let certificates = rustls_pemfile::certs(&mut CERTIFICATE.as_bytes()).collect::<Result<Vec<_>, _>>()?;
+        let key = rustls_pemfile::private_key(&mut KEY.as_bytes())?.context("No key")?;
+
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let mut crypto = rustls::ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_single_cert(certificates, key)?;
+        crypto.alpn_protocols = vec![ALPN.to_vec()];
+
+        let config = quinn::ServerConfig::with_crypto(Arc::new(QuicServerConfig::try_from(crypto)?));
+        Ok(Endpoint::server(config, "127.0.0.1:0".parse()?)?)
+    }
+
+    async fn next(stream: &mut QuicStream) -> Result<WsMessage> {
+        let message = tokio::time::timeout(Duration::from_secs(5), stream.next()).await?;
+        Ok(message.context("Stream ended")??)
+    }
+
+    #[tokio::test]
+    async fn test_connect_with_pinned_key() -> Result<()> {
+        let server = server_endpoint()?;
+        let url = format!("wss://127.0.0.1:{}", server.local_addr()?.port());
+
+        let wrong_pin = FINGERPRINT.replace("dad7", "0000");
+        let relay = RelayEndpoint::new(url.clone(), Some(&wrong_pin))?;
+        let accepting = tokio::spawn(async move {
+            let connection = server.accept().await.unwrap().await;
+            (server, connection)
+        });
+        assert!(connect(&relay).await.is_err());
+        let (server, connection) = accepting.await?;
+        assert!(connection.is_err());
+
+        let relay = RelayEndpoint::new(url, Some(FINGERPRINT))?;
+        let (client, accepted) = tokio::join!(connect(&relay), async { server.accept().await.unwrap().await });
+        let (mut client_sink, mut client_stream) = client?;
+        let server_connection = accepted?;
+        let (_server_sink, mut server_stream) = split(server_connection.clone());
+
+        let hello = WsMessage::Text(r#"{"type":"Hello","device_id":"111"}"#.to_string());
+        client_sink.send(hello.clone()).await?;
+        assert_eq!(next(&mut server_stream).await?, hello);
+
+        // A video frame arriving after a newer one is dropped
+        let frame = |n: u64| WsMessage::Text(format!(r#"{{"type":"VideoFrame","data":[{}]}}"#, n));
+        send_media(&server_connection, 5, &frame(5)).await?;
+        assert_eq!(next(&mut client_stream).await?, frame(5));
+        send_media(&server_connection, 3, &frame(3)).await?;
+        send_media(&server_connection, 6, &frame(6)).await?;
+        assert_eq!(next(&mut client_stream).await?, frame(6));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_frame_round_trip() -> Result<()> {
+        let messages = [
+            WsMessage::Text("hello".to_string()),
+            WsMessage::Binary(vec![1, 2, 3]),
+            WsMessage::Ping(vec![4]),
+            WsMessage::Pong(Vec::new()),
+            WsMessage::Close(None),
+        ];
+
+        let mut buf = Vec::new();
+        for message in &messages {
+            encode_frame(&mut buf, message)?;
+        }
+
+        let mut reader = buf.as_slice();
+        for message in messages {
+            assert_eq!(read_frame(&mut reader).await?, Some(message));
+        }
+        assert_eq!(read_frame(&mut reader).await?, None);
+
+        // A truncated frame is an error rather than the end of the stream
+        let mut truncated = &buf[..3];
+        assert!(read_frame(&mut truncated).await.is_err());
+        Ok(())
+    }
+}
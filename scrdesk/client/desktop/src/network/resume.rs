@@ -0,0 +1,325 @@
+//! Resuming a session after the relay connection drops.
+//!
+//! The relay holds a session for a grace period when a participant's connection drops,
+//! and hands out a token with `ConnectResponse` that rebinds the reconnected client to
+//! it. Messages the relay or the dead connection lost in the meantime are recovered end
+//! to end: control messages are numbered inside the seal and kept until the peer
+//! acknowledges them, and both sides send the unacknowledged ones again once either
+//! resumes. The receiver takes them strictly in order, so duplicates and anything after
+//! a gap are dropped until the gap is filled. Video is not resent but restarts with a
+//! keyframe.
+
+use crate::protocol::{Message, ParticipantInfo, ParticipantRole};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+
+const MAX_UNACKED: usize = 4096; // Control messages kept for sending again
+const ACK_DELAY: Duration = Duration::from_millis(200); // Acknowledge in batches
+
+/// Set when a viewer needs a keyframe to pick the video up again; the capture loop takes it
+#[derive(Clone, Default)]
+pub struct KeyframeRequest(Arc<AtomicBool>);
+
+impl KeyframeRequest {
+    pub fn take(&self) -> bool {
+        self.0.swap(false, Ordering::Relaxed)
+    }
+
+    pub(super) fn set(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Resumption state of the session of one connection
+pub struct ResumeState {
+    own_id: String,
+    session: Option<(String, String)>, // Session ID and the token to resume it with
+    counterpart: Option<Option<String>>, // Where our control messages go, once a roster was seen
+    next_seq: u64,
+    unacked: VecDeque<(u64, Message)>,
+    received: HashMap<String, u64>, // Last numbered message taken from each sender
+    ack_pending: HashSet<String>,
+    ack_due: Option<Instant>,
+    keyframe: KeyframeRequest,
+    awaiting_keyframe: bool, // Video is held back until a keyframe goes out
+}
+
+impl ResumeState {
+    pub fn new(own_id: String, keyframe: KeyframeRequest) -> Self {
+        Self {
+            own_id,
+            session: None,
+            counterpart: None,
+            next_seq: 1,
+            unacked: VecDeque::new(),
+            received: HashMap::new(),
+            ack_pending: HashSet::new(),
+            ack_due: None,
+            keyframe,
+            awaiting_keyframe: false,
+        }
+    }
+
+    /// A session was opened or joined. Without a token from the relay it cannot be resumed.
+    pub fn session_opened(&mut self, session_id: &str, resume_token: Option<&str>) {
+        self.reset();
+        self.session = resume_token.map(|token| (session_id.to_string(), token.to_string()));
+    }
+
+    pub fn reset(&mut self) {
+        self.session = None;
+        self.counterpart = None;
+        self.next_seq = 1;
+        self.unacked.clear();
+        self.received.clear();
+        self.ack_pending.clear();
+        self.ack_due = None;
+        self.awaiting_keyframe = false;
+    }
+
+    /// Whether the relay holds the session for us if the connection drops
+    pub fn is_resumable(&self) -> bool {
+        self.session.is_some()
+    }
+
+    pub fn is_resume_of(&self, session_id: &str) -> bool {
+        self.session.as_ref().is_some_and(|(id, _)| id == session_id)
+    }
+
+    /// Sent after Hello on a new connection to rebind to the session
+    pub fn resume_message(&self) -> Option<Message> {
+        let (session_id, resume_token) = self.session.clone()?;
+        Some(Message::ResumeSession { session_id, resume_token })
+    }
+
+    /// We or a peer rebound to the session: returns the unacknowledged control messages
+    /// to send again and has the video restart with a keyframe
+    pub fn resumed(&mut self) -> Vec<Message> {
+        self.keyframe.set();
+        self.awaiting_keyframe = true;
+        self.unacked
+            .iter()
+            .map(|(seq, message)| Message::Numbered { seq: *seq, message: Box::new(message.clone()) })
+            .collect()
+    }
+
+    /// Unacknowledged messages were meant for whoever had control; they are dropped when
+    /// control moves on, rather than replayed to someone else. The numbering taken from
+    /// senders starts over too, as they went on numbering for others in between.
+    pub fn participants_changed(&mut self, participants: &[ParticipantInfo]) {
+        let role_of = |role| participants.iter().find(|p| p.role == role).map(|p| p.device_id.clone());
+        let counterpart = match participants.iter().find(|p| p.device_id == self.own_id).map(|p| p.role) {
+            Some(ParticipantRole::Host) => role_of(ParticipantRole::Controller),
+            Some(ParticipantRole::Controller) => role_of(ParticipantRole::Host),
+            Some(ParticipantRole::Observer) | None => None,
+        };
+
+        if self.counterpart.as_ref().is_some_and(|previous| *previous != counterpart) {
+            self.unacked.clear();
+            self.received.clear();
+            self.ack_pending.clear();
+        }
+        self.counterpart = Some(counterpart);
+    }
+
+    /// Number outgoing control messages of a resumable session and hold back video
+    /// until a keyframe. Returns None for messages not to send.
+    pub fn outgoing(&mut self, message: Message) -> Option<Message> {
+        match message {
+            Message::VideoFrame { is_keyframe, .. } => {
+                if self.awaiting_keyframe && !is_keyframe {
+                    return None;
+                }
+                self.awaiting_keyframe = false;
+                Some(message)
+            }
+            Message::Numbered { .. } | Message::NumberedAck { .. } => Some(message),
+            message if self.session.is_some() && message.is_session_content() && !message.is_bulk() => {
+                if self.unacked.len() == MAX_UNACKED {
+                    tracing::warn!("Too many unacknowledged control messages, dropping the oldest");
+                    self.unacked.pop_front();
+                }
+                let seq = self.next_seq;
+                self.next_seq += 1;
+                self.unacked.push_back((seq, message.clone()));
+                Some(Message::Numbered { seq, message: Box::new(message) })
+            }
+            message => Some(message),
+        }
+    }
+
+    /// Take a message opened from `sender`: unwraps numbered messages in order and
+    /// applies acknowledgments. Returns None for messages not to hand on.
+    pub fn incoming(&mut self, sender: &str, message: Message) -> Option<Message> {
+        match message {
+            Message::Numbered { seq, message } => {
+                self.ack_pending.insert(sender.to_string());
+                self.ack_due.get_or_insert_with(|| Instant::now() + ACK_DELAY);
+
+                // The first message from a sender sets where its numbering stands
+                if self.received.get(sender).is_some_and(|last| seq != last + 1) {
+                    return None;
+                }
+                self.received.insert(sender.to_string(), seq);
+
+                match *message {
+                    Message::Numbered { .. } | Message::NumberedAck { .. } => None,
+                    message if message.is_session_content() && !message.is_bulk() => Some(message),
+                    _ => {
+                        tracing::warn!("Dropping a numbered message from {} of an unexpected type", sender);
+                        None
+                    }
+                }
+            }
+            Message::NumberedAck { device_id, seq } => {
+                if device_id == self.own_id {
+                    self.unacked.retain(|(sent, _)| *sent > seq);
+                }
+                None
+            }
+            message => Some(message),
+        }
+    }
+
+    /// Wait until acknowledgments are due; never resolves while there are none.
+    /// Returns the messages to send.
+    pub async fn next_ack(&mut self) -> Vec<Message> {
+        match self.ack_due {
+            Some(due) => tokio::time::sleep_until(due).await,
+            None => std::future::pending().await,
+        }
+
+        self.ack_due = None;
+        self.ack_pending
+            .drain()
+            .filter_map(|device_id| {
+                let seq = *self.received.get(&device_id)?;
+                Some(Message::NumberedAck { device_id, seq })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(own_id: &str) -> (ResumeState, KeyframeRequest) {
+        let keyframe = KeyframeRequest::default();
+        let mut state = ResumeState::new(own_id.to_string(), keyframe.clone());
+        state.session_opened("s1", Some("token"));
+        (state, keyframe)
+    }
+
+    fn mouse_move(x: i32) -> Message {
+        Message::MouseMove { x, y: 0 }
+    }
+
+    fn frame(is_keyframe: bool) -> Message {
+        Message::VideoFrame { data: Vec::new(), width: 1, height: 1, timestamp: 0, is_keyframe, after_input: false }
+    }
+
+    #[tokio::test]
+    async fn test_lost_messages_are_sent_again() {
+        let (mut viewer, _) = state("viewer");
+        let (mut host, _) = state("host");
+        assert!(matches!(viewer.resume_message(), Some(Message::ResumeSession { .. })));
+
+        let sent: Vec<Message> = (1..=3).map(|x| viewer.outgoing(mouse_move(x)).unwrap()).collect();
+        assert!(matches!(sent[0], Message::Numbered { seq: 1, .. }));
+        assert!(host.incoming("viewer", sent[0].clone()).is_some());
+
+        // The second is lost, so the third waits for it
+        assert!(host.incoming("viewer", sent[2].clone()).is_none());
+        let delivered: Vec<Message> = viewer
+            .resumed()
+            .into_iter()
+            .filter_map(|message| host.incoming("viewer", message))
+            .collect();
+        assert!(matches!(delivered.as_slice(), [Message::MouseMove { x: 2, .. }, Message::MouseMove { x: 3, .. }]));
+
+        // Acknowledged messages are not sent again
+        let acks = host.next_ack().await;
+        assert!(matches!(acks.as_slice(), [Message::NumberedAck { seq: 3, .. }]));
+        assert!(viewer.incoming("host", acks[0].clone()).is_none());
+        assert!(viewer.resumed().is_empty());
+
+        // Only control messages are numbered
+        let clipboard = Message::ClipboardUpdate { content: "text".to_string(), mime_type: "text/plain".to_string() };
+        assert!(matches!(viewer.outgoing(clipboard), Some(Message::Numbered { .. })));
+        assert!(matches!(viewer.outgoing(Message::Pong { timestamp: None }), Some(Message::Pong { .. })));
+        let chunk = Message::FileChunk { transfer_id: "t".to_string(), chunk_index: 0, data: Vec::new() };
+        assert!(matches!(viewer.outgoing(chunk), Some(Message::FileChunk { .. })));
+        assert!(matches!(viewer.outgoing(frame(true)), Some(Message::VideoFrame { .. })));
+    }
+
+    #[test]
+    fn test_video_restarts_with_keyframe() {
+        let (mut host, keyframe) = state("host");
+        assert!(!keyframe.take());
+
+        host.resumed();
+        assert!(keyframe.take() && !keyframe.take());
+        assert!(host.outgoing(frame(false)).is_none());
+        assert!(host.outgoing(frame(true)).is_some());
+        assert!(host.outgoing(frame(false)).is_some());
+    }
+
+    #[test]
+    fn test_unacked_messages_stay_with_their_controller() {
+        let (mut host, _) = state("host");
+        let roster = |controller: &str| -> Vec<ParticipantInfo> {
+            vec![
+                ParticipantInfo { device_id: "host".to_string(), role: ParticipantRole::Host },
+                ParticipantInfo { device_id: controller.to_string(), role: ParticipantRole::Controller },
+            ]
+        };
+
+        host.participants_changed(&roster("senior"));
+        host.outgoing(mouse_move(1));
+        host.participants_changed(&roster("senior"));
+        assert_eq!(host.resumed().len(), 1);
+
+        host.participants_changed(&roster("junior"));
+        assert!(host.resumed().is_empty());
+    }
+
+    #[test]
+    fn test_control_handed_back_keeps_messages_flowing() {
+        let (mut host, _) = state("host");
+        let (mut senior, _) = state("senior");
+        let roster = |controller: &str, observer: &str| -> Vec<ParticipantInfo> {
+            vec![
+                ParticipantInfo { device_id: "host".to_string(), role: ParticipantRole::Host },
+                ParticipantInfo { device_id: controller.to_string(), role: ParticipantRole::Controller },
+                ParticipantInfo { device_id: observer.to_string(), role: ParticipantRole::Observer },
+            ]
+        };
+        let clipboard = |content: &str| Message::ClipboardUpdate { content: content.to_string(), mime_type: "text/plain".to_string() };
+
+        for state in [&mut host, &mut senior] {
+            state.participants_changed(&roster("senior", "junior"));
+        }
+        let sent = host.outgoing(clipboard("one")).unwrap();
+        assert!(senior.incoming("host", sent).is_some());
+
+        // The host goes on numbering for junior while senior observes
+        for state in [&mut host, &mut senior] {
+            state.participants_changed(&roster("junior", "senior"));
+        }
+        host.outgoing(clipboard("two"));
+        host.outgoing(clipboard("three"));
+
+        for state in [&mut host, &mut senior] {
+            state.participants_changed(&roster("senior", "junior"));
+        }
+        let sent = host.outgoing(clipboard("four")).unwrap();
+        assert!(matches!(sent, Message::Numbered { seq: 4, .. }));
+        assert!(matches!(senior.incoming("host", sent), Some(Message::ClipboardUpdate { .. })));
+        let sent = host.outgoing(clipboard("five")).unwrap();
+        assert!(senior.incoming("host", sent).is_some());
+    }
+}
@@ -0,0 +1,359 @@
+//! Connection quality of the current session, for the stats overlay and the report
+//! sent to the backend when the session ends.
+//!
+//! The connection task pings the relay every interval and times the timestamp echoed
+//! back for the round-trip time, with jitter smoothed as in RFC 3550. It counts the
+//! video going each way and samples how many messages are waiting to be sent. The host
+//! marks the first frame it captures after applying input from the controller, so the
+//! controller can time input to display: from its first input since the last marked
+//! frame to the next marked frame. The host also counts the screen areas it redacted.
+
+use crate::api::{ApiClient, SessionQualityReport};
+use crate::protocol::Message;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+
+pub const STATS_INTERVAL: Duration = Duration::from_secs(1);
+const JITTER_GAIN: f64 = 1.0 / 16.0; // As in RFC 3550
+
+/// What the overlay shows; rates are over the last interval
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct StatsSnapshot {
+    pub rtt_ms: Option<f64>,
+    pub jitter_ms: Option<f64>,
+    pub fps_sent: f64,
+    pub fps_received: f64,
+    pub kbps_sent: f64,
+    pub kbps_received: f64,
+    pub frames_dropped: u64,
+    pub queue_depth: usize,
+    pub input_latency_ms: Option<f64>,
+    pub redactions: u64, // Areas masked before sending, over the session
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Video {
+    frames: u64,
+    bytes: u64,
+}
+
+impl Video {
+    fn add(&mut self, bytes: usize) {
+        self.frames += 1;
+        self.bytes += bytes as u64;
+    }
+}
+
+#[derive(Debug, Default)]
+struct Average {
+    sum: f64,
+    count: u64,
+}
+
+impl Average {
+    fn add(&mut self, value: f64) {
+        self.sum += value;
+        self.count += 1;
+    }
+
+    fn get(&self) -> Option<f64> {
+        (self.count > 0).then(|| self.sum / self.count as f64)
+    }
+}
+
+struct Inner {
+    session: Option<(String, Instant)>,
+    snapshot: StatsSnapshot,
+    interval_start: Instant,
+    sent: Video, // This interval
+    received: Video,
+    total_sent: Video,
+    total_received: Video,
+    rtt: Average,
+    jitter: Average,
+    input_latency: Average,
+    input_since: Option<Instant>, // Controller: first input not shown yet
+    input_applied: bool,          // Host: mark the next frame
+}
+
+impl Inner {
+    fn new() -> Self {
+        Self {
+            session: None,
+            snapshot: StatsSnapshot::default(),
+            interval_start: Instant::now(),
+            sent: Video::default(),
+            received: Video::default(),
+            total_sent: Video::default(),
+            total_received: Video::default(),
+            rtt: Average::default(),
+            jitter: Average::default(),
+            input_latency: Average::default(),
+            input_since: None,
+            input_applied: false,
+        }
+    }
+}
+
+/// Quality of the current session, shared with the UI; stays valid across reconnects
+#[derive(Clone)]
+pub struct SessionStats {
+    inner: Arc<Mutex<Inner>>,
+    epoch: Instant, // Ping timestamps count from here
+    reporter: Option<Arc<ApiClient>>,
+}
+
+impl Default for SessionStats {
+    fn default() -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(Inner::new())),
+            epoch: Instant::now(),
+            reporter: None,
+        }
+    }
+}
+
+impl SessionStats {
+    /// Report each session to the backend when it ends, while the API client is signed in
+    pub fn with_reports(mut self, api_client: Arc<ApiClient>) -> Self {
+        self.reporter = Some(api_client);
+        self
+    }
+
+    pub fn snapshot(&self) -> StatsSnapshot {
+        self.inner.lock().unwrap().snapshot.clone()
+    }
+
+    /// The session under way, if any
+    pub fn session_id(&self) -> Option<String> {
+        self.inner.lock().unwrap().session.as_ref().map(|(id, _)| id.clone())
+    }
+
+    /// A new session was opened or joined; a resumed one carries on
+    pub fn session_started(&self, session_id: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        *inner = Inner::new();
+        inner.session = Some((session_id.to_string(), Instant::now()));
+    }
+
+    /// The session ended: sends its report if reports are on
+    pub fn session_ended(&self, device_id: &str) {
+        let Some((session_id, report)) = self.finish() else {
+            return;
+        };
+        tracing::info!("Session {} quality: {:?}", session_id, report);
+
+        let Some(api_client) = self.reporter.clone() else {
+            return;
+        };
+        let device_id = device_id.to_string();
+        tokio::spawn(async move {
+            if let Err(e) = api_client.report_session_quality(&device_id, &session_id, &report).await {
+                tracing::warn!("Failed to report session quality: {:#}", e);
+            }
+        });
+    }
+
+    fn finish(&self) -> Option<(String, SessionQualityReport)> {
+        let mut inner = self.inner.lock().unwrap();
+        let (session_id, started) = inner.session.take()?;
+        let report = SessionQualityReport {
+            duration_secs: started.elapsed().as_secs_f64(),
+            frames_sent: inner.total_sent.frames,
+            frames_received: inner.total_received.frames,
+            frames_dropped: inner.snapshot.frames_dropped,
+            bytes_sent: inner.total_sent.bytes,
+            bytes_received: inner.total_received.bytes,
+            avg_rtt_ms: inner.rtt.get(),
+            avg_jitter_ms: inner.jitter.get(),
+            avg_input_latency_ms: inner.input_latency.get(),
+            redactions: inner.snapshot.redactions,
+        };
+        *inner = Inner::new();
+        Some((session_id, report))
+    }
+
+    /// A ping for the relay to echo back
+    pub fn ping(&self) -> Message {
+        Message::Ping { timestamp: Some(self.now()) }
+    }
+
+    /// The relay echoed the timestamp of one of our pings
+    pub fn pong(&self, timestamp: u64) {
+        let Some(rtt) = self.now().checked_sub(timestamp) else {
+            return;
+        };
+        let rtt_ms = rtt as f64 / 1000.0;
+
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(previous) = inner.snapshot.rtt_ms {
+            let jitter = inner.snapshot.jitter_ms.unwrap_or(0.0);
+            let jitter = jitter + ((rtt_ms - previous).abs() - jitter) * JITTER_GAIN;
+            inner.snapshot.jitter_ms = Some(jitter);
+            inner.jitter.add(jitter);
+        }
+        inner.snapshot.rtt_ms = Some(rtt_ms);
+        inner.rtt.add(rtt_ms);
+    }
+
+    /// Take a message on its way out, before it is sealed: marks the first frame after
+    /// applied input and notes when input was sent. Returns the size of a video frame.
+    pub fn outgoing(&self, message: &mut Message) -> Option<usize> {
+        match message {
+            Message::VideoFrame { data, after_input, .. } => {
+                if std::mem::take(&mut self.inner.lock().unwrap().input_applied) {
+                    *after_input = true;
+                }
+                Some(data.len())
+            }
+            message if message.is_input() => {
+                self.inner.lock().unwrap().input_since.get_or_insert_with(Instant::now);
+                None
+            }
+            _ => None,
+        }
+    }
+
+    pub fn frame_sent(&self, bytes: usize) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.sent.add(bytes);
+        inner.total_sent.add(bytes);
+    }
+
+    /// A frame was held back or could not be sealed or opened
+    pub fn frame_dropped(&self) {
+        self.inner.lock().unwrap().snapshot.frames_dropped += 1;
+    }
+
+    /// Areas of a captured frame were masked before it was sent
+    pub fn redacted(&self, areas: usize) {
+        self.inner.lock().unwrap().snapshot.redactions += areas as u64;
+    }
+
+    /// Take a message opened from a peer
+    pub fn incoming(&self, message: &Message) {
+        let mut inner = self.inner.lock().unwrap();
+        match message {
+            Message::VideoFrame { data, after_input, .. } => {
+                inner.received.add(data.len());
+                inner.total_received.add(data.len());
+                if *after_input {
+                    if let Some(since) = inner.input_since.take() {
+                        let latency_ms = since.elapsed().as_secs_f64() * 1000.0;
+                        inner.snapshot.input_latency_ms = Some(latency_ms);
+                        inner.input_latency.add(latency_ms);
+                    }
+                }
+            }
+            message if message.is_input() => inner.input_applied = true,
+            _ => {}
+        }
+    }
+
+    /// Turn the counts of the interval that just ended into rates
+    pub fn tick(&self, queue_depth: usize) {
+        let mut inner = self.inner.lock().unwrap();
+        let secs = inner.interval_start.elapsed().as_secs_f64().max(0.001);
+        let (sent, received) = (inner.sent, inner.received);
+        inner.snapshot.fps_sent = sent.frames as f64 / secs;
+        inner.snapshot.fps_received = received.frames as f64 / secs;
+        inner.snapshot.kbps_sent = sent.bytes as f64 * 8.0 / 1000.0 / secs;
+        inner.snapshot.kbps_received = received.bytes as f64 * 8.0 / 1000.0 / secs;
+        inner.snapshot.queue_depth = queue_depth;
+        inner.sent = Video::default();
+        inner.received = Video::default();
+        inner.interval_start = Instant::now();
+    }
+
+    fn now(&self) -> u64 {
+        self.epoch.elapsed().as_micros() as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(size: usize) -> Message {
+        Message::VideoFrame { data: vec![0; size], width: 1, height: 1, timestamp: 0, is_keyframe: false, after_input: false }
+    }
+
+    #[test]
+    fn test_round_trip_time_and_jitter() {
+        let stats = SessionStats::default();
+        stats.session_started("s1");
+
+        let Message::Ping { timestamp: Some(sent) } = stats.ping() else {
+            panic!("ping without a timestamp");
+        };
+        std::thread::sleep(Duration::from_millis(20));
+        stats.pong(sent);
+        let first = stats.snapshot();
+        assert!(first.rtt_ms.unwrap() >= 20.0);
+        assert!(first.jitter_ms.is_none());
+
+        stats.pong(stats.now());
+        let second = stats.snapshot();
+        assert!(second.rtt_ms.unwrap() < first.rtt_ms.unwrap());
+        assert!(second.jitter_ms.unwrap() > 0.0);
+
+        // Timestamps from the future are not ours
+        stats.pong(u64::MAX);
+        assert_eq!(stats.snapshot(), second);
+    }
+
+    #[test]
+    fn test_input_latency_is_timed_to_the_marked_frame() {
+        let host = SessionStats::default();
+        let viewer = SessionStats::default();
+
+        let mut input = Message::MouseMove { x: 1, y: 1 };
+        assert!(viewer.outgoing(&mut input).is_none());
+        std::thread::sleep(Duration::from_millis(10));
+
+        // Only the first frame after the input is marked
+        host.incoming(&input);
+        let mut marked = frame(4);
+        assert_eq!(host.outgoing(&mut marked), Some(4));
+        let mut unmarked = frame(4);
+        host.outgoing(&mut unmarked);
+        assert!(matches!(marked, Message::VideoFrame { after_input: true, .. }));
+        assert!(matches!(unmarked, Message::VideoFrame { after_input: false, .. }));
+
+        viewer.incoming(&unmarked);
+        assert!(viewer.snapshot().input_latency_ms.is_none());
+        viewer.incoming(&marked);
+        assert!(viewer.snapshot().input_latency_ms.unwrap() >= 10.0);
+    }
+
+    #[test]
+    fn test_session_report_totals() {
+        let stats = SessionStats::default();
+        assert!(stats.finish().is_none());
+
+        stats.session_started("s1");
+        stats.frame_sent(1000);
+        stats.frame_sent(500);
+        stats.frame_dropped();
+        stats.redacted(2);
+        stats.incoming(&frame(200));
+        stats.tick(3);
+
+        let snapshot = stats.snapshot();
+        assert!(snapshot.fps_sent > 0.0 && snapshot.kbps_received > 0.0);
+        assert_eq!((snapshot.frames_dropped, snapshot.queue_depth), (1, 3));
+
+        assert_eq!(stats.session_id().as_deref(), Some("s1"));
+        let (session_id, report) = stats.finish().unwrap();
+        assert_eq!(session_id, "s1");
+        assert_eq!((report.frames_sent, report.bytes_sent), (2, 1500));
+        assert_eq!((report.frames_received, report.bytes_received, report.frames_dropped), (1, 200, 1));
+        assert_eq!(report.redactions, 2);
+        assert!(report.avg_rtt_ms.is_none());
+
+        // Rates start over with the next session
+        assert!(stats.finish().is_none());
+        assert_eq!(stats.snapshot(), StatsSnapshot::default());
+    }
+}
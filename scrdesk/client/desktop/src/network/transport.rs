@@ -0,0 +1,143 @@
+//! How the connection task reaches the relay.
+//!
+//! A `Transport` opens a new connection each time the task connects or reconnects, as
+//! a sink and a stream of WebSocket messages. `RelayTransport` is the real one. The
+//! loopback hands the other end of each connection to the test playing the relay, so
+//! protocol behaviour can be tested in-process, and `Impaired` (see `impairment`) adds
+//! latency, jitter, loss and a bandwidth limit on top of either.
+
+use anyhow::Result;
+use futures::future::BoxFuture;
+use futures::{Sink, SinkExt, Stream, StreamExt};
+use rustls::pki_types::ServerName;
+use std::pin::Pin;
+use std::sync::Arc;
+use tokio::net::TcpStream;
+use tokio_rustls::TlsConnector;
+use tokio_tungstenite::client_async;
+use tokio_tungstenite::tungstenite::{protocol::Message as WsMessage, Error as WsError};
+use super::{quic, RelayEndpoint};
+
+pub type MessageSink = Pin<Box<dyn Sink<WsMessage, Error = WsError> + Send>>;
+pub type MessageStream = Pin<Box<dyn Stream<Item = Result<WsMessage, WsError>> + Send>>;
+
+pub trait Transport: Send + 'static {
+    /// Open a new connection to the relay
+    fn connect(&mut self) -> BoxFuture<'_, Result<(MessageSink, MessageStream)>>;
+
+    /// The relay's host, which also serves rendezvous for direct links; None if it has none
+    fn relay_host(&self) -> Option<String> {
+        None
+    }
+}
+
+/// The relay over WebSocket, or over QUIC where the relay offers it
+pub struct RelayTransport {
+    relay: RelayEndpoint,
+    try_quic: bool,
+}
+
+impl RelayTransport {
+    pub fn new(relay: RelayEndpoint) -> Self {
+        if !relay.is_secure() {
+            tracing::warn!("Relay connection is not encrypted, use a wss:// relay URL");
+        }
+        let try_quic = relay.quic;
+        Self { relay, try_quic }
+    }
+
+    /// Connect over QUIC if the relay offers it, falling back to WebSocket for the rest
+    /// of the session once QUIC fails, e.g. because UDP is blocked
+    async fn open(&mut self) -> Result<(MessageSink, MessageStream)> {
+        tracing::info!("Connecting to relay server: {}", self.relay.url);
+
+        if self.try_quic {
+            match quic::connect(&self.relay).await {
+                Ok((sink, stream)) => {
+                    tracing::info!("Using QUIC transport");
+                    return Ok((sink, Box::pin(stream)));
+                }
+                Err(e) => {
+                    tracing::warn!("QUIC unavailable, falling back to WebSocket: {:#}", e);
+                    self.try_quic = false;
+                }
+            }
+        }
+
+        let (host, port) = self.relay.host_and_port()?;
+        let socket = TcpStream::connect((host.as_str(), port)).await?;
+
+        if self.relay.is_secure() {
+            let connector = TlsConnector::from(Arc::new(self.relay.client_config(&[])?));
+            let stream = connector.connect(ServerName::try_from(host)?, socket).await?;
+            let (ws_stream, _) = client_async(self.relay.url.as_str(), stream).await?;
+            let (sink, stream) = ws_stream.split();
+            Ok((Box::pin(sink), Box::pin(stream)))
+        } else {
+            let (ws_stream, _) = client_async(self.relay.url.as_str(), socket).await?;
+            let (sink, stream) = ws_stream.split();
+            Ok((Box::pin(sink), Box::pin(stream)))
+        }
+    }
+}
+
+impl Transport for RelayTransport {
+    fn connect(&mut self) -> BoxFuture<'_, Result<(MessageSink, MessageStream)>> {
+        Box::pin(self.open())
+    }
+
+    fn relay_host(&self) -> Option<String> {
+        self.relay.host_and_port().ok().map(|(host, _)| host)
+    }
+}
+
+/// One direction of an in-memory connection
+pub fn channel() -> (MessageSink, MessageStream) {
+    let (tx, rx) = futures::channel::mpsc::unbounded::<WsMessage>();
+    (
+        Box::pin(tx.sink_map_err(|_| WsError::ConnectionClosed)),
+        Box::pin(rx.map(Ok)),
+    )
+}
+
+/// In-process connections for tests
+#[cfg(test)]
+pub mod loopback {
+    use super::*;
+
+    /// Two connected ends in memory; what one sends the other receives, and dropping
+    /// one's sink ends the other's stream
+    pub fn pair() -> ((MessageSink, MessageStream), (MessageSink, MessageStream)) {
+        let (a_tx, a_rx) = channel();
+        let (b_tx, b_rx) = channel();
+        ((a_tx, b_rx), (b_tx, a_rx))
+    }
+
+    /// Connects in-process to a `LoopbackListener`, which plays the relay
+    pub struct LoopbackTransport(tokio::sync::mpsc::UnboundedSender<(MessageSink, MessageStream)>);
+
+    pub struct LoopbackListener(tokio::sync::mpsc::UnboundedReceiver<(MessageSink, MessageStream)>);
+
+    pub fn loopback() -> (LoopbackTransport, LoopbackListener) {
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        (LoopbackTransport(tx), LoopbackListener(rx))
+    }
+
+    impl Transport for LoopbackTransport {
+        fn connect(&mut self) -> BoxFuture<'_, Result<(MessageSink, MessageStream)>> {
+            let (client, server) = pair();
+            let accepted = self.0.send(server).is_ok();
+            Box::pin(async move {
+                anyhow::ensure!(accepted, "Loopback listener is gone");
+                Ok(client)
+            })
+        }
+    }
+
+    impl LoopbackListener {
+        /// The relay's end of the next connection
+        pub async fn accept(&mut self) -> (MessageSink, MessageStream) {
+            self.0.recv().await.expect("Loopback transport is gone")
+        }
+    }
+}
@@ -0,0 +1,185 @@
+use crate::protocol::{Message, ParticipantInfo, ParticipantRole};
+use eframe::egui;
+
+/// An invitation to join someone else's session
+#[derive(Debug, Clone)]
+pub struct Invitation {
+    pub session_id: String,
+    pub host: String,
+    pub invited_by: String,
+    pub role: ParticipantRole,
+}
+
+/// Who is in the current session, updated from incoming protocol messages
+#[derive(Debug, Default)]
+pub struct SessionParticipants {
+    pub session_id: Option<String>,
+    pub participants: Vec<ParticipantInfo>,
+    pub invitation: Option<Invitation>,
+    pub last_error: Option<String>,
+}
+
+impl SessionParticipants {
+    /// Apply a session membership message. Returns false if the message is not one.
+    pub fn apply(&mut self, message: &Message) -> bool {
+        match message {
+            Message::ParticipantsChanged { session_id, participants } => {
+                self.session_id = Some(session_id.clone());
+                self.participants = participants.clone();
+                self.last_error = None;
+            }
+            Message::SessionInvitation { session_id, host, invited_by, role } => {
+                self.invitation = Some(Invitation {
+                    session_id: session_id.clone(),
+                    host: host.clone(),
+                    invited_by: invited_by.clone(),
+                    role: *role,
+                });
+            }
+            Message::SessionRequestFailed { error } => {
+                self.last_error = Some(error.clone());
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    pub fn role_of(&self, device_id: &str) -> Option<ParticipantRole> {
+        self.participants.iter().find(|p| p.device_id == device_id).map(|p| p.role)
+    }
+
+    /// Forget the session, e.g. after disconnecting
+    pub fn clear(&mut self) {
+        self.session_id = None;
+        self.participants.clear();
+        self.last_error = None;
+    }
+}
+
+/// Participant list with invite and control buttons
+pub struct ParticipantsView {
+    invite_input: String,
+    invite_as_controller: bool,
+}
+
+impl ParticipantsView {
+    pub fn new() -> Self {
+        Self {
+            invite_input: String::new(),
+            invite_as_controller: false,
+        }
+    }
+
+    /// Returns the messages to send to the relay
+    pub fn show(&mut self, ui: &mut egui::Ui, state: &SessionParticipants, own_device_id: &str) -> Vec<Message> {
+        let mut messages = Vec::new();
+        if state.participants.is_empty() {
+            return messages;
+        }
+
+        // Only the host and the controller manage the session
+        let can_manage = matches!(
+            state.role_of(own_device_id),
+            Some(ParticipantRole::Host | ParticipantRole::Controller)
+        );
+
+        ui.horizontal_wrapped(|ui| {
+            ui.label("👥");
+            for participant in &state.participants {
+                let label = match participant.role {
+                    ParticipantRole::Host => format!("{} (host)", participant.device_id),
+                    ParticipantRole::Controller => format!("{} (in control)", participant.device_id),
+                    ParticipantRole::Observer => format!("{} (watching)", participant.device_id),
+                };
+                ui.label(label);
+
+                if can_manage && participant.role == ParticipantRole::Observer && ui.small_button("Give control").clicked() {
+                    messages.push(Message::TransferControl { device_id: Some(participant.device_id.clone()) });
+                }
+                if can_manage && participant.role == ParticipantRole::Controller && ui.small_button("Revoke").clicked() {
+                    messages.push(Message::TransferControl { device_id: None });
+                }
+                ui.separator();
+            }
+        });
+
+        if can_manage {
+            ui.horizontal(|ui| {
+                ui.label("Invite device:");
+                ui.text_edit_singleline(&mut self.invite_input);
+                ui.checkbox(&mut self.invite_as_controller, "with control");
+
+                let device_id = self.invite_input.trim().to_string();
+                if ui.add_enabled(!device_id.is_empty(), egui::Button::new("Invite")).clicked() {
+                    let role = if self.invite_as_controller {
+                        ParticipantRole::Controller
+                    } else {
+                        ParticipantRole::Observer
+                    };
+                    messages.push(Message::InviteParticipant { device_id, role });
+                    self.invite_input.clear();
+                }
+            });
+        }
+
+        if let Some(ref error) = state.last_error {
+            ui.colored_label(egui::Color32::RED, error);
+        }
+
+        messages
+    }
+}
+
+/// Banner for a pending invitation; returns the join request if the user accepts
+pub fn show_invitation(ui: &mut egui::Ui, state: &mut SessionParticipants) -> Option<Message> {
+    let invitation = state.invitation.clone()?;
+    let mut join = None;
+
+    ui.horizontal(|ui| {
+        let role = match invitation.role {
+            ParticipantRole::Controller => "control",
+            _ => "watch",
+        };
+        ui.label(format!("📨 {} invited you to {} {}'s screen", invitation.invited_by, role, invitation.host));
+
+        if ui.button("Join").clicked() {
+            join = Some(Message::JoinSession { session_id: invitation.session_id.clone() });
+            state.invitation = None;
+        }
+        if ui.button("Decline").clicked() {
+            state.invitation = None;
+        }
+    });
+
+    join
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply_membership_messages() {
+        let mut state = SessionParticipants::default();
+
+        let invitation: Message = serde_json::from_str(
+            r#"{"type":"SessionInvitation","session_id":"s1","host":"111","invited_by":"222","role":"observer"}"#,
+        ).unwrap();
+        assert!(state.apply(&invitation));
+        assert_eq!(state.invitation.as_ref().map(|i| i.role), Some(ParticipantRole::Observer));
+
+        assert!(state.apply(&Message::ParticipantsChanged {
+            session_id: "s1".to_string(),
+            participants: vec![
+                ParticipantInfo { device_id: "111".to_string(), role: ParticipantRole::Host },
+                ParticipantInfo { device_id: "333".to_string(), role: ParticipantRole::Observer },
+            ],
+        }));
+        assert_eq!(state.role_of("333"), Some(ParticipantRole::Observer));
+        assert_eq!(state.role_of("222"), None);
+
+        assert!(!state.apply(&Message::Ping { timestamp: None }));
+        state.clear();
+        assert!(state.participants.is_empty());
+    }
+}
@@ -1,4 +1,6 @@
+use crate::transfer::delta::{DeltaOp, FileSignature};
 use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type")]
@@ -8,6 +10,8 @@ pub enum Message {
         device_id: String,
         platform: String,
         capabilities: Vec<String>,
+        #[serde(default)]
+        auth_token: Option<String>, // Access token of the signed-in user, if any
     },
     ConnectRequest {
         target_id: String,
@@ -17,6 +21,18 @@ pub enum Message {
         success: bool,
         session_id: Option<String>,
         error: Option<String>,
+        #[serde(default)]
+        error_code: Option<String>, // e.g. SESSION_LIMIT_EXCEEDED
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        resume_token: Option<String>, // Rebinds to the session after a dropped connection
+    },
+    ResumeSession {
+        session_id: String,
+        resume_token: String,
+    },
+    ParticipantResumed {
+        session_id: String,
+        device_id: String, // Reconnected; resend what it may have missed
     },
 
     // Video Streaming
@@ -26,6 +42,8 @@ pub enum Message {
         height: u32,
         timestamp: u64,
         is_keyframe: bool,
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        after_input: bool, // First frame captured after input from the controller was applied
     },
 
     // Input Events
@@ -57,17 +75,86 @@ pub enum Message {
     FileTransferResponse {
         transfer_id: String,
         accepted: bool,
+        reason: Option<String>,
     },
     FileChunk {
         transfer_id: String,
         chunk_index: u64,
         data: Vec<u8>,
     },
+    FileChunkAck {
+        transfer_id: String,
+        chunk_index: u64,
+    },
     FileTransferComplete {
         transfer_id: String,
         success: bool,
+        checksum: Option<String>, // SHA-256 (hex) of the whole file
+    },
+
+    // Delta sync (file already exists on the receiving side)
+    DeltaSyncRequest {
+        transfer_id: String,
+        filename: String,
+        filesize: u64,
+    },
+    DeltaSignature {
+        transfer_id: String,
+        signature: FileSignature, // Empty if the receiver has no copy
+    },
+    FileDelta {
+        transfer_id: String,
+        ops: Vec<DeltaOp>,
+    },
+
+    // Remote File Browser
+    ListDirectory {
+        request_id: String,
+        path: String,
+    },
+    DirectoryListing {
+        request_id: String,
+        path: String,
+        entries: Vec<FileEntry>,
+    },
+    StatPath {
+        request_id: String,
+        path: String,
+    },
+    PathStat {
+        request_id: String,
+        entry: FileEntry,
+    },
+    CreateDirectory {
+        request_id: String,
+        path: String,
+    },
+    RenamePath {
+        request_id: String,
+        from: String,
+        to: String,
+    },
+    DeletePath {
+        request_id: String,
+        path: String,
+        recursive: bool,
+    },
+    DownloadPath {
+        request_id: String, // Reused as the transfer_id of the resulting transfer
+        path: String,
+    },
+    FileOperationResult {
+        request_id: String,
+        success: bool,
+        error: Option<String>,
     },
 
+    // Display
+    SetResolution {
+        width: u32,
+        height: u32,
+    }, // The viewer asks the host for a screen of this size
+
     // Clipboard
     ClipboardUpdate {
         content: String,
@@ -75,11 +162,134 @@ pub enum Message {
     },
 
     // Control
-    Ping,
-    Pong,
+    Ping {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        timestamp: Option<u64>, // Echoed back in the Pong to measure round-trip time
+    },
+    Pong {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        timestamp: Option<u64>,
+    },
     Disconnect {
         reason: Option<String>,
     },
+
+    // Multi-participant sessions
+    InviteParticipant {
+        device_id: String,
+        role: ParticipantRole,
+    },
+    SessionInvitation {
+        session_id: String,
+        host: String,
+        invited_by: String,
+        role: ParticipantRole,
+    },
+    JoinSession {
+        session_id: String,
+    },
+    TransferControl {
+        device_id: Option<String>, // None takes control away from everyone
+    },
+    ParticipantsChanged {
+        session_id: String,
+        participants: Vec<ParticipantInfo>,
+    },
+    SessionRequestFailed {
+        error: String,
+    },
+
+    // Direct connections, negotiated through the relay
+    RendezvousInfo {
+        port: u16, // UDP and TCP port on the relay host that reports our public address
+    },
+    PeerCandidates {
+        session_id: String,
+        device_id: String,
+        candidates: Vec<Candidate>,
+        fingerprint: String, // SHA-256 of the sender's link certificate key, in hex
+    },
+    PathChanged {
+        session_id: String,
+        direct: bool,
+    },
+
+    // End-to-end encryption; the relay delivers the keys but cannot use them
+    KeyExchange {
+        session_id: String,
+        device_id: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        to: Option<String>, // None announces to every other participant
+        static_key: String,    // The device's registered X25519 key, in hex
+        ephemeral_key: String, // Fresh for each session, in hex
+    },
+    SenderKey {
+        session_id: String,
+        device_id: String,
+        to: String,
+        key_id: u32,
+        sealed_key: Vec<u8>, // Nonce and ciphertext under the pairwise key
+    },
+    Sealed(SealedMessage),
+    SealedMedia(SealedMessage), // Sealed video, fanned out to every viewer
+    SealedBulk(SealedMessage),  // Sealed file data
+
+    // Control messages are numbered inside the seal, so those lost with a dropped
+    // connection can be sent again
+    Numbered {
+        seq: u64,
+        message: Box<Message>,
+    },
+    NumberedAck {
+        device_id: String, // The sender being acknowledged
+        seq: u64,          // Everything up to here arrived
+    },
+}
+
+/// Session traffic encrypted with the sender's key; the type of the original message
+/// is only revealed as far as the relay needs it for routing
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct SealedMessage {
+    pub sender: String,
+    pub key_id: u32,
+    pub counter: u64,
+    pub ciphertext: Vec<u8>,
+}
+
+/// An address a peer may be reachable at directly
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Candidate {
+    pub protocol: CandidateProtocol,
+    pub kind: CandidateKind,
+    pub address: SocketAddr,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CandidateProtocol {
+    Udp,
+    Tcp,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CandidateKind {
+    Lan,    // A local interface address
+    Public, // As observed by the relay's rendezvous service
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ParticipantRole {
+    Host,
+    Controller,
+    Observer,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ParticipantInfo {
+    pub device_id: String,
+    pub role: ParticipantRole,
 }
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
@@ -91,7 +301,7 @@ pub enum MouseButton {
     Forward,
 }
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize, Default)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Default, PartialEq, Eq)]
 pub struct KeyModifiers {
     pub shift: bool,
     pub ctrl: bool,
@@ -105,6 +315,18 @@ pub enum TransferDirection {
     Download, // Remote to Local
 }
 
+/// A file or directory exposed by the remote file browser.
+/// `path` is a virtual path rooted at one of the host's shared directories.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct FileEntry {
+    pub name: String,
+    pub path: String,
+    pub is_dir: bool,
+    pub size: u64,
+    pub modified: Option<u64>, // Unix timestamp (seconds)
+    pub readonly: bool,
+}
+
 impl Message {
     pub fn to_json(&self) -> Result<String, serde_json::Error> {
         serde_json::to_string(self)
@@ -121,4 +343,58 @@ impl Message {
     pub fn from_bytes(bytes: &[u8]) -> Result<Self, serde_json::Error> {
         serde_json::from_slice(bytes)
     }
+
+    /// Bulk data that must never delay interactive traffic
+    pub fn is_bulk(&self) -> bool {
+        matches!(self, Message::FileChunk { .. } | Message::FileDelta { .. } | Message::SealedBulk(_))
+    }
+
+    /// Input from the controller, applied on the host
+    pub fn is_input(&self) -> bool {
+        matches!(
+            self,
+            Message::MouseMove { .. }
+                | Message::MouseButton { .. }
+                | Message::MouseScroll { .. }
+                | Message::KeyboardEvent { .. }
+        )
+    }
+
+    /// Signalling the relay acts on itself; everything else is session traffic
+    /// that can take a direct link to the peer
+    pub fn is_for_relay(&self) -> bool {
+        matches!(
+            self,
+            Message::Hello { .. }
+                | Message::ConnectRequest { .. }
+                | Message::Ping { .. }
+                | Message::Pong { .. }
+                | Message::Disconnect { .. }
+                | Message::InviteParticipant { .. }
+                | Message::JoinSession { .. }
+                | Message::TransferControl { .. }
+                | Message::PeerCandidates { .. }
+                | Message::PathChanged { .. }
+                | Message::KeyExchange { .. }
+                | Message::SenderKey { .. }
+                | Message::ResumeSession { .. }
+        )
+    }
+
+    /// Traffic between participants, which only ever travels sealed end to end
+    pub fn is_session_content(&self) -> bool {
+        !self.is_for_relay()
+            && !matches!(
+                self,
+                Message::ConnectResponse { .. }
+                    | Message::SessionInvitation { .. }
+                    | Message::ParticipantsChanged { .. }
+                    | Message::SessionRequestFailed { .. }
+                    | Message::RendezvousInfo { .. }
+                    | Message::ParticipantResumed { .. }
+                    | Message::Sealed(_)
+                    | Message::SealedMedia(_)
+                    | Message::SealedBulk(_)
+            )
+    }
 }
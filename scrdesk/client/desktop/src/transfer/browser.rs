@@ -0,0 +1,481 @@
+use anyhow::{Context, Result};
+use crate::protocol::{FileEntry, Message};
+use std::path::{Component, Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+/// Environment variable holding the shared root directories (OS path-list syntax)
+pub const BROWSE_ROOTS_ENV: &str = "SCRDESK_BROWSE_ROOTS";
+
+/// Environment variable that lets peers create, rename and delete files (`1` or `true`)
+pub const BROWSE_WRITE_ENV: &str = "SCRDESK_BROWSE_WRITE";
+
+#[derive(Debug, Clone)]
+struct BrowserRoot {
+    name: String,
+    path: PathBuf, // Canonical
+}
+
+/// Host side of the remote file browser.
+///
+/// Remote peers only ever see virtual paths of the form `/<root>/sub/dir`,
+/// where `<root>` is one of the configured shared directories. Every request
+/// is resolved against those roots and rejected if it would escape them.
+/// Browsing is read-only unless the host opts in to changes.
+pub struct FileBrowserHost {
+    roots: Vec<BrowserRoot>,
+    writable: bool,
+}
+
+impl FileBrowserHost {
+    pub fn new(roots: Vec<PathBuf>) -> Result<Self> {
+        let mut browser_roots: Vec<BrowserRoot> = Vec::new();
+
+        for root in roots {
+            let path = root.canonicalize()
+                .with_context(|| format!("Shared directory not found: {}", root.display()))?;
+
+            if !path.is_dir() {
+                anyhow::bail!("Shared path is not a directory: {}", path.display());
+            }
+
+            let base = path
+                .file_name()
+                .and_then(|n| n.to_str())
+                .unwrap_or("root")
+                .to_string();
+
+            // Keep root names unique so virtual paths stay unambiguous
+            let mut name = base.clone();
+            let mut suffix = 2;
+            while browser_roots.iter().any(|r| r.name == name) {
+                name = format!("{} ({})", base, suffix);
+                suffix += 1;
+            }
+
+            browser_roots.push(BrowserRoot { name, path });
+        }
+
+        Ok(Self { roots: browser_roots, writable: false })
+    }
+
+    /// Let peers create directories, rename and delete inside the roots
+    pub fn with_write_access(mut self, writable: bool) -> Self {
+        self.writable = writable;
+        self
+    }
+
+    /// Build from `SCRDESK_BROWSE_ROOTS`, falling back to `default_root`;
+    /// writable only if `SCRDESK_BROWSE_WRITE` is set
+    pub fn from_env(default_root: PathBuf) -> Result<Self> {
+        let roots = match std::env::var_os(BROWSE_ROOTS_ENV) {
+            Some(value) => std::env::split_paths(&value).collect(),
+            None => vec![default_root],
+        };
+        let writable = matches!(std::env::var(BROWSE_WRITE_ENV).as_deref(), Ok("1" | "true"));
+        if writable {
+            tracing::warn!("Remote peers may create, rename and delete shared files");
+        }
+
+        Ok(Self::new(roots)?.with_write_access(writable))
+    }
+
+    /// Answer a file browser request. Returns `None` for messages that are
+    /// not browser requests, and for `DownloadPath` which needs the transfer manager.
+    pub fn handle_request(&self, message: &Message) -> Option<Message> {
+        let (request_id, result) = match message {
+            Message::ListDirectory { request_id, path } => {
+                let result = self.list(path).map(|entries| Message::DirectoryListing {
+                    request_id: request_id.clone(),
+                    path: normalize_virtual(path),
+                    entries,
+                });
+                (request_id, result)
+            }
+            Message::StatPath { request_id, path } => {
+                let result = self.stat(path).map(|entry| Message::PathStat {
+                    request_id: request_id.clone(),
+                    entry,
+                });
+                (request_id, result)
+            }
+            Message::CreateDirectory { request_id, path } => {
+                let result = self.mkdir(path).map(|_| ok_result(request_id));
+                (request_id, result)
+            }
+            Message::RenamePath { request_id, from, to } => {
+                let result = self.rename(from, to).map(|_| ok_result(request_id));
+                (request_id, result)
+            }
+            Message::DeletePath { request_id, path, recursive } => {
+                let result = self.delete(path, *recursive).map(|_| ok_result(request_id));
+                (request_id, result)
+            }
+            _ => return None,
+        };
+
+        Some(result.unwrap_or_else(|e| {
+            tracing::warn!("File browser request {} failed: {}", request_id, e);
+            Message::FileOperationResult {
+                request_id: request_id.clone(),
+                success: false,
+                error: Some(e.to_string()),
+            }
+        }))
+    }
+
+    /// List a directory. The virtual root `/` lists the shared roots.
+    pub fn list(&self, virtual_path: &str) -> Result<Vec<FileEntry>> {
+        let virtual_path = normalize_virtual(virtual_path);
+
+        if virtual_path == "/" {
+            return self.roots
+                .iter()
+                .map(|root| entry_for(&root.path, root.name.clone(), format!("/{}", root.name)))
+                .collect();
+        }
+
+        let dir = self.resolve_existing(&virtual_path)?;
+        if !dir.is_dir() {
+            anyhow::bail!("Not a directory: {}", virtual_path);
+        }
+
+        let mut entries = Vec::new();
+        for item in std::fs::read_dir(&dir).context("Failed to read directory")? {
+            let item = item.context("Failed to read directory entry")?;
+            let name = item.file_name().to_string_lossy().to_string();
+            let child_virtual = format!("{}/{}", virtual_path.trim_end_matches('/'), name);
+
+            // Symlinks pointing outside the roots are hidden rather than followed
+            if self.resolve_existing(&child_virtual).is_err() {
+                continue;
+            }
+
+            match entry_for(&item.path(), name, child_virtual) {
+                Ok(entry) => entries.push(entry),
+                Err(e) => tracing::debug!("Skipping unreadable entry: {}", e),
+            }
+        }
+
+        // Directories first, then by name
+        entries.sort_by(|a, b| b.is_dir.cmp(&a.is_dir).then_with(|| a.name.cmp(&b.name)));
+
+        Ok(entries)
+    }
+
+    pub fn stat(&self, virtual_path: &str) -> Result<FileEntry> {
+        let virtual_path = normalize_virtual(virtual_path);
+        let path = self.resolve_existing(&virtual_path)?;
+        let name = virtual_path.rsplit('/').next().unwrap_or_default().to_string();
+        entry_for(&path, name, virtual_path)
+    }
+
+    pub fn mkdir(&self, virtual_path: &str) -> Result<()> {
+        self.ensure_writable()?;
+        let path = self.resolve_new(virtual_path)?;
+        std::fs::create_dir(&path).context("Failed to create directory")?;
+        tracing::info!("Remote browser created directory: {}", path.display());
+        Ok(())
+    }
+
+    pub fn rename(&self, from: &str, to: &str) -> Result<()> {
+        self.ensure_writable()?;
+        let source = self.resolve_entry(from)?;
+        let target = self.resolve_new(to)?;
+
+        std::fs::rename(&source, &target).context("Failed to rename")?;
+        tracing::info!("Remote browser renamed {} -> {}", source.display(), target.display());
+        Ok(())
+    }
+
+    pub fn delete(&self, virtual_path: &str, recursive: bool) -> Result<()> {
+        self.ensure_writable()?;
+        let path = self.resolve_entry(virtual_path)?;
+
+        let metadata = std::fs::symlink_metadata(&path).context("Failed to stat path")?;
+        if metadata.is_dir() {
+            if recursive {
+                std::fs::remove_dir_all(&path).context("Failed to delete directory")?;
+            } else {
+                std::fs::remove_dir(&path).context("Failed to delete directory (not empty?)")?;
+            }
+        } else {
+            std::fs::remove_file(&path).context("Failed to delete file")?;
+        }
+
+        tracing::info!("Remote browser deleted: {}", path.display());
+        Ok(())
+    }
+
+    /// Resolve the file a peer asked to download
+    pub fn resolve_download(&self, virtual_path: &str) -> Result<PathBuf> {
+        let path = self.resolve_existing(virtual_path)?;
+        if !path.is_file() {
+            anyhow::bail!("Not a regular file: {}", virtual_path);
+        }
+        Ok(path)
+    }
+
+    /// Resolve a virtual path to an existing location inside one of the roots
+    fn resolve_existing(&self, virtual_path: &str) -> Result<PathBuf> {
+        let (root, relative) = self.split_virtual(virtual_path)?;
+        let path = root.path.join(relative)
+            .canonicalize()
+            .with_context(|| format!("Path not found: {}", virtual_path))?;
+
+        if !path.starts_with(&root.path) {
+            anyhow::bail!("Access denied: {}", virtual_path);
+        }
+
+        Ok(path)
+    }
+
+    /// Resolve an existing entry to change (rename source, delete) without following it
+    /// if it is a symlink, so the link is changed rather than what it points to
+    fn resolve_entry(&self, virtual_path: &str) -> Result<PathBuf> {
+        // Only entries a listing shows, which hides links out of the roots
+        self.resolve_existing(virtual_path)?;
+
+        let path = self.resolve_in_parent(virtual_path)?;
+        path.symlink_metadata()
+            .with_context(|| format!("Path not found: {}", virtual_path))?;
+        Ok(path)
+    }
+
+    /// Resolve a virtual path that does not exist yet (mkdir, rename target)
+    fn resolve_new(&self, virtual_path: &str) -> Result<PathBuf> {
+        let path = self.resolve_in_parent(virtual_path)?;
+        if path.symlink_metadata().is_ok() {
+            anyhow::bail!("Path already exists: {}", virtual_path);
+        }
+        Ok(path)
+    }
+
+    /// The canonical parent directory of a virtual path, checked to be inside its root,
+    /// joined with the last name as given
+    fn resolve_in_parent(&self, virtual_path: &str) -> Result<PathBuf> {
+        let (root, relative) = self.split_virtual(virtual_path)?;
+        let name = relative
+            .file_name()
+            .context("Shared roots cannot be modified")?
+            .to_owned();
+
+        let parent = root.path.join(relative.parent().unwrap_or(Path::new("")))
+            .canonicalize()
+            .with_context(|| format!("Parent directory not found: {}", virtual_path))?;
+
+        if !parent.starts_with(&root.path) {
+            anyhow::bail!("Access denied: {}", virtual_path);
+        }
+
+        Ok(parent.join(name))
+    }
+
+    fn split_virtual(&self, virtual_path: &str) -> Result<(&BrowserRoot, PathBuf)> {
+        let virtual_path = normalize_virtual(virtual_path);
+        let mut parts = virtual_path.trim_start_matches('/').splitn(2, '/');
+
+        let root_name = parts.next().filter(|n| !n.is_empty())
+            .context("Path must start with a shared root")?;
+        let root = self.roots
+            .iter()
+            .find(|r| r.name == root_name)
+            .with_context(|| format!("Unknown shared root: {}", root_name))?;
+
+        let relative = PathBuf::from(parts.next().unwrap_or(""));
+        for component in relative.components() {
+            if !matches!(component, Component::Normal(_)) {
+                anyhow::bail!("Invalid path component in {}", virtual_path);
+            }
+        }
+
+        Ok((root, relative))
+    }
+
+    fn ensure_writable(&self) -> Result<()> {
+        if !self.writable {
+            anyhow::bail!("Shared files are read-only on this host");
+        }
+        Ok(())
+    }
+}
+
+fn ok_result(request_id: &str) -> Message {
+    Message::FileOperationResult {
+        request_id: request_id.to_string(),
+        success: true,
+        error: None,
+    }
+}
+
+/// Collapse separators so `""`, `"/"` and `"//docs/"` all have one spelling
+fn normalize_virtual(path: &str) -> String {
+    let parts: Vec<&str> = path
+        .split(|c| c == '/' || c == '\\')
+        .filter(|p| !p.is_empty() && *p != ".")
+        .collect();
+    format!("/{}", parts.join("/"))
+}
+
+fn entry_for(path: &Path, name: String, virtual_path: String) -> Result<FileEntry> {
+    let metadata = std::fs::metadata(path)
+        .with_context(|| format!("Failed to stat {}", path.display()))?;
+
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs());
+
+    Ok(FileEntry {
+        name,
+        path: virtual_path,
+        is_dir: metadata.is_dir(),
+        size: if metadata.is_dir() { 0 } else { metadata.len() },
+        modified,
+        readonly: metadata.permissions().readonly(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn setup() -> Result<(TempDir, FileBrowserHost, String)> {
+        let temp_dir = TempDir::new()?;
+        let shared = temp_dir.path().join("shared");
+        std::fs::create_dir_all(shared.join("docs"))?;
+        std::fs::write(shared.join("docs/report.txt"), b"quarterly")?;
+        std::fs::write(temp_dir.path().join("secret.txt"), b"outside")?;
+
+        let host = FileBrowserHost::new(vec![shared])?.with_write_access(true);
+        Ok((temp_dir, host, "/shared".to_string()))
+    }
+
+    #[test]
+    fn test_list_and_stat() -> Result<()> {
+        let (_temp_dir, host, root) = setup()?;
+
+        let roots = host.list("/")?;
+        assert_eq!(roots.len(), 1);
+        assert_eq!(roots[0].path, root);
+
+        let entries = host.list(&format!("{}/docs", root))?;
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].name, "report.txt");
+        assert_eq!(entries[0].size, 9);
+
+        let entry = host.stat("/shared/docs/report.txt")?;
+        assert!(!entry.is_dir);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_rejects_escape() -> Result<()> {
+        let (_temp_dir, host, _) = setup()?;
+
+        assert!(host.stat("/shared/../secret.txt").is_err());
+        assert!(host.resolve_download("/shared/docs/../../secret.txt").is_err());
+        assert!(host.mkdir("/shared/../evil").is_err());
+        assert!(host.list("/unknown").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_mkdir_rename_delete() -> Result<()> {
+        let (_temp_dir, host, _) = setup()?;
+
+        host.mkdir("/shared/new")?;
+        host.rename("/shared/docs/report.txt", "/shared/new/report.txt")?;
+        assert!(host.stat("/shared/new/report.txt").is_ok());
+
+        // Non-empty directory needs recursive delete
+        assert!(host.delete("/shared/new", false).is_err());
+        host.delete("/shared/new", true)?;
+        assert!(host.stat("/shared/new").is_err());
+
+        // Roots themselves are protected
+        assert!(host.delete("/shared", true).is_err());
+
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_symlinks_are_changed_not_their_targets() -> Result<()> {
+        let (temp_dir, host, _) = setup()?;
+        let shared = temp_dir.path().join("shared");
+        std::os::unix::fs::symlink(shared.join("docs"), shared.join("docs-link"))?;
+        std::os::unix::fs::symlink(&shared, shared.join("docs/root-link"))?;
+
+        // Deleting a link to a directory removes the link, not the directory
+        host.delete("/shared/docs-link", true)?;
+        assert!(shared.join("docs/report.txt").is_file());
+        assert!(shared.join("docs-link").symlink_metadata().is_err());
+
+        // A link to the root is an ordinary entry; the root itself stays
+        host.rename("/shared/docs/root-link", "/shared/root-link")?;
+        assert!(shared.join("root-link").symlink_metadata()?.file_type().is_symlink());
+        host.delete("/shared/root-link", true)?;
+        assert!(shared.join("docs/report.txt").is_file());
+
+        assert!(host.rename("/shared", "/shared/moved").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_only_by_default() -> Result<()> {
+        let (temp_dir, _, _) = setup()?;
+        let host = FileBrowserHost::new(vec![temp_dir.path().join("shared")])?;
+
+        let requests = [
+            Message::CreateDirectory { request_id: "mkdir".to_string(), path: "/shared/new".to_string() },
+            Message::RenamePath {
+                request_id: "rename".to_string(),
+                from: "/shared/docs/report.txt".to_string(),
+                to: "/shared/report.txt".to_string(),
+            },
+            Message::DeletePath { request_id: "delete".to_string(), path: "/shared/docs".to_string(), recursive: true },
+        ];
+        for request in &requests {
+            match host.handle_request(request) {
+                Some(Message::FileOperationResult { success, error, .. }) => {
+                    assert!(!success);
+                    assert!(error.unwrap().contains("read-only"));
+                }
+                other => panic!("Unexpected response: {:?}", other),
+            }
+        }
+
+        // Nothing changed, and reading still works
+        assert!(temp_dir.path().join("shared/docs/report.txt").is_file());
+        assert!(!temp_dir.path().join("shared/new").exists());
+        assert_eq!(host.list("/shared/docs")?.len(), 1);
+        assert!(host.resolve_download("/shared/docs/report.txt").is_ok());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_handle_request_reports_errors() -> Result<()> {
+        let (_temp_dir, host, _) = setup()?;
+
+        let response = host.handle_request(&Message::StatPath {
+            request_id: "req-1".to_string(),
+            path: "/shared/missing".to_string(),
+        });
+
+        match response {
+            Some(Message::FileOperationResult { request_id, success, error }) => {
+                assert_eq!(request_id, "req-1");
+                assert!(!success);
+                assert!(error.is_some());
+            }
+            other => panic!("Unexpected response: {:?}", other),
+        }
+
+        Ok(())
+    }
+}
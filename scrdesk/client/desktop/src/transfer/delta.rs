@@ -0,0 +1,444 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+const MIN_BLOCK_SIZE: u32 = 2 * 1024;
+const MAX_BLOCK_SIZE: u32 = 128 * 1024;
+const MAX_LITERAL: usize = 256 * 1024; // Flush literal data in pieces of this size
+const READ_SIZE: usize = 1024 * 1024;
+
+/// Literal bytes carried by one `FileDelta` message
+pub const BATCH_SIZE: usize = 512 * 1024;
+
+/// Checksums of one block of the receiver's existing copy
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct BlockSignature {
+    pub weak: u32,
+    pub strong: String, // First 16 bytes of SHA-256 (hex)
+}
+
+/// Block checksums of a whole file, sent by the receiver
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct FileSignature {
+    pub block_size: u32,
+    pub file_size: u64,
+    pub blocks: Vec<BlockSignature>,
+}
+
+impl FileSignature {
+    pub fn empty() -> Self {
+        Self {
+            block_size: MIN_BLOCK_SIZE,
+            file_size: 0,
+            blocks: Vec::new(),
+        }
+    }
+}
+
+/// One instruction for rebuilding the new file from the old one
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(tag = "op")]
+pub enum DeltaOp {
+    Copy { start_block: u64, count: u64 }, // Blocks taken from the receiver's copy
+    Data { data: Vec<u8> },                // Literal bytes
+}
+
+impl DeltaOp {
+    pub fn literal_len(&self) -> usize {
+        match self {
+            DeltaOp::Copy { .. } => 0,
+            DeltaOp::Data { data } => data.len(),
+        }
+    }
+}
+
+/// Roughly sqrt(file size), so signatures stay small for big files
+pub fn block_size_for(file_size: u64) -> u32 {
+    let size = (file_size as f64).sqrt() as u32;
+    size.next_power_of_two().clamp(MIN_BLOCK_SIZE, MAX_BLOCK_SIZE)
+}
+
+/// rsync-style rolling checksum over a fixed window
+#[derive(Debug, Default, Clone, Copy)]
+struct RollingChecksum {
+    a: u32,
+    b: u32,
+    len: u32,
+}
+
+impl RollingChecksum {
+    fn new(window: &[u8]) -> Self {
+        let mut sum = Self { a: 0, b: 0, len: window.len() as u32 };
+        for (i, byte) in window.iter().enumerate() {
+            sum.a = sum.a.wrapping_add(*byte as u32);
+            sum.b = sum.b.wrapping_add((window.len() - i) as u32 * *byte as u32);
+        }
+        sum
+    }
+
+    fn roll(&mut self, out_byte: u8, in_byte: u8) {
+        self.a = self.a.wrapping_sub(out_byte as u32).wrapping_add(in_byte as u32);
+        self.b = self.b
+            .wrapping_sub(self.len.wrapping_mul(out_byte as u32))
+            .wrapping_add(self.a);
+    }
+
+    fn digest(&self) -> u32 {
+        (self.a & 0xffff) | (self.b << 16)
+    }
+}
+
+fn strong_hash(data: &[u8]) -> String {
+    let digest = Sha256::digest(data);
+    digest[..16].iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Compute block signatures of an existing file (receiver side)
+pub fn signature(path: &Path) -> Result<FileSignature> {
+    let file = File::open(path).context("Failed to open file for signature")?;
+    let file_size = file.metadata().context("Failed to get file metadata")?.len();
+    let block_size = block_size_for(file_size);
+
+    let mut reader = BufReader::new(file);
+    let mut buffer = vec![0u8; block_size as usize];
+    let mut blocks = Vec::new();
+
+    loop {
+        let read = read_full(&mut reader, &mut buffer)?;
+        if read == 0 {
+            break;
+        }
+
+        let block = &buffer[..read];
+        blocks.push(BlockSignature {
+            weak: RollingChecksum::new(block).digest(),
+            strong: strong_hash(block),
+        });
+
+        if read < buffer.len() {
+            break;
+        }
+    }
+
+    Ok(FileSignature { block_size, file_size, blocks })
+}
+
+/// Compare `path` with the receiver's signature and emit the instructions
+/// to rebuild it (sender side). Returns the SHA-256 of the whole new file.
+pub fn compute_delta<F>(path: &Path, signature: &FileSignature, mut emit: F) -> Result<String>
+where
+    F: FnMut(DeltaOp) -> Result<()>,
+{
+    let block_size = signature.block_size as usize;
+    if block_size == 0 {
+        anyhow::bail!("Invalid block size");
+    }
+
+    // weak -> block indices; only full blocks take part in rolling matches
+    let full_blocks = (signature.file_size / block_size as u64) as usize;
+    let mut index: HashMap<u32, Vec<usize>> = HashMap::new();
+    for (i, block) in signature.blocks.iter().enumerate().take(full_blocks) {
+        index.entry(block.weak).or_default().push(i);
+    }
+    let tail_block = signature.blocks.get(full_blocks).filter(|_| signature.file_size % block_size as u64 != 0);
+
+    let mut file = File::open(path).context("Failed to open file for delta")?;
+    let mut hasher = Sha256::new();
+    let mut pending_copy: Option<(u64, u64)> = None;
+
+    let mut buf: Vec<u8> = Vec::with_capacity(READ_SIZE + block_size);
+    let mut eof = false;
+    let mut pos = 0usize; // Start of the current window in `buf`
+    let mut literal_start = 0usize;
+    let mut rolling: Option<RollingChecksum> = None;
+
+    loop {
+        // Keep at least one full window buffered
+        if !eof && buf.len() - pos < block_size {
+            // Drop bytes that have already been emitted
+            let consumed = literal_start.min(pos);
+            buf.drain(..consumed);
+            pos -= consumed;
+            literal_start -= consumed;
+
+            let old_len = buf.len();
+            buf.resize(old_len + READ_SIZE, 0);
+            let read = read_full(&mut file, &mut buf[old_len..])?;
+            buf.truncate(old_len + read);
+            hasher.update(&buf[old_len..]);
+            if read == 0 {
+                eof = true;
+            }
+            continue;
+        }
+
+        let remaining = buf.len() - pos;
+        if remaining < block_size {
+            // End of file: try the receiver's short tail block, then finish
+            let window = &buf[pos..];
+            if let Some(tail) = tail_block {
+                if !window.is_empty()
+                    && window.len() as u64 == signature.file_size % block_size as u64
+                    && RollingChecksum::new(window).digest() == tail.weak
+                    && strong_hash(window) == tail.strong
+                {
+                    if literal_start < pos {
+                        flush_copy(&mut pending_copy, &mut emit)?;
+                        emit(DeltaOp::Data { data: buf[literal_start..pos].to_vec() })?;
+                    }
+                    extend_copy(&mut pending_copy, full_blocks as u64, &mut emit)?;
+                    literal_start = buf.len();
+                }
+            }
+
+            if literal_start < buf.len() {
+                flush_copy(&mut pending_copy, &mut emit)?;
+                for piece in buf[literal_start..].chunks(MAX_LITERAL) {
+                    emit(DeltaOp::Data { data: piece.to_vec() })?;
+                }
+            }
+            flush_copy(&mut pending_copy, &mut emit)?;
+            break;
+        }
+
+        let window = &buf[pos..pos + block_size];
+        let sum = *rolling.get_or_insert_with(|| RollingChecksum::new(window));
+
+        let matched = index.get(&sum.digest()).and_then(|candidates| {
+            let strong = strong_hash(window);
+            candidates.iter().copied().find(|&i| signature.blocks[i].strong == strong)
+        });
+
+        if let Some(block_index) = matched {
+            if literal_start < pos {
+                flush_copy(&mut pending_copy, &mut emit)?;
+                emit(DeltaOp::Data { data: buf[literal_start..pos].to_vec() })?;
+            }
+            extend_copy(&mut pending_copy, block_index as u64, &mut emit)?;
+
+            pos += block_size;
+            literal_start = pos;
+            rolling = None;
+            continue;
+        }
+
+        // No match: slide the window by one byte
+        if pos + block_size < buf.len() {
+            let mut next = sum;
+            next.roll(buf[pos], buf[pos + block_size]);
+            rolling = Some(next);
+        } else {
+            rolling = None;
+        }
+        pos += 1;
+
+        if pos - literal_start >= MAX_LITERAL {
+            flush_copy(&mut pending_copy, &mut emit)?;
+            emit(DeltaOp::Data { data: buf[literal_start..pos].to_vec() })?;
+            literal_start = pos;
+        }
+    }
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+fn flush_copy<F>(pending: &mut Option<(u64, u64)>, emit: &mut F) -> Result<()>
+where
+    F: FnMut(DeltaOp) -> Result<()>,
+{
+    if let Some((start_block, count)) = pending.take() {
+        emit(DeltaOp::Copy { start_block, count })?;
+    }
+    Ok(())
+}
+
+/// Merge consecutive block copies into one instruction
+fn extend_copy<F>(pending: &mut Option<(u64, u64)>, block_index: u64, emit: &mut F) -> Result<()>
+where
+    F: FnMut(DeltaOp) -> Result<()>,
+{
+    match pending {
+        Some((start, count)) if *start + *count == block_index => {
+            *count += 1;
+        }
+        _ => {
+            flush_copy(pending, emit)?;
+            *pending = Some((block_index, 1));
+        }
+    }
+    Ok(())
+}
+
+/// Rebuilds the new file on the receiver from its old copy and the delta
+#[derive(Debug)]
+pub struct DeltaApplier {
+    basis: Option<File>,
+    basis_size: u64,
+    block_size: u64,
+}
+
+impl DeltaApplier {
+    pub fn new(basis: Option<File>, signature: &FileSignature) -> Self {
+        Self {
+            basis,
+            basis_size: signature.file_size,
+            block_size: signature.block_size as u64,
+        }
+    }
+
+    /// How many bytes `op` appends, checked against the basis file without writing
+    pub fn output_len(&self, op: &DeltaOp) -> Result<u64> {
+        match op {
+            DeltaOp::Data { data } => Ok(data.len() as u64),
+            DeltaOp::Copy { start_block, count } => {
+                let (start, end) = self.copy_range(*start_block, *count)?;
+                Ok(end - start)
+            }
+        }
+    }
+
+    /// Append the bytes described by `op` to `output`; returns how many were written
+    pub fn apply(&mut self, op: &DeltaOp, output: &mut File) -> Result<u64> {
+        match op {
+            DeltaOp::Data { data } => {
+                output.write_all(data).context("Failed to write delta data")?;
+                Ok(data.len() as u64)
+            }
+            DeltaOp::Copy { start_block, count } => {
+                let (start, end) = self.copy_range(*start_block, *count)?;
+                let basis = self.basis.as_mut().context("Delta references a missing basis file")?;
+
+                basis.seek(SeekFrom::Start(start)).context("Failed to seek in basis file")?;
+                let copied = std::io::copy(&mut basis.take(end - start), output)
+                    .context("Failed to copy from basis file")?;
+                if copied != end - start {
+                    anyhow::bail!("Basis file changed during transfer");
+                }
+
+                Ok(copied)
+            }
+        }
+    }
+
+    /// Byte range of the basis file that a block copy covers
+    fn copy_range(&self, start_block: u64, count: u64) -> Result<(u64, u64)> {
+        if self.basis.is_none() {
+            anyhow::bail!("Delta references a missing basis file");
+        }
+
+        let start = start_block.checked_mul(self.block_size).context("Invalid block range")?;
+        let end = start_block
+            .checked_add(count)
+            .and_then(|b| b.checked_mul(self.block_size))
+            .context("Invalid block range")?
+            .min(self.basis_size);
+        if start >= end {
+            anyhow::bail!("Block range outside of basis file");
+        }
+
+        Ok((start, end))
+    }
+}
+
+fn read_full<R: Read>(reader: &mut R, buffer: &mut [u8]) -> Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        let read = reader.read(&mut buffer[filled..]).context("Failed to read file")?;
+        if read == 0 {
+            break;
+        }
+        filled += read;
+    }
+    Ok(filled)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn pseudo_random(len: usize, seed: u32) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                (state >> 16) as u8
+            })
+            .collect()
+    }
+
+    fn round_trip(old: &[u8], new: &[u8]) -> Result<(Vec<u8>, usize)> {
+        let temp_dir = TempDir::new()?;
+        let old_path = temp_dir.path().join("old.bin");
+        let new_path = temp_dir.path().join("new.bin");
+        let out_path = temp_dir.path().join("out.bin");
+        std::fs::write(&old_path, old)?;
+        std::fs::write(&new_path, new)?;
+
+        let sig = signature(&old_path)?;
+        let mut ops = Vec::new();
+        let checksum = compute_delta(&new_path, &sig, |op| {
+            ops.push(op);
+            Ok(())
+        })?;
+        assert_eq!(checksum, format!("{:x}", Sha256::digest(new)));
+
+        let mut applier = DeltaApplier::new(Some(File::open(&old_path)?), &sig);
+        let mut output = File::create(&out_path)?;
+        for op in &ops {
+            applier.apply(op, &mut output)?;
+        }
+        drop(output);
+
+        let literal = ops.iter().map(|op| op.literal_len()).sum();
+        Ok((std::fs::read(&out_path)?, literal))
+    }
+
+    #[test]
+    fn test_rolling_checksum_matches_fresh_computation() {
+        let data = pseudo_random(64, 7);
+        let mut sum = RollingChecksum::new(&data[0..16]);
+        for i in 0..(data.len() - 16) {
+            sum.roll(data[i], data[i + 16]);
+            assert_eq!(sum.digest(), RollingChecksum::new(&data[i + 1..i + 17]).digest());
+        }
+    }
+
+    #[test]
+    fn test_delta_sends_only_changes() -> Result<()> {
+        let old = pseudo_random(300 * 1024, 1);
+
+        // Insert a few bytes in the middle and overwrite a region near the end
+        let mut new = old.clone();
+        new.splice(100_000..100_000, b"inserted bytes".iter().copied());
+        for byte in &mut new[250_000..250_100] {
+            *byte = 0;
+        }
+
+        let (rebuilt, literal) = round_trip(&old, &new)?;
+        assert_eq!(rebuilt, new);
+        assert!(literal < 32 * 1024, "sent {} literal bytes", literal);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_delta_edge_cases() -> Result<()> {
+        // Identical, empty basis, empty new file, unaligned sizes
+        let data = pseudo_random(10_000, 3);
+        assert_eq!(round_trip(&data, &data)?.0, data);
+        assert_eq!(round_trip(&[], &data)?.0, data);
+        assert_eq!(round_trip(&data, &[])?.0, Vec::<u8>::new());
+        assert_eq!(round_trip(&data[..5_000], &data[..7_777])?.0, data[..7_777].to_vec());
+
+        // Unchanged data is copied, not resent
+        let (_, literal) = round_trip(&data, &data)?;
+        assert_eq!(literal, 0);
+
+        Ok(())
+    }
+}
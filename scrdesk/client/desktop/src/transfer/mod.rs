@@ -3,10 +3,96 @@ use sha2::{Digest, Sha256};
 use std::collections::HashMap;
 use std::fs::{File, OpenOptions};
 use std::io::{Read, Seek, SeekFrom, Write};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use tokio::sync::mpsc;
 
+pub mod browser;
+pub mod delta;
+pub mod scheduler;
+
+use crate::dlp::Dlp;
+use delta::{DeltaApplier, DeltaOp, FileSignature};
+
 const CHUNK_SIZE: usize = 1024 * 1024; // 1 MB chunks
+const QUARANTINE_SUFFIX: &str = ".scrdesk-partial";
+const MAX_FILENAME_BYTES: usize = 255;
+const MAX_TRANSFER_ID_BYTES: usize = 64;
+
+// Device names that Windows refuses to use as file names
+const RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL",
+    "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9",
+    "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// What to do when an incoming file has the same name as an existing one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    Rename,    // Save as "name (1).ext"
+    Overwrite, // Replace the existing file
+    Ask,       // Hold the transfer until the user decides
+}
+
+/// The user's answer to a pending name conflict
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConflictResolution {
+    Rename,
+    Overwrite,
+    Reject,
+}
+
+/// Limits applied to files received from the remote peer
+#[derive(Debug, Clone)]
+pub struct DownloadPolicy {
+    pub conflict: ConflictPolicy,
+    pub max_file_size: Option<u64>,
+    pub session_quota: Option<u64>,
+    pub allowed_extensions: Option<Vec<String>>, // Lowercase, without the dot
+    pub denied_extensions: Vec<String>,
+}
+
+impl Default for DownloadPolicy {
+    fn default() -> Self {
+        Self {
+            conflict: ConflictPolicy::Rename,
+            max_file_size: Some(4 * 1024 * 1024 * 1024), // 4 GB
+            session_quota: Some(20 * 1024 * 1024 * 1024), // 20 GB
+            allowed_extensions: None,
+            denied_extensions: Vec::new(),
+        }
+    }
+}
+
+/// Outcome of accepting an incoming transfer
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DownloadStart {
+    Started,
+    NeedsConfirmation { existing: PathBuf },
+}
+
+/// Outcome of accepting an incoming delta sync
+#[derive(Debug)]
+pub enum DeltaStart {
+    Started(FileSignature), // For the sender to compute the delta against
+    NeedsConfirmation { existing: PathBuf },
+}
+
+/// How a download held for the user goes on
+#[derive(Debug)]
+pub enum ResolvedDownload {
+    Rejected,
+    Chunks,                // The sender can start sending chunks
+    Delta(FileSignature), // The sender needs the signature of the existing copy
+}
+
+#[derive(Debug, Clone)]
+pub struct PendingDownload {
+    pub transfer_id: String,
+    pub filename: String,
+    pub total_size: u64,
+    pub existing: PathBuf,
+    pub delta: bool, // Held from a delta sync rather than a plain transfer
+}
 
 #[derive(Debug, Clone)]
 pub struct TransferInfo {
@@ -25,7 +111,9 @@ pub enum TransferDirection {
 #[derive(Debug)]
 pub struct TransferState {
     pub info: TransferInfo,
-    pub file_path: PathBuf,
+    pub file_path: PathBuf, // Quarantine file while a download is in progress
+    pub final_path: Option<PathBuf>,
+    pub overwrite: bool,
     pub file_handle: File,
     pub bytes_transferred: u64,
     pub chunk_count: u64,
@@ -33,6 +121,7 @@ pub struct TransferState {
     pub checksum: Option<String>,
     pub completed: bool,
     pub error: Option<String>,
+    pub delta: Option<DeltaApplier>, // Set when the file is rebuilt from an existing copy
 }
 
 impl TransferState {
@@ -47,21 +136,49 @@ impl TransferState {
 pub struct FileTransferManager {
     transfers: HashMap<String, TransferState>,
     download_dir: PathBuf,
+    policy: DownloadPolicy,
+    pending: HashMap<String, PendingDownload>,
+    session_bytes: u64,
+    dlp: Option<Dlp>, // Inspects files before they are sent
 }
 
 impl FileTransferManager {
     pub fn new(download_dir: PathBuf) -> Result<Self> {
+        Self::with_policy(download_dir, DownloadPolicy::default())
+    }
+
+    pub fn with_policy(download_dir: PathBuf, policy: DownloadPolicy) -> Result<Self> {
         std::fs::create_dir_all(&download_dir)
             .context("Failed to create download directory")?;
 
         Ok(Self {
             transfers: HashMap::new(),
             download_dir,
+            policy,
+            pending: HashMap::new(),
+            session_bytes: 0,
+            dlp: None,
         })
     }
 
+    /// Start a new session: the per-session quota is counted from zero again
+    pub fn reset_session_quota(&mut self) {
+        self.session_bytes = 0;
+    }
+
+    /// Inspect every file with these data loss prevention rules before it is sent
+    pub fn set_dlp(&mut self, dlp: Dlp) {
+        self.dlp = Some(dlp);
+    }
+
     /// Start sending a file to remote
     pub fn start_upload(&mut self, file_path: PathBuf) -> Result<TransferInfo> {
+        let transfer_id = uuid::Uuid::new_v4().to_string();
+        self.start_upload_with_id(transfer_id, file_path)
+    }
+
+    /// Start sending a file under an ID chosen by the peer (e.g. a `DownloadPath` request)
+    pub fn start_upload_with_id(&mut self, transfer_id: String, file_path: PathBuf) -> Result<TransferInfo> {
         let file = File::open(&file_path)
             .context("Failed to open file for upload")?;
 
@@ -74,7 +191,10 @@ impl FileTransferManager {
             .context("Invalid filename")?
             .to_string();
 
-        let transfer_id = uuid::Uuid::new_v4().to_string();
+        if let Some(dlp) = &self.dlp {
+            dlp.check_file(&file_path, &filename)?;
+        }
+
         let total_size = metadata.len();
         let expected_chunks = (total_size + CHUNK_SIZE as u64 - 1) / CHUNK_SIZE as u64;
 
@@ -88,6 +208,8 @@ impl FileTransferManager {
         let state = TransferState {
             info: info.clone(),
             file_path,
+            final_path: None,
+            overwrite: false,
             file_handle: file,
             bytes_transferred: 0,
             chunk_count: 0,
@@ -95,6 +217,7 @@ impl FileTransferManager {
             checksum: None,
             completed: false,
             error: None,
+            delta: None,
         };
 
         self.transfers.insert(transfer_id, state);
@@ -143,19 +266,132 @@ impl FileTransferManager {
         Ok(Some((chunk_index, buffer)))
     }
 
-    /// Start receiving a file from remote
-    pub fn start_download(&mut self, transfer_id: String, filename: String, total_size: u64) -> Result<()> {
-        let file_path = self.download_dir.join(&filename);
+    /// Start receiving a file from remote.
+    /// The peer-supplied name is sanitized and checked against the download policy;
+    /// data is written to a quarantine file until `finish_download` verifies it.
+    pub fn start_download(&mut self, transfer_id: String, filename: String, total_size: u64) -> Result<DownloadStart> {
+        self.check_transfer_id(&transfer_id)?;
+
+        let filename = sanitize_filename(&filename)?;
+        self.check_policy(&filename, total_size)?;
+
+        let target = self.download_dir.join(&filename);
+        if target.exists() {
+            match self.policy.conflict {
+                ConflictPolicy::Ask => {
+                    self.pending.insert(transfer_id.clone(), PendingDownload {
+                        transfer_id,
+                        filename,
+                        total_size,
+                        existing: target.clone(),
+                        delta: false,
+                    });
+                    return Ok(DownloadStart::NeedsConfirmation { existing: target });
+                }
+                ConflictPolicy::Overwrite => {
+                    self.open_download(transfer_id, filename, total_size, true)?;
+                }
+                ConflictPolicy::Rename => {
+                    self.open_download(transfer_id, filename, total_size, false)?;
+                }
+            }
+        } else {
+            self.open_download(transfer_id, filename, total_size, false)?;
+        }
+
+        Ok(DownloadStart::Started)
+    }
+
+    /// Downloads waiting for the user to resolve a name conflict
+    pub fn pending_downloads(&self) -> Vec<&PendingDownload> {
+        self.pending.values().collect()
+    }
+
+    /// Resume (or reject) a download that was held by `ConflictPolicy::Ask`
+    pub fn resolve_conflict(&mut self, transfer_id: &str, resolution: ConflictResolution) -> Result<ResolvedDownload> {
+        let pending = self.pending
+            .remove(transfer_id)
+            .context("No pending download with this ID")?;
+
+        match resolution {
+            ConflictResolution::Reject => {
+                tracing::info!("Download rejected by user: {}", pending.filename);
+                Ok(ResolvedDownload::Rejected)
+            }
+            ConflictResolution::Rename | ConflictResolution::Overwrite => {
+                // Limits may have changed while the user was deciding
+                self.check_policy(&pending.filename, pending.total_size)?;
+                let overwrite = resolution == ConflictResolution::Overwrite;
+                if pending.delta {
+                    let signature = self.open_delta_download(pending.transfer_id, pending.filename, pending.total_size, overwrite)?;
+                    return Ok(ResolvedDownload::Delta(signature));
+                }
+                self.open_download(pending.transfer_id, pending.filename, pending.total_size, overwrite)?;
+                Ok(ResolvedDownload::Chunks)
+            }
+        }
+    }
+
+    /// Transfer IDs come from the peer: a short token that no other transfer uses
+    fn check_transfer_id(&self, transfer_id: &str) -> Result<()> {
+        let valid = !transfer_id.is_empty()
+            && transfer_id.len() <= MAX_TRANSFER_ID_BYTES
+            && transfer_id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if !valid {
+            anyhow::bail!("Invalid transfer ID: {:?}", transfer_id);
+        }
+        if self.transfers.contains_key(transfer_id) || self.pending.contains_key(transfer_id) {
+            anyhow::bail!("Duplicate transfer ID: {}", transfer_id);
+        }
+        Ok(())
+    }
+
+    fn check_policy(&self, filename: &str, total_size: u64) -> Result<()> {
+        if let Some(max) = self.policy.max_file_size {
+            if total_size > max {
+                anyhow::bail!("File too large: {} bytes (limit {} bytes)", total_size, max);
+            }
+        }
+
+        if let Some(quota) = self.policy.session_quota {
+            if self.session_bytes.saturating_add(total_size) > quota {
+                anyhow::bail!("Session transfer quota exceeded ({} bytes)", quota);
+            }
+        }
+
+        let extension = Path::new(filename)
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_lowercase())
+            .unwrap_or_default();
+
+        if let Some(allowed) = &self.policy.allowed_extensions {
+            if !allowed.iter().any(|a| a.eq_ignore_ascii_case(&extension)) {
+                anyhow::bail!("File type not allowed: .{}", extension);
+            }
+        }
+
+        if self.policy.denied_extensions.iter().any(|d| d.eq_ignore_ascii_case(&extension)) {
+            anyhow::bail!("File type blocked: .{}", extension);
+        }
+
+        Ok(())
+    }
+
+    fn open_download(&mut self, transfer_id: String, filename: String, total_size: u64, overwrite: bool) -> Result<()> {
+        // Named locally, never after anything the peer sent
+        let file_path = self.download_dir.join(format!(".{}{}", uuid::Uuid::new_v4(), QUARANTINE_SUFFIX));
 
-        // Create or truncate file
+        // Read access is needed to verify the checksum before release
         let file_handle = OpenOptions::new()
+            .read(true)
             .write(true)
-            .create(true)
-            .truncate(true)
+            .create_new(true)
             .open(&file_path)
-            .context("Failed to create download file")?;
+            .context("Failed to create quarantine file")?;
 
         let expected_chunks = (total_size + CHUNK_SIZE as u64 - 1) / CHUNK_SIZE as u64;
+        self.session_bytes += total_size;
 
         let info = TransferInfo {
             transfer_id: transfer_id.clone(),
@@ -167,6 +403,8 @@ impl FileTransferManager {
         let state = TransferState {
             info,
             file_path,
+            final_path: None,
+            overwrite,
             file_handle,
             bytes_transferred: 0,
             chunk_count: 0,
@@ -174,6 +412,7 @@ impl FileTransferManager {
             checksum: None,
             completed: false,
             error: None,
+            delta: None,
         };
 
         self.transfers.insert(transfer_id, state);
@@ -181,14 +420,107 @@ impl FileTransferManager {
         Ok(())
     }
 
-    /// Write received chunk to file
+    /// Start receiving a file as a delta against the existing copy with the same name.
+    /// The existing copy is handled like a name conflict: the rebuilt file replaces it
+    /// only under `ConflictPolicy::Overwrite` or once the user chooses to overwrite,
+    /// and is saved under a new name otherwise. Once started, the block signature of
+    /// the existing copy (empty if there is none) goes to the sender.
+    pub fn start_delta_download(&mut self, transfer_id: String, filename: String, total_size: u64) -> Result<DeltaStart> {
+        self.check_transfer_id(&transfer_id)?;
+
+        let filename = sanitize_filename(&filename)?;
+        self.check_policy(&filename, total_size)?;
+
+        let existing = self.download_dir.join(&filename);
+        let overwrite = if existing.exists() {
+            match self.policy.conflict {
+                ConflictPolicy::Ask => {
+                    self.pending.insert(transfer_id.clone(), PendingDownload {
+                        transfer_id,
+                        filename,
+                        total_size,
+                        existing: existing.clone(),
+                        delta: true,
+                    });
+                    return Ok(DeltaStart::NeedsConfirmation { existing });
+                }
+                ConflictPolicy::Overwrite => true,
+                ConflictPolicy::Rename => false,
+            }
+        } else {
+            false
+        };
+
+        let signature = self.open_delta_download(transfer_id, filename, total_size, overwrite)?;
+        Ok(DeltaStart::Started(signature))
+    }
+
+    fn open_delta_download(&mut self, transfer_id: String, filename: String, total_size: u64, overwrite: bool) -> Result<FileSignature> {
+        let existing = self.download_dir.join(&filename);
+        let (basis, signature) = if existing.is_file() {
+            let signature = delta::signature(&existing)?;
+            let basis = File::open(&existing).context("Failed to open existing file")?;
+            (Some(basis), signature)
+        } else {
+            (None, FileSignature::empty())
+        };
+
+        self.open_download(transfer_id.clone(), filename, total_size, overwrite)?;
+        if let Some(state) = self.transfers.get_mut(&transfer_id) {
+            state.delta = Some(DeltaApplier::new(basis, &signature));
+        }
+
+        Ok(signature)
+    }
+
+    /// Append delta instructions to the quarantine file
+    pub fn apply_delta(&mut self, transfer_id: &str, ops: &[DeltaOp]) -> Result<()> {
+        let state = self.transfers
+            .get_mut(transfer_id)
+            .context("Transfer not found")?;
+
+        if state.completed {
+            anyhow::bail!("Transfer already finished");
+        }
+
+        let applier = state.delta.as_mut().context("Transfer is not a delta transfer")?;
+        for op in ops {
+            // Checked before anything is written
+            let len = applier.output_len(op)?;
+            match state.bytes_transferred.checked_add(len) {
+                Some(total) if total <= state.info.total_size => {}
+                _ => anyhow::bail!("Delta exceeds announced file size"),
+            }
+
+            state.bytes_transferred += applier.apply(op, &mut state.file_handle)?;
+        }
+
+        state.chunk_count += 1;
+        tracing::info!("Delta applied for {} ({:.1}%)", state.info.filename, state.progress_percent());
+
+        Ok(())
+    }
+
+    /// Write received chunk to the quarantine file
     pub fn write_chunk(&mut self, transfer_id: &str, chunk_index: u64, data: Vec<u8>) -> Result<()> {
         let state = self.transfers
             .get_mut(transfer_id)
             .context("Transfer not found")?;
 
+        if state.completed {
+            anyhow::bail!("Transfer already finished");
+        }
+
+        // Never accept more data than was announced (and counted against the quota)
+        let end = chunk_index
+            .checked_mul(CHUNK_SIZE as u64)
+            .and_then(|offset| offset.checked_add(data.len() as u64).map(|end| (offset, end)));
+        let offset = match end {
+            Some((offset, end)) if data.len() <= CHUNK_SIZE && end <= state.info.total_size => offset,
+            _ => anyhow::bail!("Chunk {} exceeds announced file size", chunk_index),
+        };
+
         // Seek to correct position
-        let offset = chunk_index * CHUNK_SIZE as u64;
         state.file_handle.seek(SeekFrom::Start(offset))
             .context("Failed to seek in file")?;
 
@@ -209,20 +541,77 @@ impl FileTransferManager {
             state.progress_percent()
         );
 
-        // Check if complete
-        if state.chunk_count >= state.expected_chunks {
-            state.completed = true;
-            let filename = state.info.filename.clone();
-            // Calculate checksum after dropping the mutable borrow
-            drop(state);
-            let checksum = self.calculate_checksum(transfer_id)?;
-            if let Some(state) = self.transfers.get_mut(transfer_id) {
-                state.checksum = Some(checksum.clone());
+        Ok(())
+    }
+
+    /// Verify a finished download against the sender's checksum and move it
+    /// out of quarantine. On mismatch the quarantine file is deleted.
+    pub fn finish_download(&mut self, transfer_id: &str, expected_checksum: Option<&str>) -> Result<PathBuf> {
+        let result = self.release_download(transfer_id, expected_checksum);
+
+        if let Err(ref e) = result {
+            if let Some(state) = self.transfers.get(transfer_id) {
+                let _ = std::fs::remove_file(&state.file_path);
             }
-            tracing::info!("Download complete: {} (checksum: {})", filename, checksum);
+            self.mark_failed(transfer_id, e.to_string());
         }
 
-        Ok(())
+        result
+    }
+
+    fn release_download(&mut self, transfer_id: &str, expected_checksum: Option<&str>) -> Result<PathBuf> {
+        let expected = expected_checksum.context("Sender did not provide a checksum")?;
+
+        {
+            let state = self.transfers
+                .get(transfer_id)
+                .context("Transfer not found")?;
+
+            if state.info.direction != TransferDirection::Download || state.completed {
+                anyhow::bail!("Transfer is not an active download");
+            }
+            if state.bytes_transferred != state.info.total_size {
+                anyhow::bail!(
+                    "Incomplete download: {} of {} bytes",
+                    state.bytes_transferred,
+                    state.info.total_size
+                );
+            }
+        }
+
+        let checksum = self.calculate_checksum(transfer_id)?;
+        if !checksum.eq_ignore_ascii_case(expected) {
+            anyhow::bail!("Checksum mismatch (expected {}, got {})", expected, checksum);
+        }
+
+        let download_dir = self.download_dir.clone();
+        let state = self.transfers
+            .get_mut(transfer_id)
+            .context("Transfer not found")?;
+
+        // Release the basis file before it gets replaced
+        state.delta = None;
+
+        let mut target = download_dir.join(&state.info.filename);
+        if target.exists() {
+            if state.overwrite {
+                std::fs::remove_file(&target).context("Failed to replace existing file")?;
+            } else {
+                target = unique_path(&download_dir, &state.info.filename);
+            }
+        }
+
+        std::fs::rename(&state.file_path, &target)
+            .context("Failed to move file out of quarantine")?;
+
+        state.file_path = target.clone();
+        state.final_path = Some(target.clone());
+        state.checksum = Some(checksum.clone());
+        state.completed = true;
+
+        tracing::info!("Download complete: {} (checksum: {})", target.display(), checksum);
+
+        Ok(target)
     }
 
     /// Calculate SHA256 checksum of file
@@ -267,6 +656,10 @@ impl FileTransferManager {
     /// Mark transfer as failed
     pub fn mark_failed(&mut self, transfer_id: &str, error: String) {
         if let Some(state) = self.transfers.get_mut(transfer_id) {
+            // Unverified data never leaves quarantine
+            if state.info.direction == TransferDirection::Download && state.final_path.is_none() {
+                let _ = std::fs::remove_file(&state.file_path);
+            }
             state.error = Some(error);
             state.completed = true;
         }
@@ -274,9 +667,10 @@ impl FileTransferManager {
 
     /// Cancel and remove transfer
     pub fn cancel_transfer(&mut self, transfer_id: &str) -> Result<()> {
+        self.pending.remove(transfer_id);
         if let Some(state) = self.transfers.remove(transfer_id) {
             // Delete partial file for downloads
-            if state.info.direction == TransferDirection::Download && !state.completed {
+            if state.info.direction == TransferDirection::Download && state.final_path.is_none() {
                 let _ = std::fs::remove_file(&state.file_path);
             }
             tracing::info!("Transfer cancelled: {}", transfer_id);
@@ -298,6 +692,71 @@ impl FileTransferManager {
     }
 }
 
+/// Reduce a peer-supplied file name to a single safe path component.
+/// Directory parts, control characters and characters that are invalid on
+/// common filesystems are removed; names that end up empty are rejected.
+pub fn sanitize_filename(name: &str) -> Result<String> {
+    // Only the last component counts, whatever separator the peer used
+    let base = name.rsplit(|c| c == '/' || c == '\\').next().unwrap_or_default();
+
+    let cleaned: String = base
+        .chars()
+        .filter(|c| !c.is_control() && !matches!(c, '<' | '>' | ':' | '"' | '|' | '?' | '*'))
+        .collect();
+
+    // No hidden files and no trailing dots/spaces (Windows strips those silently)
+    let mut cleaned = cleaned
+        .trim_start_matches(|c: char| c == '.' || c.is_whitespace())
+        .trim_end_matches(|c: char| c == '.' || c.is_whitespace())
+        .to_string();
+
+    if cleaned.is_empty() {
+        anyhow::bail!("Invalid file name: {:?}", name);
+    }
+
+    let stem = cleaned.split('.').next().unwrap_or_default();
+    if RESERVED_NAMES.iter().any(|r| r.eq_ignore_ascii_case(stem)) {
+        cleaned.insert(0, '_');
+    }
+
+    if cleaned.ends_with(QUARANTINE_SUFFIX) {
+        cleaned.push_str(".file");
+    }
+
+    // Truncate on a char boundary, keeping the extension where possible
+    if cleaned.len() > MAX_FILENAME_BYTES {
+        let extension = Path::new(&cleaned)
+            .extension()
+            .and_then(|e| e.to_str())
+            .filter(|e| e.len() < 16)
+            .map(|e| format!(".{}", e))
+            .unwrap_or_default();
+
+        let mut stem_len = MAX_FILENAME_BYTES - extension.len();
+        while !cleaned.is_char_boundary(stem_len) {
+            stem_len -= 1;
+        }
+        cleaned = format!("{}{}", &cleaned[..stem_len], extension);
+    }
+
+    Ok(cleaned)
+}
+
+/// First free "name (n).ext" in `dir`
+fn unique_path(dir: &Path, filename: &str) -> PathBuf {
+    let path = Path::new(filename);
+    let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or(filename);
+    let extension = path.extension().and_then(|e| e.to_str());
+
+    (1..)
+        .map(|n| match extension {
+            Some(ext) => dir.join(format!("{} ({}).{}", stem, n, ext)),
+            None => dir.join(format!("{} ({})", stem, n)),
+        })
+        .find(|candidate| !candidate.exists())
+        .expect("unbounded range always yields a free name")
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -352,13 +811,19 @@ mod tests {
         // Write chunk
         manager.write_chunk("test-123", 0, b"Hello, World!".to_vec())?;
 
+        // Data stays in quarantine until verified
+        let file_path = temp_dir.path().join("received.txt");
+        assert!(!file_path.exists());
+
+        let checksum = format!("{:x}", Sha256::digest(b"Hello, World!"));
+        manager.finish_download("test-123", Some(&checksum))?;
+
         // Check state
         let state = manager.get_transfer("test-123").unwrap();
         assert!(state.completed);
         assert_eq!(state.bytes_transferred, 13);
 
         // Verify file exists
-        let file_path = temp_dir.path().join("received.txt");
         assert!(file_path.exists());
 
         let contents = std::fs::read_to_string(file_path)?;
@@ -366,4 +831,232 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_sanitize_filename() -> Result<()> {
+        assert_eq!(sanitize_filename("../../.bashrc")?, "bashrc");
+        assert_eq!(sanitize_filename("..\\..\\Windows\\evil.exe")?, "evil.exe");
+        assert_eq!(sanitize_filename("/etc/passwd")?, "passwd");
+        assert_eq!(sanitize_filename("report<1>.pdf")?, "report1.pdf");
+        assert_eq!(sanitize_filename("CON.txt")?, "_CON.txt");
+        assert_eq!(sanitize_filename("notes.txt. ")?, "notes.txt");
+        assert!(sanitize_filename("..").is_err());
+        assert!(sanitize_filename("dir/").is_err());
+
+        let long = format!("{}.txt", "a".repeat(400));
+        let sanitized = sanitize_filename(&long)?;
+        assert!(sanitized.len() <= MAX_FILENAME_BYTES);
+        assert!(sanitized.ends_with(".txt"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_download_rejects_traversal_and_limits() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let download_dir = temp_dir.path().join("downloads");
+        let policy = DownloadPolicy {
+            max_file_size: Some(100),
+            session_quota: Some(150),
+            denied_extensions: vec!["exe".to_string()],
+            ..Default::default()
+        };
+        let mut manager = FileTransferManager::with_policy(download_dir.clone(), policy)?;
+
+        manager.start_download("t1".to_string(), "../../escape.txt".to_string(), 5)?;
+        manager.write_chunk("t1", 0, b"12345".to_vec())?;
+        let checksum = format!("{:x}", Sha256::digest(b"12345"));
+        let path = manager.finish_download("t1", Some(&checksum))?;
+        assert_eq!(path, download_dir.join("escape.txt"));
+        assert!(!temp_dir.path().join("escape.txt").exists());
+
+        assert!(manager.start_download("t2".to_string(), "big.bin".to_string(), 101).is_err());
+        assert!(manager.start_download("t3".to_string(), "tool.EXE".to_string(), 1).is_err());
+
+        // 5 bytes used, 100 more would exceed the 150 byte quota
+        manager.start_download("t4".to_string(), "a.bin".to_string(), 100)?;
+        assert!(manager.start_download("t5".to_string(), "b.bin".to_string(), 50).is_err());
+
+        // Chunks beyond the announced size are refused, also where the offset would overflow
+        assert!(manager.write_chunk("t4", 0, vec![0u8; 101]).is_err());
+        assert!(manager.write_chunk("t4", u64::MAX / 2, vec![0u8; 10]).is_err());
+        assert_eq!(manager.get_transfer("t4").unwrap().bytes_transferred, 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_transfer_id_never_reaches_the_filesystem() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let download_dir = temp_dir.path().join("downloads");
+        let mut manager = FileTransferManager::new(download_dir.clone())?;
+
+        for transfer_id in ["/../../../x", "../x", "a/b", "a\\b", "", &"a".repeat(65)] {
+            assert!(manager.start_download(transfer_id.to_string(), "x.txt".to_string(), 1).is_err());
+            assert!(manager.start_delta_download(transfer_id.to_string(), "x.txt".to_string(), 1).is_err());
+        }
+        assert!(!temp_dir.path().join("x.scrdesk-partial").exists());
+
+        // Quarantine files are named locally
+        manager.start_download("t-1".to_string(), "x.txt".to_string(), 1)?;
+        let names: Vec<String> = std::fs::read_dir(&download_dir)?
+            .map(|entry| entry.map(|e| e.file_name().to_string_lossy().into_owned()))
+            .collect::<std::io::Result<_>>()?;
+        assert_eq!(names.len(), 1);
+        assert!(names[0].ends_with(QUARANTINE_SUFFIX) && !names[0].contains("t-1"), "{:?}", names);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_checksum_mismatch_discards_file() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let mut manager = FileTransferManager::new(temp_dir.path().to_path_buf())?;
+
+        manager.start_download("t1".to_string(), "data.txt".to_string(), 4)?;
+        manager.write_chunk("t1", 0, b"evil".to_vec())?;
+
+        assert!(manager.finish_download("t1", Some("deadbeef")).is_err());
+        assert!(!temp_dir.path().join("data.txt").exists());
+
+        let leftovers: Vec<_> = std::fs::read_dir(temp_dir.path())?.collect();
+        assert!(leftovers.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_conflict_policies() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        std::fs::write(temp_dir.path().join("file.txt"), b"old")?;
+        let checksum = format!("{:x}", Sha256::digest(b"new"));
+
+        // Rename keeps the original
+        let mut manager = FileTransferManager::new(temp_dir.path().to_path_buf())?;
+        manager.start_download("r".to_string(), "file.txt".to_string(), 3)?;
+        manager.write_chunk("r", 0, b"new".to_vec())?;
+        let path = manager.finish_download("r", Some(&checksum))?;
+        assert_eq!(path, temp_dir.path().join("file (1).txt"));
+        assert_eq!(std::fs::read(temp_dir.path().join("file.txt"))?, b"old");
+
+        // Ask holds the transfer until resolved
+        let policy = DownloadPolicy { conflict: ConflictPolicy::Ask, ..Default::default() };
+        let mut manager = FileTransferManager::with_policy(temp_dir.path().to_path_buf(), policy)?;
+        let start = manager.start_download("a".to_string(), "file.txt".to_string(), 3)?;
+        assert!(matches!(start, DownloadStart::NeedsConfirmation { .. }));
+        assert!(manager.write_chunk("a", 0, b"new".to_vec()).is_err());
+
+        assert!(matches!(manager.resolve_conflict("a", ConflictResolution::Overwrite)?, ResolvedDownload::Chunks));
+        manager.write_chunk("a", 0, b"new".to_vec())?;
+        manager.finish_download("a", Some(&checksum))?;
+        assert_eq!(std::fs::read(temp_dir.path().join("file.txt"))?, b"new");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_delta_download_replaces_existing_copy() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let download_dir = temp_dir.path().join("downloads");
+        let policy = DownloadPolicy { conflict: ConflictPolicy::Overwrite, ..Default::default() };
+        let mut manager = FileTransferManager::with_policy(download_dir.clone(), policy)?;
+
+        let old: Vec<u8> = (0..50_000u32).map(|i| (i % 251) as u8).collect();
+        let mut new = old.clone();
+        new[20_000..20_010].copy_from_slice(b"0123456789");
+        std::fs::write(download_dir.join("image.bin"), &old)?;
+
+        let source = temp_dir.path().join("image.bin");
+        std::fs::write(&source, &new)?;
+
+        let signature = started(manager.start_delta_download("d1".to_string(), "image.bin".to_string(), new.len() as u64)?);
+        assert!(!signature.blocks.is_empty());
+
+        let mut ops = Vec::new();
+        let checksum = delta::compute_delta(&source, &signature, |op| {
+            ops.push(op);
+            Ok(())
+        })?;
+        manager.apply_delta("d1", &ops)?;
+
+        // Existing copy is untouched until the result is verified
+        assert_eq!(std::fs::read(download_dir.join("image.bin"))?, old);
+
+        let target = manager.finish_download("d1", Some(&checksum))?;
+        assert_eq!(target, download_dir.join("image.bin"));
+        assert_eq!(std::fs::read(&target)?, new);
+
+        // A bad checksum leaves the existing file alone
+        let signature = started(manager.start_delta_download("d2".to_string(), "image.bin".to_string(), new.len() as u64)?);
+        let mut ops = Vec::new();
+        delta::compute_delta(&source, &signature, |op| {
+            ops.push(op);
+            Ok(())
+        })?;
+        manager.apply_delta("d2", &ops)?;
+        assert!(manager.finish_download("d2", Some("00")).is_err());
+        assert_eq!(std::fs::read(&target)?, new);
+
+        // Ops that would grow the file past the announced size are refused before they are written
+        let signature = started(manager.start_delta_download("d3".to_string(), "image.bin".to_string(), 10)?);
+        assert!(!signature.blocks.is_empty());
+        let copy_all = DeltaOp::Copy { start_block: 0, count: signature.blocks.len() as u64 };
+        assert!(manager.apply_delta("d3", &[copy_all]).is_err());
+        assert_eq!(manager.get_transfer("d3").unwrap().bytes_transferred, 0);
+        assert_eq!(manager.get_transfer("d3").unwrap().file_handle.metadata()?.len(), 0);
+
+        Ok(())
+    }
+
+    fn started(start: DeltaStart) -> FileSignature {
+        match start {
+            DeltaStart::Started(signature) => signature,
+            DeltaStart::NeedsConfirmation { existing } => panic!("Held for {}", existing.display()),
+        }
+    }
+
+    #[test]
+    fn test_delta_download_follows_conflict_policy() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let old = vec![7u8; 10_000];
+        std::fs::write(temp_dir.path().join("log.txt"), &old)?;
+        let source = temp_dir.path().join("source.txt");
+        std::fs::write(&source, vec![8u8; 10_000])?;
+
+        let sync = |manager: &mut FileTransferManager, transfer_id: &str, signature: &FileSignature| -> Result<PathBuf> {
+            let mut ops = Vec::new();
+            let checksum = delta::compute_delta(&source, signature, |op| {
+                ops.push(op);
+                Ok(())
+            })?;
+            manager.apply_delta(transfer_id, &ops)?;
+            manager.finish_download(transfer_id, Some(&checksum))
+        };
+
+        // Rename keeps the existing copy and saves the result next to it
+        let mut manager = FileTransferManager::new(temp_dir.path().to_path_buf())?;
+        let signature = started(manager.start_delta_download("r".to_string(), "log.txt".to_string(), 10_000)?);
+        assert_eq!(sync(&mut manager, "r", &signature)?, temp_dir.path().join("log (1).txt"));
+        assert_eq!(std::fs::read(temp_dir.path().join("log.txt"))?, old);
+
+        // Ask holds the sync until the user decides
+        let policy = DownloadPolicy { conflict: ConflictPolicy::Ask, ..Default::default() };
+        let mut manager = FileTransferManager::with_policy(temp_dir.path().to_path_buf(), policy)?;
+        let start = manager.start_delta_download("a".to_string(), "log.txt".to_string(), 10_000)?;
+        assert!(matches!(start, DeltaStart::NeedsConfirmation { .. }));
+        assert!(manager.pending_downloads()[0].delta);
+        assert!(manager.apply_delta("a", &[]).is_err());
+
+        let start = manager.start_delta_download("b".to_string(), "log.txt".to_string(), 10_000)?;
+        assert!(matches!(start, DeltaStart::NeedsConfirmation { .. }));
+        assert!(matches!(manager.resolve_conflict("b", ConflictResolution::Reject)?, ResolvedDownload::Rejected));
+
+        let ResolvedDownload::Delta(signature) = manager.resolve_conflict("a", ConflictResolution::Overwrite)? else {
+            panic!("Expected a signature");
+        };
+        assert_eq!(sync(&mut manager, "a", &signature)?, temp_dir.path().join("log.txt"));
+        assert_eq!(std::fs::read(temp_dir.path().join("log.txt"))?, vec![8u8; 10_000]);
+
+        Ok(())
+    }
 }
@@ -0,0 +1,241 @@
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+/// Chunks a single transfer may have unacknowledged at any time
+pub const DEFAULT_WINDOW_SIZE: usize = 4;
+
+#[derive(Debug)]
+struct UploadFlow {
+    accepted: bool,
+    in_flight: BTreeSet<u64>,
+    read_complete: bool,
+    failed: bool,
+}
+
+/// Sender-side flow control for file uploads.
+///
+/// Each transfer may have at most `window_size` chunks in flight; a new chunk
+/// is only released once the receiver acknowledges an earlier one. Transfers
+/// that can send are served round-robin so one large file cannot starve the
+/// others, and an optional token bucket caps the total upload bandwidth.
+pub struct TransferScheduler {
+    window_size: usize,
+    flows: HashMap<String, UploadFlow>,
+    order: VecDeque<String>,
+    limiter: Option<RateLimiter>,
+}
+
+impl TransferScheduler {
+    pub fn new(window_size: usize, bandwidth_limit: Option<u64>) -> Self {
+        Self {
+            window_size: window_size.max(1),
+            flows: HashMap::new(),
+            order: VecDeque::new(),
+            limiter: bandwidth_limit.map(RateLimiter::new),
+        }
+    }
+
+    /// Change the bandwidth cap (bytes per second); `None` removes it
+    pub fn set_bandwidth_limit(&mut self, bandwidth_limit: Option<u64>) {
+        self.limiter = bandwidth_limit.map(RateLimiter::new);
+    }
+
+    /// Register an upload. Nothing is sent until the receiver accepts it.
+    pub fn add(&mut self, transfer_id: String) {
+        self.flows.insert(transfer_id.clone(), UploadFlow {
+            accepted: false,
+            in_flight: BTreeSet::new(),
+            read_complete: false,
+            failed: false,
+        });
+        self.order.push_back(transfer_id);
+    }
+
+    /// Receiver answered the `FileTransferRequest`
+    pub fn on_response(&mut self, transfer_id: &str, accepted: bool) {
+        if let Some(flow) = self.flows.get_mut(transfer_id) {
+            if accepted {
+                flow.accepted = true;
+            } else {
+                flow.failed = true;
+            }
+        }
+    }
+
+    /// Receiver confirmed a chunk was written
+    pub fn on_ack(&mut self, transfer_id: &str, chunk_index: u64) {
+        if let Some(flow) = self.flows.get_mut(transfer_id) {
+            flow.in_flight.remove(&chunk_index);
+        }
+    }
+
+    pub fn on_sent(&mut self, transfer_id: &str, chunk_index: u64) {
+        if let Some(flow) = self.flows.get_mut(transfer_id) {
+            flow.in_flight.insert(chunk_index);
+        }
+    }
+
+    /// The file has been read to the end; completes once all chunks are acknowledged
+    pub fn on_read_complete(&mut self, transfer_id: &str) {
+        if let Some(flow) = self.flows.get_mut(transfer_id) {
+            flow.read_complete = true;
+        }
+    }
+
+    pub fn on_failed(&mut self, transfer_id: &str) {
+        if let Some(flow) = self.flows.get_mut(transfer_id) {
+            flow.failed = true;
+        }
+    }
+
+    /// Next transfer allowed to send a chunk, rotating fairly between transfers
+    pub fn next_ready(&mut self) -> Option<String> {
+        for _ in 0..self.order.len() {
+            let transfer_id = self.order.pop_front()?;
+            self.order.push_back(transfer_id.clone());
+
+            if let Some(flow) = self.flows.get(&transfer_id) {
+                let can_send = flow.accepted
+                    && !flow.failed
+                    && !flow.read_complete
+                    && flow.in_flight.len() < self.window_size;
+                if can_send {
+                    return Some(transfer_id);
+                }
+            }
+        }
+
+        None
+    }
+
+    /// Remove and return transfers that are finished: `(transfer_id, success)`
+    pub fn take_finished(&mut self) -> Vec<(String, bool)> {
+        let finished: Vec<(String, bool)> = self.flows
+            .iter()
+            .filter(|(_, flow)| flow.failed || (flow.read_complete && flow.in_flight.is_empty()))
+            .map(|(id, flow)| (id.clone(), !flow.failed))
+            .collect();
+
+        for (transfer_id, _) in &finished {
+            self.flows.remove(transfer_id);
+            self.order.retain(|id| id != transfer_id);
+        }
+
+        finished
+    }
+
+    /// How long to wait before `bytes` may be sent under the bandwidth cap
+    pub fn reserve_bandwidth(&mut self, bytes: usize) -> Duration {
+        match self.limiter.as_mut() {
+            Some(limiter) => limiter.reserve(bytes as u64, Instant::now()),
+            None => Duration::ZERO,
+        }
+    }
+
+    pub fn is_idle(&self) -> bool {
+        self.flows.is_empty()
+    }
+}
+
+/// Token bucket allowing one second worth of burst
+#[derive(Debug)]
+struct RateLimiter {
+    rate: u64, // Bytes per second
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    fn new(rate: u64) -> Self {
+        Self {
+            rate: rate.max(1),
+            tokens: rate as f64,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Take `bytes` tokens, going into debt if needed; returns the time until the debt is repaid
+    fn reserve(&mut self, bytes: u64, now: Instant) -> Duration {
+        let elapsed = now.saturating_duration_since(self.last_refill).as_secs_f64();
+        self.last_refill = now;
+        self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.rate as f64);
+        self.tokens -= bytes as f64;
+
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate as f64)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_window_limits_in_flight_chunks() {
+        let mut scheduler = TransferScheduler::new(2, None);
+        scheduler.add("a".to_string());
+
+        // Nothing moves before the receiver accepts
+        assert_eq!(scheduler.next_ready(), None);
+        scheduler.on_response("a", true);
+
+        scheduler.on_sent("a", 0);
+        scheduler.on_sent("a", 1);
+        assert_eq!(scheduler.next_ready(), None);
+
+        scheduler.on_ack("a", 0);
+        assert_eq!(scheduler.next_ready().as_deref(), Some("a"));
+    }
+
+    #[test]
+    fn test_round_robin_between_transfers() {
+        let mut scheduler = TransferScheduler::new(4, None);
+        for id in ["a", "b", "c"] {
+            scheduler.add(id.to_string());
+            scheduler.on_response(id, true);
+        }
+
+        let picks: Vec<String> = (0..6).filter_map(|_| scheduler.next_ready()).collect();
+        assert_eq!(picks, vec!["a", "b", "c", "a", "b", "c"]);
+    }
+
+    #[test]
+    fn test_finishes_after_last_ack() {
+        let mut scheduler = TransferScheduler::new(4, None);
+        scheduler.add("a".to_string());
+        scheduler.add("b".to_string());
+        scheduler.on_response("a", true);
+        scheduler.on_response("b", false);
+
+        scheduler.on_sent("a", 0);
+        scheduler.on_read_complete("a");
+        assert_eq!(scheduler.take_finished(), vec![("b".to_string(), false)]);
+
+        scheduler.on_ack("a", 0);
+        assert_eq!(scheduler.take_finished(), vec![("a".to_string(), true)]);
+        assert!(scheduler.is_idle());
+    }
+
+    #[test]
+    fn test_rate_limiter() {
+        let start = Instant::now();
+        let mut limiter = RateLimiter {
+            rate: 1000,
+            tokens: 1000.0,
+            last_refill: start,
+        };
+
+        // Burst up to the bucket size is free
+        assert_eq!(limiter.reserve(1000, start), Duration::ZERO);
+
+        // The next 500 bytes need half a second
+        let wait = limiter.reserve(500, start);
+        assert!((wait.as_secs_f64() - 0.5).abs() < 1e-6);
+
+        // After a full second the debt is repaid and 500 tokens remain
+        assert_eq!(limiter.reserve(500, start + Duration::from_secs(1)), Duration::ZERO);
+    }
+}
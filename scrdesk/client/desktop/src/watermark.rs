@@ -0,0 +1,197 @@
+//! Forensic watermark for viewers: who is watching, the session and the time, tiled
+//! faintly across the remote screen so that a photo of it shows where it came from.
+//!
+//! The text is burned into the pixels of each frame as it arrives, before the frame
+//! becomes the remote screen texture, so anything made from the frames, such as a
+//! local recording, carries it as well. Each text pixel is pulled toward black on
+//! light backgrounds and toward white on dark ones, so it shows on any content.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const GLYPH_WIDTH: usize = 5;
+const GLYPH_HEIGHT: usize = 7;
+const SCALE: usize = 2; // Screen pixels per font pixel
+const ALPHA: i32 = 56; // Out of 255
+const ROW_SPACING: usize = GLYPH_HEIGHT * SCALE * 7;
+const TILE_GAP: usize = 96;
+
+/// On when `SCRDESK_WATERMARK` is set; a policy can also make it mandatory
+pub fn enabled_from_env() -> bool {
+    matches!(std::env::var("SCRDESK_WATERMARK").as_deref(), Ok("1" | "true"))
+}
+
+pub struct Watermark {
+    text: String,
+}
+
+impl Watermark {
+    pub fn new(viewer: &str, session_id: &str, unix_secs: u64) -> Self {
+        Self { text: format!("{}  {}  {}", viewer, session_id, utc_timestamp(unix_secs)).to_uppercase() }
+    }
+
+    /// For a frame shown now
+    pub fn now(viewer: &str, session_id: &str) -> Self {
+        let secs = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default();
+        Self::new(viewer, session_id, secs)
+    }
+
+    /// Blend the text into an RGBA frame, tiled over all of it in staggered rows
+    pub fn burn(&self, data: &mut [u8], width: u32, height: u32) {
+        let (width, height) = (width as usize, height as usize);
+        if data.len() < width * height * 4 || self.text.is_empty() {
+            return;
+        }
+        let text_width = self.text.chars().count() * (GLYPH_WIDTH + 1) * SCALE;
+        let period = text_width + TILE_GAP;
+
+        for (row, top) in (0..height).step_by(ROW_SPACING).enumerate() {
+            // Every other row starts half a tile further left
+            let mut left = -((row % 2 * period / 2) as isize);
+            while left < width as isize {
+                self.draw(data, width, height, left, top as isize);
+                left += period as isize;
+            }
+        }
+    }
+
+    fn draw(&self, data: &mut [u8], width: usize, height: usize, left: isize, top: isize) {
+        for (index, c) in self.text.chars().enumerate() {
+            let glyph_left = left + (index * (GLYPH_WIDTH + 1) * SCALE) as isize;
+            for (glyph_y, bits) in glyph(c).iter().enumerate() {
+                for glyph_x in 0..GLYPH_WIDTH {
+                    if bits & (0x10 >> glyph_x) == 0 {
+                        continue;
+                    }
+                    for dy in 0..SCALE {
+                        for dx in 0..SCALE {
+                            let x = glyph_left + (glyph_x * SCALE + dx) as isize;
+                            let y = top + (glyph_y * SCALE + dy) as isize;
+                            if (0..width as isize).contains(&x) && (0..height as isize).contains(&y) {
+                                blend(&mut data[(y as usize * width + x as usize) * 4..][..3]);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn blend(pixel: &mut [u8]) {
+    let light = pixel.iter().map(|&c| c as u32).sum::<u32>() > 3 * 127;
+    let target = if light { 0 } else { 255 };
+    for c in pixel {
+        *c = (*c as i32 + (target - *c as i32) * ALPHA / 255) as u8;
+    }
+}
+
+/// `2023-11-14 22:13:20 UTC`
+fn utc_timestamp(unix_secs: u64) -> String {
+    let (days, secs) = (unix_secs / 86_400, unix_secs % 86_400);
+
+    // Civil date from days since 1970-01-01, after Howard Hinnant's days_from_civil
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + (month <= 2) as i64;
+
+    format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC", year, month, day, secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+/// Rows of a 5x7 glyph, top first, leftmost pixel in bit 4
+fn glyph(c: char) -> [u8; GLYPH_HEIGHT] {
+    match c {
+        'A' => [0x0E, 0x11, 0x11, 0x1F, 0x11, 0x11, 0x11],
+        'B' => [0x1E, 0x11, 0x11, 0x1E, 0x11, 0x11, 0x1E],
+        'C' => [0x0E, 0x11, 0x10, 0x10, 0x10, 0x11, 0x0E],
+        'D' => [0x1C, 0x12, 0x11, 0x11, 0x11, 0x12, 0x1C],
+        'E' => [0x1F, 0x10, 0x10, 0x1E, 0x10, 0x10, 0x1F],
+        'F' => [0x1F, 0x10, 0x10, 0x1E, 0x10, 0x10, 0x10],
+        'G' => [0x0E, 0x11, 0x10, 0x17, 0x11, 0x11, 0x0F],
+        'H' => [0x11, 0x11, 0x11, 0x1F, 0x11, 0x11, 0x11],
+        'I' => [0x0E, 0x04, 0x04, 0x04, 0x04, 0x04, 0x0E],
+        'J' => [0x07, 0x02, 0x02, 0x02, 0x02, 0x12, 0x0C],
+        'K' => [0x11, 0x12, 0x14, 0x18, 0x14, 0x12, 0x11],
+        'L' => [0x10, 0x10, 0x10, 0x10, 0x10, 0x10, 0x1F],
+        'M' => [0x11, 0x1B, 0x15, 0x15, 0x11, 0x11, 0x11],
+        'N' => [0x11, 0x11, 0x19, 0x15, 0x13, 0x11, 0x11],
+        'O' => [0x0E, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0E],
+        'P' => [0x1E, 0x11, 0x11, 0x1E, 0x10, 0x10, 0x10],
+        'Q' => [0x0E, 0x11, 0x11, 0x11, 0x15, 0x12, 0x0D],
+        'R' => [0x1E, 0x11, 0x11, 0x1E, 0x14, 0x12, 0x11],
+        'S' => [0x0F, 0x10, 0x10, 0x0E, 0x01, 0x01, 0x1E],
+        'T' => [0x1F, 0x04, 0x04, 0x04, 0x04, 0x04, 0x04],
+        'U' => [0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0E],
+        'V' => [0x11, 0x11, 0x11, 0x11, 0x11, 0x0A, 0x04],
+        'W' => [0x11, 0x11, 0x11, 0x15, 0x15, 0x15, 0x0A],
+        'X' => [0x11, 0x11, 0x0A, 0x04, 0x0A, 0x11, 0x11],
+        'Y' => [0x11, 0x11, 0x11, 0x0A, 0x04, 0x04, 0x04],
+        'Z' => [0x1F, 0x01, 0x02, 0x04, 0x08, 0x10, 0x1F],
+        '0' => [0x0E, 0x11, 0x13, 0x15, 0x19, 0x11, 0x0E],
+        '1' => [0x04, 0x0C, 0x04, 0x04, 0x04, 0x04, 0x0E],
+        '2' => [0x0E, 0x11, 0x01, 0x02, 0x04, 0x08, 0x1F],
+        '3' => [0x1F, 0x02, 0x04, 0x02, 0x01, 0x11, 0x0E],
+        '4' => [0x02, 0x06, 0x0A, 0x12, 0x1F, 0x02, 0x02],
+        '5' => [0x1F, 0x10, 0x1E, 0x01, 0x01, 0x11, 0x0E],
+        '6' => [0x06, 0x08, 0x10, 0x1E, 0x11, 0x11, 0x0E],
+        '7' => [0x1F, 0x01, 0x02, 0x04, 0x08, 0x08, 0x08],
+        '8' => [0x0E, 0x11, 0x11, 0x0E, 0x11, 0x11, 0x0E],
+        '9' => [0x0E, 0x11, 0x11, 0x0F, 0x01, 0x02, 0x0C],
+        '@' => [0x0E, 0x11, 0x17, 0x15, 0x17, 0x10, 0x0F],
+        '.' => [0x00, 0x00, 0x00, 0x00, 0x00, 0x0C, 0x0C],
+        '-' => [0x00, 0x00, 0x00, 0x1F, 0x00, 0x00, 0x00],
+        '_' => [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x1F],
+        ':' => [0x00, 0x0C, 0x0C, 0x00, 0x0C, 0x0C, 0x00],
+        '+' => [0x00, 0x04, 0x04, 0x1F, 0x04, 0x04, 0x00],
+        '/' => [0x00, 0x01, 0x02, 0x04, 0x08, 0x10, 0x00],
+        ' ' => [0x00; GLYPH_HEIGHT],
+        _ => [0x0E, 0x11, 0x01, 0x02, 0x04, 0x00, 0x04], // ?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_utc_timestamp() {
+        assert_eq!(utc_timestamp(0), "1970-01-01 00:00:00 UTC");
+        assert_eq!(utc_timestamp(1_700_000_000), "2023-11-14 22:13:20 UTC");
+        assert_eq!(utc_timestamp(951_782_400), "2000-02-29 00:00:00 UTC");
+    }
+
+    #[test]
+    fn test_burn_marks_light_and_dark_frames() {
+        let watermark = Watermark::new("ana@example.com", "5f0c", 1_700_000_000);
+        assert_eq!(watermark.text, "ANA@EXAMPLE.COM  5F0C  2023-11-14 22:13:20 UTC");
+
+        let (width, height) = (300u32, 200u32);
+        for background in [0u8, 255] {
+            let mut frame = vec![background; (width * height * 4) as usize];
+            watermark.burn(&mut frame, width, height);
+
+            let marked = frame.chunks(4).filter(|pixel| pixel[0] != background).count();
+            let total = (width * height) as usize;
+            assert!(marked > total / 100 && marked < total / 4, "{} of {} pixels marked", marked, total);
+            assert!(frame.chunks(4).all(|pixel| pixel[3] == background), "alpha is left alone");
+
+            // Faint, and the same every time
+            assert!(frame.iter().all(|&c| (c as i32 - background as i32).abs() <= ALPHA));
+            let mut again = vec![background; frame.len()];
+            watermark.burn(&mut again, width, height);
+            assert_eq!(again, frame);
+        }
+
+        // Frames smaller than a glyph, or shorter than they claim, are left as they are
+        let mut tiny = vec![128; 4];
+        watermark.burn(&mut tiny, 1, 1);
+        let mut short = vec![128; 8];
+        watermark.burn(&mut short, 4, 4);
+        assert_eq!(short, vec![128; 8]);
+    }
+}
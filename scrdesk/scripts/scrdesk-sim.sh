@@ -0,0 +1,22 @@
+#!/bin/bash
+# Headless end-to-end session test: builds the relay and the client, then runs
+# `scrdesk sim`, which starts the relay on free local ports and checks a host and a
+# viewer against it with synthetic capture and recorded input.
+#
+# Usage: scripts/scrdesk-sim.sh [<frames>]
+# RELAY_BIN and CLIENT_BIN skip the builds and use the given binaries.
+set -euo pipefail
+
+ROOT="$(cd "$(dirname "$0")/.." && pwd)"
+
+if [ -z "${RELAY_BIN:-}" ]; then
+    (cd "$ROOT/backend" && cargo build -p scrdesk-relay-cluster)
+    RELAY_BIN="$ROOT/backend/target/debug/scrdesk-relay-cluster"
+fi
+if [ -z "${CLIENT_BIN:-}" ]; then
+    (cd "$ROOT/client/desktop" && cargo build)
+    CLIENT_BIN="$ROOT/client/desktop/target/debug/scrdesk"
+fi
+
+# The relay runs without database or Redis, so it only listens once they time out
+exec "$CLIENT_BIN" sim "$RELAY_BIN" "$@"